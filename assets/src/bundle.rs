@@ -0,0 +1,277 @@
+//! Asset bundles: a set of images and fonts packed together, to be copied into the external
+//! flash so they can be changed without reflashing the firmware.
+//!
+//! A bundle starts with a 12 byte header:
+//!
+//! | offset | size | field                                   |
+//! |--------|------|-----------------------------------------|
+//! | 0      | 4    | magic, `b"DJAB"`                        |
+//! | 4      | 1    | version, currently 1                    |
+//! | 5      | 1    | reserved, must be 0                     |
+//! | 6      | 2    | number of entries (big-endian)          |
+//! | 8      | 4    | CRC-32 of the entry table (big-endian)  |
+//!
+//! Then the table of entries, 48 bytes each:
+//!
+//! | offset | size | field                                              |
+//! |--------|------|----------------------------------------------------|
+//! | 0      | 32   | name, ASCII, padded with 0s                        |
+//! | 32     | 2    | id (big-endian)                                    |
+//! | 34     | 1    | kind, see `AssetKind`                              |
+//! | 35     | 1    | reserved, must be 0                                |
+//! | 36     | 4    | offset of the data from the start of the bundle    |
+//! | 40     | 4    | length of the data                                 |
+//! | 44     | 4    | CRC-32 of the data                                 |
+//!
+//! The data of each entry follows: an image file (see the `image` module) or a font (see the
+//! `font` module), exactly as it would be on its own.
+//!
+//! The table is small and read an entry at a time, so the firmware can look things up without
+//! copying the index into RAM.
+use crate::crc::crc32;
+use core::{fmt, str};
+
+pub const MAGIC: [u8; 4] = *b"DJAB";
+pub const VERSION: u8 = 1;
+pub const HEADER_LEN: usize = 12;
+pub const ENTRY_LEN: usize = 48;
+/// The longest name, in bytes.
+pub const NAME_LEN: usize = 32;
+
+/// What an entry holds.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-impl", derive(defmt::Format))]
+#[repr(u8)]
+pub enum AssetKind {
+    Image = 0,
+    Font = 1,
+}
+
+impl AssetKind {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(AssetKind::Image),
+            1 => Some(AssetKind::Font),
+            _ => None,
+        }
+    }
+}
+
+/// The header at the start of a bundle.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-impl", derive(defmt::Format))]
+pub struct BundleHeader {
+    pub count: u16,
+    pub table_crc: u32,
+}
+
+impl BundleHeader {
+    pub fn to_bytes(&self) -> [u8; HEADER_LEN] {
+        let mut bytes = [0; HEADER_LEN];
+        bytes[0..4].copy_from_slice(&MAGIC);
+        bytes[4] = VERSION;
+        bytes[6..8].copy_from_slice(&self.count.to_be_bytes());
+        bytes[8..12].copy_from_slice(&self.table_crc.to_be_bytes());
+        bytes
+    }
+
+    /// Read and check the header at the start of `data`.
+    pub fn parse(data: &[u8]) -> Result<Self, BundleError> {
+        if data.len() < HEADER_LEN {
+            return Err(BundleError::TooShort);
+        }
+        if data[0..4] != MAGIC {
+            return Err(BundleError::BadMagic);
+        }
+        if data[4] != VERSION {
+            return Err(BundleError::UnsupportedVersion(data[4]));
+        }
+        Ok(BundleHeader {
+            count: u16::from_be_bytes([data[6], data[7]]),
+            table_crc: u32::from_be_bytes([data[8], data[9], data[10], data[11]]),
+        })
+    }
+
+    /// The length of the entry table, in bytes.
+    pub fn table_len(&self) -> usize {
+        usize::from(self.count) * ENTRY_LEN
+    }
+
+    /// Where entry `index` is, from the start of the bundle.
+    pub fn entry_offset(index: u16) -> usize {
+        HEADER_LEN + usize::from(index) * ENTRY_LEN
+    }
+}
+
+/// One asset in the table.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-impl", derive(defmt::Format))]
+pub struct Entry {
+    name: [u8; NAME_LEN],
+    pub id: u16,
+    pub kind: AssetKind,
+    /// From the start of the bundle.
+    pub offset: u32,
+    pub len: u32,
+    pub crc: u32,
+}
+
+impl Entry {
+    /// An entry for `data`, which will be at `offset` in the bundle.
+    pub fn new(
+        name: &str,
+        id: u16,
+        kind: AssetKind,
+        offset: u32,
+        data: &[u8],
+    ) -> Result<Self, BundleError> {
+        if name.len() > NAME_LEN || name.is_empty() || !name.is_ascii() || name.contains('\0') {
+            return Err(BundleError::BadName);
+        }
+        let mut padded = [0; NAME_LEN];
+        padded[..name.len()].copy_from_slice(name.as_bytes());
+        Ok(Entry {
+            name: padded,
+            id,
+            kind,
+            offset,
+            len: data.len() as u32,
+            crc: crc32(data),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        let len = self.name.iter().position(|b| *b == 0).unwrap_or(NAME_LEN);
+        // Checked to be ASCII when the entry was made.
+        str::from_utf8(&self.name[..len]).unwrap_or("")
+    }
+
+    pub fn to_bytes(&self) -> [u8; ENTRY_LEN] {
+        let mut bytes = [0; ENTRY_LEN];
+        bytes[0..32].copy_from_slice(&self.name);
+        bytes[32..34].copy_from_slice(&self.id.to_be_bytes());
+        bytes[34] = self.kind as u8;
+        bytes[36..40].copy_from_slice(&self.offset.to_be_bytes());
+        bytes[40..44].copy_from_slice(&self.len.to_be_bytes());
+        bytes[44..48].copy_from_slice(&self.crc.to_be_bytes());
+        bytes
+    }
+
+    pub fn parse(data: &[u8; ENTRY_LEN]) -> Result<Self, BundleError> {
+        let mut name = [0; NAME_LEN];
+        name.copy_from_slice(&data[0..32]);
+        let len = name.iter().position(|b| *b == 0).unwrap_or(NAME_LEN);
+        if len == 0 || !name[..len].is_ascii() {
+            return Err(BundleError::BadName);
+        }
+        let be32 =
+            |at: usize| u32::from_be_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]]);
+        Ok(Entry {
+            name,
+            id: u16::from_be_bytes([data[32], data[33]]),
+            kind: AssetKind::from_u8(data[34]).ok_or(BundleError::UnknownKind(data[34]))?,
+            offset: be32(36),
+            len: be32(40),
+            crc: be32(44),
+        })
+    }
+
+    /// Where the data ends, from the start of the bundle.
+    pub fn end(&self) -> Option<u32> {
+        self.offset.checked_add(self.len)
+    }
+}
+
+/// Why a bundle (or an asset in it) couldn't be read.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-impl", derive(defmt::Format))]
+pub enum BundleError {
+    /// There isn't enough data for the header and table.
+    TooShort,
+    /// The data doesn't start with `MAGIC`, so it probably isn't a bundle (or hasn't been
+    /// written yet).
+    BadMagic,
+    UnsupportedVersion(u8),
+    /// The entry table doesn't match its CRC.
+    BadTableCrc,
+    UnknownKind(u8),
+    /// A name is empty, too long or not ASCII.
+    BadName,
+    /// An entry's data runs past the end of the bundle.
+    OutOfBounds,
+    /// An entry's data doesn't match its CRC.
+    BadCrc,
+}
+
+impl fmt::Display for BundleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BundleError::TooShort => write!(f, "too short to hold a bundle header and table"),
+            BundleError::BadMagic => write!(f, "not an asset bundle (bad magic number)"),
+            BundleError::UnsupportedVersion(v) => write!(f, "unsupported bundle version {}", v),
+            BundleError::BadTableCrc => write!(f, "the entry table is corrupt (bad CRC)"),
+            BundleError::UnknownKind(kind) => write!(f, "unknown asset kind {}", kind),
+            BundleError::BadName => {
+                write!(f, "asset names must be 1 to {} ASCII characters", NAME_LEN)
+            }
+            BundleError::OutOfBounds => write!(f, "an asset runs past the end of the bundle"),
+            BundleError::BadCrc => write!(f, "an asset is corrupt (bad CRC)"),
+        }
+    }
+}
+
+/// A whole bundle in memory (the firmware reads them from flash instead, an entry at a time).
+#[derive(Debug, Copy, Clone)]
+pub struct Bundle<'a> {
+    header: BundleHeader,
+    data: &'a [u8],
+}
+
+impl<'a> Bundle<'a> {
+    /// Check the header and the table. The assets are checked when they are read.
+    pub fn parse(data: &'a [u8]) -> Result<Self, BundleError> {
+        let header = BundleHeader::parse(data)?;
+        let table = data
+            .get(HEADER_LEN..HEADER_LEN + header.table_len())
+            .ok_or(BundleError::TooShort)?;
+        if crc32(table) != header.table_crc {
+            return Err(BundleError::BadTableCrc);
+        }
+        let bundle = Bundle { header, data };
+        for entry in bundle.entries() {
+            entry?;
+        }
+        Ok(bundle)
+    }
+
+    pub fn header(&self) -> BundleHeader {
+        self.header
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = Result<Entry, BundleError>> + 'a {
+        let data = self.data;
+        (0..self.header.count).map(move |index| {
+            let at = BundleHeader::entry_offset(index);
+            let mut raw = [0; ENTRY_LEN];
+            raw.copy_from_slice(&data[at..at + ENTRY_LEN]);
+            Entry::parse(&raw)
+        })
+    }
+
+    pub fn find(&self, name: &str) -> Option<Entry> {
+        self.entries().flatten().find(|entry| entry.name() == name)
+    }
+
+    /// The data of an entry, checked against its CRC.
+    pub fn get(&self, entry: &Entry) -> Result<&'a [u8], BundleError> {
+        let end = entry.end().ok_or(BundleError::OutOfBounds)?;
+        let data = self
+            .data
+            .get(entry.offset as usize..end as usize)
+            .ok_or(BundleError::OutOfBounds)?;
+        if crc32(data) != entry.crc {
+            return Err(BundleError::BadCrc);
+        }
+        Ok(data)
+    }
+}
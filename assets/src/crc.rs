@@ -0,0 +1,51 @@
+//! CRC-32 (the one used by zip and ethernet), for checking assets that have been copied to flash,
+//! and the records in the settings store.
+//!
+//! Bit at a time rather than with a table: assets are checked when they are loaded and records
+//! are short, so 1K of flash for a table isn't worth it.
+
+/// The reversed polynomial.
+const POLY: u32 = 0xEDB8_8320;
+
+/// Start a CRC. Feed data with `update`, then `finish`.
+pub const INIT: u32 = !0;
+
+pub fn update(mut crc: u32, data: &[u8]) -> u32 {
+    for byte in data {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (POLY & mask);
+        }
+    }
+    crc
+}
+
+pub fn finish(crc: u32) -> u32 {
+    !crc
+}
+
+pub fn crc32(data: &[u8]) -> u32 {
+    finish(update(INIT, data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_value() {
+        // The standard check: the CRC of the ASCII digits 1 to 9.
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn in_pieces() {
+        let data = b"123456789";
+        for split in 0..=data.len() {
+            let (a, b) = data.split_at(split);
+            assert_eq!(finish(update(update(INIT, a), b)), crc32(data));
+        }
+    }
+}
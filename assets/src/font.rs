@@ -0,0 +1,281 @@
+//! Bitmap fonts. The font built into the firmware and fonts in an asset bundle are both files in
+//! this format.
+//!
+//! The layout is:
+//!
+//! | offset | size | field                                                       |
+//! |--------|------|-------------------------------------------------------------|
+//! | 0      | 4    | magic, `b"DJFT"`                                            |
+//! | 4      | 1    | version, currently 1                                        |
+//! | 5      | 1    | reserved, must be 0                                         |
+//! | 6      | 2    | height of every glyph, in pixels (big-endian)               |
+//! | 8      | 6    | the colors of pixel values 1 to 3 (rgb565, big-endian)      |
+//! | 14     | 512  | for each ASCII code, where its glyph is in the glyph data   |
+//! |        |      | (u32, big-endian), or `NO_GLYPH`                            |
+//! | 526    |      | glyph data                                                  |
+//!
+//! Each glyph is its length in pixels (u32, big-endian) followed by 2 bits per pixel, 4 pixels
+//! to a byte with the first in the high bits, row by row (like a 2 bit indexed image, see
+//! [`indexed::unpack`]). Pixel value 0 is transparent.
+use crate::indexed;
+use core::fmt;
+
+pub const MAGIC: [u8; 4] = *b"DJFT";
+pub const VERSION: u8 = 1;
+/// The number of ASCII codes with a slot in the table.
+pub const KEYS: usize = 128;
+/// Where the glyph data starts.
+pub const DATA_OFFSET: usize = 14 + KEYS * 4;
+/// The table entry for a character with no glyph.
+pub const NO_GLYPH: u32 = u32::MAX;
+
+/// The header and table, ready to have the glyph data appended.
+pub fn header(height: u16, palette: [u16; 3], keys: &[u32; KEYS]) -> [u8; DATA_OFFSET] {
+    let mut bytes = [0; DATA_OFFSET];
+    bytes[0..4].copy_from_slice(&MAGIC);
+    bytes[4] = VERSION;
+    bytes[6..8].copy_from_slice(&height.to_be_bytes());
+    for (i, color) in palette.iter().enumerate() {
+        bytes[8 + i * 2..10 + i * 2].copy_from_slice(&color.to_be_bytes());
+    }
+    for (i, key) in keys.iter().enumerate() {
+        bytes[14 + i * 4..18 + i * 4].copy_from_slice(&key.to_be_bytes());
+    }
+    bytes
+}
+
+/// A checked font.
+#[derive(Debug, Copy, Clone)]
+pub struct FontData<'a> {
+    pub height: u16,
+    pub palette: [u16; 3],
+    keys: &'a [u8],
+    /// The glyph data.
+    pub glyphs: &'a [u8],
+}
+
+impl<'a> FontData<'a> {
+    /// Check the header, and that every glyph is inside the data.
+    pub fn parse(data: &'a [u8]) -> Result<Self, FontError> {
+        if data.len() < DATA_OFFSET {
+            return Err(FontError::TooShort);
+        }
+        if data[0..4] != MAGIC {
+            return Err(FontError::BadMagic);
+        }
+        if data[4] != VERSION {
+            return Err(FontError::UnsupportedVersion(data[4]));
+        }
+        let height = u16::from_be_bytes([data[6], data[7]]);
+        if height == 0 {
+            return Err(FontError::ZeroHeight);
+        }
+        let color = |i: usize| u16::from_be_bytes([data[8 + i * 2], data[9 + i * 2]]);
+        let font = FontData {
+            height,
+            palette: [color(0), color(1), color(2)],
+            keys: &data[14..DATA_OFFSET],
+            glyphs: &data[DATA_OFFSET..],
+        };
+        for ch in 0..KEYS as u8 {
+            let offset = match font.glyph_offset(ch) {
+                Some(offset) => offset as usize,
+                None => continue,
+            };
+            let bad_glyph = FontError::BadGlyph(ch);
+            let glyph = font.glyphs.get(offset..).ok_or(bad_glyph)?;
+            let len = glyph.get(..4).ok_or(bad_glyph)?;
+            let len = u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as usize;
+            let fits =
+                matches!(((len + 3) / 4).checked_add(4), Some(bytes) if bytes <= glyph.len());
+            if len % usize::from(height) != 0 || !fits {
+                return Err(bad_glyph);
+            }
+        }
+        Ok(font)
+    }
+
+    /// Where the glyph for `ch` is in the glyph data, if there is one.
+    pub fn glyph_offset(&self, ch: u8) -> Option<u32> {
+        let at = usize::from(ch) * 4;
+        let raw = self.keys.get(at..at + 4)?;
+        match u32::from_be_bytes([raw[0], raw[1], raw[2], raw[3]]) {
+            NO_GLYPH => None,
+            offset => Some(offset),
+        }
+    }
+
+    /// The glyph for `ch`, if the font has one.
+    pub fn glyph(&self, ch: u8) -> Option<Glyph<'a>> {
+        let glyph = self.glyphs.get(self.glyph_offset(ch)? as usize..)?;
+        let len = glyph.get(..4)?;
+        let len = u32::from_be_bytes([len[0], len[1], len[2], len[3]]);
+        let height = u32::from(self.height);
+        Some(Glyph {
+            width: len / height,
+            height,
+            palette: self.palette,
+            pixels: glyph.get(4..4 + (len as usize + 3) / 4)?,
+        })
+    }
+}
+
+/// One character of a font.
+#[derive(Debug, Copy, Clone)]
+pub struct Glyph<'a> {
+    /// In pixels.
+    pub width: u32,
+    /// In pixels, the same for every glyph in the font.
+    pub height: u32,
+    palette: [u16; 3],
+    pixels: &'a [u8],
+}
+
+impl<'a> Glyph<'a> {
+    /// The rgb565 colors of the glyph, row by row, with every pixel repeated to make a `scale` by
+    /// `scale` square. Transparent pixels are `None`.
+    pub fn pixels(&self, scale: u8) -> GlyphPixels<'a> {
+        GlyphPixels {
+            glyph: *self,
+            scale: usize::from(scale),
+            idx: 0,
+        }
+    }
+}
+
+/// See [`Glyph::pixels`].
+#[derive(Debug, Clone)]
+pub struct GlyphPixels<'a> {
+    glyph: Glyph<'a>,
+    scale: usize,
+    /// Which (scaled) pixel is next.
+    idx: usize,
+}
+
+impl Iterator for GlyphPixels<'_> {
+    type Item = Option<u16>;
+
+    fn next(&mut self) -> Option<Option<u16>> {
+        let width = self.glyph.width as usize;
+        let scaled_width = width * self.scale;
+        if self.idx >= scaled_width * self.glyph.height as usize * self.scale {
+            return None;
+        }
+        // Convert scaled co-ords to unscaled
+        let (x, y) = (
+            self.idx % scaled_width / self.scale,
+            self.idx / scaled_width / self.scale,
+        );
+        self.idx += 1;
+        Some(match indexed::unpack(self.glyph.pixels, 2, y * width + x) {
+            0 => None,
+            value => Some(self.glyph.palette[usize::from(value) - 1]),
+        })
+    }
+}
+
+/// Why a font couldn't be read.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-impl", derive(defmt::Format))]
+pub enum FontError {
+    /// There isn't enough data for the header and table.
+    TooShort,
+    /// The data doesn't start with `MAGIC`, so it probably isn't a font.
+    BadMagic,
+    UnsupportedVersion(u8),
+    ZeroHeight,
+    /// The glyph for this character runs past the end of the data, or isn't a whole number of
+    /// rows.
+    BadGlyph(u8),
+}
+
+impl fmt::Display for FontError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FontError::TooShort => write!(f, "too short to hold a font header"),
+            FontError::BadMagic => write!(f, "not a font (bad magic number)"),
+            FontError::UnsupportedVersion(v) => write!(f, "unsupported font version {}", v),
+            FontError::ZeroHeight => write!(f, "font has a height of 0"),
+            FontError::BadGlyph(ch) => write!(f, "the glyph for ascii {} is corrupt", ch),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RED: u16 = 0xF800;
+    const GREEN: u16 = 0x07E0;
+    const BLUE: u16 = 0x001F;
+
+    /// A font 2 pixels high, with a 3 pixel wide `A` and nothing else.
+    fn font_file() -> [u8; DATA_OFFSET + 6] {
+        let mut keys = [NO_GLYPH; KEYS];
+        keys[usize::from(b'A')] = 0;
+        let mut data = [0; DATA_OFFSET + 6];
+        data[..DATA_OFFSET].copy_from_slice(&header(2, [RED, GREEN, BLUE], &keys));
+        // 6 pixels: 1 0 2 / 3 0 1
+        data[DATA_OFFSET..].copy_from_slice(&[0, 0, 0, 6, 0b0100_1011, 0b0001_0000]);
+        data
+    }
+
+    #[test]
+    fn glyph_pixels() {
+        let data = font_file();
+        let font = FontData::parse(&data).unwrap();
+        assert!(font.glyph(b'B').is_none());
+        let glyph = font.glyph(b'A').unwrap();
+        assert_eq!((glyph.width, glyph.height), (3, 2));
+        let expected = [Some(RED), None, Some(GREEN), Some(BLUE), None, Some(RED)];
+        assert!(glyph.pixels(1).eq(expected.iter().copied()));
+
+        let doubled = [
+            [Some(RED), Some(RED), None, None, Some(GREEN), Some(GREEN)],
+            [Some(BLUE), Some(BLUE), None, None, Some(RED), Some(RED)],
+        ];
+        let expected = doubled.iter().flat_map(|row| [row, row]).flatten().copied();
+        assert!(glyph.pixels(2).eq(expected));
+        assert_eq!(glyph.pixels(0).count(), 0);
+    }
+
+    #[test]
+    fn bad_glyphs() {
+        let mut data = font_file();
+        // Not a whole number of rows.
+        data[DATA_OFFSET + 3] = 5;
+        assert_eq!(
+            FontData::parse(&data).unwrap_err(),
+            FontError::BadGlyph(b'A')
+        );
+        // Runs past the end.
+        data[DATA_OFFSET + 3] = 10;
+        assert_eq!(
+            FontData::parse(&data).unwrap_err(),
+            FontError::BadGlyph(b'A')
+        );
+        // A huge length mustn't overflow.
+        data[DATA_OFFSET..DATA_OFFSET + 4].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert_eq!(
+            FontData::parse(&data).unwrap_err(),
+            FontError::BadGlyph(b'A')
+        );
+        // The table points past the end.
+        let mut data = font_file();
+        data[14 + usize::from(b'A') * 4 + 3] = 3;
+        assert_eq!(
+            FontData::parse(&data).unwrap_err(),
+            FontError::BadGlyph(b'A')
+        );
+        data[14 + usize::from(b'A') * 4..18 + usize::from(b'A') * 4]
+            .copy_from_slice(&(u32::MAX - 1).to_be_bytes());
+        assert_eq!(
+            FontData::parse(&data).unwrap_err(),
+            FontError::BadGlyph(b'A')
+        );
+        assert_eq!(
+            FontData::parse(&data[..10]).unwrap_err(),
+            FontError::TooShort
+        );
+    }
+}
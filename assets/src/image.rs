@@ -0,0 +1,561 @@
+//! Image files.
+//!
+//! Every image starts with a 12 byte header:
+//!
+//! | offset | size | field                                         |
+//! |--------|------|-----------------------------------------------|
+//! | 0      | 4    | magic, `b"DJIM"`                              |
+//! | 4      | 1    | version, currently 1                          |
+//! | 5      | 1    | how the pixel data is encoded, see `Encoding` |
+//! | 6      | 1    | flags, see `ImageHeader::MASK_1` and `MASK_4` |
+//! | 7      | 1    | reserved, must be 0                           |
+//! | 8      | 2    | width (big-endian)                            |
+//! | 10     | 2    | height (big-endian)                           |
+//!
+//! The alpha mask (if there is one, see the `mask` module) follows straight after, then the pixel
+//! data.
+//!
+//! QOI files can also be read as they are, see the `qoi` module.
+use crate::{indexed, mask, mask::Alphas, qoi, rle};
+use core::fmt;
+
+/// How the pixels of an image are stored.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-impl", derive(defmt::Format))]
+#[repr(u8)]
+pub enum Encoding {
+    /// 2 bytes per pixel, big-endian rgb565, row by row. This is what the display takes.
+    Rgb565 = 0,
+    /// Run-length encoded rgb565, see the `rle` module.
+    Rle565 = 1,
+    /// Palette-indexed, see the `indexed` module. The number is the bits per pixel.
+    Indexed1 = 2,
+    Indexed2 = 3,
+    Indexed4 = 4,
+    Indexed8 = 5,
+    /// QOI data, see the `qoi` module. This is usually read from a `.qoi` file, with the QOI
+    /// header instead of ours.
+    Qoi = 6,
+}
+
+impl Encoding {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Encoding::Rgb565),
+            1 => Some(Encoding::Rle565),
+            2 => Some(Encoding::Indexed1),
+            3 => Some(Encoding::Indexed2),
+            4 => Some(Encoding::Indexed4),
+            5 => Some(Encoding::Indexed8),
+            6 => Some(Encoding::Qoi),
+            _ => None,
+        }
+    }
+
+    /// The palette-indexed encoding with the fewest bits that fit `colors` colors.
+    pub fn indexed_for(colors: usize) -> Option<Self> {
+        match colors {
+            0..=2 => Some(Encoding::Indexed1),
+            3..=4 => Some(Encoding::Indexed2),
+            5..=16 => Some(Encoding::Indexed4),
+            17..=256 => Some(Encoding::Indexed8),
+            _ => None,
+        }
+    }
+
+    /// The bits per index, for palette-indexed encodings.
+    pub fn index_bits(self) -> Option<u8> {
+        match self {
+            Encoding::Rgb565 | Encoding::Rle565 | Encoding::Qoi => None,
+            Encoding::Indexed1 => Some(1),
+            Encoding::Indexed2 => Some(2),
+            Encoding::Indexed4 => Some(4),
+            Encoding::Indexed8 => Some(8),
+        }
+    }
+}
+
+/// The header at the start of every image.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-impl", derive(defmt::Format))]
+pub struct ImageHeader {
+    pub encoding: Encoding,
+    pub flags: u8,
+    pub width: u16,
+    pub height: u16,
+}
+
+impl ImageHeader {
+    pub const LEN: usize = 12;
+    pub const MAGIC: [u8; 4] = *b"DJIM";
+    pub const VERSION: u8 = 1;
+    /// There is a 1 bit alpha mask.
+    pub const MASK_1: u8 = 0x01;
+    /// There is a 4 bit alpha mask.
+    pub const MASK_4: u8 = 0x02;
+    /// The flags that this version knows about.
+    const KNOWN_FLAGS: u8 = Self::MASK_1 | Self::MASK_4;
+
+    pub fn new(encoding: Encoding, width: u16, height: u16) -> Self {
+        ImageHeader {
+            encoding,
+            flags: 0,
+            width,
+            height,
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; Self::LEN] {
+        let [m0, m1, m2, m3] = Self::MAGIC;
+        let [w0, w1] = self.width.to_be_bytes();
+        let [h0, h1] = self.height.to_be_bytes();
+        [
+            m0,
+            m1,
+            m2,
+            m3,
+            Self::VERSION,
+            self.encoding as u8,
+            self.flags,
+            0,
+            w0,
+            w1,
+            h0,
+            h1,
+        ]
+    }
+
+    /// Read and check the header at the start of `data`.
+    pub fn parse(data: &[u8]) -> Result<Self, ImageError> {
+        if data.len() < Self::LEN {
+            return Err(ImageError::TooShort);
+        }
+        if data[0..4] != Self::MAGIC {
+            return Err(ImageError::BadMagic);
+        }
+        if data[4] != Self::VERSION {
+            return Err(ImageError::UnsupportedVersion(data[4]));
+        }
+        let encoding = Encoding::from_u8(data[5]).ok_or(ImageError::UnknownEncoding(data[5]))?;
+        let flags = data[6];
+        if flags & !Self::KNOWN_FLAGS != 0 || flags == Self::MASK_1 | Self::MASK_4 {
+            return Err(ImageError::UnknownFlags(flags));
+        }
+        Ok(ImageHeader {
+            encoding,
+            flags,
+            width: u16::from_be_bytes([data[8], data[9]]),
+            height: u16::from_be_bytes([data[10], data[11]]),
+        })
+    }
+
+    /// Add an alpha mask with 1 or 4 bits per pixel.
+    pub fn with_mask(mut self, bits: u8) -> Self {
+        self.flags = match bits {
+            1 => Self::MASK_1,
+            4 => Self::MASK_4,
+            _ => panic!("masks must have 1 or 4 bits per pixel"),
+        };
+        self
+    }
+
+    pub fn pixel_count(&self) -> usize {
+        usize::from(self.width) * usize::from(self.height)
+    }
+
+    /// The length of the alpha mask, or `None` if it is too big to address.
+    pub fn mask_len(&self) -> Option<usize> {
+        match self.mask_bits() {
+            Some(bits) => mask::len(self.pixel_count(), bits),
+            None => Some(0),
+        }
+    }
+
+    /// The bits per pixel of the alpha mask, if there is one.
+    pub fn mask_bits(&self) -> Option<u8> {
+        match self.flags {
+            Self::MASK_1 => Some(1),
+            Self::MASK_4 => Some(4),
+            _ => None,
+        }
+    }
+}
+
+/// Why an image couldn't be read.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-impl", derive(defmt::Format))]
+pub enum ImageError {
+    /// There isn't enough data for the header.
+    TooShort,
+    /// The data doesn't start with `ImageHeader::MAGIC`, so it probably isn't an image.
+    BadMagic,
+    /// The image was written by a newer (or older) version of the tools.
+    UnsupportedVersion(u8),
+    UnknownEncoding(u8),
+    UnknownFlags(u8),
+    /// There is less pixel data than the header says (in bytes).
+    Truncated {
+        expected: usize,
+        found: usize,
+    },
+    /// The compressed data decodes to a different number of pixels than the header says.
+    WrongPixelCount {
+        expected: usize,
+        found: usize,
+    },
+    /// A palette-indexed image uses a color that isn't in its palette.
+    BadPaletteIndex(u8),
+    /// A QOI image is too big to fit in our header, or an image has more data than can be
+    /// addressed.
+    TooBig {
+        width: u32,
+        height: u32,
+    },
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ImageError::TooShort => write!(f, "too short to hold an image header"),
+            ImageError::BadMagic => write!(f, "not an image (bad magic number)"),
+            ImageError::UnsupportedVersion(v) => write!(f, "unsupported image version {}", v),
+            ImageError::UnknownEncoding(e) => write!(f, "unknown pixel encoding {}", e),
+            ImageError::UnknownFlags(flags) => write!(f, "unknown flags {:#010b}", flags),
+            ImageError::Truncated { expected, found } => write!(
+                f,
+                "image data is truncated (expected {} bytes, found {})",
+                expected, found
+            ),
+            ImageError::WrongPixelCount { expected, found } => write!(
+                f,
+                "image data has the wrong number of pixels (expected {}, found {})",
+                expected, found
+            ),
+            ImageError::BadPaletteIndex(index) => {
+                write!(f, "palette index {} is past the end of the palette", index)
+            }
+            ImageError::TooBig { width, height } => {
+                write!(f, "image is too big ({}x{})", width, height)
+            }
+        }
+    }
+}
+
+/// A checked image, ready to draw.
+#[derive(Debug, Copy, Clone)]
+pub struct Image<'a> {
+    header: ImageHeader,
+    /// The alpha mask, empty if there isn't one.
+    mask: &'a [u8],
+    /// The pixel data (after the header).
+    data: &'a [u8],
+}
+
+impl<'a> Image<'a> {
+    /// Check the header, and that there is enough pixel data.
+    ///
+    /// For compressed images this runs through all the data.
+    pub fn parse(bytes: &'a [u8]) -> Result<Self, ImageError> {
+        let (header, data) = match bytes.starts_with(&qoi::MAGIC) {
+            true => qoi::parse_header(bytes)?,
+            false => (ImageHeader::parse(bytes)?, &bytes[ImageHeader::LEN..]),
+        };
+        let too_big = ImageError::TooBig {
+            width: header.width.into(),
+            height: header.height.into(),
+        };
+        let mask_len = header.mask_len().ok_or(too_big)?;
+        if data.len() < mask_len {
+            return Err(ImageError::Truncated {
+                expected: mask_len,
+                found: data.len(),
+            });
+        }
+        let (mask, data) = data.split_at(mask_len);
+        match header.encoding {
+            Encoding::Rgb565 => {
+                let expected = header.pixel_count().checked_mul(2).ok_or(too_big)?;
+                if data.len() < expected {
+                    return Err(ImageError::Truncated {
+                        expected,
+                        found: data.len(),
+                    });
+                }
+            }
+            Encoding::Rle565 => {
+                let found = rle::pixel_count(data)?;
+                if found != header.pixel_count() {
+                    return Err(ImageError::WrongPixelCount {
+                        expected: header.pixel_count(),
+                        found,
+                    });
+                }
+            }
+            Encoding::Indexed1 | Encoding::Indexed2 | Encoding::Indexed4 | Encoding::Indexed8 => {
+                let bits = header.encoding.index_bits().unwrap();
+                indexed::Indexed::parse(data, bits, header.pixel_count())?;
+            }
+            Encoding::Qoi => {
+                let mut decoder = qoi::Decoder::new(data, header.pixel_count());
+                let mut cache = qoi::Cache::new();
+                let mut found = 0;
+                while decoder.next_rgba(&mut cache).is_some() {
+                    found += 1;
+                }
+                if found != header.pixel_count() {
+                    return Err(ImageError::WrongPixelCount {
+                        expected: header.pixel_count(),
+                        found,
+                    });
+                }
+            }
+        }
+        Ok(Image { header, mask, data })
+    }
+
+    pub fn header(&self) -> ImageHeader {
+        self.header
+    }
+
+    pub fn width(&self) -> u32 {
+        self.header.width.into()
+    }
+
+    pub fn height(&self) -> u32 {
+        self.header.height.into()
+    }
+
+    /// Whether some pixels are (partly) see-through.
+    pub fn has_mask(&self) -> bool {
+        self.header.mask_bits().is_some()
+    }
+
+    /// The encoded pixel data.
+    pub fn data(&self) -> &'a [u8] {
+        self.data
+    }
+
+    /// Decode the pixels, row by row.
+    pub fn pixels(&self) -> Pixels<'a> {
+        match self.header.encoding {
+            Encoding::Rgb565 => Pixels::Raw(self.data),
+            Encoding::Rle565 => Pixels::Rle(rle::Decoder::new(self.data)),
+            Encoding::Qoi => Pixels::Qoi(qoi::Decoder::new(self.data, self.header.pixel_count())),
+            encoding => {
+                let bits = encoding.index_bits().unwrap_or(8);
+                // Already checked in `parse`.
+                match indexed::Indexed::split(self.data, bits, self.header.pixel_count()) {
+                    Ok(image) => Pixels::Indexed(indexed::Decoder::new(image)),
+                    Err(_) => Pixels::Raw(&[]),
+                }
+            }
+        }
+    }
+
+    /// The alpha of each pixel, row by row.
+    pub fn alphas(&self) -> Alphas<'a> {
+        match self.header.mask_bits() {
+            Some(bits) => Alphas::new(self.mask, bits, self.header.pixel_count()),
+            None => Alphas::Opaque,
+        }
+    }
+}
+
+/// The rgb565 colors of an image, row by row, whatever the encoding.
+///
+/// This is a position in the pixel data, so it is cheap to copy. QOI images also need a
+/// `qoi::Cache` to decode, which has to be passed in (and copied along with the position, see
+/// `fork`). It is ignored for the other encodings.
+#[derive(Debug, Clone)]
+pub enum Pixels<'a> {
+    /// The pixel data that is left.
+    Raw(&'a [u8]),
+    Rle(rle::Decoder<'a>),
+    Indexed(indexed::Decoder<'a>),
+    Qoi(qoi::Decoder<'a>),
+}
+
+impl<'a> Pixels<'a> {
+    /// The next color.
+    pub fn next_color(&mut self, cache: &mut qoi::Cache) -> Option<u16> {
+        match self {
+            Pixels::Raw(data) => {
+                let (color, rest) = match data {
+                    [hi, lo, rest @ ..] => (u16::from_be_bytes([*hi, *lo]), rest),
+                    _ => return None,
+                };
+                *data = rest;
+                Some(color)
+            }
+            Pixels::Rle(decoder) => decoder.next(),
+            Pixels::Indexed(decoder) => decoder.next(),
+            Pixels::Qoi(decoder) => decoder.next_color(cache),
+        }
+    }
+
+    /// Skip `n` pixels. This is quicker than calling `next_color` `n` times.
+    pub fn skip_pixels(&mut self, n: usize, cache: &mut qoi::Cache) {
+        match self {
+            Pixels::Raw(data) => *data = data.get(n * 2..).unwrap_or(&[]),
+            Pixels::Rle(decoder) => decoder.skip(n),
+            Pixels::Indexed(decoder) => decoder.skip(n),
+            Pixels::Qoi(decoder) => decoder.skip(n, cache),
+        }
+    }
+
+    /// A copy that carries on from the same place, and puts a copy of `cache` in `fork_cache` if
+    /// it is needed.
+    pub fn fork(&self, cache: &qoi::Cache, fork_cache: &mut qoi::Cache) -> Self {
+        if let Pixels::Qoi(_) = self {
+            fork_cache.clone_from(cache);
+        }
+        self.clone()
+    }
+}
+
+#[cfg(feature = "defmt-impl")]
+impl defmt::Format for Image<'_> {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(f, "Image {{ {:?} }}", self.header)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::vec::Vec;
+
+    /// A header followed by `data`.
+    fn file(header: ImageHeader, data: &[u8]) -> Vec<u8> {
+        let mut out = header.to_bytes().to_vec();
+        out.extend_from_slice(data);
+        out
+    }
+
+    fn parse_error(bytes: &[u8]) -> ImageError {
+        Image::parse(bytes).unwrap_err()
+    }
+
+    #[test]
+    fn bad_headers() {
+        let header = ImageHeader::new(Encoding::Rgb565, 1, 1).to_bytes();
+        assert_eq!(parse_error(&[]), ImageError::TooShort);
+        assert_eq!(parse_error(&header[..11]), ImageError::TooShort);
+
+        let mut bad = header;
+        bad[0] = b'X';
+        assert_eq!(parse_error(&bad), ImageError::BadMagic);
+
+        let mut bad = header;
+        bad[4] = 2;
+        assert_eq!(parse_error(&bad), ImageError::UnsupportedVersion(2));
+
+        let mut bad = header;
+        bad[5] = 7;
+        assert_eq!(parse_error(&bad), ImageError::UnknownEncoding(7));
+
+        let mut bad = header;
+        bad[6] = 0x04;
+        assert_eq!(parse_error(&bad), ImageError::UnknownFlags(0x04));
+        // A mask can't be both 1 and 4 bits.
+        bad[6] = ImageHeader::MASK_1 | ImageHeader::MASK_4;
+        assert_eq!(parse_error(&bad), ImageError::UnknownFlags(0x03));
+    }
+
+    #[test]
+    fn truncated() {
+        // 4x4 with a 1 bit mask, which needs 2 bytes.
+        let header = ImageHeader::new(Encoding::Rgb565, 4, 4).with_mask(1);
+        assert_eq!(
+            parse_error(&file(header, &[0xff])),
+            ImageError::Truncated {
+                expected: 2,
+                found: 1
+            }
+        );
+        // 2x2 raw pixels need 8 bytes, after the mask.
+        let header = ImageHeader::new(Encoding::Rgb565, 2, 2).with_mask(4);
+        assert_eq!(
+            parse_error(&file(header, &[0xff; 2 + 7])),
+            ImageError::Truncated {
+                expected: 8,
+                found: 7
+            }
+        );
+        assert!(Image::parse(&file(header, &[0xff; 2 + 8])).is_ok());
+    }
+
+    #[test]
+    fn wrong_pixel_count() {
+        // A run of 2 pixels, for a 3 pixel image.
+        let header = ImageHeader::new(Encoding::Rle565, 3, 1);
+        assert_eq!(
+            parse_error(&file(header, &[0x81, 0xf8, 0x00])),
+            ImageError::WrongPixelCount {
+                expected: 3,
+                found: 2
+            }
+        );
+        // A QOI file 3 pixels wide that ends after 1.
+        let mut qoi = qoi::MAGIC.to_vec();
+        qoi.extend(&3u32.to_be_bytes());
+        qoi.extend(&1u32.to_be_bytes());
+        qoi.extend(&[3, 0, qoi::OP_RGB, 0xff, 0, 0]);
+        assert_eq!(
+            parse_error(&qoi),
+            ImageError::WrongPixelCount {
+                expected: 3,
+                found: 1
+            }
+        );
+    }
+
+    /// The background that the firmware draws behind the clock.
+    #[test]
+    fn clock_background() {
+        let raw = Image::parse(include_bytes!("../../data/pictures/clock_bg.rgb565")).unwrap();
+        let rle = Image::parse(include_bytes!("../../data/pictures/clock_bg.rle565")).unwrap();
+        assert_eq!(rle.header().encoding, Encoding::Rle565);
+        assert_eq!((rle.width(), rle.height()), (raw.width(), raw.height()));
+        let colors = |image: &Image| {
+            let mut cache = qoi::Cache::default();
+            let mut pixels = image.pixels();
+            std::iter::from_fn(|| pixels.next_color(&mut cache)).collect::<Vec<_>>()
+        };
+        assert_eq!(colors(&rle), colors(&raw));
+    }
+
+    #[test]
+    fn too_big() {
+        // On the watch, the number of bits in the mask doesn't fit in a `usize`. On the host it
+        // does, but there isn't that much data.
+        let header = ImageHeader::new(Encoding::Rgb565, 65535, 65535).with_mask(4);
+        let expected = if cfg!(target_pointer_width = "32") {
+            ImageError::TooBig {
+                width: 65535,
+                height: 65535,
+            }
+        } else {
+            ImageError::Truncated {
+                expected: header.mask_len().unwrap(),
+                found: 0,
+            }
+        };
+        assert_eq!(parse_error(&file(header, &[])), expected);
+
+        // Too wide for our header.
+        let mut qoi = qoi::MAGIC.to_vec();
+        qoi.extend(&70_000u32.to_be_bytes());
+        qoi.extend(&1u32.to_be_bytes());
+        qoi.extend(&[3, 0]);
+        assert_eq!(
+            parse_error(&qoi),
+            ImageError::TooBig {
+                width: 70_000,
+                height: 1
+            }
+        );
+    }
+}
@@ -0,0 +1,166 @@
+//! Palette-indexed pixels, at 1, 2, 4 or 8 bits per pixel.
+//!
+//! The data starts with the number of palette entries minus 1 (1 byte), then the palette (big-endian
+//! rgb565, 2 bytes per entry), then the palette indices. Indices are packed most significant bits
+//! first, and rows follow straight on from each other without padding. Font glyphs are packed the
+//! same way, so they are both read with [`unpack`].
+use crate::image::ImageError;
+
+/// The palette and index data of an indexed image, after checking them.
+#[derive(Debug, Copy, Clone)]
+pub struct Indexed<'a> {
+    palette: &'a [u8],
+    indices: &'a [u8],
+    bits: u8,
+    pixel_count: usize,
+}
+
+impl<'a> Indexed<'a> {
+    /// Split up and check the data for `pixel_count` pixels of `bits` bits.
+    ///
+    /// This checks every index, so drawing can't read past the palette.
+    pub fn parse(data: &'a [u8], bits: u8, pixel_count: usize) -> Result<Self, ImageError> {
+        let indexed = Self::split(data, bits, pixel_count)?;
+        let mut decoder = Decoder::new(indexed);
+        for _ in 0..pixel_count {
+            let index = decoder.next_index();
+            if usize::from(index) >= indexed.palette_len() {
+                return Err(ImageError::BadPaletteIndex(index));
+            }
+        }
+        Ok(indexed)
+    }
+
+    /// Split up the data, only checking that it is long enough.
+    pub fn split(data: &'a [u8], bits: u8, pixel_count: usize) -> Result<Self, ImageError> {
+        debug_assert!(matches!(bits, 1 | 2 | 4 | 8));
+        let palette_len = match data.first() {
+            Some(len) => usize::from(*len) + 1,
+            None => return Err(ImageError::TooShort),
+        };
+        // If this overflows there can't be enough data.
+        let expected = pixel_count
+            .checked_mul(usize::from(bits))
+            .and_then(|index_bits| ((index_bits + 7) / 8).checked_add(1 + palette_len * 2))
+            .unwrap_or(usize::MAX);
+        if data.len() < expected {
+            return Err(ImageError::Truncated {
+                expected,
+                found: data.len(),
+            });
+        }
+        Ok(Indexed {
+            palette: &data[1..1 + palette_len * 2],
+            indices: &data[1 + palette_len * 2..expected],
+            bits,
+            pixel_count,
+        })
+    }
+
+    pub fn palette_len(&self) -> usize {
+        self.palette.len() / 2
+    }
+
+    fn color(&self, index: u8) -> u16 {
+        let idx = usize::from(index) * 2;
+        match self.palette.get(idx..idx + 2) {
+            Some(bytes) => u16::from_be_bytes([bytes[0], bytes[1]]),
+            None => 0,
+        }
+    }
+}
+
+/// Looks up each index in the palette.
+#[derive(Debug, Clone)]
+pub struct Decoder<'a> {
+    image: Indexed<'a>,
+    /// Which pixel is next.
+    pos: usize,
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(image: Indexed<'a>) -> Self {
+        Decoder { image, pos: 0 }
+    }
+
+    pub fn skip(&mut self, n: usize) {
+        self.pos += n;
+    }
+
+    fn next_index(&mut self) -> u8 {
+        let index = unpack(self.image.indices, self.image.bits, self.pos);
+        self.pos += 1;
+        index
+    }
+}
+
+impl Iterator for Decoder<'_> {
+    type Item = u16;
+
+    fn next(&mut self) -> Option<u16> {
+        if self.pos >= self.image.pixel_count {
+            return None;
+        }
+        let index = self.next_index();
+        Some(self.image.color(index))
+    }
+}
+
+/// Read value number `pos` from `data`, where values are `bits` bits long and packed most
+/// significant bits first. Reads past the end give 0.
+///
+/// Font glyphs (see `assets::font`) are packed the same way, at 2 bits per pixel.
+pub fn unpack(data: &[u8], bits: u8, pos: usize) -> u8 {
+    let bits = usize::from(bits);
+    let bit = pos * bits;
+    let byte = data.get(bit / 8).copied().unwrap_or(0);
+    let shift = 8 - bits - bit % 8;
+    (byte >> shift) & (0xff >> (8 - bits))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unpack_msb_first() {
+        let data = [0b1001_0011, 0b0100_0000];
+        let twos: [u8; 6] = [2, 1, 0, 3, 1, 0];
+        for (pos, value) in twos.iter().enumerate() {
+            assert_eq!(unpack(&data, 2, pos), *value, "2 bits, pos {}", pos);
+        }
+        assert_eq!(unpack(&data, 1, 0), 1);
+        assert_eq!(unpack(&data, 1, 9), 1);
+        assert_eq!(unpack(&data, 4, 1), 0b0011);
+        assert_eq!(unpack(&data, 8, 1), 0b0100_0000);
+        // past the end
+        assert_eq!(unpack(&data, 2, 8), 0);
+    }
+
+    #[test]
+    fn decodes_through_palette() {
+        // 2 colors, 3 pixels at 1 bit: 1, 0, 1
+        let data = [1, 0x12, 0x34, 0xAB, 0xCD, 0b1010_0000];
+        let image = Indexed::parse(&data, 1, 3).unwrap();
+        let expected = [0xABCD, 0x1234, 0xABCD];
+        assert!(Decoder::new(image).eq(expected.iter().copied()));
+        assert_eq!(
+            Indexed::split(&data[..5], 1, 3).unwrap_err(),
+            ImageError::Truncated {
+                expected: 6,
+                found: 5
+            }
+        );
+    }
+    #[test]
+    fn too_many_pixels() {
+        let data = [0, 0x12, 0x34, 0];
+        assert_eq!(
+            Indexed::split(&data, 8, usize::MAX).unwrap_err(),
+            ImageError::Truncated {
+                expected: usize::MAX,
+                found: 4
+            }
+        );
+    }
+}
@@ -0,0 +1,23 @@
+//! File formats for the things we store for the watch to draw: images, fonts, and bundles of
+//! them to go in the external flash.
+//!
+//! Shared between the firmware, which reads them, and `tools`, which writes them. This crate is
+//! `no_std` and doesn't allocate, so the firmware can decode straight out of flash.
+#![no_std]
+
+pub mod bundle;
+pub mod crc;
+pub mod font;
+pub mod image;
+pub mod indexed;
+pub mod mask;
+pub mod qoi;
+pub mod resample;
+pub mod rle;
+
+pub use crate::{
+    bundle::{AssetKind, Bundle, BundleError, Entry},
+    font::{FontData, FontError},
+    image::{Encoding, Image, ImageError, ImageHeader, Pixels},
+    mask::Alphas,
+};
@@ -0,0 +1,84 @@
+//! Alpha masks, which make parts of an image see-through.
+//!
+//! An image with one of the mask flags set has a mask between the header and the pixel data. The
+//! mask has 1 or 4 bits of alpha per pixel, packed most significant bits first with no padding at
+//! the end of rows (like palette indices). 0 is fully transparent, and all bits set is opaque.
+use crate::indexed::unpack;
+
+/// The alpha values of an image, from 0 (transparent) to 255 (opaque), row by row.
+#[derive(Debug, Clone)]
+pub enum Alphas<'a> {
+    /// There is no mask, every pixel is 255.
+    Opaque,
+    Mask {
+        data: &'a [u8],
+        bits: u8,
+        /// Which pixel is next.
+        pos: usize,
+        pixel_count: usize,
+    },
+}
+
+impl<'a> Alphas<'a> {
+    pub fn new(data: &'a [u8], bits: u8, pixel_count: usize) -> Self {
+        debug_assert!(matches!(bits, 1 | 4));
+        Alphas::Mask {
+            data,
+            bits,
+            pos: 0,
+            pixel_count,
+        }
+    }
+
+    /// Skip `n` pixels. (Not `skip`, which would be `Iterator::skip`.)
+    pub fn skip_alphas(&mut self, n: usize) {
+        if let Alphas::Mask { pos, .. } = self {
+            *pos += n;
+        }
+    }
+}
+
+impl Iterator for Alphas<'_> {
+    type Item = u8;
+
+    fn next(&mut self) -> Option<u8> {
+        match self {
+            Alphas::Opaque => Some(255),
+            Alphas::Mask {
+                data,
+                bits,
+                pos,
+                pixel_count,
+            } => {
+                if *pos >= *pixel_count {
+                    return None;
+                }
+                let value = unpack(data, *bits, *pos);
+                *pos += 1;
+                // Stretch to 0..=255, so opaque is opaque.
+                Some(match bits {
+                    1 => value * 255,
+                    _ => value * 17,
+                })
+            }
+        }
+    }
+}
+
+/// The number of bytes in a mask, or `None` if that doesn't fit in a `usize`.
+pub fn len(pixel_count: usize, bits: u8) -> Option<usize> {
+    Some((pixel_count.checked_mul(usize::from(bits))? + 7) / 8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lengths() {
+        assert_eq!(len(7, 4), Some(4));
+        assert_eq!(len(8, 1), Some(1));
+        assert_eq!(len(9, 1), Some(2));
+        assert_eq!(len(usize::MAX / 2, 4), None);
+    }
+}
@@ -0,0 +1,555 @@
+//! Resampling images to the size they are drawn at.
+//!
+//! Drawing maps pixel centers in the destination back onto the source area, so a whole image
+//! scaled by an integer comes out the same as plain pixel doubling. The alpha mask (if any) is
+//! resampled along with the colors.
+use crate::{image::Pixels, mask::Alphas, qoi, Image};
+use enigita::Blend;
+
+/// How to pick colors when an image is drawn at a different size.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-impl", derive(defmt::Format))]
+pub enum Filter {
+    /// Take the closest source pixel. Fast, and keeps pixel art sharp.
+    Nearest,
+    /// Blend the 4 closest source pixels. Smoother, but slower. When shrinking by more than half
+    /// some source pixels are skipped, as with `Nearest`.
+    Bilinear,
+}
+
+/// A rectangle of pixels.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-impl", derive(defmt::Format))]
+pub struct Area {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Area {
+    pub fn new(x: u32, y: u32, width: u32, height: u32) -> Self {
+        Area {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    /// The whole of an image.
+    pub fn of_image(image: &Image) -> Self {
+        Area::new(0, 0, image.width(), image.height())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.width == 0 || self.height == 0
+    }
+
+    /// Whether `other` is completely inside this area.
+    pub fn contains(&self, other: &Area) -> bool {
+        let end = |start: u32, len: u32| u64::from(start) + u64::from(len);
+        other.x >= self.x
+            && other.y >= self.y
+            && end(other.x, other.width) <= end(self.x, self.width)
+            && end(other.y, other.height) <= end(self.y, self.height)
+    }
+
+    /// The part of this area that is also in `other`, which is empty if they don't overlap.
+    pub fn intersection(&self, other: &Area) -> Area {
+        let end = |start: u32, len: u32| u64::from(start) + u64::from(len);
+        let (x, y) = (self.x.max(other.x), self.y.max(other.y));
+        let right = end(self.x, self.width).min(end(other.x, other.width));
+        let bottom = end(self.y, self.height).min(end(other.y, other.height));
+        Area::new(
+            x,
+            y,
+            right.saturating_sub(x.into()) as u32,
+            bottom.saturating_sub(y.into()) as u32,
+        )
+    }
+
+    /// The last column, for an area that isn't empty.
+    fn right(&self) -> u32 {
+        self.x + self.width - 1
+    }
+
+    /// The last row, for an area that isn't empty.
+    fn bottom(&self) -> u32 {
+        self.y + self.height - 1
+    }
+}
+
+/// Maps destination pixels onto an area of an image.
+#[derive(Debug, Copy, Clone)]
+pub struct Sampler<'a> {
+    image: Image<'a>,
+    /// The area of the image to draw. Must be inside the image.
+    source: Area,
+    /// The size to draw it at.
+    width: u32,
+    height: u32,
+    filter: Filter,
+}
+
+impl<'a> Sampler<'a> {
+    /// Draw `source` (which must be inside the image, and not empty) at `width` x `height`.
+    pub fn new(image: Image<'a>, source: Area, width: u32, height: u32, filter: Filter) -> Self {
+        assert!(
+            !source.is_empty() && Area::of_image(&image).contains(&source),
+            "source rect must be inside the image, and not empty"
+        );
+        Sampler {
+            image,
+            source,
+            width,
+            height,
+            filter,
+        }
+    }
+
+    /// The colors and alphas of the destination pixels in `window` (relative to the top-left of
+    /// the drawn image), row by row.
+    ///
+    /// Compressed images can only be decoded from the start, so this works in a single pass over
+    /// the image, with a cursor for each source row that is needed.
+    pub fn pixels(self, window: Area) -> Resampled<'a> {
+        let window = window.intersection(&Area::new(0, 0, self.width, self.height));
+        let skip = self.source.y as usize * self.image.width() as usize;
+        let mut rows_cache = qoi::Cache::new();
+        let mut rows = self.image.pixels();
+        rows.skip_pixels(skip, &mut rows_cache);
+        let mut alpha_rows = self.image.alphas();
+        alpha_rows.skip_alphas(skip);
+        let top = RowCursor::new(
+            &rows,
+            &rows_cache,
+            &alpha_rows,
+            0,
+            self.source.x,
+            self.source.right(),
+        );
+        Resampled {
+            sampler: self,
+            window,
+            pos: (window.x, window.y),
+            rows,
+            rows_cache,
+            alpha_rows,
+            row: self.source.y,
+            bottom: top.clone(),
+            top,
+        }
+    }
+}
+
+/// Iterator returned by [`Sampler::pixels`].
+#[derive(Debug, Clone)]
+pub struct Resampled<'a> {
+    sampler: Sampler<'a>,
+    window: Area,
+    /// The next destination pixel.
+    pos: (u32, u32),
+    /// The image's pixels, from the start of source row `row`.
+    rows: Pixels<'a>,
+    rows_cache: qoi::Cache,
+    /// The mask, from the start of source row `row`.
+    alpha_rows: Alphas<'a>,
+    row: u32,
+    /// The source row at or above the current destination row.
+    top: RowCursor<'a>,
+    /// The source row below `top` (or `top` again at the bottom of the source rect).
+    bottom: RowCursor<'a>,
+}
+
+impl Resampled<'_> {
+    /// Set up the row cursors for a new destination row.
+    fn start_row(&mut self, y: u32) {
+        let s = &self.sampler;
+        let (sy, fy) = map_axis(y, s.source.height, s.height);
+        let y0 = s.source.y + sy;
+        let y1 = (y0 + 1).min(s.source.bottom());
+        // Nearest only needs one row, so pick it now.
+        let (y0, y1) = match s.filter {
+            Filter::Nearest if fy >= 128 => (y1, y1),
+            Filter::Nearest => (y0, y0),
+            Filter::Bilinear => (y0, y1),
+        };
+        let width = s.image.width() as usize;
+        let skip = (y0 - self.row) as usize * width;
+        self.rows.skip_pixels(skip, &mut self.rows_cache);
+        self.alpha_rows.skip_alphas(skip);
+        self.row = y0;
+        let (first, last) = (s.source.x, s.source.right());
+        let cursor = |below| {
+            RowCursor::new(
+                &self.rows,
+                &self.rows_cache,
+                &self.alpha_rows,
+                below,
+                first,
+                last,
+            )
+        };
+        let top = cursor(0);
+        self.bottom = match y1 == y0 {
+            true => top.clone(),
+            false => cursor(width),
+        };
+        self.top = top;
+    }
+}
+
+impl Iterator for Resampled<'_> {
+    /// rgb565 color, and alpha from 0 (transparent) to 255 (opaque).
+    type Item = (u16, u8);
+
+    fn next(&mut self) -> Option<(u16, u8)> {
+        if self.window.is_empty() || self.pos.1 > self.window.bottom() {
+            return None;
+        }
+        let (x, y) = self.pos;
+        if x == self.window.x {
+            self.start_row(y);
+        }
+        self.pos = match x < self.window.right() {
+            true => (x + 1, y),
+            false => (self.window.x, y + 1),
+        };
+
+        let s = &self.sampler;
+        let (sx, fx) = map_axis(x, s.source.width, s.width);
+        let x0 = s.source.x + sx;
+        let (top_left, top_right) = self.top.at(x0);
+        Some(match s.filter {
+            // `top` and `bottom` are the same row.
+            Filter::Nearest if fx >= 128 => top_right,
+            Filter::Nearest => top_left,
+            Filter::Bilinear => {
+                let (_, fy) = map_axis(y, s.source.height, s.height);
+                let (bottom_left, bottom_right) = self.bottom.at(x0);
+                let top = lerp_texel(top_left, top_right, fx);
+                let bottom = lerp_texel(bottom_left, bottom_right, fx);
+                lerp_texel(top, bottom, fy)
+            }
+        })
+    }
+}
+
+/// Reads along one row of the source rect, for columns that only go forwards.
+#[derive(Debug, Clone)]
+struct RowCursor<'a> {
+    /// The image's pixels, after `next`.
+    pixels: Pixels<'a>,
+    cache: qoi::Cache,
+    /// The mask, in step with `pixels`.
+    alphas: Alphas<'a>,
+    /// The column of `cur`.
+    col: u32,
+    /// The last column of the source rect.
+    last: u32,
+    cur: (u16, u8),
+    /// The pixel after `cur`, or `cur` again at the end of the source rect.
+    next: (u16, u8),
+}
+
+impl<'a> RowCursor<'a> {
+    /// A cursor `row_start` pixels on from `rows` and `alpha_rows`, which are left where they are.
+    fn new(
+        rows: &Pixels<'a>,
+        rows_cache: &qoi::Cache,
+        alpha_rows: &Alphas<'a>,
+        row_start: usize,
+        first: u32,
+        last: u32,
+    ) -> Self {
+        let mut cache = qoi::Cache::new();
+        let mut pixels = rows.fork(rows_cache, &mut cache);
+        let mut alphas = alpha_rows.clone();
+        pixels.skip_pixels(row_start + first as usize, &mut cache);
+        alphas.skip_alphas(row_start + first as usize);
+        let mut read = || {
+            (
+                pixels.next_color(&mut cache).unwrap_or(0),
+                alphas.next().unwrap_or(0),
+            )
+        };
+        let cur = read();
+        let next = match first < last {
+            true => read(),
+            false => cur,
+        };
+        RowCursor {
+            pixels,
+            cache,
+            alphas,
+            col: first,
+            last,
+            cur,
+            next,
+        }
+    }
+
+    /// The pixels at `col` and the column after it.
+    fn at(&mut self, col: u32) -> ((u16, u8), (u16, u8)) {
+        while self.col < col {
+            self.col += 1;
+            self.cur = self.next;
+            if self.col < self.last {
+                self.next = (
+                    self.pixels.next_color(&mut self.cache).unwrap_or(0),
+                    self.alphas.next().unwrap_or(0),
+                );
+            }
+        }
+        (self.cur, self.next)
+    }
+}
+
+/// Map a destination position onto a source one, matching up pixel centers.
+///
+/// Returns the source pixel at or before the mapped position, and how far towards the next one it
+/// is, in 256ths.
+fn map_axis(pos: u32, source_len: u32, dest_len: u32) -> (u32, u32) {
+    // (pos + 0.5) * source_len / dest_len - 0.5, in 8.8 fixed point. This needs more than 32 bits
+    // for big images.
+    let mapped = (2 * u64::from(pos) + 1) * u64::from(source_len) * 128 / u64::from(dest_len);
+    let mapped = mapped.saturating_sub(128);
+    ((mapped >> 8) as u32, (mapped & 0xff) as u32)
+}
+
+/// Blend 2 pixels, colors and alphas separately, `t` being 0 (all `a`) to 255 (nearly all `b`).
+fn lerp_texel((a, a_alpha): (u16, u8), (b, b_alpha): (u16, u8), t: u32) -> (u16, u8) {
+    let alpha = (u32::from(a_alpha) * (256 - t) + u32::from(b_alpha) * t + 128) >> 8;
+    (a.blend(b, t as u8), alpha as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::{image::ImageHeader, qoi, Encoding};
+    use std::{vec, vec::Vec};
+
+    const WIDTH: u16 = 7;
+    const HEIGHT: u16 = 6;
+
+    /// Noisy rgb565 colors, and 4 bit alphas.
+    fn source_pixels() -> Vec<(u16, u8)> {
+        let mut state = 12345u32;
+        (0..WIDTH * HEIGHT)
+            .map(|_| {
+                state = state.wrapping_mul(1103515245).wrapping_add(12345);
+                ((state >> 8) as u16, (state >> 28) as u8 * 17)
+            })
+            .collect()
+    }
+
+    fn raw_file(masked: bool) -> Vec<u8> {
+        let pixels = source_pixels();
+        let mut header = ImageHeader::new(Encoding::Rgb565, WIDTH, HEIGHT);
+        if masked {
+            header = header.with_mask(4);
+        }
+        let mut out = header.to_bytes().to_vec();
+        if masked {
+            for pair in pixels.chunks(2) {
+                let alpha = |i: usize| pair.get(i).map_or(0, |(_, a)| a / 17);
+                out.push(alpha(0) << 4 | alpha(1));
+            }
+        }
+        for (color, _) in &pixels {
+            out.extend(&color.to_be_bytes());
+        }
+        out
+    }
+
+    /// All literal packets.
+    fn rle_file() -> Vec<u8> {
+        let header = ImageHeader::new(Encoding::Rle565, WIDTH, HEIGHT);
+        let mut out = header.to_bytes().to_vec();
+        for packet in source_pixels().chunks(16) {
+            out.push(packet.len() as u8 - 1);
+            for (color, _) in packet {
+                out.extend(&color.to_be_bytes());
+            }
+        }
+        out
+    }
+
+    /// Every pixel as an `OP_RGB`, in the top bits of each channel so it decodes to the same
+    /// rgb565.
+    fn qoi_file() -> Vec<u8> {
+        let mut out = qoi::MAGIC.to_vec();
+        out.extend(&u32::from(WIDTH).to_be_bytes());
+        out.extend(&u32::from(HEIGHT).to_be_bytes());
+        out.extend(&[3, 0]);
+        for (color, _) in source_pixels() {
+            let (r, g, b) = (color >> 11, (color >> 5) & 0x3f, color & 0x1f);
+            out.extend(&[qoi::OP_RGB, (r << 3) as u8, (g << 2) as u8, (b << 3) as u8]);
+        }
+        out.extend(&qoi::END_MARKER);
+        out
+    }
+
+    /// Where the center of destination pixel `pos` lands in the source, in source pixels.
+    fn mapped(pos: u32, source_len: u32, dest_len: u32) -> f64 {
+        let centre = (f64::from(pos) + 0.5) * f64::from(source_len) / f64::from(dest_len) - 0.5;
+        centre.max(0.0)
+    }
+
+    /// Straightforward nearest neighbour, with the rounding done exactly.
+    fn reference_nearest(source: Area, width: u32, height: u32, masked: bool) -> Vec<(u16, u8)> {
+        let pixels = source_pixels();
+        let pick = |pos: u32, source_len: u32, dest_len: u32| {
+            // floor(mapped + 0.5), as a fraction.
+            let index = u64::from(2 * pos + 1) * u64::from(source_len) / u64::from(2 * dest_len);
+            (index as u32).min(source_len - 1)
+        };
+        let mut out = Vec::new();
+        for y in 0..height {
+            for x in 0..width {
+                let sx = source.x + pick(x, source.width, width);
+                let sy = source.y + pick(y, source.height, height);
+                let (color, alpha) = pixels[(sy * u32::from(WIDTH) + sx) as usize];
+                out.push((color, if masked { alpha } else { 255 }));
+            }
+        }
+        out
+    }
+
+    /// Bilinear filtering in floating point, one channel at a time. Alphas are blended like
+    /// another channel.
+    fn reference_bilinear(source: Area, width: u32, height: u32) -> Vec<[f64; 4]> {
+        let pixels = source_pixels();
+        let channels = |x: u32, y: u32| {
+            let (color, alpha) = pixels[(y * u32::from(WIDTH) + x) as usize];
+            [
+                f64::from(color >> 11),
+                f64::from((color >> 5) & 0x3f),
+                f64::from(color & 0x1f),
+                f64::from(alpha),
+            ]
+        };
+        let lerp = |a: [f64; 4], b: [f64; 4], t: f64| {
+            let mut out = [0.0; 4];
+            for i in 0..4 {
+                out[i] = a[i] * (1.0 - t) + b[i] * t;
+            }
+            out
+        };
+        let mut out = Vec::new();
+        for y in 0..height {
+            let my = mapped(y, source.height, height);
+            let y0 = source.y + my as u32;
+            let y1 = (y0 + 1).min(source.bottom());
+            for x in 0..width {
+                let mx = mapped(x, source.width, width);
+                let x0 = source.x + mx as u32;
+                let x1 = (x0 + 1).min(source.right());
+                let top = lerp(channels(x0, y0), channels(x1, y0), mx.fract());
+                let bottom = lerp(channels(x0, y1), channels(x1, y1), mx.fract());
+                out.push(lerp(top, bottom, my.fract()));
+            }
+        }
+        out
+    }
+
+    /// Sizes to draw at: the same, bigger, smaller, stretched one way and squashed the other.
+    const SIZES: [(u32, u32); 6] = [(5, 4), (10, 8), (13, 9), (3, 2), (1, 1), (11, 3)];
+
+    fn source_area() -> Area {
+        Area::new(1, 2, 5, 4)
+    }
+
+    #[test]
+    fn nearest_matches_reference() {
+        let files = [
+            (raw_file(false), false),
+            (raw_file(true), true),
+            (rle_file(), false),
+            (qoi_file(), false),
+        ];
+        for (file, masked) in &files {
+            let image = Image::parse(file).unwrap();
+            for (width, height) in SIZES.iter().copied() {
+                let sampler = Sampler::new(image, source_area(), width, height, Filter::Nearest);
+                let got: Vec<_> = sampler.pixels(Area::new(0, 0, width, height)).collect();
+                let expected = reference_nearest(source_area(), width, height, *masked);
+                assert_eq!(got, expected, "{:?} at {}x{}", image, width, height);
+            }
+        }
+    }
+
+    #[test]
+    fn bilinear_matches_reference() {
+        let image_file = raw_file(true);
+        let image = Image::parse(&image_file).unwrap();
+        for (width, height) in SIZES.iter().copied() {
+            let sampler = Sampler::new(image, source_area(), width, height, Filter::Bilinear);
+            let got = sampler.pixels(Area::new(0, 0, width, height));
+            let expected = reference_bilinear(source_area(), width, height);
+            for (i, ((color, alpha), want)) in got.zip(expected).enumerate() {
+                let channels = [color >> 11, (color >> 5) & 0x3f, color & 0x1f];
+                for (channel, want) in channels.iter().zip(&want) {
+                    let error = (f64::from(*channel) - want).abs();
+                    assert!(
+                        error <= 1.0,
+                        "{}x{} pixel {}: {:#06x}",
+                        width,
+                        height,
+                        i,
+                        color
+                    );
+                }
+                // Rounded twice, over a bigger range than the colors.
+                let error = (f64::from(alpha) - want[3]).abs();
+                assert!(
+                    error <= 2.0,
+                    "{}x{} pixel {}: alpha {}, not {}",
+                    width,
+                    height,
+                    i,
+                    alpha,
+                    want[3]
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn windows_match_the_whole() {
+        for image_file in &[rle_file(), qoi_file()] {
+            let image = Image::parse(image_file).unwrap();
+            for filter in [Filter::Nearest, Filter::Bilinear].iter().copied() {
+                let (width, height) = (13, 9);
+                let sampler = Sampler::new(image, source_area(), width, height, filter);
+                let whole: Vec<_> = sampler.pixels(Area::new(0, 0, width, height)).collect();
+                // Part of the image, and one that hangs off the bottom right.
+                for window in [Area::new(3, 2, 6, 5), Area::new(10, 7, 8, 8)].iter() {
+                    let got: Vec<_> = sampler.pixels(*window).collect();
+                    let mut expected = vec![];
+                    for y in window.y..(window.y + window.height).min(height) {
+                        for x in window.x..(window.x + window.width).min(width) {
+                            expected.push(whole[(y * width + x) as usize]);
+                        }
+                    }
+                    assert_eq!(got, expected, "{:?} {:?}", filter, window);
+                }
+                assert_eq!(sampler.pixels(Area::new(20, 0, 5, 5)).count(), 0);
+            }
+        }
+    }
+
+    #[test]
+    fn map_axis_big_images() {
+        assert_eq!(map_axis(0, 1, 2), (0, 0));
+        assert_eq!(map_axis(1, 1, 2), (0, 64));
+        assert_eq!(map_axis(3, 2, 4), (1, 64));
+        // (2 * pos + 1) * source_len * 128 doesn't fit in 32 bits.
+        assert_eq!(map_axis(65534, 65535, 65535), (65534, 0));
+        assert_eq!(map_axis(0, 65535, 1), (32767, 0));
+        assert_eq!(map_axis(0, 65535, 65535), (0, 0));
+    }
+}
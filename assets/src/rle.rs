@@ -0,0 +1,212 @@
+//! Run-length encoded rgb565 pixels.
+//!
+//! The pixels are stored row by row as a series of packets, which can run over the end of a row.
+//! Each packet starts with a control byte `c`:
+//!
+//! - `c & 0x80 != 0`: a run. The next pixel (2 bytes, big-endian) is repeated `(c & 0x7f) + 1`
+//!   times.
+//! - otherwise: literals. The next `c + 1` pixels are copied as they are.
+//!
+//! Flat areas (backgrounds, UI elements) shrink a lot, and noisy areas cost 1 extra byte per 128
+//! pixels.
+use crate::image::ImageError;
+
+/// The most pixels in one packet.
+pub const MAX_PACKET: usize = 128;
+const RUN: u8 = 0x80;
+
+/// Count the pixels in some RLE data, checking that the packets are whole.
+pub fn pixel_count(data: &[u8]) -> Result<usize, ImageError> {
+    let mut pixels = 0;
+    let mut pos = 0;
+    while pos < data.len() {
+        let (count, len) = packet(data[pos]);
+        pos += len;
+        if pos > data.len() {
+            return Err(ImageError::Truncated {
+                expected: pos,
+                found: data.len(),
+            });
+        }
+        pixels += count;
+    }
+    Ok(pixels)
+}
+
+/// The number of pixels in a packet, and how many bytes it takes up (including the control
+/// byte).
+fn packet(control: u8) -> (usize, usize) {
+    let count = usize::from(control & !RUN) + 1;
+    if control & RUN != 0 {
+        (count, 3)
+    } else {
+        (count, 1 + count * 2)
+    }
+}
+
+/// Decodes RLE data into rgb565 colors.
+#[derive(Debug, Clone)]
+pub struct Decoder<'a> {
+    data: &'a [u8],
+    /// Where the next packet starts.
+    pos: usize,
+    /// Pixels left in the current packet.
+    left: usize,
+    /// The color in a run, or `None` in a literal packet.
+    run: Option<u16>,
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Decoder {
+            data,
+            pos: 0,
+            left: 0,
+            run: None,
+        }
+    }
+
+    /// Skip `n` pixels, a packet at a time where possible.
+    pub fn skip(&mut self, mut n: usize) {
+        loop {
+            let step = n.min(self.left);
+            self.left -= step;
+            if self.run.is_none() {
+                self.pos += step * 2;
+            }
+            n -= step;
+            if n == 0 || !self.start_packet() {
+                return;
+            }
+        }
+    }
+
+    /// Move on to the next packet. Returns false at the end of the data.
+    fn start_packet(&mut self) -> bool {
+        let control = match self.data.get(self.pos) {
+            Some(c) => *c,
+            None => return false,
+        };
+        let (count, _) = packet(control);
+        self.pos += 1;
+        self.left = count;
+        self.run = None;
+        if control & RUN != 0 {
+            self.run = self.read_pixel();
+        }
+        true
+    }
+
+    fn read_pixel(&mut self) -> Option<u16> {
+        let bytes = self.data.get(self.pos..self.pos + 2)?;
+        self.pos += 2;
+        Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+}
+
+impl Iterator for Decoder<'_> {
+    type Item = u16;
+
+    fn next(&mut self) -> Option<u16> {
+        if self.left == 0 && !self.start_packet() {
+            return None;
+        }
+        self.left -= 1;
+        match self.run {
+            Some(color) => Some(color),
+            None => self.read_pixel(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::vec::Vec;
+
+    /// A run packet of `len` (1 to 128) pixels.
+    fn run(len: usize, color: u16) -> Vec<u8> {
+        let mut out = std::vec![RUN | (len - 1) as u8];
+        out.extend_from_slice(&color.to_be_bytes());
+        out
+    }
+
+    /// A literal packet of 1 to 128 pixels.
+    fn literal(colors: &[u16]) -> Vec<u8> {
+        let mut out = std::vec![(colors.len() - 1) as u8];
+        for color in colors {
+            out.extend_from_slice(&color.to_be_bytes());
+        }
+        out
+    }
+
+    /// Runs of 2, 128 and 129 (which takes two packets), then 128 literals.
+    fn packets() -> (Vec<u8>, Vec<u16>) {
+        let literals: Vec<u16> = (0..128).map(|i| i * 3).collect();
+        let data = [
+            run(2, 0x1111),
+            run(128, 0x2222),
+            run(128, 0x3333),
+            run(1, 0x3333),
+            literal(&literals),
+        ]
+        .concat();
+        let mut pixels = std::vec![0x1111; 2];
+        pixels.extend(std::iter::repeat(0x2222).take(128));
+        pixels.extend(std::iter::repeat(0x3333).take(129));
+        pixels.extend(literals);
+        (data, pixels)
+    }
+
+    #[test]
+    fn decodes_runs_and_literals() {
+        let (data, pixels) = packets();
+        assert_eq!(pixel_count(&data), Ok(pixels.len()));
+        assert_eq!(Decoder::new(&data).collect::<Vec<_>>(), pixels);
+        assert_eq!(pixel_count(&[]), Ok(0));
+    }
+
+    #[test]
+    fn skips_into_the_middle_of_packets() {
+        let (data, pixels) = packets();
+        // Inside the first run, at the end of it, inside the run of 129, between its two packets,
+        // inside the literals, and past the end.
+        for n in [1, 2, 200, 258, 300, pixels.len(), pixels.len() + 5] {
+            let mut decoder = Decoder::new(&data);
+            // Not `Iterator::skip`.
+            Decoder::skip(&mut decoder, n);
+            let rest: Vec<u16> = pixels.iter().copied().skip(n).collect();
+            assert_eq!(decoder.collect::<Vec<_>>(), rest, "skipping {}", n);
+        }
+        // A skip that ends inside a run, then another from there.
+        let mut decoder = Decoder::new(&data);
+        Decoder::skip(&mut decoder, 50);
+        assert_eq!(decoder.next(), Some(0x2222));
+        Decoder::skip(&mut decoder, 200);
+        assert_eq!(decoder.next(), Some(pixels[251]));
+    }
+
+    #[test]
+    fn counts_truncated_packets() {
+        let (data, _) = packets();
+        // The run is missing its second color byte.
+        assert_eq!(
+            pixel_count(&data[..2]),
+            Err(ImageError::Truncated {
+                expected: 3,
+                found: 2
+            })
+        );
+        // The literal packet is missing its last pixel.
+        let len = data.len();
+        assert_eq!(
+            pixel_count(&data[..len - 2]),
+            Err(ImageError::Truncated {
+                expected: len,
+                found: len - 2
+            })
+        );
+    }
+}
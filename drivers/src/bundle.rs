@@ -0,0 +1,244 @@
+//! Looking assets up in an asset bundle (see `assets::bundle`, and `tools pack`) in the external
+//! flash.
+//!
+//! The table is read an entry at a time, so lookups don't need any RAM for the index. Lookups
+//! give back a handle that holds the asset's place and CRC, so it stays valid until the bundle is
+//! rewritten. Small assets are copied into an `AssetBuf` and checked against their CRC there;
+//! big ones can be checked without keeping them, and then streamed from the flash.
+use assets::{
+    bundle::{BundleHeader, ENTRY_LEN, HEADER_LEN, NAME_LEN},
+    crc, AssetKind, BundleError, Entry, FontError, ImageError,
+};
+use embedded_storage::nor_flash::ReadNorFlash;
+use heapless::String;
+
+pub type AssetName = String<NAME_LEN>;
+
+/// How to find an asset: by its name (the file it was packed from), or its id.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "defmt-impl", derive(defmt::Format))]
+pub enum AssetKey {
+    Name(AssetName),
+    /// Ids are given out in order of name, so they change if files are added to the bundle.
+    Id(u16),
+}
+
+impl AssetKey {
+    fn matches(&self, entry: &Entry) -> bool {
+        match self {
+            AssetKey::Name(name) => entry.name() == name.as_str(),
+            AssetKey::Id(id) => entry.id == *id,
+        }
+    }
+}
+
+/// Where a bundle is in the flash: it starts at `start`, and its assets must end before `end`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct FlashBundle {
+    start: u32,
+    end: u32,
+}
+
+impl FlashBundle {
+    pub const fn new(start: u32, end: u32) -> Self {
+        FlashBundle { start, end }
+    }
+
+    /// Look an asset up in the bundle.
+    pub fn find<F: ReadNorFlash>(
+        &self,
+        flash: &mut F,
+        key: &AssetKey,
+    ) -> Result<Option<Asset>, AssetError<F::Error>> {
+        let header = self.read_header(flash)?;
+        for index in 0..header.count {
+            let entry =
+                Entry::parse(&self.read_entry(flash, index)?).map_err(AssetError::Bundle)?;
+            if key.matches(&entry) {
+                return self.asset(&entry).map(Some);
+            }
+        }
+        Ok(None)
+    }
+
+    /// Read the header, and check the table against its CRC before anything in it is trusted.
+    pub fn read_header<F: ReadNorFlash>(
+        &self,
+        flash: &mut F,
+    ) -> Result<BundleHeader, AssetError<F::Error>> {
+        let mut raw = [0; HEADER_LEN];
+        flash
+            .read(self.start, &mut raw)
+            .map_err(AssetError::Flash)?;
+        let header = BundleHeader::parse(&raw).map_err(AssetError::Bundle)?;
+        let mut table_crc = crc::INIT;
+        for index in 0..header.count {
+            table_crc = crc::update(table_crc, &self.read_entry(flash, index)?);
+        }
+        match crc::finish(table_crc) == header.table_crc {
+            true => Ok(header),
+            false => Err(AssetError::Bundle(BundleError::BadTableCrc)),
+        }
+    }
+
+    fn read_entry<F: ReadNorFlash>(
+        &self,
+        flash: &mut F,
+        index: u16,
+    ) -> Result<[u8; ENTRY_LEN], AssetError<F::Error>> {
+        let mut raw = [0; ENTRY_LEN];
+        let address = self.start + BundleHeader::entry_offset(index) as u32;
+        flash.read(address, &mut raw).map_err(AssetError::Flash)?;
+        Ok(raw)
+    }
+
+    fn asset<E>(&self, entry: &Entry) -> Result<Asset, AssetError<E>> {
+        match entry.end() {
+            Some(end) if end <= self.end - self.start => Ok(Asset {
+                id: entry.id,
+                kind: entry.kind,
+                address: self.start + entry.offset,
+                len: entry.len,
+                crc: entry.crc,
+            }),
+            _ => Err(AssetError::Bundle(BundleError::OutOfBounds)),
+        }
+    }
+}
+
+/// Where an asset is in the flash.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-impl", derive(defmt::Format))]
+pub struct Asset {
+    pub id: u16,
+    pub kind: AssetKind,
+    /// The address of the start of the asset.
+    pub address: u32,
+    pub len: u32,
+    pub crc: u32,
+}
+
+impl Asset {
+    pub fn image(self) -> Option<ImageAsset> {
+        match self.kind {
+            AssetKind::Image => Some(ImageAsset(self)),
+            _ => None,
+        }
+    }
+
+    pub fn font(self) -> Option<FontAsset> {
+        match self.kind {
+            AssetKind::Font => Some(FontAsset(self)),
+            _ => None,
+        }
+    }
+}
+
+/// An asset that is known to be an image.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-impl", derive(defmt::Format))]
+pub struct ImageAsset(Asset);
+
+impl ImageAsset {
+    pub fn asset(self) -> Asset {
+        self.0
+    }
+}
+
+/// An asset that is known to be a font.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-impl", derive(defmt::Format))]
+pub struct FontAsset(Asset);
+
+impl FontAsset {
+    pub fn asset(self) -> Asset {
+        self.0
+    }
+}
+
+/// Why an asset couldn't be found or loaded.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-impl", derive(defmt::Format))]
+pub enum AssetError<E> {
+    Flash(E),
+    /// The bundle is missing or corrupt, or the asset doesn't match its CRC.
+    Bundle(BundleError),
+    Image(ImageError),
+    Font(FontError),
+    /// The asset is this long, and doesn't fit in the `AssetBuf`.
+    TooBig(u32),
+}
+
+/// RAM to load an asset into, which keeps the last one loaded (usually a font that is drawn
+/// over and over).
+pub struct AssetBuf {
+    data: &'static mut [u8],
+    loaded: Option<Asset>,
+    /// The last asset that was too big to load, but was checked with `check`.
+    checked: Option<Asset>,
+}
+
+impl AssetBuf {
+    pub fn new(data: &'static mut [u8]) -> Self {
+        AssetBuf {
+            data,
+            loaded: None,
+            checked: None,
+        }
+    }
+
+    /// The asset's data, checked against its CRC.
+    pub fn load<F: ReadNorFlash>(
+        &mut self,
+        flash: &mut F,
+        asset: Asset,
+    ) -> Result<&[u8], AssetError<F::Error>> {
+        let len = asset.len as usize;
+        if len > self.data.len() {
+            return Err(AssetError::TooBig(asset.len));
+        }
+        if self.loaded != Some(asset) {
+            self.loaded = None;
+            let data = &mut self.data[..len];
+            flash.read(asset.address, data).map_err(AssetError::Flash)?;
+            if crc::crc32(data) != asset.crc {
+                return Err(AssetError::Bundle(BundleError::BadCrc));
+            }
+            self.loaded = Some(asset);
+        }
+        Ok(&self.data[..len])
+    }
+
+    /// Check an asset against its CRC without keeping it, for assets too big to load. The last
+    /// one checked is remembered, so drawing the same big image again only reads it once.
+    pub fn check<F: ReadNorFlash>(
+        &mut self,
+        flash: &mut F,
+        asset: Asset,
+    ) -> Result<(), AssetError<F::Error>> {
+        if self.checked == Some(asset) {
+            return Ok(());
+        }
+        self.checked = None;
+        let mut buf = [0; 256];
+        let mut crc = crc::INIT;
+        let end = asset.address + asset.len;
+        for address in (asset.address..end).step_by(buf.len()) {
+            let chunk_len = ((end - address) as usize).min(buf.len());
+            let chunk = &mut buf[..chunk_len];
+            flash.read(address, chunk).map_err(AssetError::Flash)?;
+            crc = crc::update(crc, chunk);
+        }
+        if crc::finish(crc) != asset.crc {
+            return Err(AssetError::Bundle(BundleError::BadCrc));
+        }
+        self.checked = Some(asset);
+        Ok(())
+    }
+
+    /// Forget what was loaded and checked, because the flash has changed.
+    pub fn invalidate(&mut self) {
+        self.loaded = None;
+        self.checked = None;
+    }
+}
@@ -0,0 +1,33 @@
+//! What the drivers need from the hardware, beyond the `embedded-hal` blocking SPI and pins.
+//!
+//! On the watch these are embassy's SPIM and timer. In the `sim` crate they are the mock bus and
+//! a clock that only moves when something waits on it.
+use core::{fmt::Debug, future::Future};
+
+/// Writing to the SPI bus without holding up the executor (with EasyDMA, on the watch).
+pub trait AsyncWrite {
+    type Error: Debug;
+    type WriteFuture<'a>: Future<Output = Result<(), Self::Error>> + 'a
+    where
+        Self: 'a;
+
+    /// Send `data`. On the watch it must be in RAM, as EasyDMA can't read from the chip's own
+    /// flash.
+    fn write<'a>(&'a mut self, data: &'a [u8]) -> Self::WriteFuture<'a>;
+}
+
+/// Waiting between commands, for the chips' timing requirements.
+pub trait Clock {
+    type Delay: Future<Output = ()>;
+
+    /// Wait at least `micros` microseconds.
+    fn delay_micros(&self, micros: u64) -> Self::Delay;
+
+    fn delay_millis(&self, millis: u64) -> Self::Delay {
+        self.delay_micros(millis * 1000)
+    }
+
+    /// Microseconds since some fixed point (e.g. boot), for waits that start before a command
+    /// and end after it.
+    fn now_micros(&self) -> u64;
+}
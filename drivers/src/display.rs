@@ -0,0 +1,1113 @@
+//! Driver for the ST7789 display controller on the PineTime.
+//!
+//! The panel is 240x240, but the controller has 320 lines of video memory, and supports hardware
+//! scrolling, partial mode, rotation and a few pixel formats. [`Display`] keeps track of all of
+//! these in a [`DisplayState`], so that drawing always happens in screen coordinates.
+use core::{convert::Infallible, fmt::Debug, iter, mem};
+use embedded_graphics::primitives::{ContainsPoint, PointsIter};
+pub use embedded_graphics::{
+    geometry::{Point, Size},
+    pixelcolor::{IntoStorage, Rgb565, RgbColor},
+    primitives::Rectangle,
+};
+use embedded_hal::{blocking, digital::v2::OutputPin};
+use enigita::Blend;
+use futures::future;
+
+use crate::{
+    bus::{AsyncWrite, Clock},
+    EASY_DMA_SIZE,
+};
+
+mod backlight;
+mod flash_image;
+mod format;
+mod graphics;
+mod image;
+mod panel;
+mod rotation;
+mod scene;
+mod status;
+pub mod text;
+
+pub use self::{
+    backlight::{Backlight, PinConfig, Pins},
+    flash_image::{FlashImage, Unstreamable},
+    format::PixelFormat,
+    graphics::{Graphic, Graphics, Style, MAX_GRAPHICS},
+    image::{bounds, Filter, Image},
+    panel::PanelConfig,
+    rotation::{Orientation, Rotation},
+    scene::{Scene, MAX_DRAWABLES},
+    status::{DisplayId, DisplayStatus},
+};
+use self::{image::Resampled, text::Font};
+
+pub const DISPLAY_WIDTH: usize = 240;
+pub const DISPLAY_HEIGHT: usize = 240;
+pub const DISPLAY_PIXELS: usize = DISPLAY_WIDTH * DISPLAY_HEIGHT;
+/// The number of lines in the display controller's video memory. Only the first
+/// `DISPLAY_HEIGHT` are visible.
+const GRAM_LINES: u16 = 320;
+/// How long the panel needs after `SleepIn` (or a reset) before it can take `SleepOut`.
+const SLEEP_IN_MICROS: u64 = 120_000;
+/// How long the panel takes to reset, before it will take commands.
+const RESET_MICROS: u64 = 5_000;
+
+/// Display settings that persist between commands (and across sleep).
+#[derive(Debug, Default)]
+#[cfg_attr(feature = "defmt-impl", derive(defmt::Format))]
+pub struct DisplayState {
+    pub power: PanelPower,
+    pub scroll: ScrollState,
+    pub mode: DisplayMode,
+    pub rotation: Rotation,
+    pub panel: PanelConfig,
+    pub pixel_format: PixelFormat,
+    /// When `SleepIn` was last sent, by `Clock::now_micros`. The panel can't take `SleepOut`
+    /// until `SLEEP_IN_MICROS` after that.
+    pub sleep_in_at: Option<u64>,
+}
+
+/// Whether the panel is awake, and whether it still has the settings we sent it.
+///
+/// ```text
+/// Uninitialised --SleepOff (full init)--> Active --SleepOn--> Sleeping
+///                                         Active <--SleepOff-- Sleeping
+/// any --PowerOff--> Off --SleepOff (full init)--> Active
+/// ```
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-impl", derive(defmt::Format))]
+pub enum PanelPower {
+    /// The panel has just been reset (at boot, or by `check_panel`), so it is asleep with its
+    /// registers at their defaults.
+    Uninitialised,
+    /// Set up, awake and showing what is in its memory.
+    Active,
+    /// In sleep mode. Registers and memory are kept, so waking only takes `SleepOut`.
+    Sleeping,
+    /// Held in reset. Everything is lost.
+    Off,
+}
+
+impl Default for PanelPower {
+    fn default() -> Self {
+        PanelPower::Uninitialised
+    }
+}
+
+/// How much of the panel is lit.
+#[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "defmt-impl", derive(defmt::Format))]
+pub enum DisplayMode {
+    /// The whole panel.
+    Normal,
+    /// Only panel lines `start..=end`. The rest of the panel is black.
+    Partial { start: u16, end: u16 },
+}
+
+impl Default for DisplayMode {
+    fn default() -> Self {
+        DisplayMode::Normal
+    }
+}
+
+/// The hardware scrolling setup, in panel lines.
+#[derive(Debug, Default, Copy, Clone)]
+#[cfg_attr(feature = "defmt-impl", derive(defmt::Format))]
+pub struct ScrollState {
+    /// Lines at the top of the panel that don't scroll.
+    pub top_fixed: u16,
+    /// Visible lines at the bottom of the panel that don't scroll.
+    pub bottom_fixed: u16,
+    /// How many lines into the scroll area the panel starts showing it.
+    pub offset: u16,
+}
+
+impl ScrollState {
+    /// The number of visible lines that scroll.
+    pub fn scroll_area(&self) -> u16 {
+        DISPLAY_HEIGHT as u16 - self.top_fixed - self.bottom_fixed
+    }
+
+    /// The video memory line that is currently shown on the given panel line.
+    pub fn gram_line(&self, line: u16) -> u16 {
+        let scroll_end = self.top_fixed + self.scroll_area();
+        if line < self.top_fixed || line >= scroll_end {
+            line
+        } else {
+            self.top_fixed + (line - self.top_fixed + self.offset) % self.scroll_area()
+        }
+    }
+}
+
+/// What to show behind text or masked images, where they are transparent.
+#[cfg_attr(feature = "defmt-impl", derive(defmt::Format))]
+pub enum Background {
+    Color(Rgb565),
+    /// Show the matching part of an image, so things can be drawn over it without a box around
+    /// them.
+    ///
+    /// This should be the same image (at the same place) that is already on screen. Anything that
+    /// goes past the edge of the image (or through its mask) has a black background.
+    Image(PlacedImage<'static>),
+}
+
+impl Background {
+    /// The background colors for an area of the screen, row by row.
+    fn pixels(&self, area: Rectangle) -> impl Iterator<Item = u16> + Clone + '_ {
+        let (fill, covered, mut image_pixels) = match self {
+            Background::Color(color) => (color.into_storage(), Rectangle::zero(), None),
+            Background::Image(image) => {
+                let covered = image.area().intersection(&area);
+                let pixels = image.pixels_in(covered);
+                (Rgb565::BLACK.into_storage(), covered, Some(pixels))
+            }
+        };
+        area.points().map(
+            move |point| match (&mut image_pixels, covered.contains(point)) {
+                (Some(pixels), true) => pixels
+                    .next()
+                    .map_or(fill, |(color, alpha)| fill.blend(color, alpha)),
+                _ => fill,
+            },
+        )
+    }
+}
+
+/// An image, and where and how to draw it.
+#[cfg_attr(feature = "defmt-impl", derive(defmt::Format))]
+pub struct PlacedImage<'a> {
+    pub top_left: Point,
+    pub image: Image<'a>,
+    /// The part of the image to draw, in image pixels.
+    pub source: Rectangle,
+    /// The size to draw it at on screen.
+    pub size: Size,
+    pub filter: Filter,
+}
+
+impl<'a> PlacedImage<'a> {
+    /// The whole image, scaled up by a whole number.
+    pub fn new(top_left: Point, image: Image<'a>, scale: u8) -> Self {
+        let source = bounds(&image);
+        PlacedImage {
+            top_left,
+            image,
+            source,
+            size: source.size * u32::from(scale),
+            filter: Filter::Nearest,
+        }
+    }
+
+    /// Part of an image (e.g. one sprite from a sheet), stretched or shrunk to fit `size`.
+    pub fn with_source(
+        top_left: Point,
+        image: Image<'a>,
+        source: Rectangle,
+        size: Size,
+        filter: Filter,
+    ) -> Self {
+        PlacedImage {
+            top_left,
+            image,
+            source,
+            size,
+            filter,
+        }
+    }
+
+    /// Where the image is drawn on screen.
+    pub fn area(&self) -> Rectangle {
+        Rectangle::new(self.top_left, self.size)
+    }
+
+    /// The colors and alphas of the image in an area of the screen (which should be inside
+    /// `area()`), row by row.
+    pub fn pixels_in(&self, area: Rectangle) -> Resampled<'a> {
+        let window = Rectangle::new(area.top_left - self.top_left, area.size);
+        image::resample(self.image, self.source, self.size, self.filter, window)
+    }
+}
+
+/// Initialize the display.
+///
+/// The SPI is powered down when this function exits. The backlight is handled separately.
+///
+/// The driver is generic over the bus and clock so that it can be run against the mock bus in the
+/// `sim` crate. On the watch it is always the firmware's `PineTimeDisplay`.
+pub struct Display<'a, SPI, RST, CS, DC, C> {
+    spim: SPI,
+    /// Borrowed, so that it stays low while the panel is off.
+    reset_pin: &'a mut RST,
+    cs_pin: CS,
+    dc_pin: DC,
+    clock: C,
+    state: &'a mut DisplayState,
+}
+
+impl<'a, SPI, RST, CS, DC, C> Display<'a, SPI, RST, CS, DC, C>
+where
+    SPI: AsyncWrite + blocking::spi::Write<u8> + blocking::spi::Transfer<u8>,
+    <SPI as blocking::spi::Write<u8>>::Error: Debug,
+    <SPI as blocking::spi::Transfer<u8>>::Error: Debug,
+    RST: OutputPin<Error = Infallible>,
+    CS: OutputPin<Error = Infallible>,
+    DC: OutputPin<Error = Infallible>,
+    C: Clock,
+{
+    /// Create a display from a bus that is already set up.
+    ///
+    /// The chip select should start high.
+    pub fn from_parts(
+        spim: SPI,
+        reset_pin: &'a mut RST,
+        cs_pin: CS,
+        dc_pin: DC,
+        clock: C,
+        state: &'a mut DisplayState,
+    ) -> Self {
+        Self {
+            spim,
+            reset_pin,
+            cs_pin,
+            dc_pin,
+            clock,
+            state,
+        }
+    }
+
+    /// Turns on the screen. The panel is only set up from scratch if it has lost its settings.
+    pub async fn sleep_off(&mut self) {
+        match self.state.power {
+            PanelPower::Active => return,
+            PanelPower::Sleeping => {
+                self.cs_pin.set_low().unwrap();
+                self.sleep_out().await;
+                self.send_command(Instruction::DisplayOn);
+                self.cs_pin.set_high().unwrap();
+            }
+            PanelPower::Uninitialised | PanelPower::Off => self.init().await,
+        }
+        self.set_power(PanelPower::Active);
+    }
+
+    /// Reset the panel, and send it everything in `DisplayState`.
+    async fn init(&mut self) {
+        self.hard_reset().await;
+        self.cs_pin.set_low().unwrap();
+        self.soft_reset().await;
+        self.sleep_out().await;
+        let (color, ctrl) = self.state.pixel_format.interface_formats();
+        self.pixel_format(color, ctrl);
+        self.apply_panel_config();
+        self.apply_scroll_state();
+        self.apply_rotation();
+        self.invert_display(true).await;
+        self.apply_mode();
+        self.clock.delay_millis(10).await;
+        self.send_command(Instruction::DisplayOn);
+        self.cs_pin.set_high().unwrap();
+    }
+
+    /// Puts the screen to sleep. It is already asleep unless it is `Active`.
+    pub async fn sleep_on(&mut self) {
+        if self.state.power != PanelPower::Active {
+            return;
+        }
+        self.cs_pin.set_low().unwrap();
+        self.sleep_in().await;
+        self.cs_pin.set_high().unwrap();
+        self.set_power(PanelPower::Sleeping);
+    }
+
+    /// Turns the screen off, and holds the panel in reset until the next `sleep_off`.
+    pub async fn power_off(&mut self) {
+        if self.state.power == PanelPower::Active {
+            self.cs_pin.set_low().unwrap();
+            self.send_command(Instruction::DisplayOff);
+            self.sleep_in().await;
+            self.cs_pin.set_high().unwrap();
+        }
+        self.reset_pin.set_low().unwrap();
+        self.set_power(PanelPower::Off);
+    }
+
+    /// Where the panel is in its power transitions.
+    pub fn power(&self) -> PanelPower {
+        self.state.power
+    }
+
+    fn set_power(&mut self, power: PanelPower) {
+        if self.state.power != power {
+            log!(info, "panel power: {:?} -> {:?}", self.state.power, power);
+            self.state.power = power;
+        }
+    }
+
+    /// Reset the panel and read back its ID and status, to check that it is there. It is left
+    /// `Uninitialised`, so the next `sleep_off` sets it up again.
+    pub async fn probe(&mut self) -> (DisplayId, DisplayStatus) {
+        self.hard_reset().await;
+        (self.read_id(), self.read_status())
+    }
+
+    /// Read the manufacturer and version of the panel.
+    pub fn read_id(&mut self) -> DisplayId {
+        let mut raw = [0; DisplayId::RESPONSE_LEN];
+        self.read_register(Instruction::ReadDisplayID, &mut raw);
+        DisplayId::from_response(raw)
+    }
+
+    /// Read the panel's current state (sleep, inversion, MADCTL, etc.).
+    pub fn read_status(&mut self) -> DisplayStatus {
+        let mut raw = [0; DisplayStatus::RESPONSE_LEN];
+        self.read_register(Instruction::ReadDisplayStatus, &mut raw);
+        DisplayStatus::from_response(raw)
+    }
+
+    /// Only light panel lines `start..=end`. Can also be used to move the lit band when already in
+    /// partial mode.
+    pub async fn enter_partial_mode(&mut self, start: u16, end: u16) {
+        assert!(start <= end && end < DISPLAY_HEIGHT as u16);
+        self.state.mode = DisplayMode::Partial { start, end };
+        self.cs_pin.set_low().unwrap();
+        self.apply_mode();
+        self.cs_pin.set_high().unwrap();
+    }
+
+    /// Light the whole panel.
+    pub async fn leave_partial_mode(&mut self) {
+        self.state.mode = DisplayMode::Normal;
+        self.cs_pin.set_low().unwrap();
+        self.apply_mode();
+        self.cs_pin.set_high().unwrap();
+    }
+
+    /// Change which way up the screen is. Only affects things drawn afterwards.
+    pub async fn set_rotation(&mut self, rotation: Rotation) {
+        self.state.rotation = rotation;
+        self.cs_pin.set_low().unwrap();
+        self.apply_rotation();
+        self.cs_pin.set_high().unwrap();
+    }
+
+    /// Change the number of bits per pixel sent to the display.
+    pub async fn set_pixel_format(&mut self, format: PixelFormat) {
+        self.state.pixel_format = format;
+        let (color, ctrl) = format.interface_formats();
+        self.cs_pin.set_low().unwrap();
+        self.pixel_format(color, ctrl);
+        self.cs_pin.set_high().unwrap();
+    }
+
+    /// Change the panel's analog tuning. This is also sent every time the display wakes.
+    pub async fn set_panel_config(&mut self, config: PanelConfig) {
+        self.state.panel = config;
+        self.cs_pin.set_low().unwrap();
+        self.apply_panel_config();
+        self.cs_pin.set_high().unwrap();
+    }
+
+    /// Set the fixed areas at the top and bottom of the panel, and scroll back to the start.
+    pub async fn define_scroll_region(&mut self, top_fixed: u16, bottom_fixed: u16) {
+        assert!(top_fixed + bottom_fixed <= DISPLAY_HEIGHT as u16);
+        self.state.scroll = ScrollState {
+            top_fixed,
+            bottom_fixed,
+            offset: 0,
+        };
+        self.cs_pin.set_low().unwrap();
+        self.apply_scroll_state();
+        self.cs_pin.set_high().unwrap();
+    }
+
+    /// Scroll so that the line `offset` lines into the scroll area is shown at its start.
+    ///
+    /// Drawing coordinates stay in screen space, so after scrolling you only need to draw the
+    /// lines that came into view.
+    pub async fn scroll(&mut self, offset: u16) {
+        let scroll_area = self.state.scroll.scroll_area();
+        if scroll_area == 0 {
+            log!(warn, "cannot scroll: the whole panel is fixed");
+            return;
+        }
+        self.state.scroll.offset = offset % scroll_area;
+        self.cs_pin.set_low().unwrap();
+        self.vertical_scroll_start_address(self.state.scroll.top_fixed + self.state.scroll.offset);
+        self.cs_pin.set_high().unwrap();
+    }
+
+    /// Copy a pre-existing buffer of data in RAM onto the screen.
+    pub async fn draw_rect_buf(
+        &mut self,
+        area: Rectangle,
+        // This byte array should contain 16 bit Rgb565 colors in big-endian order.
+        buf: &[u8],
+    ) {
+        self.draw_rect_iter(area, buf.iter().copied()).await
+    }
+
+    /// Draw an image, resampling it to the size it is placed at.
+    pub async fn draw_image(&mut self, image: &PlacedImage<'_>, bg: &Background) {
+        log!(
+            debug,
+            "Drawing image: size {:?} from {:?}",
+            image.size,
+            image.source
+        );
+        let area = image.area();
+        let pixels = image.pixels_in(area);
+        if image.image.has_mask() {
+            let pixels = bg
+                .pixels(area)
+                .zip(pixels)
+                .map(|(bg, (color, alpha))| bg.blend(color, alpha));
+            self.draw_rect_iter_pixels(area, pixels).await
+        } else {
+            // Don't bother working out the background.
+            self.draw_rect_iter_pixels(area, pixels.map(|(color, _)| color))
+                .await
+        }
+    }
+
+    /// Draw a filled Rectangle with the given color.
+    ///
+    /// This method copies into an intermediate buffer. It assumes that the color has already been
+    /// converted into bytes, with the first byte sent on the wire first. (i.e. big endian)
+    pub async fn draw_rect_color<const COLOR_BYTES: usize>(
+        &mut self,
+        area: Rectangle,
+        color: [u8; COLOR_BYTES],
+    ) {
+        self.draw_rect_iter(
+            area,
+            iter::repeat(&color)
+                .take((area.size.width * area.size.height) as usize)
+                .flatten()
+                .copied(),
+        )
+        .await;
+    }
+
+    /// The blocking version of `draw_rect_color`.
+    pub fn draw_rect_color_blocking<const COLOR_BYTES: usize>(
+        &mut self,
+        area: Rectangle,
+        color: [u8; COLOR_BYTES],
+    ) {
+        self.draw_rect_iter_blocking(
+            area,
+            iter::repeat(&color)
+                .take((area.size.width * area.size.height) as usize)
+                .flatten()
+                .copied(),
+        );
+    }
+
+    pub async fn clear(&mut self, color: Rgb565) {
+        self.draw_rect_color(
+            Rectangle::new(Point::new(0, 0), Size::new(240, 240)),
+            color.into_storage().to_be_bytes(),
+        )
+        .await
+    }
+
+    /// Only works on ascii - will get garbage with anything else.
+    ///
+    /// The transparent parts of the glyphs are filled in from `bg`.
+    pub async fn draw_text(
+        &mut self,
+        mut top_left: Point,
+        text: &str,
+        scale: u8,
+        font: &Font<'_>,
+        bg: &Background,
+    ) {
+        for ch in text.chars().map(|ch| ch as u8) {
+            log!(info, "ch {}", ch as char);
+            let glyph = match font.glyph(ch) {
+                Some(g) => g,
+                None => {
+                    log!(warn, "character {} cannot be drawn", ch as char);
+                    continue;
+                }
+            };
+            log!(info, "get pixels");
+            let area = Rectangle::new(top_left, text::glyph_size(&glyph, scale));
+            let pixels = glyph
+                .pixels(scale)
+                .zip(bg.pixels(area))
+                .map(|(col, bg)| col.unwrap_or(bg));
+            log!(info, "draw text");
+            self.draw_rect_iter_pixels(area, pixels).await;
+            top_left.x += area.size.width as i32;
+        }
+    }
+
+    pub async fn draw_rect_iter_pixels<I>(&mut self, area: Rectangle, pixel_colors: I)
+    where
+        I: IntoIterator<Item = u16>,
+        I::IntoIter: Clone,
+    {
+        self.draw_rect_iter(
+            area,
+            pixel_colors
+                .into_iter()
+                .flat_map(|color| color.to_be_bytes()),
+        )
+        .await
+    }
+
+    /// Copy data from an iterator onto the screen.
+    ///
+    /// This is the core method for getting pixels on screen: every other method uses this one.
+    /// The data is always big-endian rgb565, and is converted to the current `PixelFormat` as it
+    /// is sent.
+    ///
+    /// When the display is scrolled, an area can be split across the wrap-around point of the
+    /// scroll area (or across a fixed area). In that case each part is drawn separately, running
+    /// through a clone of the iterator for each and skipping the pixels that belong elsewhere.
+    pub async fn draw_rect_iter<I>(&mut self, area: Rectangle, data: I)
+    where
+        I: IntoIterator<Item = u8>,
+        I::IntoIter: Clone,
+    {
+        check_rect_u16(area);
+        let data = data.into_iter();
+        let parts = self.split_at_scroll(area);
+        if parts.len() <= 1 {
+            return self.draw_window_iter(area, data).await;
+        }
+        let width = area.size.width as i32;
+        let top_left = area.top_left;
+        for part in parts {
+            // 2 bytes per pixel
+            let part_data = data
+                .clone()
+                .enumerate()
+                .filter(move |(idx, _)| {
+                    let idx = (idx / 2) as i32;
+                    part.contains(top_left + Point::new(idx % width, idx / width))
+                })
+                .map(|(_, byte)| byte);
+            self.draw_window_iter(part, part_data).await;
+        }
+    }
+
+    /// The blocking version of `draw_rect_iter`, for when we can't `await` (e.g. in
+    /// `DrawTarget`).
+    ///
+    /// This doesn't use DMA, so it's slower, but it only runs through the iterator once. When the
+    /// area is split by scrolling, the parts are fed from the same iterator in the order its data
+    /// comes in: one after the other if they are stacked, a row at a time if they are side by
+    /// side.
+    pub fn draw_rect_iter_blocking(&mut self, area: Rectangle, data: impl IntoIterator<Item = u8>) {
+        check_rect_u16(area);
+        let mut data = data.into_iter();
+        let parts = self.split_at_scroll(area);
+        if parts.len() <= 1 {
+            return self.draw_window_iter_blocking(area, data);
+        }
+        if self.state.rotation.orientation().lines_along_x() {
+            for y in area.rows() {
+                for part in &parts {
+                    let row = Rectangle::new(
+                        Point::new(part.top_left.x, y),
+                        Size::new(part.size.width, 1),
+                    );
+                    // 2 bytes per pixel
+                    let len = part.size.width as usize * 2;
+                    self.draw_window_iter_blocking(row, data.by_ref().take(len));
+                }
+            }
+        } else {
+            for part in parts {
+                let len = (part.size.width * part.size.height) as usize * 2;
+                self.draw_window_iter_blocking(part, data.by_ref().take(len));
+            }
+        }
+    }
+
+    /// Copy data from an iterator into an area that maps onto a contiguous address window,
+    /// without DMA.
+    fn draw_window_iter_blocking(&mut self, area: Rectangle, data: impl Iterator<Item = u8>) {
+        let mut data = self.state.pixel_format.encode(data);
+        self.cs_pin.set_low().unwrap();
+        self.set_address_window(area);
+        self.send_command(Instruction::WriteToRam);
+        let mut buf = [0u8; EASY_DMA_SIZE];
+        loop {
+            let len = fill_buffer(&mut buf, &mut data);
+            if len == 0 {
+                break;
+            }
+            self.send_data_blocking(&buf[..len]);
+        }
+        self.cs_pin.set_high().unwrap();
+    }
+
+    /// Copy data from an iterator into an area that maps onto a contiguous address window.
+    ///
+    /// This method copies into two intermediate buffers: while one is on the wire (DMA), the next
+    /// one is filled from the iterator.
+    async fn draw_window_iter(&mut self, area: Rectangle, data: impl Iterator<Item = u8>) {
+        self.cs_pin.set_low().unwrap();
+        self.set_address_window(area);
+
+        self.send_command(Instruction::WriteToRam);
+        // chunk into slices of max EASY_DMA_SIZE
+        let mut data = self.state.pixel_format.encode(data);
+        let mut buffers = [[0u8; EASY_DMA_SIZE]; 2];
+        let [front, back] = &mut buffers;
+        let (mut front, mut back) = (front, back);
+        let mut len = fill_buffer(front, &mut data);
+        while len > 0 {
+            // `join` polls the send first, which starts the DMA, then fills the other buffer while
+            // the transfer is in flight.
+            let ((), next_len) = future::join(self.send_data(&front[..len]), async {
+                fill_buffer(back, &mut data)
+            })
+            .await;
+            len = next_len;
+            mem::swap(&mut front, &mut back);
+        }
+        self.cs_pin.set_high().unwrap();
+    }
+
+    pub async fn power_on(&mut self) {
+        self.sleep_off().await;
+        // clear screen
+        self.clear(Rgb565::WHITE).await;
+    }
+
+    // Display commands and their args.
+
+    #[inline]
+    async fn hard_reset(&mut self) {
+        //defmt::debug!("hard_reset");
+        self.reset_pin.set_high().unwrap();
+        self.clock.delay_micros(10).await;
+        self.reset_pin.set_low().unwrap();
+        self.clock.delay_micros(10).await;
+        self.reset_pin.set_high().unwrap();
+        // The panel ignores commands until it has finished resetting.
+        self.clock.delay_micros(RESET_MICROS).await;
+        self.set_power(PanelPower::Uninitialised);
+    }
+
+    #[inline]
+    async fn soft_reset(&mut self) {
+        //defmt::debug!("soft_reset");
+        self.send_command(Instruction::SoftwareReset);
+        self.clock.delay_millis(150).await;
+    }
+
+    #[inline]
+    async fn sleep_out(&mut self) {
+        //defmt::debug!("sleep_out");
+        if let Some(sleep_in_at) = self.state.sleep_in_at.take() {
+            let asleep = self.clock.now_micros().saturating_sub(sleep_in_at);
+            if asleep < SLEEP_IN_MICROS {
+                self.clock.delay_micros(SLEEP_IN_MICROS - asleep).await;
+            }
+        }
+        self.send_command(Instruction::SleepOut);
+        self.clock.delay_millis(10).await;
+    }
+
+    #[inline]
+    async fn sleep_in(&mut self) {
+        //defmt::debug!("sleep_in");
+        self.send_command(Instruction::SleepIn);
+        self.state.sleep_in_at = Some(self.clock.now_micros());
+        self.clock.delay_millis(10).await;
+    }
+
+    #[inline]
+    fn pixel_format(
+        &mut self,
+        color: RgbInterfaceColorFormat,
+        ctrl: ControlInterfaceColorFormat, // this is the one that we use I think
+    ) {
+        //defmt::debug!("pixel_format");
+        self.send_command(Instruction::InterfacePixelFormat);
+        self.send_data_blocking(&[color as u8 | ctrl as u8]);
+    }
+
+    /// Invert the colors of the display, so e.g. red would become cyan.
+    #[inline]
+    async fn invert_display(&mut self, invert: bool) {
+        if invert {
+            self.send_command(Instruction::DisplayInversionOn);
+        } else {
+            self.send_command(Instruction::DisplayInversionOff);
+        }
+        self.clock.delay_millis(10).await;
+    }
+
+    /// Send the display mode we have saved to the display.
+    fn apply_mode(&mut self) {
+        match self.state.mode {
+            DisplayMode::Normal => self.send_command(Instruction::NormalModeOn),
+            DisplayMode::Partial { start, end } => {
+                self.partial_area(start, end);
+                self.send_command(Instruction::PartialModeOn);
+            }
+        }
+    }
+
+    #[inline]
+    fn partial_area(&mut self, start_line: u16, end_line: u16) {
+        let start = start_line.to_be_bytes();
+        let end = end_line.to_be_bytes();
+        self.send_command(Instruction::PartialStartEndAddressSet);
+        self.send_data_blocking(&[start[0], start[1], end[0], end[1]]);
+    }
+
+    /// Send the rotation we have saved to the display.
+    fn apply_rotation(&mut self) {
+        let orientation = self.state.rotation.orientation();
+        let lines = match orientation.flip_lines {
+            true => ColDir::BottomToTop,
+            false => ColDir::TopToBottom,
+        };
+        let columns = match orientation.flip_columns {
+            true => RowDir::RightToLeft,
+            false => RowDir::LeftToRight,
+        };
+        // Refresh the panel in the same direction that we address it.
+        self.memory_data_access_control(
+            lines,
+            columns,
+            orientation.swap_axes,
+            lines,
+            ColorOrder::Rgb,
+            RowDir::LeftToRight,
+        );
+    }
+
+    /// Send the gamma, voltage and timing settings we have saved to the display.
+    fn apply_panel_config(&mut self) {
+        let config = self.state.panel;
+        for (inst, data) in config.commands() {
+            self.send_command(inst);
+            self.send_data_blocking(data);
+        }
+    }
+
+    /// Send the scroll region and offset we have saved to the display.
+    fn apply_scroll_state(&mut self) {
+        let scroll = self.state.scroll;
+        // The lines of video memory past the end of the panel are never shown, so we put them in
+        // the bottom fixed area.
+        self.vertical_scrolling_definition(
+            scroll.top_fixed,
+            scroll.scroll_area(),
+            scroll.bottom_fixed + (GRAM_LINES - DISPLAY_HEIGHT as u16),
+        );
+        self.vertical_scroll_start_address(scroll.top_fixed + scroll.offset);
+    }
+
+    #[inline]
+    fn vertical_scrolling_definition(
+        &mut self,
+        top_fixed_area: u16,
+        vertical_scrolling_area: u16,
+        bottom_fixed_area: u16,
+    ) {
+        //defmt::debug!("vertical_scrolling_definition");
+        debug_assert_eq!(
+            top_fixed_area + vertical_scrolling_area + bottom_fixed_area,
+            GRAM_LINES
+        );
+        let tfa = top_fixed_area.to_be_bytes();
+        let vsa = vertical_scrolling_area.to_be_bytes();
+        let bfa = bottom_fixed_area.to_be_bytes();
+        let data = [tfa[0], tfa[1], vsa[0], vsa[1], bfa[0], bfa[1]];
+        self.send_command(Instruction::VerticalScrollingDefinition);
+        self.send_data_blocking(&data);
+    }
+
+    #[inline]
+    fn memory_data_access_control(
+        &mut self,
+        page_address_order: ColDir,
+        column_address_order: RowDir,
+        page_col_order_reverse: bool,
+        line_address_order: ColDir,
+        color_order: ColorOrder,
+        display_data_latch_order: RowDir,
+    ) {
+        // let's hope this all disappears through optimization (constant propagation)
+        let mut mdac = 0;
+        if matches!(page_address_order, ColDir::BottomToTop) {
+            mdac |= 1 << 7;
+        }
+        if matches!(column_address_order, RowDir::RightToLeft) {
+            mdac |= 1 << 6;
+        }
+        if page_col_order_reverse {
+            mdac |= 1 << 5;
+        }
+        if matches!(line_address_order, ColDir::BottomToTop) {
+            mdac |= 1 << 4;
+        }
+        if matches!(color_order, ColorOrder::Bgr) {
+            mdac |= 1 << 3;
+        }
+        if matches!(display_data_latch_order, RowDir::RightToLeft) {
+            mdac |= 1 << 2;
+        }
+        self.send_command(Instruction::MemoryDataAccessControl);
+        self.send_data_blocking(&[mdac]);
+    }
+
+    /// Split an area into the parts that map to contiguous video memory, given the current
+    /// scroll state and rotation. Usually there is only 1.
+    fn split_at_scroll(&self, area: Rectangle) -> heapless::Vec<Rectangle, 4> {
+        let mut parts = heapless::Vec::new();
+        let along_x = self.state.rotation.orientation().lines_along_x();
+        // Work along the axis the panel lines are on.
+        let (tl, len) = match along_x {
+            true => (area.top_left.x, area.size.width as i32),
+            false => (area.top_left.y, area.size.height as i32),
+        };
+        let part = |start: i32, end: i32| match along_x {
+            true => Rectangle::new(
+                Point::new(start, area.top_left.y),
+                Size::new((end - start) as u32, area.size.height),
+            ),
+            false => Rectangle::new(
+                Point::new(area.top_left.x, start),
+                Size::new(area.size.width, (end - start) as u32),
+            ),
+        };
+        let mut start = tl;
+        for pos in (tl + 1)..(tl + len) {
+            if self.line_address(pos as u16) != self.line_address((pos - 1) as u16).wrapping_add(1)
+            {
+                parts.push(part(start, pos)).unwrap();
+                start = pos;
+            }
+        }
+        parts.push(part(start, tl + len)).unwrap();
+        parts
+    }
+
+    /// The address to send to the display so that pixels at `pos` along the axis that the panel
+    /// lines run along end up at `pos` on screen.
+    ///
+    /// The screen we use (ST7789) actually has video memory that is slightly bigger than the
+    /// screen, and supports scrolling. I think this is there to mean you can update the image
+    /// faster than you can write data to it using the SPI.
+    /// When lines are flipped, the controller mirrors them across the whole of video memory, so
+    /// when not scrolled this is an offset of 80.
+    fn line_address(&self, pos: u16) -> u16 {
+        let scroll = &self.state.scroll;
+        if !self.state.rotation.orientation().flip_lines {
+            return match pos < DISPLAY_HEIGHT as u16 {
+                true => scroll.gram_line(pos),
+                // off the panel, so scrolling doesn't matter.
+                false => pos,
+            };
+        }
+        match (DISPLAY_HEIGHT as u16 - 1).checked_sub(pos) {
+            Some(line) => GRAM_LINES - 1 - scroll.gram_line(line),
+            // off the panel, so scrolling doesn't matter.
+            None => pos.saturating_add(GRAM_LINES - DISPLAY_HEIGHT as u16),
+        }
+    }
+
+    /// Set the area of video memory that we are writing to. The area must not be split by
+    /// scrolling (see `split_at_scroll`).
+    #[inline]
+    fn set_address_window(&mut self, area: Rectangle) {
+        let tl = area.top_left;
+        // No area means top-left = bottom-right
+        let br = match area.bottom_right() {
+            Some(x) => x,
+            None => tl,
+        };
+        let (start, end) = ((tl.x as u16, tl.y as u16), (br.x as u16, br.y as u16));
+        // The column and row addresses are swapped by the controller if needed, so we only need
+        // to adjust the axis that the lines run along.
+        let (start, end) = match self.state.rotation.orientation().lines_along_x() {
+            true => (
+                (self.line_address(start.0), start.1),
+                (self.line_address(end.0), end.1),
+            ),
+            false => (
+                (start.0, self.line_address(start.1)),
+                (end.0, self.line_address(end.1)),
+            ),
+        };
+        let startx = start.0.to_be_bytes();
+        let starty = start.1.to_be_bytes();
+        let endx = end.0.to_be_bytes();
+        let endy = end.1.to_be_bytes();
+
+        // column address
+        self.send_command(Instruction::ColumnAddressSet);
+        self.send_data_blocking(&[startx[0], startx[1], endx[0], endx[1]]);
+
+        // row address
+        self.send_command(Instruction::RowAddressSet);
+        self.send_data_blocking(&[starty[0], starty[1], endy[0], endy[1]]);
+    }
+
+    #[inline]
+    fn vertical_scroll_start_address(&mut self, line: u16) {
+        self.send_command(Instruction::VerticalScrollStartAddress);
+        self.send_data_blocking(&line.to_be_bytes());
+    }
+
+    // raw methods for commands & data
+
+    #[inline]
+    fn send_command(&mut self, inst: Instruction) {
+        self.dc_pin.set_low().unwrap();
+        blocking::spi::Write::write(&mut self.spim, &[inst.into()]).unwrap();
+    }
+
+    #[inline]
+    fn send_data_blocking(&mut self, data: &[u8]) {
+        self.dc_pin.set_high().unwrap();
+        blocking::spi::Write::write(&mut self.spim, data).unwrap();
+    }
+
+    /// Send a read instruction, and read the raw reply (still including the dummy bit) into
+    /// `buf`.
+    fn read_register(&mut self, inst: Instruction, buf: &mut [u8]) {
+        self.cs_pin.set_low().unwrap();
+        self.send_command(inst);
+        self.dc_pin.set_high().unwrap();
+        blocking::spi::Transfer::transfer(&mut self.spim, buf).unwrap();
+        self.cs_pin.set_high().unwrap();
+    }
+
+    #[inline]
+    async fn send_data(&mut self, data: &[u8]) {
+        /*
+        defmt::debug!(
+            "writing data at {=usize:x} -> {=usize:x}",
+            data.as_ptr() as _,
+            (data.as_ptr() as usize) + data.len()
+        );
+        */
+        self.dc_pin.set_high().unwrap();
+        //defmt::debug!("let's go");
+        AsyncWrite::write(&mut self.spim, data).await.unwrap();
+        //defmt::debug!("write finished");
+    }
+}
+
+/// ST7789 instructions (some of them).
+#[allow(dead_code)]
+#[repr(u8)]
+enum Instruction {
+    NoOp = 0x00,
+    SoftwareReset = 0x01,
+    ReadDisplayID = 0x04,
+    ReadDisplayStatus = 0x09,
+    SleepIn = 0x10,
+    SleepOut = 0x11,
+    PartialModeOn = 0x12,
+    NormalModeOn = 0x13,
+    DisplayInversionOff = 0x20,
+    DisplayInversionOn = 0x21,
+    DisplayOff = 0x28,
+    DisplayOn = 0x29,
+    ColumnAddressSet = 0x2A,
+    RowAddressSet = 0x2B,
+    WriteToRam = 0x2C,
+    ReadFromRam = 0x2E,
+    PartialStartEndAddressSet = 0x30,
+    VerticalScrollingDefinition = 0x33,
+    MemoryDataAccessControl = 0x36,
+    VerticalScrollStartAddress = 0x37,
+    InterfacePixelFormat = 0x3A,
+    PorchControl = 0xB2,
+    GateControl = 0xB7,
+    VcomSetting = 0xBB,
+    LcmControl = 0xC0,
+    VdvVrhEnable = 0xC2,
+    VrhSet = 0xC3,
+    VdvSet = 0xC4,
+    VcomOffsetSet = 0xC5,
+    FrameRateControl = 0xC6,
+    PowerControl1 = 0xD0,
+    PositiveGamma = 0xE0,
+    NegativeGamma = 0xE1,
+}
+
+#[repr(u8)]
+enum RgbInterfaceColorFormat {
+    _65KOfRgb = 0b0101_0000,
+    _262KOfRgb = 0b0110_0000,
+}
+
+#[repr(u8)]
+enum ControlInterfaceColorFormat {
+    _12BitPerPixel = 0b0011,
+    _16BitPerPixel = 0b0101,
+    // default (after hw reset and power on)
+    _18BitPerPixel = 0b0110,
+    _16MTruncated = 0b0111,
+}
+
+#[derive(Copy, Clone)]
+enum ColDir {
+    TopToBottom,
+    BottomToTop,
+}
+
+#[derive(Copy, Clone)]
+enum RowDir {
+    LeftToRight,
+    RightToLeft,
+}
+
+#[allow(dead_code)]
+enum ColorOrder {
+    Rgb,
+    Bgr,
+}
+
+impl From<Instruction> for u8 {
+    fn from(inst: Instruction) -> u8 {
+        inst as u8
+    }
+}
+
+/// Copy bytes from `data` into `buf` until one of them runs out. Returns the number of bytes
+/// copied.
+fn fill_buffer(buf: &mut [u8], data: &mut impl Iterator<Item = u8>) -> usize {
+    let mut len = 0;
+    // `buf` goes first so we don't pull a byte from `data` that we have nowhere to put.
+    for (slot, byte) in buf.iter_mut().zip(data) {
+        *slot = byte;
+        len += 1;
+    }
+    len
+}
+
+fn check_rect_u16(r: Rectangle) {
+    let tl = r.top_left;
+    // No area means top-left = bottom-right
+    let br = match r.bottom_right() {
+        Some(x) => x,
+        None => tl,
+    };
+    let max = u16::MAX as i32;
+    assert!(
+        tl.x >= 0
+            && tl.x <= max
+            && tl.y >= 0
+            && tl.y <= max
+            && br.x >= 0
+            && br.x <= max
+            && br.y >= 0
+            && br.y <= max
+    );
+}
@@ -0,0 +1,192 @@
+//! Backlight brightness.
+//!
+//! The PineTime has 3 backlight pins (low, mid and high), each switching a different current
+//! through the LEDs. The pins are active low. We assume the currents are roughly in the ratio
+//! 1:2:4, so that turning on combinations of pins gives 7 evenly spaced brightness steps. Between
+//! steps, the brightest pin of the combination is dimmed using PWM.
+//!
+//! This works out which pins to drive. Driving them is up to the firmware.
+#[cfg(feature = "kv")]
+use kv::Value;
+
+/// How bright the backlight is, from 0 (off) to 255 (all pins fully on).
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "defmt-impl", derive(defmt::Format))]
+pub struct Backlight(pub u8);
+
+impl Backlight {
+    pub const OFF: Backlight = Backlight(0);
+    /// The dimmest level that is still on.
+    pub const MIN: Backlight = Backlight(1);
+    /// About the same as only the low pin being on.
+    pub const LOW: Backlight = Backlight(36);
+    /// About the same as only the mid pin being on.
+    pub const MID: Backlight = Backlight(72);
+    /// All pins on.
+    pub const HIGH: Backlight = Backlight(255);
+
+    /// Work out which pins to turn on, and how to dim them.
+    pub fn pins(self) -> PinConfig {
+        if self.0 == 0 {
+            return PinConfig {
+                steady: Pins::NONE,
+                dimmed: None,
+            };
+        }
+        // Brightness in 255ths of the low pin.
+        let target = u32::from(self.0) * 7;
+        // The combination of pins that is at least as bright as the target.
+        let step = (target + 254) / 255;
+        let brightest = 1 << (31 - step.leading_zeros());
+        // Leave the other pins on, and dim the brightest to make up the difference.
+        let duty = (target - (step - brightest) * 255) / brightest;
+        let steady = Pins((step - brightest) as u8);
+        let dimmed = Pins(brightest as u8);
+        if duty >= 255 {
+            PinConfig {
+                steady: steady.with(dimmed),
+                dimmed: None,
+            }
+        } else {
+            PinConfig {
+                steady,
+                dimmed: Some((dimmed, duty as u8)),
+            }
+        }
+    }
+}
+
+/// A set of backlight pins.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-impl", derive(defmt::Format))]
+pub struct Pins(pub u8);
+
+impl Pins {
+    pub const NONE: Pins = Pins(0);
+    pub const LOW: Pins = Pins(0b001);
+    pub const MID: Pins = Pins(0b010);
+    pub const HIGH: Pins = Pins(0b100);
+
+    pub fn contains(self, other: Pins) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn with(self, other: Pins) -> Pins {
+        Pins(self.0 | other.0)
+    }
+}
+
+/// The pins to turn on for a brightness level.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-impl", derive(defmt::Format))]
+pub struct PinConfig {
+    /// Pins that are fully on.
+    pub steady: Pins,
+    /// A single pin that is on for `duty / 255` of the time.
+    pub dimmed: Option<(Pins, u8)>,
+}
+
+#[cfg(feature = "kv")]
+impl Value for Backlight {
+    fn encode(&self, buf: &mut [u8]) -> Option<usize> {
+        self.0.encode(buf)
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        u8::decode(bytes).map(Backlight)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dimmed(pin: Pins, duty: u8) -> PinConfig {
+        PinConfig {
+            steady: Pins::NONE,
+            dimmed: Some((pin, duty)),
+        }
+    }
+
+    #[test]
+    fn off_and_full() {
+        assert_eq!(
+            Backlight::OFF.pins(),
+            PinConfig {
+                steady: Pins::NONE,
+                dimmed: None
+            }
+        );
+        assert_eq!(
+            Backlight::HIGH.pins(),
+            PinConfig {
+                steady: Pins(0b111),
+                dimmed: None
+            }
+        );
+    }
+
+    #[test]
+    fn dimmest_levels() {
+        assert_eq!(Backlight::MIN.pins(), dimmed(Pins::LOW, 7));
+        assert_eq!(Backlight::LOW.pins(), dimmed(Pins::LOW, 252));
+    }
+
+    /// Either side of each point where another pin is needed.
+    #[test]
+    fn pin_boundaries() {
+        let with = |steady: Pins, pin: Pins, duty: u8| PinConfig {
+            steady,
+            dimmed: Some((pin, duty)),
+        };
+        let cases = [
+            (36, dimmed(Pins::LOW, 252)),
+            (37, dimmed(Pins::MID, 129)),
+            (72, dimmed(Pins::MID, 252)),
+            (73, with(Pins::LOW, Pins::MID, 128)),
+            (109, with(Pins::LOW, Pins::MID, 254)),
+            (110, dimmed(Pins::HIGH, 192)),
+            (145, dimmed(Pins::HIGH, 253)),
+            (146, with(Pins::LOW, Pins::HIGH, 191)),
+            (182, with(Pins::LOW, Pins::HIGH, 254)),
+            (183, with(Pins::MID, Pins::HIGH, 192)),
+            (218, with(Pins::MID, Pins::HIGH, 254)),
+            (219, with(Pins::LOW.with(Pins::MID), Pins::HIGH, 192)),
+            (254, with(Pins::LOW.with(Pins::MID), Pins::HIGH, 253)),
+        ];
+        for (level, pins) in cases {
+            assert_eq!(Backlight(level).pins(), pins, "level {}", level);
+        }
+    }
+
+    /// With the pins at 1:2:4, every level is just under its brightness, and never dimmer than
+    /// the one before.
+    #[test]
+    fn levels_get_brighter() {
+        let mut last = 0;
+        for level in 0..=255 {
+            let PinConfig { steady, dimmed } = Backlight(level).pins();
+            let (pin, duty) = dimmed.unwrap_or((Pins::NONE, 0));
+            assert!(!steady.contains(pin) || pin == Pins::NONE);
+            let brightness = u32::from(steady.0) * 255 + u32::from(pin.0) * u32::from(duty);
+            let target = u32::from(level) * 7;
+            assert!(
+                brightness <= target && target - brightness < 4,
+                "level {}",
+                level
+            );
+            assert!(brightness >= last, "level {}", level);
+            last = brightness;
+        }
+    }
+
+    #[test]
+    fn pins() {
+        let both = Pins::LOW.with(Pins::HIGH);
+        assert!(both.contains(Pins::LOW));
+        assert!(both.contains(Pins::HIGH));
+        assert!(!both.contains(Pins::MID));
+        assert!(both.contains(Pins::NONE));
+        assert_eq!(both.with(Pins::MID), Pins(0b111));
+    }
+}
@@ -0,0 +1,92 @@
+//! Raw images in the external flash, drawn a row at a time.
+//!
+//! The flash and the display share the bus, so they have to take turns: read a row of the image
+//! into RAM, then draw it (`scale` times over). Only uncompressed rgb565 images without a mask
+//! can be drawn like this, as their rows need no decoding.
+use assets::{Encoding, ImageHeader};
+use core::iter;
+
+use super::{Point, Rectangle, Size, DISPLAY_HEIGHT, DISPLAY_WIDTH};
+
+/// Why an image can't be drawn a row at a time.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-impl", derive(defmt::Format))]
+pub enum Unstreamable {
+    /// Only raw rgb565 images (without a mask) can be streamed from flash.
+    Unsupported(Encoding),
+    /// The image is wider than the screen.
+    TooWide(u16),
+    /// The image is this tall, and would be taller than the screen once scaled up.
+    TooTall(u16),
+}
+
+/// A raw rgb565 image in the flash, and where to draw it.
+#[derive(Debug, Copy, Clone)]
+pub struct FlashImage {
+    /// The address of the first row, just after the header.
+    rows: u32,
+    width: u16,
+    height: u16,
+    top_left: Point,
+    scale: u8,
+}
+
+impl FlashImage {
+    /// The image whose header is at `address`, if it can be drawn from the flash.
+    pub fn new(
+        address: u32,
+        header: &ImageHeader,
+        top_left: Point,
+        scale: u8,
+    ) -> Result<Self, Unstreamable> {
+        if header.encoding != Encoding::Rgb565 || header.mask_bits().is_some() {
+            return Err(Unstreamable::Unsupported(header.encoding));
+        }
+        if usize::from(header.width) > DISPLAY_WIDTH {
+            return Err(Unstreamable::TooWide(header.width));
+        }
+        if u32::from(header.height) * u32::from(scale) > DISPLAY_HEIGHT as u32 {
+            return Err(Unstreamable::TooTall(header.height));
+        }
+        Ok(FlashImage {
+            rows: address + ImageHeader::LEN as u32,
+            width: header.width,
+            height: header.height,
+            top_left,
+            scale,
+        })
+    }
+
+    pub fn height(&self) -> u16 {
+        self.height
+    }
+
+    /// The length of a row in bytes. This is at most `DISPLAY_WIDTH * 2`.
+    pub fn row_len(&self) -> usize {
+        usize::from(self.width) * 2
+    }
+
+    /// Where row `y` is in the flash.
+    pub fn row_address(&self, y: u16) -> u32 {
+        self.rows + u32::from(y) * self.row_len() as u32
+    }
+
+    /// Where row `y` goes on screen, once scaled up.
+    pub fn row_area(&self, y: u16) -> Rectangle {
+        let scale = u32::from(self.scale);
+        Rectangle::new(
+            self.top_left + Point::new(0, (u32::from(y) * scale) as i32),
+            Size::new(u32::from(self.width) * scale, scale),
+        )
+    }
+
+    /// The colors to draw over `row_area`, given the bytes of the row.
+    pub fn row_pixels<'a>(&self, row: &'a [u8]) -> impl Iterator<Item = u16> + Clone + 'a {
+        let scale = usize::from(self.scale);
+        (0..scale).flat_map(move |_| {
+            row.chunks(2).flat_map(move |bytes| {
+                iter::repeat(u16::from_be_bytes([bytes[0], bytes[1]])).take(scale)
+            })
+        })
+    }
+}
@@ -0,0 +1,202 @@
+//! The color format used for pixel data on the wire.
+//!
+//! Everything above `draw_window_iter` works in big-endian rgb565, which is what our images and
+//! fonts are stored as. The conversion to the wire format happens as the bytes are streamed out.
+use super::{ControlInterfaceColorFormat, RgbInterfaceColorFormat};
+
+/// How many bits per pixel are sent to the display.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-impl", derive(defmt::Format))]
+pub enum PixelFormat {
+    /// 12 bits per pixel, 2 pixels in 3 bytes. About 25% less data than `Rgb565`, for animations.
+    Rgb444,
+    /// 16 bits per pixel, the same as we store.
+    Rgb565,
+    /// 18 bits per pixel, 1 pixel in 3 bytes. Red and blue are expanded from 5 to 6 bits, which
+    /// gives smoother gradients.
+    Rgb666,
+}
+
+impl Default for PixelFormat {
+    fn default() -> Self {
+        PixelFormat::Rgb565
+    }
+}
+
+impl PixelFormat {
+    pub(super) fn interface_formats(
+        self,
+    ) -> (RgbInterfaceColorFormat, ControlInterfaceColorFormat) {
+        match self {
+            PixelFormat::Rgb444 => (
+                RgbInterfaceColorFormat::_65KOfRgb,
+                ControlInterfaceColorFormat::_12BitPerPixel,
+            ),
+            PixelFormat::Rgb565 => (
+                RgbInterfaceColorFormat::_65KOfRgb,
+                ControlInterfaceColorFormat::_16BitPerPixel,
+            ),
+            PixelFormat::Rgb666 => (
+                RgbInterfaceColorFormat::_262KOfRgb,
+                ControlInterfaceColorFormat::_18BitPerPixel,
+            ),
+        }
+    }
+
+    /// Convert a stream of big-endian rgb565 bytes into this format.
+    pub fn encode<I: Iterator<Item = u8>>(self, data: I) -> Encode<I> {
+        Encode {
+            format: self,
+            data,
+            out: [0; 3],
+            pos: 0,
+            len: 0,
+        }
+    }
+}
+
+/// Iterator returned by [`PixelFormat::encode`].
+#[derive(Clone)]
+pub struct Encode<I> {
+    format: PixelFormat,
+    data: I,
+    /// Converted bytes waiting to go out.
+    out: [u8; 3],
+    pos: u8,
+    len: u8,
+}
+
+impl<I: Iterator<Item = u8>> Encode<I> {
+    fn next_pixel(&mut self) -> Option<(u8, u8, u8)> {
+        let hi = self.data.next()?;
+        let lo = self.data.next()?;
+        let color = u16::from_be_bytes([hi, lo]);
+        Some((
+            (color >> 11) as u8,
+            ((color >> 5) & 0x3f) as u8,
+            (color & 0x1f) as u8,
+        ))
+    }
+
+    /// Convert the next pixel (or 2 for `Rgb444`) into `out`.
+    fn refill(&mut self) {
+        self.pos = 0;
+        self.len = 0;
+        match self.format {
+            // handled in `next`.
+            PixelFormat::Rgb565 => (),
+            PixelFormat::Rgb666 => {
+                if let Some((r, g, b)) = self.next_pixel() {
+                    // The 6 bits go in the top of each byte. Copy the top bit of red and blue
+                    // into the bottom, so full brightness stays full brightness.
+                    self.out = [(r << 3) | (r >> 2), g << 2, (b << 3) | (b >> 2)];
+                    self.len = 3;
+                }
+            }
+            PixelFormat::Rgb444 => {
+                let to_444 = |(r, g, b): (u8, u8, u8)| (r >> 1, g >> 2, b >> 1);
+                if let Some((r1, g1, b1)) = self.next_pixel().map(to_444) {
+                    match self.next_pixel().map(to_444) {
+                        Some((r2, g2, b2)) => {
+                            self.out = [r1 << 4 | g1, b1 << 4 | r2, g2 << 4 | b2];
+                            self.len = 3;
+                        }
+                        // An odd pixel at the end: pad it out to a whole byte.
+                        None => {
+                            self.out = [r1 << 4 | g1, b1 << 4, 0];
+                            self.len = 2;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<I: Iterator<Item = u8>> Iterator for Encode<I> {
+    type Item = u8;
+
+    fn next(&mut self) -> Option<u8> {
+        if self.format == PixelFormat::Rgb565 {
+            return self.data.next();
+        }
+        if self.pos == self.len {
+            self.refill();
+        }
+        if self.pos == self.len {
+            return None;
+        }
+        let byte = self.out[usize::from(self.pos)];
+        self.pos += 1;
+        Some(byte)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::vec::Vec;
+
+    /// Magenta, green, and a color with a different value in each channel (r 2, g 17, b 20).
+    const PIXELS: [u16; 3] = [0xF81F, 0x07E0, 0x1234];
+
+    fn encode(format: PixelFormat, pixels: &[u16]) -> Vec<u8> {
+        let data = pixels.iter().flat_map(|color| color.to_be_bytes());
+        format.encode(data).collect()
+    }
+
+    #[test]
+    fn rgb565_is_unchanged() {
+        assert_eq!(encode(PixelFormat::Rgb565, &PIXELS[..1]), [0xF8, 0x1F]);
+        assert_eq!(
+            encode(PixelFormat::Rgb565, &PIXELS),
+            [0xF8, 0x1F, 0x07, 0xE0, 0x12, 0x34]
+        );
+    }
+
+    #[test]
+    fn rgb444() {
+        let format = PixelFormat::Rgb444;
+        // An odd pixel is padded out to a whole byte.
+        assert_eq!(encode(format, &PIXELS[..1]), [0xF0, 0xF0]);
+        assert_eq!(encode(format, &PIXELS[..2]), [0xF0, 0xF0, 0xF0]);
+        assert_eq!(encode(format, &PIXELS), [0xF0, 0xF0, 0xF0, 0x14, 0xA0]);
+    }
+
+    #[test]
+    fn rgb666() {
+        let format = PixelFormat::Rgb666;
+        // Full red and blue stay full.
+        assert_eq!(encode(format, &PIXELS[..1]), [0xFF, 0x00, 0xFF]);
+        assert_eq!(
+            encode(format, &PIXELS[..2]),
+            [0xFF, 0x00, 0xFF, 0x00, 0xFC, 0x00]
+        );
+        assert_eq!(
+            encode(format, &PIXELS),
+            [0xFF, 0x00, 0xFF, 0x00, 0xFC, 0x00, 0x10, 0x44, 0xA5]
+        );
+    }
+
+    #[test]
+    fn half_a_pixel_is_dropped() {
+        let data = [0xF8, 0x1F, 0x07];
+        let encoded: Vec<u8> = PixelFormat::Rgb444.encode(data.iter().copied()).collect();
+        assert_eq!(encoded, [0xF0, 0xF0]);
+        let encoded: Vec<u8> = PixelFormat::Rgb666.encode(data.iter().copied()).collect();
+        assert_eq!(encoded, [0xFF, 0x00, 0xFF]);
+    }
+
+    #[test]
+    fn nothing_in_nothing_out() {
+        for format in [
+            PixelFormat::Rgb444,
+            PixelFormat::Rgb565,
+            PixelFormat::Rgb666,
+        ] {
+            assert_eq!(encode(format, &[]), []);
+        }
+    }
+}
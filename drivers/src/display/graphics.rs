@@ -0,0 +1,226 @@
+//! Drawing with `embedded-graphics`.
+//!
+//! `Display` is a `DrawTarget`, so any e-g drawable can be drawn on it directly. `DrawTarget` is
+//! not async, so this goes through the blocking (non-DMA) path: fine for lines and widgets, but
+//! big images are better sent with `draw_rect_iter`.
+//!
+//! e-g drawables borrow their data and aren't `Format`, so they can't go in a `Cmd` as they are.
+//! Instead there is [`Graphic`], a small owned description of the common primitives.
+use core::{convert::Infallible, fmt::Debug};
+use embedded_graphics::{
+    draw_target::DrawTarget,
+    geometry::{Dimensions, OriginDimensions, Point, Size},
+    mono_font::{ascii::FONT_10X20, MonoTextStyle},
+    pixelcolor::{IntoStorage, Rgb565},
+    primitives::{
+        Circle, ContainsPoint, Line, PointsIter, Primitive, PrimitiveStyle, PrimitiveStyleBuilder,
+        Rectangle, RoundedRectangle, Triangle,
+    },
+    text::{Baseline, Text},
+    Drawable, Pixel,
+};
+use embedded_hal::{blocking, digital::v2::OutputPin};
+use heapless::{String, Vec};
+
+use super::{Display, DISPLAY_HEIGHT, DISPLAY_WIDTH};
+use crate::bus::{AsyncWrite, Clock};
+
+/// The most graphics that a single `Cmd::DrawGraphics` can carry.
+pub const MAX_GRAPHICS: usize = 8;
+
+/// A list of graphics to draw in one go.
+pub type Graphics = Vec<Graphic, MAX_GRAPHICS>;
+
+/// An `embedded-graphics` primitive that can be sent to the display task.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "defmt-impl", derive(defmt::Format))]
+pub enum Graphic {
+    Line {
+        start: Point,
+        end: Point,
+        color: Rgb565,
+        width: u8,
+    },
+    Rectangle {
+        area: Rectangle,
+        /// 0 for square corners.
+        corner_radius: u8,
+        style: Style,
+    },
+    Circle {
+        top_left: Point,
+        diameter: u32,
+        style: Style,
+    },
+    Triangle {
+        vertices: [Point; 3],
+        style: Style,
+    },
+    /// Text in e-g's 10x20 font, on a transparent background.
+    Text {
+        top_left: Point,
+        text: String<16>,
+        color: Rgb565,
+    },
+}
+
+impl Graphic {
+    pub fn draw<D>(&self, target: &mut D) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = Rgb565>,
+    {
+        match self {
+            Graphic::Line {
+                start,
+                end,
+                color,
+                width,
+            } => Line::new(*start, *end)
+                .into_styled(PrimitiveStyle::with_stroke(*color, (*width).into()))
+                .draw(target),
+            Graphic::Rectangle {
+                area,
+                corner_radius: 0,
+                style,
+            } => area.into_styled(style.primitive_style()).draw(target),
+            Graphic::Rectangle {
+                area,
+                corner_radius,
+                style,
+            } => {
+                let radius = u32::from(*corner_radius);
+                RoundedRectangle::with_equal_corners(*area, Size::new(radius, radius))
+                    .into_styled(style.primitive_style())
+                    .draw(target)
+            }
+            Graphic::Circle {
+                top_left,
+                diameter,
+                style,
+            } => Circle::new(*top_left, *diameter)
+                .into_styled(style.primitive_style())
+                .draw(target),
+            Graphic::Triangle { vertices, style } => {
+                let [a, b, c] = *vertices;
+                Triangle::new(a, b, c)
+                    .into_styled(style.primitive_style())
+                    .draw(target)
+            }
+            Graphic::Text {
+                top_left,
+                text,
+                color,
+            } => Text::with_baseline(
+                text,
+                *top_left,
+                MonoTextStyle::new(&FONT_10X20, *color),
+                Baseline::Top,
+            )
+            .draw(target)
+            .map(|_| ()),
+        }
+    }
+}
+
+/// How to fill and outline a [`Graphic`]. Leaving both colors out draws nothing.
+#[derive(Debug, Copy, Clone, Default)]
+#[cfg_attr(feature = "defmt-impl", derive(defmt::Format))]
+pub struct Style {
+    pub fill: Option<Rgb565>,
+    pub stroke: Option<Rgb565>,
+    pub stroke_width: u8,
+}
+
+impl Style {
+    pub fn fill(color: Rgb565) -> Self {
+        Style {
+            fill: Some(color),
+            ..Style::default()
+        }
+    }
+
+    pub fn stroke(color: Rgb565, width: u8) -> Self {
+        Style {
+            stroke: Some(color),
+            stroke_width: width,
+            ..Style::default()
+        }
+    }
+
+    fn primitive_style(self) -> PrimitiveStyle<Rgb565> {
+        let mut style = PrimitiveStyleBuilder::new().stroke_width(self.stroke_width.into());
+        if let Some(color) = self.fill {
+            style = style.fill_color(color);
+        }
+        if let Some(color) = self.stroke {
+            style = style.stroke_color(color);
+        }
+        style.build()
+    }
+}
+
+impl<SPI, RST, CS, DC, C> OriginDimensions for Display<'_, SPI, RST, CS, DC, C> {
+    fn size(&self) -> Size {
+        Size::new(DISPLAY_WIDTH as u32, DISPLAY_HEIGHT as u32)
+    }
+}
+
+impl<SPI, RST, CS, DC, C> DrawTarget for Display<'_, SPI, RST, CS, DC, C>
+where
+    SPI: AsyncWrite + blocking::spi::Write<u8> + blocking::spi::Transfer<u8>,
+    <SPI as blocking::spi::Write<u8>>::Error: Debug,
+    <SPI as blocking::spi::Transfer<u8>>::Error: Debug,
+    RST: OutputPin<Error = Infallible>,
+    CS: OutputPin<Error = Infallible>,
+    DC: OutputPin<Error = Infallible>,
+    C: Clock,
+{
+    type Color = Rgb565;
+    type Error = Infallible;
+
+    /// Each pixel gets its own address window, so this is slow. Most primitives use
+    /// `fill_contiguous` or `fill_solid` where they can.
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Rgb565>>,
+    {
+        let bounds = self.bounding_box();
+        for Pixel(point, color) in pixels {
+            if bounds.contains(point) {
+                self.draw_rect_color_blocking(
+                    Rectangle::new(point, Size::new(1, 1)),
+                    color.into_storage().to_be_bytes(),
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn fill_contiguous<I>(&mut self, area: &Rectangle, colors: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Rgb565>,
+    {
+        let clipped = area.intersection(&self.bounding_box());
+        if clipped.is_zero_sized() {
+            return Ok(());
+        }
+        // The colors are in row-major order over the whole area, so skip the ones that fall off
+        // the screen.
+        let data = area
+            .points()
+            .zip(colors)
+            .filter(move |(point, _)| clipped.contains(*point))
+            .flat_map(|(_, color)| color.into_storage().to_be_bytes());
+        self.draw_rect_iter_blocking(clipped, data);
+        Ok(())
+    }
+
+    fn fill_solid(&mut self, area: &Rectangle, color: Rgb565) -> Result<(), Self::Error> {
+        let clipped = area.intersection(&self.bounding_box());
+        if clipped.is_zero_sized() {
+            return Ok(());
+        }
+        self.draw_rect_color_blocking(clipped, color.into_storage().to_be_bytes());
+        Ok(())
+    }
+}
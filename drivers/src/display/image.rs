@@ -0,0 +1,36 @@
+//! Drawing images.
+//!
+//! The file format and the resampling are in the `assets` crate. This converts to and from
+//! embedded-graphics' geometry.
+use assets::resample::{Area, Sampler};
+pub use assets::{
+    resample::{Filter, Resampled},
+    Image,
+};
+use embedded_graphics::{
+    geometry::{Point, Size},
+    primitives::Rectangle,
+};
+
+/// The colors and alphas of `source` (an area of the image) drawn at `size`, in `window` (relative
+/// to the top-left of the drawn image), row by row.
+pub fn resample<'a>(
+    image: Image<'a>,
+    source: Rectangle,
+    size: Size,
+    filter: Filter,
+    window: Rectangle,
+) -> Resampled<'a> {
+    Sampler::new(image, area(source), size.width, size.height, filter).pixels(area(window))
+}
+
+/// The whole of an image.
+pub fn bounds(image: &Image) -> Rectangle {
+    Rectangle::new(Point::zero(), Size::new(image.width(), image.height()))
+}
+
+fn area(rect: Rectangle) -> Area {
+    let Point { x, y } = rect.top_left;
+    assert!(x >= 0 && y >= 0, "image areas start at 0, 0");
+    Area::new(x as u32, y as u32, rect.size.width, rect.size.height)
+}
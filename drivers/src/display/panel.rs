@@ -0,0 +1,153 @@
+//! Analog tuning of the panel: gamma curves, voltages, porches and frame rate.
+//!
+//! The controller resets these to values that suit no panel in particular, which makes colors
+//! look washed out on the PineTime. The register layouts are in the ST7789V datasheet, section 9.2.
+use core::slice;
+
+use super::Instruction;
+
+/// The ST7789's analog and timing settings, sent to the panel on wake.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-impl", derive(defmt::Format))]
+pub struct PanelConfig {
+    /// PORCTRL: back porch, front porch, separate porch enable, then the idle and partial mode
+    /// porches (4 bits back, 4 bits front).
+    pub porch: [u8; 5],
+    /// GCTRL: VGH (bits 6 - 4) and VGL (bits 2 - 0) gate voltages.
+    pub gate_voltages: u8,
+    /// VCOMS: the common voltage, 0.1V + 0.025V per step.
+    pub vcom: u8,
+    /// LCMCTRL: flips and inversions of the interface signals.
+    pub lcm_control: u8,
+    /// VRHS: the VAP (GVDD) voltage, 3.55V + 0.05V per step.
+    pub vrh: u8,
+    /// VDVS: the VDV voltage, -0.8V + 0.025V per step from 0x20.
+    pub vdv: u8,
+    /// FRCTRL2: inversion mode (bits 7 - 5) and frame rate in normal mode (bits 4 - 0, 0x0F is
+    /// 60Hz, 0x1F is 39Hz).
+    pub frame_rate: u8,
+    /// PWCTRL1: the first byte is always 0xA4, then AVDD (bits 7 - 6), AVCL (bits 5 - 4) and VDS
+    /// (bits 1 - 0).
+    pub power: [u8; 2],
+    /// PVGAMCTRL: the gamma curve for positive voltages.
+    pub positive_gamma: [u8; 14],
+    /// NVGAMCTRL: the gamma curve for negative voltages.
+    pub negative_gamma: [u8; 14],
+}
+
+impl PanelConfig {
+    /// The values from Sitronix's sample code, used by most ST7789 drivers.
+    pub const STANDARD: PanelConfig = PanelConfig {
+        porch: [0x0C, 0x0C, 0x00, 0x33, 0x33],
+        gate_voltages: 0x35,
+        vcom: 0x19,
+        lcm_control: 0x2C,
+        vrh: 0x12,
+        vdv: 0x20,
+        frame_rate: 0x0F,
+        power: [0xA4, 0xA1],
+        positive_gamma: [
+            0xD0, 0x04, 0x0D, 0x11, 0x13, 0x2B, 0x3F, 0x54, 0x4C, 0x18, 0x0D, 0x0B, 0x1F, 0x23,
+        ],
+        negative_gamma: [
+            0xD0, 0x04, 0x0C, 0x11, 0x13, 0x2C, 0x3F, 0x44, 0x51, 0x2F, 0x1F, 0x1F, 0x20, 0x23,
+        ],
+    };
+
+    /// `STANDARD`, with a lower frame rate and smaller gate voltages to save power. Motion looks
+    /// a bit worse, so this is best for mostly static screens like a watch face.
+    pub const LOW_POWER: PanelConfig = PanelConfig {
+        gate_voltages: 0x14,
+        frame_rate: 0x1F,
+        ..PanelConfig::STANDARD
+    };
+
+    /// Each register to write, with its parameters, in the order they are sent.
+    pub(super) fn commands(&self) -> [(Instruction, &[u8]); 11] {
+        // Take VRH and VDV from the registers below, rather than from NVM.
+        const VDV_VRH_FROM_COMMANDS: [u8; 2] = [0x01, 0xFF];
+        [
+            (Instruction::PorchControl, &self.porch),
+            (
+                Instruction::GateControl,
+                slice::from_ref(&self.gate_voltages),
+            ),
+            (Instruction::VcomSetting, slice::from_ref(&self.vcom)),
+            (Instruction::LcmControl, slice::from_ref(&self.lcm_control)),
+            (Instruction::VdvVrhEnable, &VDV_VRH_FROM_COMMANDS),
+            (Instruction::VrhSet, slice::from_ref(&self.vrh)),
+            (Instruction::VdvSet, slice::from_ref(&self.vdv)),
+            (
+                Instruction::FrameRateControl,
+                slice::from_ref(&self.frame_rate),
+            ),
+            (Instruction::PowerControl1, &self.power),
+            (Instruction::PositiveGamma, &self.positive_gamma),
+            (Instruction::NegativeGamma, &self.negative_gamma),
+        ]
+    }
+}
+
+impl Default for PanelConfig {
+    fn default() -> Self {
+        PanelConfig::STANDARD
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POSITIVE_GAMMA: &[u8] = &[
+        0xD0, 0x04, 0x0D, 0x11, 0x13, 0x2B, 0x3F, 0x54, 0x4C, 0x18, 0x0D, 0x0B, 0x1F, 0x23,
+    ];
+    const NEGATIVE_GAMMA: &[u8] = &[
+        0xD0, 0x04, 0x0C, 0x11, 0x13, 0x2C, 0x3F, 0x44, 0x51, 0x2F, 0x1F, 0x1F, 0x20, 0x23,
+    ];
+
+    /// The commands as opcodes, so they can be compared with the datasheet.
+    fn opcodes(config: &PanelConfig) -> [(u8, &[u8]); 11] {
+        config
+            .commands()
+            .map(|(inst, params)| (inst.into(), params))
+    }
+
+    #[test]
+    fn standard_commands() {
+        let expected: [(u8, &[u8]); 11] = [
+            (0xB2, &[0x0C, 0x0C, 0x00, 0x33, 0x33]),
+            (0xB7, &[0x35]),
+            (0xBB, &[0x19]),
+            (0xC0, &[0x2C]),
+            (0xC2, &[0x01, 0xFF]),
+            (0xC3, &[0x12]),
+            (0xC4, &[0x20]),
+            (0xC6, &[0x0F]),
+            (0xD0, &[0xA4, 0xA1]),
+            (0xE0, POSITIVE_GAMMA),
+            (0xE1, NEGATIVE_GAMMA),
+        ];
+        assert_eq!(opcodes(&PanelConfig::STANDARD), expected);
+        assert_eq!(PanelConfig::default(), PanelConfig::STANDARD);
+    }
+
+    #[test]
+    fn low_power_commands() {
+        let expected: [(u8, &[u8]); 11] = [
+            (0xB2, &[0x0C, 0x0C, 0x00, 0x33, 0x33]),
+            // Smaller gate voltages.
+            (0xB7, &[0x14]),
+            (0xBB, &[0x19]),
+            (0xC0, &[0x2C]),
+            (0xC2, &[0x01, 0xFF]),
+            (0xC3, &[0x12]),
+            (0xC4, &[0x20]),
+            // 39Hz.
+            (0xC6, &[0x1F]),
+            (0xD0, &[0xA4, 0xA1]),
+            (0xE0, POSITIVE_GAMMA),
+            (0xE1, NEGATIVE_GAMMA),
+        ];
+        assert_eq!(opcodes(&PanelConfig::LOW_POWER), expected);
+    }
+}
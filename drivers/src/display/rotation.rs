@@ -0,0 +1,103 @@
+//! Screen orientation.
+//!
+//! The panel's own idea of "up" isn't the way we wear the watch, so even the default rotation
+//! remaps coordinates. Everything here is relative to the panel's native layout: columns `c` run
+//! across the panel lines, and lines `l` are what the controller scans and scrolls.
+#[cfg(feature = "kv")]
+use kv::Value;
+
+/// Which way up the screen is, relative to the normal way of wearing the watch.
+///
+/// Rotations are clockwise. The mirrored variants flip the screen left-to-right before rotating.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-impl", derive(defmt::Format))]
+pub enum Rotation {
+    Deg0,
+    Deg90,
+    Deg180,
+    Deg270,
+    MirroredDeg0,
+    MirroredDeg90,
+    MirroredDeg180,
+    MirroredDeg270,
+}
+
+impl Default for Rotation {
+    fn default() -> Self {
+        Rotation::Deg0
+    }
+}
+
+/// How screen coordinates map onto the panel's columns and lines.
+///
+/// First `x` and `y` are swapped if `swap_axes` is set, then the first becomes the column and the
+/// second the line, each counted from the other end if the matching `flip` is set.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-impl", derive(defmt::Format))]
+pub struct Orientation {
+    pub swap_axes: bool,
+    pub flip_columns: bool,
+    pub flip_lines: bool,
+}
+
+impl Rotation {
+    pub fn orientation(self) -> Orientation {
+        use Rotation::*;
+        let (swap_axes, flip_columns, flip_lines) = match self {
+            Deg0 => (true, false, true),
+            Deg90 => (false, false, false),
+            Deg180 => (true, true, false),
+            Deg270 => (false, true, true),
+            MirroredDeg0 => (true, false, false),
+            MirroredDeg90 => (false, true, false),
+            MirroredDeg180 => (true, true, true),
+            MirroredDeg270 => (false, false, true),
+        };
+        Orientation {
+            swap_axes,
+            flip_columns,
+            flip_lines,
+        }
+    }
+}
+
+impl Orientation {
+    /// Whether panel lines run along the x axis (otherwise they run along y).
+    pub fn lines_along_x(self) -> bool {
+        self.swap_axes
+    }
+}
+
+/// Stored as one byte, so it can be kept in the settings.
+#[cfg(feature = "kv")]
+impl Value for Rotation {
+    fn encode(&self, buf: &mut [u8]) -> Option<usize> {
+        use Rotation::*;
+        let raw: u8 = match self {
+            Deg0 => 0,
+            Deg90 => 1,
+            Deg180 => 2,
+            Deg270 => 3,
+            MirroredDeg0 => 4,
+            MirroredDeg90 => 5,
+            MirroredDeg180 => 6,
+            MirroredDeg270 => 7,
+        };
+        raw.encode(buf)
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        use Rotation::*;
+        Some(match u8::decode(bytes)? {
+            0 => Deg0,
+            1 => Deg90,
+            2 => Deg180,
+            3 => Deg270,
+            4 => MirroredDeg0,
+            5 => MirroredDeg90,
+            6 => MirroredDeg180,
+            7 => MirroredDeg270,
+            _ => return None,
+        })
+    }
+}
@@ -0,0 +1,53 @@
+//! Drawing retained scenes with `enigita`.
+//!
+//! A [`Scene`] is a stack of shapes and images over a background color, which remembers the area
+//! that has changed since it was last drawn. Only that area is sent to the panel, so e.g. the
+//! minute digits of a watch face can be redrawn without pushing the rest of the screen again.
+//!
+//! The sender keeps its scene between draws: `replace` what changed, then lend it to the display
+//! task with `Cmd::DrawScene`. It comes back marked as drawn.
+use core::{convert::Infallible, fmt::Debug};
+use embedded_graphics::{
+    geometry::{Point, Size},
+    primitives::Rectangle,
+};
+use embedded_hal::{blocking, digital::v2::OutputPin};
+use enigita::Frame;
+
+use super::{Display, DISPLAY_HEIGHT, DISPLAY_WIDTH};
+use crate::bus::{AsyncWrite, Clock};
+
+/// The most shapes and images that a scene can hold.
+pub const MAX_DRAWABLES: usize = 8;
+
+/// A whole screen of rgb565 drawables. Images must be `'static` to be sent to the display task.
+pub type Scene =
+    Frame<'static, u16, { DISPLAY_WIDTH as u16 }, { DISPLAY_HEIGHT as u16 }, MAX_DRAWABLES>;
+
+impl<SPI, RST, CS, DC, C> Display<'_, SPI, RST, CS, DC, C>
+where
+    SPI: AsyncWrite + blocking::spi::Write<u8> + blocking::spi::Transfer<u8>,
+    <SPI as blocking::spi::Write<u8>>::Error: Debug,
+    <SPI as blocking::spi::Transfer<u8>>::Error: Debug,
+    RST: OutputPin<Error = Infallible>,
+    CS: OutputPin<Error = Infallible>,
+    DC: OutputPin<Error = Infallible>,
+    C: Clock,
+{
+    /// Draw the part of a frame that needs redrawing. Does nothing if nothing has changed.
+    pub async fn draw_frame<const WIDTH: u16, const HEIGHT: u16, const LEN: usize>(
+        &mut self,
+        frame: &Frame<'_, u16, WIDTH, HEIGHT, LEN>,
+    ) {
+        let area = frame.draw_area();
+        if area.is_empty() {
+            return;
+        }
+        let size = area.size();
+        let area = Rectangle::new(
+            Point::new(area.x0().into(), area.y0().into()),
+            Size::new(size.width.into(), size.height.into()),
+        );
+        self.draw_rect_iter_pixels(area, frame.render()).await;
+    }
+}
@@ -0,0 +1,158 @@
+//! Decoding of the display's read commands.
+//!
+//! In serial mode the ST7789 clocks out a dummy bit before the reply to `RDDID` and `RDDST`, so
+//! the reply is read as one byte more than its length and shifted back into place here.
+/// The reply to `ReadDisplayID`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-impl", derive(defmt::Format))]
+pub struct DisplayId {
+    /// LCD module manufacturer ID.
+    pub manufacturer: u8,
+    /// LCD module/driver version ID.
+    pub version: u8,
+    /// LCD module/driver ID.
+    pub driver: u8,
+}
+
+impl DisplayId {
+    /// Number of bytes to read from the wire (including the dummy bit).
+    pub const RESPONSE_LEN: usize = 4;
+
+    /// Decode the bytes read after sending `ReadDisplayID`.
+    pub fn from_response(raw: [u8; Self::RESPONSE_LEN]) -> Self {
+        let [manufacturer, version, driver] = unshift(raw);
+        DisplayId {
+            manufacturer,
+            version,
+            driver,
+        }
+    }
+
+    /// If the bus is not connected (or the panel is dead) we read all 0s or all 1s.
+    pub fn is_plausible(&self) -> bool {
+        let bytes = [self.manufacturer, self.version, self.driver];
+        bytes != [0x00; 3] && bytes != [0xFF; 3]
+    }
+}
+
+/// The reply to `ReadDisplayStatus`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-impl", derive(defmt::Format))]
+pub struct DisplayStatus {
+    pub booster_on: bool,
+    /// The memory data access control register, in the same bit positions as when writing it.
+    pub madctl: u8,
+    /// The control interface color format, as written with `InterfacePixelFormat`.
+    pub pixel_format: u8,
+    pub idle_mode: bool,
+    pub partial_mode: bool,
+    pub sleeping: bool,
+    pub normal_mode: bool,
+    pub scrolling: bool,
+    pub inverted: bool,
+    pub display_on: bool,
+}
+
+impl DisplayStatus {
+    /// Number of bytes to read from the wire (including the dummy bit).
+    pub const RESPONSE_LEN: usize = 5;
+
+    /// Decode the bytes read after sending `ReadDisplayStatus`.
+    pub fn from_response(raw: [u8; Self::RESPONSE_LEN]) -> Self {
+        let raw = u32::from_be_bytes(unshift(raw));
+        let bit = |n: u32| raw & (1 << n) != 0;
+        DisplayStatus {
+            booster_on: bit(31),
+            // bits 30 - 25 are MY, MX, MV, ML, RGB, MH: the same as bits 7 - 2 of MADCTL.
+            madctl: ((raw >> 23) & 0b1111_1100) as u8,
+            pixel_format: ((raw >> 20) & 0b111) as u8,
+            idle_mode: bit(19),
+            partial_mode: bit(18),
+            sleeping: !bit(17),
+            normal_mode: bit(16),
+            scrolling: bit(15),
+            inverted: bit(13),
+            display_on: bit(10),
+        }
+    }
+}
+
+/// Remove the leading dummy bit from a reply, dropping the last (partial) byte.
+fn unshift<const IN: usize, const OUT: usize>(raw: [u8; IN]) -> [u8; OUT] {
+    debug_assert_eq!(IN, OUT + 1);
+    let mut out = [0; OUT];
+    for (idx, byte) in out.iter_mut().enumerate() {
+        *byte = raw[idx] << 1 | raw[idx + 1] >> 7;
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display_id() {
+        // 85 85 52 after the dummy bit, then whatever the line does until the byte is done.
+        let id = DisplayId::from_response([0x42, 0xC2, 0xA9, 0x3F]);
+        assert_eq!(
+            id,
+            DisplayId {
+                manufacturer: 0x85,
+                version: 0x85,
+                driver: 0x52,
+            }
+        );
+        assert!(id.is_plausible());
+        // The dummy bit isn't part of the reply.
+        assert_eq!(DisplayId::from_response([0xC2, 0xC2, 0xA9, 0x00]), id);
+    }
+
+    #[test]
+    fn nobody_listening() {
+        assert!(!DisplayId::from_response([0x00; 4]).is_plausible());
+        assert!(!DisplayId::from_response([0xFF; 4]).is_plausible());
+    }
+
+    #[test]
+    fn display_status_awake() {
+        // b0 53 24 00: booster on, MX and MV, 16 bit pixels, awake, normal mode, inverted and on.
+        let status = DisplayStatus::from_response([0x58, 0x29, 0x92, 0x00, 0x7F]);
+        assert_eq!(
+            status,
+            DisplayStatus {
+                booster_on: true,
+                madctl: 0x60,
+                pixel_format: 0b101,
+                idle_mode: false,
+                partial_mode: false,
+                sleeping: false,
+                normal_mode: true,
+                scrolling: false,
+                inverted: true,
+                display_on: true,
+            }
+        );
+    }
+
+    #[test]
+    fn display_status_asleep() {
+        // 00 6c 80 00: 18 bit pixels, idle, partial and scrolling, but asleep.
+        let status = DisplayStatus::from_response([0x80, 0x36, 0x40, 0x00, 0x00]);
+        assert_eq!(
+            status,
+            DisplayStatus {
+                booster_on: false,
+                madctl: 0,
+                pixel_format: 0b110,
+                idle_mode: true,
+                partial_mode: true,
+                sleeping: true,
+                normal_mode: false,
+                scrolling: true,
+                inverted: false,
+                display_on: false,
+            }
+        );
+    }
+}
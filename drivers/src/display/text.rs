@@ -0,0 +1,32 @@
+use assets::{font::Glyph, FontData};
+use embedded_graphics::geometry::Size;
+
+/// My own compact font format (see `assets::font`).
+///
+/// The firmware's built in font is compiled in, others are loaded from an asset bundle. Both are
+/// in the same format.
+#[derive(Copy, Clone)]
+pub struct Font<'a> {
+    data: FontData<'a>,
+}
+
+impl<'a> Font<'a> {
+    /// A font file, which has already been checked by `FontData::parse`.
+    pub fn from_data(data: FontData<'a>) -> Self {
+        Font { data }
+    }
+
+    /// The glyph for `ch`. Only ascii characters have glyphs.
+    pub fn glyph(&self, ch: u8) -> Option<Glyph<'a>> {
+        self.data.glyph(ch)
+    }
+}
+
+/// The size of `glyph` on the screen.
+pub fn glyph_size(glyph: &Glyph, scale: u8) -> Size {
+    let scale = u32::from(scale);
+    Size {
+        width: glyph.width * scale,
+        height: glyph.height * scale,
+    }
+}
@@ -0,0 +1,396 @@
+//! Driver for the SPI NOR flash on the PineTime (an XTX XT25F32B, 4 MiB).
+//!
+//! The flash shares the SPI bus with the display, so on the watch it is reached through the
+//! firmware's `DisplayFlashSpi::flash`. Like the display driver it is generic over the bus, so it
+//! can be run against the NOR model in the `sim` crate.
+//!
+//! NOR flash can only clear bits when programming, so an area has to be erased (set to all 1s) a
+//! sector at a time before it is written.
+//!
+//! The driver also implements the blocking `embedded-storage` NOR traits, for the settings store.
+//! Those spin on the status register instead of sleeping, so a sector erase holds up the executor
+//! for around 50ms.
+use core::{convert::Infallible, fmt::Debug};
+use embedded_hal::{blocking, digital::v2::OutputPin};
+use embedded_storage::nor_flash::{
+    ErrorType, NorFlash, NorFlashError, NorFlashErrorKind, ReadNorFlash,
+};
+
+use crate::{bus::Clock, EASY_DMA_SIZE};
+
+/// The size of the flash, in bytes.
+pub const FLASH_SIZE: u32 = 4 * 1024 * 1024;
+/// The most that can be programmed at once. Writes are split at page boundaries.
+pub const PAGE_SIZE: u32 = 256;
+/// The smallest area that can be erased.
+pub const SECTOR_SIZE: u32 = 4 * 1024;
+/// The size of a block erase.
+pub const BLOCK_SIZE: u32 = 64 * 1024;
+/// How long a page program can take before we give up on it, in microseconds. It usually takes
+/// under a millisecond.
+const PROGRAM_TIMEOUT: u64 = 5_000;
+
+/// How big an area to erase.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-impl", derive(defmt::Format))]
+pub enum EraseSize {
+    /// 4 KiB, takes around 50ms.
+    Sector,
+    /// 64 KiB, takes around 150ms.
+    Block,
+}
+
+impl EraseSize {
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(self) -> u32 {
+        match self {
+            EraseSize::Sector => SECTOR_SIZE,
+            EraseSize::Block => BLOCK_SIZE,
+        }
+    }
+
+    fn instruction(self) -> Instruction {
+        match self {
+            EraseSize::Sector => Instruction::SectorErase,
+            EraseSize::Block => Instruction::BlockErase,
+        }
+    }
+
+    /// How often to check whether the erase has finished, in microseconds.
+    fn poll_interval(self) -> u64 {
+        match self {
+            EraseSize::Sector => 5_000,
+            EraseSize::Block => 20_000,
+        }
+    }
+
+    /// How long the erase can take before we give up on it, in microseconds: about ten times as
+    /// long as it usually takes.
+    fn timeout(self) -> u64 {
+        match self {
+            EraseSize::Sector => 500_000,
+            EraseSize::Block => 2_000_000,
+        }
+    }
+}
+
+/// Why a flash operation wasn't carried out.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-impl", derive(defmt::Format))]
+pub enum FlashError {
+    /// The area runs past the end of the flash.
+    OutOfBounds,
+    /// An erase address isn't at the start of a sector or block.
+    NotAligned,
+    /// A program or erase was still going long after it should have finished, so the chip is
+    /// probably missing or broken.
+    Timeout,
+}
+
+impl NorFlashError for FlashError {
+    fn kind(&self) -> NorFlashErrorKind {
+        match self {
+            FlashError::OutOfBounds => NorFlashErrorKind::OutOfBounds,
+            FlashError::NotAligned => NorFlashErrorKind::NotAligned,
+            FlashError::Timeout => NorFlashErrorKind::Other,
+        }
+    }
+}
+
+/// The reply to `ReadJedecId`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-impl", derive(defmt::Format))]
+pub struct JedecId {
+    pub manufacturer: u8,
+    pub memory_type: u8,
+    /// log2 of the size in bytes.
+    pub capacity: u8,
+}
+
+impl JedecId {
+    /// If the bus is not connected (or the chip is asleep) we read all 0s or all 1s.
+    pub fn is_plausible(&self) -> bool {
+        let bytes = [self.manufacturer, self.memory_type, self.capacity];
+        bytes != [0x00; 3] && bytes != [0xFF; 3]
+    }
+
+    /// The size of the flash in bytes, according to the chip.
+    pub fn size(&self) -> Option<u32> {
+        1u32.checked_shl(self.capacity.into())
+    }
+}
+
+/// The status register.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-impl", derive(defmt::Format))]
+pub struct Status(pub u8);
+
+impl Status {
+    /// A program or erase is in progress.
+    pub fn is_busy(self) -> bool {
+        self.0 & 0x01 != 0
+    }
+
+    /// The write enable latch is set, so the next program or erase will be carried out.
+    pub fn write_enabled(self) -> bool {
+        self.0 & 0x02 != 0
+    }
+}
+
+pub struct Flash<SPI, CS, C> {
+    spim: SPI,
+    cs_pin: CS,
+    clock: C,
+}
+
+impl<SPI, CS, C> Flash<SPI, CS, C>
+where
+    SPI: blocking::spi::Write<u8> + blocking::spi::Transfer<u8>,
+    <SPI as blocking::spi::Write<u8>>::Error: Debug,
+    <SPI as blocking::spi::Transfer<u8>>::Error: Debug,
+    CS: OutputPin<Error = Infallible>,
+    C: Clock,
+{
+    /// Create a flash driver from a bus that is already set up.
+    ///
+    /// The chip select should start high.
+    pub fn from_parts(spim: SPI, cs_pin: CS, clock: C) -> Self {
+        Flash {
+            spim,
+            cs_pin,
+            clock,
+        }
+    }
+
+    pub fn read_jedec_id(&mut self) -> JedecId {
+        let mut raw = [0; 3];
+        self.read_register(Instruction::ReadJedecId, &mut raw);
+        let [manufacturer, memory_type, capacity] = raw;
+        JedecId {
+            manufacturer,
+            memory_type,
+            capacity,
+        }
+    }
+
+    pub fn read_status(&mut self) -> Status {
+        let mut raw = [0];
+        self.read_register(Instruction::ReadStatus, &mut raw);
+        Status(raw[0])
+    }
+
+    /// Read `buf.len()` bytes starting at `address`.
+    pub fn read(&mut self, address: u32, buf: &mut [u8]) -> Result<(), FlashError> {
+        check_bounds(address, buf.len())?;
+        let [_, a2, a1, a0] = address.to_be_bytes();
+        self.cs_pin.set_low().unwrap();
+        // The last byte is a dummy, which gives the chip time to start reading at full speed.
+        self.send(&[Instruction::FastRead as u8, a2, a1, a0, 0]);
+        // The chip keeps going on to the next byte for as long as we keep clocking.
+        for chunk in buf.chunks_mut(EASY_DMA_SIZE) {
+            blocking::spi::Transfer::transfer(&mut self.spim, chunk).unwrap();
+        }
+        self.cs_pin.set_high().unwrap();
+        Ok(())
+    }
+
+    /// Program `data` starting at `address`. The area must have been erased first.
+    ///
+    /// `data` must be in RAM, as EasyDMA can't read from the chip's own flash.
+    pub async fn write(&mut self, address: u32, data: &[u8]) -> Result<(), FlashError> {
+        check_bounds(address, data.len())?;
+        let mut address = address;
+        let mut data = data;
+        while !data.is_empty() {
+            let page = self.program_page(address, data);
+            // Takes under a millisecond.
+            self.wait_idle(200, PROGRAM_TIMEOUT).await?;
+            address += page as u32;
+            data = &data[page..];
+        }
+        Ok(())
+    }
+
+    /// Erase (set to 0xFF) the sector or block starting at `address`.
+    pub async fn erase(&mut self, address: u32, size: EraseSize) -> Result<(), FlashError> {
+        self.start_erase(address, size)?;
+        self.wait_idle(size.poll_interval(), size.timeout()).await
+    }
+
+    /// `write`, spinning until each page is done.
+    pub fn write_blocking(&mut self, address: u32, data: &[u8]) -> Result<(), FlashError> {
+        check_bounds(address, data.len())?;
+        let mut address = address;
+        let mut data = data;
+        while !data.is_empty() {
+            let page = self.program_page(address, data);
+            self.wait_idle_blocking(PROGRAM_TIMEOUT)?;
+            address += page as u32;
+            data = &data[page..];
+        }
+        Ok(())
+    }
+
+    /// `erase`, spinning until it is done.
+    pub fn erase_blocking(&mut self, address: u32, size: EraseSize) -> Result<(), FlashError> {
+        self.start_erase(address, size)?;
+        self.wait_idle_blocking(size.timeout())
+    }
+
+    /// Go into deep power-down, where the chip draws around 1µA. Nothing but
+    /// `release_power_down` works until it is woken again.
+    pub fn power_down(&mut self) {
+        self.command(Instruction::DeepPowerDown);
+    }
+
+    /// Wake from deep power-down.
+    pub async fn release_power_down(&mut self) {
+        self.command(Instruction::ReleasePowerDown);
+        // tRES1 is at most 20µs.
+        self.clock.delay_micros(20).await;
+    }
+
+    /// Start programming the start of `data`, up to the end of the page, returning how many
+    /// bytes that is.
+    fn program_page(&mut self, address: u32, data: &[u8]) -> usize {
+        // A page program wraps around at the end of the page, so stop there.
+        let to_page_end = (PAGE_SIZE - address % PAGE_SIZE) as usize;
+        let page = &data[..to_page_end.min(data.len())];
+        self.command(Instruction::WriteEnable);
+        let [_, a2, a1, a0] = address.to_be_bytes();
+        self.cs_pin.set_low().unwrap();
+        self.send(&[Instruction::PageProgram as u8, a2, a1, a0]);
+        for chunk in page.chunks(EASY_DMA_SIZE) {
+            self.send(chunk);
+        }
+        self.cs_pin.set_high().unwrap();
+        page.len()
+    }
+
+    fn start_erase(&mut self, address: u32, size: EraseSize) -> Result<(), FlashError> {
+        if address % size.len() != 0 {
+            return Err(FlashError::NotAligned);
+        }
+        check_bounds(address, size.len() as usize)?;
+        self.command(Instruction::WriteEnable);
+        let [_, a2, a1, a0] = address.to_be_bytes();
+        self.cs_pin.set_low().unwrap();
+        self.send(&[size.instruction() as u8, a2, a1, a0]);
+        self.cs_pin.set_high().unwrap();
+        Ok(())
+    }
+
+    /// Poll the status register (every `poll` microseconds) until a program or erase has
+    /// finished, for up to `timeout` microseconds.
+    async fn wait_idle(&mut self, poll: u64, timeout: u64) -> Result<(), FlashError> {
+        let start = self.clock.now_micros();
+        while self.read_status().is_busy() {
+            if self.clock.now_micros() - start > timeout {
+                return Err(FlashError::Timeout);
+            }
+            self.clock.delay_micros(poll).await;
+        }
+        Ok(())
+    }
+
+    /// `wait_idle`, spinning.
+    fn wait_idle_blocking(&mut self, timeout: u64) -> Result<(), FlashError> {
+        let start = self.clock.now_micros();
+        while self.read_status().is_busy() {
+            if self.clock.now_micros() - start > timeout {
+                return Err(FlashError::Timeout);
+            }
+        }
+        Ok(())
+    }
+
+    /// Send an instruction with no parameters.
+    fn command(&mut self, inst: Instruction) {
+        self.cs_pin.set_low().unwrap();
+        self.send(&[inst as u8]);
+        self.cs_pin.set_high().unwrap();
+    }
+
+    fn read_register(&mut self, inst: Instruction, buf: &mut [u8]) {
+        self.cs_pin.set_low().unwrap();
+        self.send(&[inst as u8]);
+        blocking::spi::Transfer::transfer(&mut self.spim, buf).unwrap();
+        self.cs_pin.set_high().unwrap();
+    }
+
+    fn send(&mut self, data: &[u8]) {
+        blocking::spi::Write::write(&mut self.spim, data).unwrap();
+    }
+}
+
+impl<SPI, CS, C> ErrorType for Flash<SPI, CS, C> {
+    type Error = FlashError;
+}
+
+impl<SPI, CS, C> ReadNorFlash for Flash<SPI, CS, C>
+where
+    SPI: blocking::spi::Write<u8> + blocking::spi::Transfer<u8>,
+    <SPI as blocking::spi::Write<u8>>::Error: Debug,
+    <SPI as blocking::spi::Transfer<u8>>::Error: Debug,
+    CS: OutputPin<Error = Infallible>,
+    C: Clock,
+{
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), FlashError> {
+        Flash::read(self, offset, bytes)
+    }
+
+    fn capacity(&self) -> usize {
+        FLASH_SIZE as usize
+    }
+}
+
+impl<SPI, CS, C> NorFlash for Flash<SPI, CS, C>
+where
+    SPI: blocking::spi::Write<u8> + blocking::spi::Transfer<u8>,
+    <SPI as blocking::spi::Write<u8>>::Error: Debug,
+    <SPI as blocking::spi::Transfer<u8>>::Error: Debug,
+    CS: OutputPin<Error = Infallible>,
+    C: Clock,
+{
+    const WRITE_SIZE: usize = 1;
+    const ERASE_SIZE: usize = SECTOR_SIZE as usize;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), FlashError> {
+        if to % SECTOR_SIZE != 0 {
+            return Err(FlashError::NotAligned);
+        }
+        for address in (from..to).step_by(SECTOR_SIZE as usize) {
+            self.erase_blocking(address, EraseSize::Sector)?;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), FlashError> {
+        self.write_blocking(offset, bytes)
+    }
+}
+
+fn check_bounds(address: u32, len: usize) -> Result<(), FlashError> {
+    match address.checked_add(len as u32) {
+        Some(end) if end <= FLASH_SIZE => Ok(()),
+        _ => Err(FlashError::OutOfBounds),
+    }
+}
+
+/// SPI NOR flash instructions (the ones we use).
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-impl", derive(defmt::Format))]
+enum Instruction {
+    WriteEnable = 0x06,
+    ReadStatus = 0x05,
+    PageProgram = 0x02,
+    FastRead = 0x0B,
+    SectorErase = 0x20,
+    BlockErase = 0xD8,
+    ReadJedecId = 0x9F,
+    DeepPowerDown = 0xB9,
+    ReleasePowerDown = 0xAB,
+}
@@ -0,0 +1,23 @@
+//! Drivers for the chips on the PineTime's SPI bus: the ST7789 display and the NOR flash, and
+//! looking assets up in a bundle in the flash.
+//!
+//! They are generic over the bus and the clock (see [`bus`]), so the same code runs on the watch
+//! and against the models in the `sim` crate, where it is tested on the host.
+#![no_std]
+#![cfg_attr(feature = "nightly", feature(generic_associated_types))]
+
+/// Log through defmt on the watch. There is nowhere to log to on the host.
+macro_rules! log {
+    ($level:ident, $($arg:tt)+) => {
+        #[cfg(feature = "defmt-impl")]
+        defmt::$level!($($arg)+)
+    };
+}
+
+pub mod bundle;
+pub mod bus;
+pub mod display;
+pub mod flash;
+
+/// The most bytes that the nRF52832's EasyDMA can send in one go.
+pub const EASY_DMA_SIZE: usize = 255;
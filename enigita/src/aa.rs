@@ -0,0 +1,153 @@
+//! Fixed-point helpers for anti-aliasing.
+//!
+//! Distances are in 1/256ths of a pixel, and the middle of pixel `(x, y)` is at `(x, y)`. A pixel
+//! is taken to be covered by an edge in proportion to how far its middle is inside, so a pixel
+//! whose middle is right on the edge is half covered. This isn't exact at corners, but it is
+//! cheap and looks right.
+//!
+//! There is no floating point `sqrt` or `sin` in `core`, so we have our own integer versions.
+
+/// Completely covered.
+pub const OPAQUE: u8 = 255;
+/// One pixel, as a distance.
+pub const ONE: i64 = 256;
+const HALF: i64 = ONE / 2;
+
+/// How much of a pixel is covered by an edge, when its middle is `inside` (in 1/256ths of a
+/// pixel) inside the edge. Negative is outside.
+#[inline]
+pub fn coverage(inside: i64) -> u8 {
+    (inside + HALF).clamp(0, i64::from(OPAQUE)) as u8
+}
+
+/// The coverage inside two edges that cross, e.g. at a corner.
+#[inline]
+pub fn both(a: u8, b: u8) -> u8 {
+    ((u32::from(a) * u32::from(b) + 127) / 255) as u8
+}
+
+/// The coverage inside either of two edges that cross.
+#[inline]
+pub fn either(a: u8, b: u8) -> u8 {
+    OPAQUE - both(OPAQUE - a, OPAQUE - b)
+}
+
+/// How much of a pixel is covered by a disc of radius `radius` (in 1/256ths), when the pixel's
+/// middle is `dist_sq` (in whole pixels squared) from its centre.
+///
+/// The square root is only taken for pixels on the edge.
+#[inline]
+pub fn disc_coverage(dist_sq: i64, radius: i64) -> u8 {
+    let inner = radius - HALF;
+    let outer = radius + HALF;
+    let dist_sq = dist_sq * ONE * ONE;
+    if inner >= 0 && dist_sq <= inner * inner {
+        OPAQUE
+    } else if dist_sq >= outer * outer {
+        0
+    } else {
+        coverage(radius - isqrt(dist_sq as u64) as i64)
+    }
+}
+
+/// The integer square root, rounded down.
+pub fn isqrt(n: u64) -> u64 {
+    if n < 2 {
+        return n;
+    }
+    // Newton's method, starting from a power of 2 that is at least the root.
+    let mut x = 1 << (32 - n.leading_zeros() / 2);
+    loop {
+        let next = (x + n / x) / 2;
+        if next >= x {
+            return x;
+        }
+        x = next;
+    }
+}
+
+/// `sin` of whole degrees 0 to 90, times 2^14.
+const SIN: [i16; 91] = [
+    0, 286, 572, 857, 1143, 1428, 1713, 1997, 2280, 2563, 2845, 3126, 3406, 3686, 3964, 4240, 4516,
+    4790, 5063, 5334, 5604, 5872, 6138, 6402, 6664, 6924, 7182, 7438, 7692, 7943, 8192, 8438, 8682,
+    8923, 9162, 9397, 9630, 9860, 10087, 10311, 10531, 10749, 10963, 11174, 11381, 11585, 11786,
+    11982, 12176, 12365, 12551, 12733, 12911, 13085, 13255, 13421, 13583, 13741, 13894, 14044,
+    14189, 14330, 14466, 14598, 14726, 14849, 14968, 15082, 15191, 15296, 15396, 15491, 15582,
+    15668, 15749, 15826, 15897, 15964, 16026, 16083, 16135, 16182, 16225, 16262, 16294, 16322,
+    16344, 16362, 16374, 16382, 16384,
+];
+/// The scale of `SIN`, as a shift.
+pub const SIN_SHIFT: u32 = 14;
+
+/// `sin` of an angle in degrees, times 2^14.
+fn sin(degrees: i16) -> i64 {
+    let degrees = degrees.rem_euclid(360) as usize;
+    let value = match degrees {
+        0..=90 => SIN[degrees],
+        91..=180 => SIN[180 - degrees],
+        181..=270 => -SIN[degrees - 180],
+        _ => -SIN[360 - degrees],
+    };
+    value.into()
+}
+
+/// The unit vector pointing `degrees` clockwise from straight up (on a screen, where y goes
+/// down), times 2^14.
+pub fn direction(degrees: i16) -> (i64, i64) {
+    let degrees = degrees.rem_euclid(360);
+    (sin(degrees), -sin(degrees + 90))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn isqrt_rounds_down() {
+        for n in (0..1_000_000).chain(u64::MAX - 1000..=u64::MAX) {
+            let root = isqrt(n);
+            assert!(root * root <= n, "isqrt({}) = {}", n, root);
+            assert!(
+                (root + 1)
+                    .checked_mul(root + 1)
+                    .map_or(true, |next| next > n),
+                "isqrt({}) = {}",
+                n,
+                root
+            );
+        }
+    }
+
+    #[test]
+    fn direction_matches_sin_and_cos() {
+        extern crate std;
+
+        for degrees in -720..=720 {
+            let (x, y) = direction(degrees);
+            let radians = f64::from(degrees).to_radians();
+            let scale = f64::from(1 << SIN_SHIFT);
+            assert!(
+                (x as f64 - radians.sin() * scale).abs() <= 1.0,
+                "{}",
+                degrees
+            );
+            assert!(
+                (y as f64 + radians.cos() * scale).abs() <= 1.0,
+                "{}",
+                degrees
+            );
+        }
+    }
+
+    #[test]
+    fn disc_coverage_at_the_edge() {
+        let radius = 10 * ONE;
+        assert_eq!(disc_coverage(0, radius), OPAQUE);
+        assert_eq!(disc_coverage(9 * 9, radius), OPAQUE);
+        // Middle right on the edge, so half covered.
+        assert_eq!(disc_coverage(10 * 10, radius), 128);
+        assert_eq!(disc_coverage(11 * 11, radius), 0);
+        // A radius 0 disc still covers half of its middle pixel.
+        assert_eq!(disc_coverage(0, 0), 128);
+    }
+}
@@ -1,4 +1,4 @@
-//! An experiment to draw primitives on the PineTime, or any system were there is not enough memory
+//! Draw primitives on the PineTime, or any system were there is not enough memory
 //! for a framebuffer, and where pixels need to be batched in rectangles for performance.
 //!
 //! The idea is that we have a stack of things to draw, bounding boxes so most inside/outside tests
@@ -7,33 +7,73 @@
 //! the color.
 //!
 //! Use signed integers so we can have shapes that go offscreen. The offscreen bits won't be drawn.
+//!
+//! A `Frame` is meant to be kept around as a retained scene: build it once, then `replace` the
+//! things that change (e.g. the minute digits), draw `render()` over `draw_area()`, and call
+//! `mark_drawn` so the next draw only covers the next change.
+//!
+//! Circles, arcs and lines are anti-aliased: each pixel on an edge gets a coverage, and is blended
+//! with whatever is under it in the stack (see `Blend`). Rects and images have hard edges on
+//! pixel boundaries, so they always cover a pixel completely.
 
-#![allow(incomplete_features)]
-#![feature(const_generics)]
-#![feature(const_evaluatable_checked)]
 #![no_std]
 
-use core::{marker::PhantomData, mem};
-use embedded_graphics::pixelcolor::PixelColor;
-use staticvec::StaticVec as Vec;
+use heapless::Vec;
+
+mod aa;
+
+use aa::OPAQUE;
+
+/// Colors that anti-aliased edges can be blended with.
+pub trait Blend: Copy {
+    /// Draw `over` on top of `self`, covering `coverage` of the pixel, from 0 (none of it) to 255
+    /// (all of it).
+    fn blend(self, over: Self, coverage: u8) -> Self;
+}
+
+/// `u16` colors are rgb565, as the PineTime's display uses.
+impl Blend for u16 {
+    fn blend(self, over: Self, coverage: u8) -> Self {
+        // Stretch to 0..=256 so 255 would be all `over`.
+        let t = u32::from(coverage) + u32::from(coverage >> 7);
+        let channel = |shift: u32, mask: u32| {
+            let (a, b) = (
+                (u32::from(self) >> shift) & mask,
+                (u32::from(over) >> shift) & mask,
+            );
+            ((a * (256 - t) + b * t + 128) >> 8) << shift
+        };
+        (channel(11, 0x1f) | channel(5, 0x3f) | channel(0, 0x1f)) as u16
+    }
+}
 
-/*
-/// A frame to draw.
+/// A frame to draw, kept between draws so that only what changed is sent to the screen.
 ///
 /// `LEN` is the maximum number of drawables that can be queued.
-pub struct Frame<Color: 'static, const WIDTH: u16, const HEIGHT: u16, const LEN: usize> {
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "defmt-impl", derive(defmt::Format))]
+pub struct Frame<'a, Color, const WIDTH: u16, const HEIGHT: u16, const LEN: usize> {
     /// This is the color that the pixel will be if nothing is over it.
     bg_color: Color,
     /// The area that needs to be redrawn.
     draw_area: Rect,
     /// The stack of things to draw, ordered last = top to first = bottom.
-    draw_stack: Vec<Drawable<Color>, LEN>,
+    draw_stack: Vec<Drawable<'a, Color>, LEN>,
 }
 
-impl<Color, const WIDTH: u16, const HEIGHT: u16, const LEN: usize> Frame<Color, WIDTH, HEIGHT, LEN>
+impl<'a, Color, const WIDTH: u16, const HEIGHT: u16, const LEN: usize>
+    Frame<'a, Color, WIDTH, HEIGHT, LEN>
 where
-    Color: Copy + 'static,
+    Color: Blend,
 {
+    /// The whole screen.
+    pub const SCREEN: Rect = Rect {
+        x0: 0,
+        y0: 0,
+        x1: WIDTH as i16,
+        y1: HEIGHT as i16,
+    };
+
     #[inline]
     pub fn new(background_color: Color) -> Self {
         Self {
@@ -45,7 +85,8 @@ where
 
     /// Remove all the queued shapes.
     ///
-    /// Call this after drawing the frame to start a new frame.
+    /// Nothing is marked for redrawing, so this is for starting again on a screen that is
+    /// already blank. Use `clear` as well to blank it.
     #[inline]
     pub fn new_frame(&mut self) -> &mut Self {
         self.draw_stack.clear();
@@ -60,112 +101,198 @@ where
     }
 
     /// Sets the color that will be used as the background.
+    ///
+    /// The background shows everywhere, so the whole screen is marked for redrawing.
     #[inline]
     pub fn set_bg_color(&mut self, color: Color) -> &mut Self {
         self.bg_color = color;
-        self
+        self.clear()
     }
 
-    /// Overwrite the screen with the background color.
+    /// Mark the whole screen for redrawing.
     ///
     /// It is best to avoid this if possible because it requires drawing the whole screen.
     #[inline]
     pub fn clear(&mut self) -> &mut Self {
-        self.draw_area = Rect::ZERO.with_size(Size::new(WIDTH, HEIGHT));
+        self.draw_area = Self::SCREEN;
         self
     }
 
     /// Adds a shape to the draw list, on top of existing shapes.
+    ///
+    /// Panics if there are already `LEN` drawables.
     #[inline]
     pub fn draw<D>(&mut self, drawable: D) -> &mut Self
     where
-        D: Into<Drawable<Color>>,
-        Color: 'static,
+        D: Into<Drawable<'a, Color>>,
     {
         let drawable = drawable.into();
-        self.draw_area = self.draw_area.union(drawable.bounding_box());
-        // this panics for now, maybe we want to return an error eventually.
-        self.draw_stack.push(drawable);
+        self.invalidate(drawable.bounding_box());
+        if self.draw_stack.push(drawable).is_err() {
+            panic!("too many drawables in the frame");
+        }
         self
     }
 
+    /// Swap the drawable at `index` (counting up from the bottom of the stack) for a new one,
+    /// e.g. when a digit changes. Both the old and new areas are marked for redrawing.
+    ///
+    /// Panics if there is no drawable at `index`.
+    pub fn replace<D>(&mut self, index: usize, drawable: D) -> &mut Self
+    where
+        D: Into<Drawable<'a, Color>>,
+    {
+        let drawable = drawable.into();
+        let old = &mut self.draw_stack[index];
+        let area = old.bounding_box().union(drawable.bounding_box());
+        *old = drawable;
+        self.invalidate(area)
+    }
+
+    /// Mark an area for redrawing.
+    #[inline]
+    pub fn invalidate(&mut self, area: Rect) -> &mut Self {
+        self.draw_area = self.draw_area.union(area);
+        self
+    }
+
+    /// The number of drawables in the stack.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.draw_stack.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.draw_stack.is_empty()
+    }
+
     // methods to draw a frame
 
-    /// The area that needs to be drawn
+    /// The area that needs to be drawn, clipped to the screen. Empty if nothing has changed.
     #[inline]
     pub fn draw_area(&self) -> Rect {
-        self.draw_area
+        self.draw_area.intersect(Self::SCREEN)
     }
 
     /// Iterates over the pixels inside `self.draw_area()` in row-major order, left-to-right,
     /// top-to-bottom.
-    pub fn render<'a>(&'a self) -> impl Iterator<Item = Color> + 'a {
+    pub fn render(&self) -> RenderFrame<'_, 'a, Color, WIDTH, HEIGHT, LEN> {
         RenderFrame::new(self)
     }
 
+    /// Call this once the frame has been drawn, so the next draw only covers what changes after
+    /// this. The draw list is kept.
+    #[inline]
+    pub fn mark_drawn(&mut self) -> &mut Self {
+        self.draw_area = Rect::ZERO;
+        self
+    }
+
     // helpers
 
     fn pixel_color(&self, pixel: Point) -> Color {
-        self.draw_stack
-            .iter()
-            .filter_map(|d| d.pixel_color(pixel))
-            .next_back()
-            .unwrap_or(self.bg_color)
+        color_at(&self.draw_stack, self.bg_color, pixel)
     }
 }
 
-struct RenderFrame<'a, Color: 'static, const WIDTH: u16, const HEIGHT: u16, const LEN: usize> {
-    frame: &'a Frame<Color, WIDTH, HEIGHT, LEN>,
+/// The color of a pixel with `stack` drawn over `bg_color`. The stack is walked down from the top
+/// until something covers the pixel completely, and anything partly covering it on the way is
+/// blended over that.
+fn color_at<Color: Blend>(stack: &[Drawable<'_, Color>], bg_color: Color, pixel: Point) -> Color {
+    for (index, drawable) in stack.iter().enumerate().rev() {
+        match drawable.pixel(pixel) {
+            Some((color, OPAQUE)) => return color,
+            Some((color, coverage)) => {
+                return color_at(&stack[..index], bg_color, pixel).blend(color, coverage)
+            }
+            None => (),
+        }
+    }
+    bg_color
+}
+
+/// The pixels of a frame's draw area. See `Frame::render`.
+#[derive(Debug, Clone)]
+pub struct RenderFrame<'f, 'a, Color, const WIDTH: u16, const HEIGHT: u16, const LEN: usize> {
+    frame: &'f Frame<'a, Color, WIDTH, HEIGHT, LEN>,
+    area: Rect,
     pos: Point,
 }
 
-impl<'a, Color, const WIDTH: u16, const HEIGHT: u16, const LEN: usize>
-    RenderFrame<'a, Color, WIDTH, HEIGHT, LEN>
+impl<'f, 'a, Color, const WIDTH: u16, const HEIGHT: u16, const LEN: usize>
+    RenderFrame<'f, 'a, Color, WIDTH, HEIGHT, LEN>
+where
+    Color: Blend,
 {
-    fn new(frame: &'a Frame<Color, WIDTH, HEIGHT, LEN>) -> Self {
+    fn new(frame: &'f Frame<'a, Color, WIDTH, HEIGHT, LEN>) -> Self {
+        let area = frame.draw_area();
         Self {
             frame,
-            pos: frame.draw_area.top_left(),
+            area,
+            pos: area.top_left(),
         }
     }
 }
 
-impl<'a, Color, const WIDTH: u16, const HEIGHT: u16, const LEN: usize> Iterator
-    for RenderFrame<'a, Color, WIDTH, HEIGHT, LEN>
+impl<'f, 'a, Color, const WIDTH: u16, const HEIGHT: u16, const LEN: usize> Iterator
+    for RenderFrame<'f, 'a, Color, WIDTH, HEIGHT, LEN>
 where
-    Color: Copy,
+    Color: Blend,
 {
     type Item = Color;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.frame.draw_area.y1 <= self.pos.y {
+        if self.area.is_empty() || self.area.y1 <= self.pos.y {
             return None;
         }
         let color = self.frame.pixel_color(self.pos);
         self.pos.x += 1;
         // check for new row
-        if self.pos.x == self.frame.draw_area.x1 {
-            self.pos.x = self.frame.draw_area.x0;
+        if self.pos.x == self.area.x1 {
+            self.pos.x = self.area.x0;
             self.pos.y += 1;
         }
         Some(color)
     }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = if self.area.is_empty() || self.area.y1 <= self.pos.y {
+            0
+        } else {
+            let width = self.area.size().width as usize;
+            let rows_left = (self.area.y1 - self.pos.y) as usize;
+            rows_left * width - (self.pos.x - self.area.x0) as usize
+        };
+        (len, Some(len))
+    }
+}
+
+impl<'f, 'a, Color, const WIDTH: u16, const HEIGHT: u16, const LEN: usize> ExactSizeIterator
+    for RenderFrame<'f, 'a, Color, WIDTH, HEIGHT, LEN>
+where
+    Color: Blend,
+{
 }
 
-pub enum Drawable<'a> {
+/// Something that can go in a frame's draw stack.
+#[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "defmt-impl", derive(defmt::Format))]
+pub enum Drawable<'a, Color> {
     Shape(StyledShape<Color>),
-    Image(Image<'a>),
+    Image(Image<'a, Color>),
 }
 
-impl<Color: 'static + Copy> Drawable<Color> {
-    fn pixel_color(&self, pixel: Point) -> Option<Color> {
+impl<'a, Color: Copy> Drawable<'a, Color> {
+    /// The color at a pixel, and how much of the pixel it covers (255 is all of it).
+    fn pixel(&self, pixel: Point) -> Option<(Color, u8)> {
         match self {
-            Drawable::Shape(s) => s.pixel_color(pixel),
-            Drawable::Image(i) => i.pixel_color(pixel),
+            Drawable::Shape(s) => s.pixel(pixel),
+            Drawable::Image(i) => i.pixel_color(pixel).map(|color| (color, OPAQUE)),
         }
     }
 
-    fn bounding_box(&self) -> Rect {
+    pub fn bounding_box(&self) -> Rect {
         match self {
             Drawable::Shape(s) => s.bounding_box(),
             Drawable::Image(i) => i.bounding_box(),
@@ -173,40 +300,45 @@ impl<Color: 'static + Copy> Drawable<Color> {
     }
 }
 
-impl<Color: 'static> From<StyledShape<Color>> for Drawable<Color> {
+impl<'a, Color> From<StyledShape<Color>> for Drawable<'a, Color> {
     fn from(shape: StyledShape<Color>) -> Self {
         Drawable::Shape(shape)
     }
 }
-*/
 
-pub struct StyledShape<Color: 'static> {
+impl<'a, Color> From<Image<'a, Color>> for Drawable<'a, Color> {
+    fn from(image: Image<'a, Color>) -> Self {
+        Drawable::Image(image)
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "defmt-impl", derive(defmt::Format))]
+pub struct StyledShape<Color> {
     fill_color: Color,
     shape: Shape,
 }
 
-impl<Color: 'static + Copy> StyledShape<Color> {
-    fn pixel_color(&self, pixel: Point) -> Option<Color> {
-        match self.shape {
-            Shape::Rect(r) => {
-                if r.hit_test(pixel) {
-                    Some(self.fill_color)
-                } else {
-                    None
-                }
-            }
+impl<Color: Copy> StyledShape<Color> {
+    fn pixel(&self, pixel: Point) -> Option<(Color, u8)> {
+        match self.shape.coverage(pixel) {
+            0 => None,
+            coverage => Some((self.fill_color, coverage)),
         }
     }
 
     fn bounding_box(&self) -> Rect {
-        match self.shape {
-            Shape::Rect(r) => r,
-        }
+        self.shape.bounding_box()
     }
 }
 
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt-impl", derive(defmt::Format))]
 pub enum Shape {
     Rect(Rect),
+    Circle(Circle),
+    Arc(Arc),
+    Line(Line),
 }
 
 impl Shape {
@@ -214,12 +346,29 @@ impl Shape {
     pub fn bounding_box(&self) -> Rect {
         match self {
             Shape::Rect(r) => (*r).bounding_box(),
+            Shape::Circle(c) => c.bounding_box(),
+            Shape::Arc(a) => a.bounding_box(),
+            Shape::Line(l) => l.bounding_box(),
         }
     }
 
     pub fn hit_test(&self, point: Point) -> bool {
+        self.coverage(point) > 0
+    }
+
+    /// How much of the pixel at `point` the shape covers, from 0 (none of it) to 255 (all of it).
+    pub fn coverage(&self, point: Point) -> u8 {
         match self {
-            Shape::Rect(r) => r.hit_test(point),
+            Shape::Rect(r) => {
+                if r.hit_test(point) {
+                    OPAQUE
+                } else {
+                    0
+                }
+            }
+            Shape::Circle(c) => c.coverage(point),
+            Shape::Arc(a) => a.coverage(point),
+            Shape::Line(l) => l.coverage(point),
         }
     }
 
@@ -235,6 +384,7 @@ impl Shape {
 
 /// A rectangle
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt-impl", derive(defmt::Format))]
 pub struct Rect {
     /// top left x of the rectangle
     x0: i16,
@@ -381,12 +531,18 @@ impl Rect {
         }
     }
 
+    /// The area inside both rectangles, or `Rect::ZERO` if they don't overlap.
     pub fn intersect(self, other: Self) -> Self {
-        Self {
+        let rect = Self {
             x0: self.x0.max(other.x0),
             x1: self.x1.min(other.x1),
             y0: self.y0.max(other.y0),
             y1: self.y1.min(other.y1),
+        };
+        if rect.x0 >= rect.x1 || rect.y0 >= rect.y1 {
+            Rect::ZERO
+        } else {
+            rect
         }
     }
 
@@ -405,53 +561,341 @@ impl From<Rect> for Shape {
     }
 }
 
-// Circle - this is not included because drawing scanlines of circles is hard. Recommended way to
-// draw circles is to render them to memory using 1 bit per pixel in an ordering suitable for fast
-// conversion into scanlines.
+// Circle
 
-/*
+/// A filled circle, centred on the middle of a pixel.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt-impl", derive(defmt::Format))]
 pub struct Circle {
     center: Point,
     radius: u16,
 }
 
-impl Ellipse {
+impl Circle {
+    #[inline]
     pub fn new(center: Point, radius: u16) -> Self {
         Self { center, radius }
     }
 
-    pub fn center(&self) -> Point {
+    #[inline]
+    pub fn center(self) -> Point {
         self.center
     }
 
-    pub fn set_center(&mut self, center: Point) -> &mut Self {
-        self.center = center;
-        self
+    #[inline]
+    pub fn radius(self) -> u16 {
+        self.radius
+    }
+
+    #[inline]
+    pub fn bounding_box(self) -> Rect {
+        around(self.center, self.radius)
+    }
+
+    /// How much of the pixel at `point` is inside the circle.
+    pub fn coverage(&self, point: Point) -> u8 {
+        let (dx, dy) = offset(self.center, point);
+        aa::disc_coverage(dx * dx + dy * dy, i64::from(self.radius) * aa::ONE)
+    }
+
+    #[inline]
+    pub fn style<Color>(self, fill_color: Color) -> StyledShape<Color> {
+        StyledShape {
+            fill_color,
+            shape: self.into(),
+        }
+    }
+}
+
+impl From<Circle> for Shape {
+    fn from(circle: Circle) -> Self {
+        Self::Circle(circle)
+    }
+}
+
+// Arc
+
+/// A ring, or part of one (e.g. for a progress indicator).
+///
+/// Angles are in degrees clockwise from straight up, like on a clock face. The arc goes clockwise
+/// from `start` to `end`, so 350 to 10 is 20 degrees over the top, and 0 to 360 is a whole ring.
+/// The ends are cut off square, along the radius.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt-impl", derive(defmt::Format))]
+pub struct Arc {
+    center: Point,
+    /// The outside radius.
+    radius: u16,
+    /// How far the ring goes in from `radius`. If this is at least `radius` the arc is a pie
+    /// slice.
+    thickness: u16,
+    start: i16,
+    end: i16,
+}
+
+impl Arc {
+    #[inline]
+    pub fn new(center: Point, radius: u16, thickness: u16, start: i16, end: i16) -> Self {
+        Self {
+            center,
+            radius,
+            thickness,
+            start,
+            end,
+        }
     }
 
-    pub fn radius(&self) -> u16 {
+    #[inline]
+    pub fn center(self) -> Point {
+        self.center
+    }
+
+    #[inline]
+    pub fn radius(self) -> u16 {
         self.radius
     }
 
-    pub fn set_radius(&mut self, radius: u16) -> &mut Self {
-        self.radius = radius;
-        self
+    #[inline]
+    pub fn thickness(self) -> u16 {
+        self.thickness
+    }
+
+    /// The start and end angles.
+    #[inline]
+    pub fn angles(self) -> (i16, i16) {
+        (self.start, self.end)
     }
 
-    pub fn hit_test(&self, point: Point) -> bool {}
+    /// The bounding box of the whole ring.
+    #[inline]
+    pub fn bounding_box(self) -> Rect {
+        around(self.center, self.radius)
+    }
+
+    /// How much of the pixel at `point` is inside the arc.
+    pub fn coverage(&self, point: Point) -> u8 {
+        let (dx, dy) = offset(self.center, point);
+        let dist_sq = dx * dx + dy * dy;
+        let mut coverage = aa::disc_coverage(dist_sq, i64::from(self.radius) * aa::ONE);
+        if self.thickness < self.radius && coverage > 0 {
+            let inner = i64::from(self.radius - self.thickness) * aa::ONE;
+            coverage = coverage.min(OPAQUE - aa::disc_coverage(dist_sq, inner));
+        }
+        if coverage == 0 {
+            return 0;
+        }
+        aa::both(coverage, self.angle_coverage(dx, dy))
+    }
+
+    /// How much of a pixel at `(dx, dy)` from the centre is between the start and end angles.
+    fn angle_coverage(&self, dx: i64, dy: i64) -> u8 {
+        let sweep = i32::from(self.end) - i32::from(self.start);
+        if sweep >= 360 {
+            return OPAQUE;
+        }
+        let sweep = sweep.rem_euclid(360);
+        if sweep == 0 {
+            return 0;
+        }
+        // The distances from the lines through the ends, positive on the side the arc is on.
+        let shift = aa::SIN_SHIFT - 8;
+        let (sx, sy) = aa::direction(self.start);
+        let (ex, ey) = aa::direction(self.end);
+        let past_start = aa::coverage((sx * dy - sy * dx) >> shift);
+        let before_end = aa::coverage((dx * ey - dy * ex) >> shift);
+        // Up to half a turn, the arc is where both sides overlap. Past that, it is everywhere
+        // except where neither does.
+        if sweep <= 180 {
+            aa::both(past_start, before_end)
+        } else {
+            aa::either(past_start, before_end)
+        }
+    }
+
+    #[inline]
+    pub fn style<Color>(self, fill_color: Color) -> StyledShape<Color> {
+        StyledShape {
+            fill_color,
+            shape: self.into(),
+        }
+    }
+}
+
+impl From<Arc> for Shape {
+    fn from(arc: Arc) -> Self {
+        Self::Arc(arc)
+    }
+}
+
+// Line
+
+/// A straight line with round ends, e.g. a watch hand.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt-impl", derive(defmt::Format))]
+pub struct Line {
+    start: Point,
+    end: Point,
+    width: u16,
+    /// The length, in 1/256ths of a pixel, so it doesn't need working out for every pixel.
+    len: u64,
+}
+
+impl Line {
+    pub fn new(start: Point, end: Point, width: u16) -> Self {
+        let (dx, dy) = offset(start, end);
+        let len = aa::isqrt(((dx * dx + dy * dy) * aa::ONE * aa::ONE) as u64);
+        Self {
+            start,
+            end,
+            width,
+            len,
+        }
+    }
+
+    /// A line `length` long, out from `center` at `degrees` clockwise from straight up.
+    pub fn hand(center: Point, length: u16, degrees: i16, width: u16) -> Self {
+        let (x, y) = aa::direction(degrees);
+        let length = i64::from(length);
+        let round = 1 << (aa::SIN_SHIFT - 1);
+        let end = Point {
+            x: center.x + ((x * length + round) >> aa::SIN_SHIFT) as i16,
+            y: center.y + ((y * length + round) >> aa::SIN_SHIFT) as i16,
+        };
+        Line::new(center, end, width)
+    }
+
+    #[inline]
+    pub fn start(self) -> Point {
+        self.start
+    }
+
+    #[inline]
+    pub fn end(self) -> Point {
+        self.end
+    }
+
+    #[inline]
+    pub fn width(self) -> u16 {
+        self.width
+    }
+
+    pub fn bounding_box(self) -> Rect {
+        let margin = i32::from(self.width / 2 + 1);
+        let (x0, x1) = min_max(self.start.x, self.end.x);
+        let (y0, y1) = min_max(self.start.y, self.end.y);
+        Rect::new_unchecked(
+            clamp_i16(x0 - margin),
+            clamp_i16(y0 - margin),
+            clamp_i16(x1 + margin + 1),
+            clamp_i16(y1 + margin + 1),
+        )
+    }
+
+    /// How much of the pixel at `point` is inside the line.
+    pub fn coverage(&self, point: Point) -> u8 {
+        let half_width = i64::from(self.width) * aa::ONE / 2;
+        let (vx, vy) = offset(self.start, self.end);
+        let (wx, wy) = offset(self.start, point);
+        let len_sq = vx * vx + vy * vy;
+        let along = wx * vx + wy * vy;
+        if along <= 0 || len_sq == 0 {
+            // By the start, so the distance is to the start.
+            aa::disc_coverage(wx * wx + wy * wy, half_width)
+        } else if along >= len_sq {
+            let (dx, dy) = offset(self.end, point);
+            aa::disc_coverage(dx * dx + dy * dy, half_width)
+        } else {
+            let across = (vx * wy - vy * wx).abs();
+            aa::coverage(half_width - across * aa::ONE * aa::ONE / self.len as i64)
+        }
+    }
+
+    #[inline]
+    pub fn style<Color>(self, fill_color: Color) -> StyledShape<Color> {
+        StyledShape {
+            fill_color,
+            shape: self.into(),
+        }
+    }
+}
+
+impl From<Line> for Shape {
+    fn from(line: Line) -> Self {
+        Self::Line(line)
+    }
+}
+
+/// `to - from`, widened so it can be squared.
+#[inline]
+fn offset(from: Point, to: Point) -> (i64, i64) {
+    (
+        i64::from(to.x) - i64::from(from.x),
+        i64::from(to.y) - i64::from(from.y),
+    )
+}
+
+/// The smallest rect holding every pixel that is partly within `radius` of `center`.
+fn around(center: Point, radius: u16) -> Rect {
+    let (x, y, r) = (i32::from(center.x), i32::from(center.y), i32::from(radius));
+    Rect::new_unchecked(
+        clamp_i16(x - r),
+        clamp_i16(y - r),
+        clamp_i16(x + r + 1),
+        clamp_i16(y + r + 1),
+    )
+}
+
+#[inline]
+fn min_max(a: i16, b: i16) -> (i32, i32) {
+    (a.min(b).into(), a.max(b).into())
+}
+
+#[inline]
+fn clamp_i16(value: i32) -> i16 {
+    value.clamp(i16::MIN.into(), i16::MAX.into()) as i16
 }
-*/
 
 // Image
 
-pub struct Image<'a> {
+/// Pixels in row-major order, covering `area`.
+#[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "defmt-impl", derive(defmt::Format))]
+pub struct Image<'a, Color> {
     pub area: Rect,
-    pub buf: &'a [u8],
+    pub pixels: &'a [Color],
+}
+
+impl<'a, Color: Copy> Image<'a, Color> {
+    /// Returns `None` if there aren't enough pixels to fill the area.
+    pub fn new(area: Rect, pixels: &'a [Color]) -> Option<Self> {
+        if pixels.len() < usize::from(area.size().width) * usize::from(area.size().height) {
+            None
+        } else {
+            Some(Self { area, pixels })
+        }
+    }
+
+    #[inline]
+    pub fn bounding_box(&self) -> Rect {
+        self.area
+    }
+
+    fn pixel_color(&self, pixel: Point) -> Option<Color> {
+        if !self.area.hit_test(pixel) {
+            return None;
+        }
+        let x = (pixel.x - self.area.x0) as usize;
+        let y = (pixel.y - self.area.y0) as usize;
+        self.pixels
+            .get(y * usize::from(self.area.size().width) + x)
+            .copied()
+    }
 }
 
 // Point
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt-impl", derive(defmt::Format))]
 pub struct Point {
     pub x: i16,
     pub y: i16,
@@ -471,6 +915,7 @@ impl From<(i16, i16)> for Point {
 // Size
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt-impl", derive(defmt::Format))]
 pub struct Size {
     pub width: u16,
     pub height: u16,
@@ -481,3 +926,330 @@ impl Size {
         Size { width, height }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::{env, fs, path::PathBuf, vec::Vec};
+
+    const SIZE: u16 = 48;
+
+    type TestFrame = Frame<'static, u16, SIZE, SIZE, 8>;
+
+    const NAVY: u16 = 0x0010;
+    const WHITE: u16 = 0xffff;
+    const RED: u16 = 0xf800;
+    const GREEN: u16 = 0x07e0;
+    const YELLOW: u16 = 0xffe0;
+
+    /// A frame small enough to write out by hand, with colors as labels.
+    type SmallFrame<'a> = Frame<'a, u16, 6, 4, 3>;
+
+    fn rect(x0: i16, y0: i16, x1: i16, y1: i16) -> Rect {
+        Rect::new(x0, y0, x1, y1).unwrap()
+    }
+
+    #[test]
+    fn stacks_rects_and_images() {
+        static PIXELS: [u16; 4] = [7, 8, 9, 10];
+        let image = Image::new(rect(3, 1, 5, 3), &PIXELS).unwrap();
+        let mut frame = SmallFrame::new(0);
+        frame.draw(rect(0, 0, 4, 3).style(1)).draw(image);
+        assert_eq!(frame.draw_area(), rect(0, 0, 5, 3));
+        let pixels: Vec<u16> = frame.render().collect();
+        #[rustfmt::skip]
+        assert_eq!(pixels, [
+            1, 1, 1, 1, 0,
+            1, 1, 1, 7, 8,
+            1, 1, 1, 9, 10,
+        ]);
+
+        // The rect on top of the image.
+        frame.draw(rect(4, 2, 6, 4).style(2));
+        assert_eq!(frame.draw_area(), SmallFrame::SCREEN);
+        let pixels: Vec<u16> = frame.render().collect();
+        #[rustfmt::skip]
+        assert_eq!(pixels, [
+            1, 1, 1, 1, 0, 0,
+            1, 1, 1, 7, 8, 0,
+            1, 1, 1, 9, 2, 2,
+            0, 0, 0, 0, 2, 2,
+        ]);
+    }
+
+    #[test]
+    fn an_image_needs_enough_pixels() {
+        assert!(Image::new(rect(0, 0, 2, 2), &[1u16, 2, 3]).is_none());
+    }
+
+    #[test]
+    fn replaces_partly_offscreen() {
+        let mut frame = SmallFrame::new(0);
+        frame
+            .draw(rect(1, 1, 3, 3).style(1))
+            .draw(rect(0, 0, 1, 1).style(2));
+        frame.mark_drawn();
+        assert_eq!(frame.draw_area(), Rect::ZERO);
+        assert_eq!(frame.render().len(), 0);
+        assert_eq!(frame.render().next(), None);
+
+        // Off the bottom right. The old area has to be redrawn too.
+        frame.replace(0, rect(4, 2, 9, 9).style(3));
+        assert_eq!(frame.len(), 2);
+        assert_eq!(frame.draw_area(), rect(1, 1, 6, 4));
+        let pixels = frame.render();
+        assert_eq!(pixels.len(), 15);
+        let pixels: Vec<u16> = pixels.collect();
+        #[rustfmt::skip]
+        assert_eq!(pixels, [
+            0, 0, 0, 0, 0,
+            0, 0, 0, 3, 3,
+            0, 0, 0, 3, 3,
+        ]);
+
+        // Entirely off the screen, so nothing to draw.
+        frame.mark_drawn();
+        frame.invalidate(rect(-5, -5, -1, 2));
+        assert_eq!(frame.draw_area(), Rect::ZERO);
+        assert_eq!(frame.render().count(), 0);
+    }
+
+    #[test]
+    fn render_length_counts_down() {
+        let mut frame = SmallFrame::new(0);
+        frame.draw(rect(1, 1, 4, 3).style(1));
+        let mut pixels = frame.render();
+        for left in (0..=6).rev() {
+            assert_eq!(pixels.len(), left);
+            assert_eq!(pixels.next().is_some(), left > 0);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "too many drawables")]
+    fn panics_when_full() {
+        let mut frame = SmallFrame::new(0);
+        for color in 0..4 {
+            frame.draw(rect(0, 0, 1, 1).style(color));
+        }
+    }
+
+    #[test]
+    fn rect_union_and_intersect() {
+        let (a, b) = (rect(0, 0, 4, 4), rect(2, 3, 6, 8));
+        assert_eq!(a.union(b), rect(0, 0, 6, 8));
+        assert_eq!(a.intersect(b), rect(2, 3, 4, 4));
+        // Empty rects are left out of unions.
+        assert_eq!(Rect::ZERO.union(b), b);
+        assert_eq!(a.union(rect(9, 9, 9, 12)), a);
+        // Touching, or apart, is no overlap.
+        assert_eq!(a.intersect(rect(4, 0, 8, 4)), Rect::ZERO);
+        assert_eq!(a.intersect(rect(-9, -9, -5, -5)), Rect::ZERO);
+        assert_eq!(Rect::new(3, 0, 2, 1), None);
+    }
+
+    /// A small watch face: a dial, a ring for the seconds, two hands and a box behind them.
+    fn watch_face() -> TestFrame {
+        let center = Point { x: 24, y: 24 };
+        let mut frame = TestFrame::new(NAVY);
+        frame
+            .draw(Rect::new(30, 2, 46, 14).unwrap().style(GREEN))
+            .draw(Circle::new(center, 20).style(WHITE))
+            .draw(Arc::new(center, 20, 3, 300, 60).style(RED))
+            .draw(Line::hand(center, 16, 100, 3).style(NAVY))
+            .draw(Line::hand(center, 11, 225, 4).style(NAVY))
+            .draw(Circle::new(center, 2).style(YELLOW));
+        frame
+    }
+
+    fn golden(name: &str) -> PathBuf {
+        [env!("CARGO_MANIFEST_DIR"), "golden", name]
+            .iter()
+            .collect()
+    }
+
+    /// The frame's draw area as a binary ppm, so the goldens can be looked at.
+    fn ppm(frame: &TestFrame) -> Vec<u8> {
+        let size = frame.draw_area().size();
+        let mut out = std::format!("P6\n{} {}\n255\n", size.width, size.height).into_bytes();
+        for color in frame.render() {
+            let (r, g, b) = (color >> 11, (color >> 5) & 0x3f, color & 0x1f);
+            out.extend([r << 3 | r >> 2, g << 2 | g >> 4, b << 3 | b >> 2].map(|c| c as u8));
+        }
+        out
+    }
+
+    /// Compare the frame's draw area with the golden ppm. With `UPDATE_SCREENSHOTS` set in the
+    /// environment, the golden is saved instead. Look at the changes before committing them!
+    fn assert_golden(frame: &TestFrame, name: &str) {
+        let path = golden(name);
+        let actual = ppm(frame);
+        if env::var_os("UPDATE_SCREENSHOTS").is_some() {
+            fs::write(&path, &actual).expect("saving the golden");
+            return;
+        }
+        let expected =
+            fs::read(&path).unwrap_or_else(|err| panic!("reading {}: {}", path.display(), err));
+        if actual != expected {
+            let saved = env::temp_dir().join(name);
+            fs::write(&saved, &actual).expect("saving the frame");
+            panic!(
+                "{} differs from the frame, which is in {}",
+                path.display(),
+                saved.display()
+            );
+        }
+    }
+
+    #[test]
+    fn renders_the_golden_watch_face() {
+        let frame = watch_face();
+        assert_eq!(frame.draw_area(), Rect::new(4, 2, 46, 45).unwrap());
+        assert_golden(&frame, "watch_face.ppm");
+    }
+
+    #[test]
+    fn redraws_just_the_moved_hand() {
+        let mut frame = watch_face();
+        frame.mark_drawn();
+        frame.replace(
+            3,
+            Line::hand(Point { x: 24, y: 24 }, 16, 106, 3).style(NAVY),
+        );
+        assert_eq!(frame.draw_area(), Rect::new(22, 22, 43, 31).unwrap());
+        assert_golden(&frame, "watch_face_moved_hand.ppm");
+
+        // The same pixels as drawing the whole frame again.
+        let area = frame.draw_area();
+        let mut whole = frame.clone();
+        whole.clear();
+        let whole: Vec<u16> = whole.render().collect();
+        let mut pixels = frame.render();
+        for y in area.y0()..area.y1() {
+            for x in area.x0()..area.x1() {
+                let index = usize::from(y as u16 * SIZE + x as u16);
+                assert_eq!(pixels.next(), Some(whole[index]), "at ({}, {})", x, y);
+            }
+        }
+        assert_eq!(pixels.next(), None);
+    }
+
+    /// How much of the pixel at `point` is inside, sampled 16 times each way.
+    fn supersampled(inside: impl Fn(f64, f64) -> bool, point: Point) -> f64 {
+        let mut hits = 0;
+        for sy in 0..16 {
+            for sx in 0..16 {
+                let x = f64::from(point.x) - 0.5 + (f64::from(sx) + 0.5) / 16.0;
+                let y = f64::from(point.y) - 0.5 + (f64::from(sy) + 0.5) / 16.0;
+                if inside(x, y) {
+                    hits += 1;
+                }
+            }
+        }
+        f64::from(hits) * 255.0 / 256.0
+    }
+
+    /// Check a shape's coverage against a supersampled reference, over a margin around its
+    /// bounding box. Nothing can be outside the bounding box, and edges must be close.
+    fn check_coverage(shape: Shape, inside: impl Fn(f64, f64) -> bool) {
+        let bounds = shape.bounding_box();
+        let (mut edge_error, mut edge_pixels) = (0.0, 0);
+        for y in bounds.y0() - 3..bounds.y1() + 3 {
+            for x in bounds.x0() - 3..bounds.x1() + 3 {
+                let point = Point { x, y };
+                let expected = supersampled(&inside, point);
+                let actual = f64::from(shape.coverage(point));
+                if !bounds.hit_test(point) {
+                    assert_eq!(expected, 0.0, "{:?} outside the bounding box", point);
+                    assert_eq!(actual, 0.0, "{:?} outside the bounding box", point);
+                }
+                let error = (actual - expected).abs();
+                assert!(error <= 24.0, "{:?}: {} not {}", point, actual, expected);
+                if expected != 0.0 && expected != 255.0 {
+                    edge_error += error;
+                    edge_pixels += 1;
+                }
+            }
+        }
+        assert!(edge_pixels > 0);
+        let mean = edge_error / f64::from(edge_pixels);
+        assert!(
+            mean <= 10.0,
+            "mean error {} on the edges of {:?}",
+            mean,
+            shape
+        );
+    }
+
+    /// The clockwise angle from straight up to `(dx, dy)`, in degrees from 0 to 360.
+    fn angle(dx: f64, dy: f64) -> f64 {
+        dx.atan2(-dy).to_degrees().rem_euclid(360.0)
+    }
+
+    #[test]
+    fn circle_coverage() {
+        for radius in [1, 2, 5, 20] {
+            let circle = Circle::new(Point { x: 3, y: -2 }, radius);
+            let r = f64::from(radius);
+            check_coverage(circle.into(), |x, y| {
+                (x - 3.0).powi(2) + (y + 2.0).powi(2) <= r * r
+            });
+        }
+    }
+
+    #[test]
+    fn arc_coverage() {
+        for (radius, thickness, start, end) in [
+            (20, 4, 0, 90),
+            (20, 4, 300, 60),
+            (15, 15, 45, 315),
+            (12, 3, 0, 360),
+        ] {
+            let arc = Arc::new(Point::ORIGIN, radius, thickness, start, end);
+            let (outer, inner) = (f64::from(radius), f64::from(radius - thickness));
+            let sweep = f64::from(end - start).rem_euclid(360.0);
+            let sweep = if end - start >= 360 { 360.0 } else { sweep };
+            check_coverage(arc.into(), |x, y| {
+                let dist_sq = x * x + y * y;
+                let along = (angle(x, y) - f64::from(start)).rem_euclid(360.0);
+                dist_sq <= outer * outer && dist_sq >= inner * inner && along <= sweep
+            });
+        }
+    }
+
+    #[test]
+    fn line_coverage() {
+        for (start, end, width) in [
+            ((0, 0), (20, 0), 3),
+            ((0, 0), (0, -15), 2),
+            ((-5, 3), (17, 11), 4),
+            ((2, 2), (2, 2), 6),
+        ] {
+            let line = Line::new(start.into(), end.into(), width);
+            let (sx, sy) = (f64::from(start.0), f64::from(start.1));
+            let (vx, vy) = (f64::from(end.0) - sx, f64::from(end.1) - sy);
+            let half_width = f64::from(width) / 2.0;
+            check_coverage(line.into(), |x, y| {
+                let (wx, wy) = (x - sx, y - sy);
+                let len_sq = vx * vx + vy * vy;
+                let t = if len_sq == 0.0 {
+                    0.0
+                } else {
+                    ((wx * vx + wy * vy) / len_sq).clamp(0.0, 1.0)
+                };
+                (wx - t * vx).powi(2) + (wy - t * vy).powi(2) <= half_width * half_width
+            });
+        }
+    }
+
+    #[test]
+    fn blends_rgb565() {
+        assert_eq!(NAVY.blend(WHITE, 0), NAVY);
+        assert_eq!(NAVY.blend(WHITE, 255), WHITE);
+        // Halfway, rounded.
+        assert_eq!(0u16.blend(WHITE, 128), 0x8410);
+        assert_eq!(RED.blend(GREEN, 128), 0x7c00);
+    }
+}
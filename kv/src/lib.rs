@@ -0,0 +1,646 @@
+//! A key/value store for small values (settings and the like) on NOR flash, that survives losing
+//! power at any point.
+//!
+//! The store takes a run of erase sectors, and appends records to a log in them. Changing a value
+//! writes a new record rather than erasing anything, and the last record for a key wins. When a
+//! sector fills up the log moves on to the next erased one, going round the sectors in turn so
+//! that they all wear at the same rate. One sector is always kept erased: before the last one is
+//! used, the records still live in the oldest sector are copied into it, and the oldest sector is
+//! erased ("garbage collection").
+//!
+//! Writes are ordered so that losing power part way through leaves either the old value or the
+//! new one:
+//!
+//! - Every record ends in a CRC. A torn record fails it, and nothing more is written to its
+//!   sector (as where the next record would start is lost).
+//! - Every sector starts with a header holding a sequence number, which puts the sectors in log
+//!   order. A sector being filled by garbage collection is marked as such until the copying is
+//!   done, and the sector the records came from is only erased after that.
+//! - [`Store::mount`] erases anything half written (a torn header, an unfinished copy or a half
+//!   erased sector), and finishes a garbage collection that was interrupted before the erase.
+//!
+//! The store only keeps an index of where each key's latest record is, not the flash, so the
+//! firmware can lend it the flash driver while the bus is free. Any flash that implements the
+//! `embedded-storage` NOR traits and can write single bytes will do.
+//!
+//! # Layout
+//!
+//! Numbers are little endian.
+//!
+//! A sector starts with a 16 byte header: `b"DJKV"`, the sequence number (u32), a CRC-32 of the
+//! 8 bytes before, a state byte (0xFF while garbage collection is filling the sector, 0x00 once
+//! it is ready), then 3 bytes of 0xFF.
+//!
+//! Records follow one after another: the key (u16), the length of the value (u16, with the top
+//! bit set if the key was removed), the value, then a CRC-32 of the rest of the record. The first
+//! record whose first 4 bytes are still erased marks the end of the log in that sector.
+#![no_std]
+
+mod value;
+
+pub use crate::value::{Key, Value};
+
+use assets::crc::crc32;
+use core::convert::TryInto;
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+use heapless::{LinearMap, Vec};
+
+/// The most sectors the store can use.
+pub const MAX_SECTORS: usize = 16;
+/// The most different keys that can be stored.
+pub const MAX_KEYS: usize = 64;
+/// The longest value, in bytes.
+pub const MAX_VALUE_LEN: usize = 256;
+
+const MAGIC: [u8; 4] = *b"DJKV";
+const HEADER_LEN: u32 = 16;
+/// Where the state byte is in the sector header.
+const STATE_OFFSET: u32 = 12;
+/// Garbage collection is copying records into the sector.
+const COPYING: u8 = 0xFF;
+const READY: u8 = 0x00;
+/// The key, length and CRC.
+const RECORD_OVERHEAD: usize = 8;
+const MAX_RECORD_LEN: usize = RECORD_OVERHEAD + MAX_VALUE_LEN;
+/// Set in the length of a record that removes its key.
+const REMOVED: u16 = 0x8000;
+/// Erased flash reads as this, so it can't be a key.
+const NO_KEY: u16 = 0xFFFF;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-impl", derive(defmt::Format))]
+pub enum Error<E> {
+    /// The flash reported an error. The store should be mounted again before it is used further.
+    Flash(E),
+    /// There isn't room for the record, even after garbage collection.
+    Full,
+    /// The value is longer than [`MAX_VALUE_LEN`], or than the buffer it is being read into.
+    TooBig,
+    /// There are already [`MAX_KEYS`] keys.
+    TooManyKeys,
+    /// `0xFFFF` was used as a key.
+    BadKey,
+    /// A record failed its CRC when read back.
+    Corrupt,
+    /// The stored value couldn't be decoded as the key's type.
+    WrongType,
+    /// The store must be at least 2 and at most [`MAX_SECTORS`] whole sectors, inside the flash.
+    BadRegion,
+}
+
+impl<E> From<E> for Error<E> {
+    fn from(e: E) -> Self {
+        Error::Flash(e)
+    }
+}
+
+pub struct Store {
+    start: u32,
+    sector_size: u32,
+    /// The sequence number of each sector, or `None` if it is erased.
+    sectors: Vec<Option<u32>, MAX_SECTORS>,
+    /// The sector the log is being appended to, and where the next record goes in it.
+    active: Option<(usize, u32)>,
+    /// The address of the latest record for each key that hasn't been removed.
+    index: LinearMap<u16, u32, MAX_KEYS>,
+}
+
+/// What a sector header says.
+enum Header {
+    Erased,
+    Valid { seq: u32, ready: bool },
+    Bad,
+}
+
+/// What was found where a record should be.
+enum Record {
+    /// Erased flash: this is the end of the log in the sector.
+    End,
+    Good {
+        key: u16,
+        len: usize,
+        removed: bool,
+    },
+    /// A record that was only partly written (or has been damaged since).
+    Torn,
+}
+
+impl Store {
+    /// Find the store in the `sectors` erase sectors starting at `start`, cleaning up after any
+    /// write that was cut short.
+    ///
+    /// Blank flash is an empty store. Anything else in the area that isn't part of a store is
+    /// erased.
+    pub fn mount<F: NorFlash>(
+        flash: &mut F,
+        start: u32,
+        sectors: usize,
+    ) -> Result<Self, Error<F::Error>> {
+        assert!(F::WRITE_SIZE == 1, "the store writes single bytes");
+        let sector_size = F::ERASE_SIZE as u32;
+        let fits = matches!(
+            (start as usize).checked_add(sectors * F::ERASE_SIZE),
+            Some(end) if end <= flash.capacity()
+        );
+        if !(2..=MAX_SECTORS).contains(&sectors) || start % sector_size != 0 || !fits {
+            return Err(Error::BadRegion);
+        }
+        let mut store = Store {
+            start,
+            sector_size,
+            sectors: Vec::new(),
+            active: None,
+            index: LinearMap::new(),
+        };
+        for sector in 0..sectors {
+            let addr = store.sector_addr(sector);
+            let seq = match read_header(flash, addr)? {
+                Header::Valid { seq, ready: true } => Some(seq),
+                Header::Erased if is_erased(flash, addr, addr + sector_size)? => None,
+                // Half written, half erased, or an unfinished garbage collection (where
+                // everything copied is still in the sector it came from).
+                _ => {
+                    flash.erase(addr, addr + sector_size)?;
+                    None
+                }
+            };
+            // Can't fail, we checked `sectors` above.
+            let _ = store.sectors.push(seq);
+        }
+        // There is always an erased sector, except when garbage collection has finished copying
+        // but not yet erased the sector it copied from.
+        if let (false, Some(oldest)) = (store.has_erased(), store.oldest()) {
+            store.erase(flash, oldest)?;
+        }
+
+        let mut order: Vec<(u32, usize), MAX_SECTORS> = store
+            .sectors
+            .iter()
+            .enumerate()
+            .filter_map(|(sector, seq)| Some(((*seq)?, sector)))
+            .collect();
+        order.sort_unstable();
+        let mut buf = [0; MAX_RECORD_LEN];
+        for (_, sector) in order {
+            let addr = store.sector_addr(sector);
+            let mut offset = HEADER_LEN;
+            let end = loop {
+                match read_record(flash, addr + offset, sector_size - offset, &mut buf)? {
+                    Record::Good { key, len, removed } => {
+                        if removed {
+                            store.index.remove(&key);
+                        } else if store.index.insert(key, addr + offset).is_err() {
+                            return Err(Error::TooManyKeys);
+                        }
+                        offset += (RECORD_OVERHEAD + len) as u32;
+                    }
+                    // Only use the rest of the sector if it really is erased.
+                    Record::End if is_erased(flash, addr + offset, addr + sector_size)? => {
+                        break offset
+                    }
+                    Record::End | Record::Torn => break sector_size,
+                }
+            };
+            store.active = Some((sector, end));
+        }
+        Ok(store)
+    }
+
+    /// Read the value for `key`, or `None` if it isn't set.
+    pub fn get<F: ReadNorFlash, T: Value>(
+        &self,
+        flash: &mut F,
+        key: Key<T>,
+    ) -> Result<Option<T>, Error<F::Error>> {
+        let mut buf = [0; MAX_VALUE_LEN];
+        match self.get_raw(flash, key.id(), &mut buf)? {
+            Some(len) => T::decode(&buf[..len]).map(Some).ok_or(Error::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    /// Set the value for `key`. Setting the value it already has doesn't write anything.
+    pub fn set<F: NorFlash, T: Value>(
+        &mut self,
+        flash: &mut F,
+        key: Key<T>,
+        value: &T,
+    ) -> Result<(), Error<F::Error>> {
+        let mut buf = [0; MAX_VALUE_LEN];
+        let len = value.encode(&mut buf).ok_or(Error::TooBig)?;
+        self.set_raw(flash, key.id(), &buf[..len])
+    }
+
+    /// Remove `key`, so `get` returns `None`.
+    pub fn remove<F: NorFlash, T>(
+        &mut self,
+        flash: &mut F,
+        key: Key<T>,
+    ) -> Result<(), Error<F::Error>> {
+        self.remove_raw(flash, key.id())
+    }
+
+    /// Read the value for `key` into `buf`, returning its length, or `None` if it isn't set.
+    pub fn get_raw<F: ReadNorFlash>(
+        &self,
+        flash: &mut F,
+        key: u16,
+        buf: &mut [u8],
+    ) -> Result<Option<usize>, Error<F::Error>> {
+        let addr = match self.index.get(&key) {
+            Some(addr) => *addr,
+            None => return Ok(None),
+        };
+        let mut record = [0; MAX_RECORD_LEN];
+        let room = self.sector_size - addr % self.sector_size;
+        match read_record(flash, addr, room, &mut record)? {
+            Record::Good { len, .. } => {
+                let value = buf.get_mut(..len).ok_or(Error::TooBig)?;
+                value.copy_from_slice(&record[4..4 + len]);
+                Ok(Some(len))
+            }
+            _ => Err(Error::Corrupt),
+        }
+    }
+
+    pub fn set_raw<F: NorFlash>(
+        &mut self,
+        flash: &mut F,
+        key: u16,
+        value: &[u8],
+    ) -> Result<(), Error<F::Error>> {
+        if key == NO_KEY {
+            return Err(Error::BadKey);
+        }
+        if value.len() > MAX_VALUE_LEN {
+            return Err(Error::TooBig);
+        }
+        let mut old = [0; MAX_VALUE_LEN];
+        match self.get_raw(flash, key, &mut old) {
+            Ok(Some(len)) if old[..len] == *value => return Ok(()),
+            Ok(_) | Err(Error::Corrupt) => (),
+            Err(e) => return Err(e),
+        }
+        if self.index.len() == MAX_KEYS && !self.index.contains_key(&key) {
+            return Err(Error::TooManyKeys);
+        }
+        let addr = self.append(flash, key, value.len() as u16, value)?;
+        // Can't fail, we checked there was room above.
+        let _ = self.index.insert(key, addr);
+        Ok(())
+    }
+
+    pub fn remove_raw<F: NorFlash>(
+        &mut self,
+        flash: &mut F,
+        key: u16,
+    ) -> Result<(), Error<F::Error>> {
+        if !self.index.contains_key(&key) {
+            return Ok(());
+        }
+        self.append(flash, key, REMOVED, &[])?;
+        self.index.remove(&key);
+        Ok(())
+    }
+
+    /// The number of keys that are set.
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    /// Write a record to the end of the log, returning its address.
+    fn append<F: NorFlash>(
+        &mut self,
+        flash: &mut F,
+        key: u16,
+        len: u16,
+        value: &[u8],
+    ) -> Result<u32, Error<F::Error>> {
+        let record_len = RECORD_OVERHEAD + value.len();
+        let mut record = [0; MAX_RECORD_LEN];
+        record[0..2].copy_from_slice(&key.to_le_bytes());
+        record[2..4].copy_from_slice(&len.to_le_bytes());
+        record[4..4 + value.len()].copy_from_slice(value);
+        let crc = crc32(&record[..4 + value.len()]);
+        record[4 + value.len()..record_len].copy_from_slice(&crc.to_le_bytes());
+
+        let (sector, offset) = self.make_room(flash, record_len as u32)?;
+        let addr = self.sector_addr(sector) + offset;
+        // If the write fails part way, where the next record starts is lost, so nothing more can
+        // go in this sector.
+        self.active = Some((sector, self.sector_size));
+        flash.write(addr, &record[..record_len])?;
+        self.active = Some((sector, offset + record_len as u32));
+        Ok(addr)
+    }
+
+    /// Make sure there is room for `len` bytes in the active sector, moving on to new sectors
+    /// if needed. Returns the active sector and where the record should go.
+    fn make_room<F: NorFlash>(
+        &mut self,
+        flash: &mut F,
+        len: u32,
+    ) -> Result<(usize, u32), Error<F::Error>> {
+        // If every live record stays live, each new sector fills up with copies. Once we've been
+        // all the way round there's no point going on.
+        for _ in 0..=self.sectors.len() {
+            match self.active {
+                Some((sector, offset)) if offset + len <= self.sector_size => {
+                    return Ok((sector, offset))
+                }
+                _ => self.open_sector(flash)?,
+            }
+        }
+        Err(Error::Full)
+    }
+
+    /// Start appending to the next erased sector. If it is the last one, garbage collect the
+    /// oldest sector into it.
+    fn open_sector<F: NorFlash>(&mut self, flash: &mut F) -> Result<(), Error<F::Error>> {
+        let count = self.sectors.len();
+        // Go round the sectors in order, for wear levelling.
+        let first = self.active.map_or(0, |(sector, _)| sector + 1);
+        let sector = (first..first + count)
+            .map(|sector| sector % count)
+            .find(|sector| self.sectors[*sector].is_none())
+            .ok_or(Error::Full)?;
+        let seq = self.sectors.iter().flatten().max().map_or(0, |seq| seq + 1);
+        let erased = self.sectors.iter().filter(|seq| seq.is_none()).count();
+        let addr = self.sector_addr(sector);
+        let oldest = match self.oldest() {
+            Some(oldest) if erased == 1 => oldest,
+            _ => {
+                write_header(flash, addr, seq, READY)?;
+                self.sectors[sector] = Some(seq);
+                self.active = Some((sector, HEADER_LEN));
+                return Ok(());
+            }
+        };
+
+        write_header(flash, addr, seq, COPYING)?;
+        self.sectors[sector] = Some(seq);
+        // Nothing else can be written here if the copying goes wrong.
+        self.active = Some((sector, self.sector_size));
+        let old_addr = self.sector_addr(oldest);
+        let mut buf = [0; MAX_RECORD_LEN];
+        let mut from = HEADER_LEN;
+        let mut to = HEADER_LEN;
+        // Live records from one sector always fit in an empty one.
+        while let Record::Good { key, len, .. } =
+            read_record(flash, old_addr + from, self.sector_size - from, &mut buf)?
+        {
+            let record_len = RECORD_OVERHEAD + len;
+            if self.index.get(&key) == Some(&(old_addr + from)) {
+                flash.write(addr + to, &buf[..record_len])?;
+                let _ = self.index.insert(key, addr + to);
+                to += record_len as u32;
+            }
+            from += record_len as u32;
+        }
+        flash.write(addr + STATE_OFFSET, &[READY])?;
+        self.erase(flash, oldest)?;
+        self.active = Some((sector, to));
+        Ok(())
+    }
+
+    fn erase<F: NorFlash>(&mut self, flash: &mut F, sector: usize) -> Result<(), Error<F::Error>> {
+        let addr = self.sector_addr(sector);
+        flash.erase(addr, addr + self.sector_size)?;
+        self.sectors[sector] = None;
+        Ok(())
+    }
+
+    fn sector_addr(&self, sector: usize) -> u32 {
+        self.start + sector as u32 * self.sector_size
+    }
+
+    fn has_erased(&self) -> bool {
+        self.sectors.iter().any(Option::is_none)
+    }
+
+    /// The sector with the lowest sequence number, the start of the log.
+    fn oldest(&self) -> Option<usize> {
+        let sectors = self.sectors.iter().enumerate();
+        let seqs = sectors.filter_map(|(sector, seq)| Some(((*seq)?, sector)));
+        seqs.min().map(|(_, sector)| sector)
+    }
+}
+
+fn read_header<F: ReadNorFlash>(flash: &mut F, addr: u32) -> Result<Header, F::Error> {
+    let mut raw = [0; HEADER_LEN as usize];
+    flash.read(addr, &mut raw)?;
+    if raw.iter().all(|byte| *byte == 0xFF) {
+        return Ok(Header::Erased);
+    }
+    let crc = u32::from_le_bytes(raw[8..12].try_into().unwrap());
+    if raw[0..4] != MAGIC || crc32(&raw[0..8]) != crc || raw[13..] != [0xFF; 3] {
+        return Ok(Header::Bad);
+    }
+    let seq = u32::from_le_bytes(raw[4..8].try_into().unwrap());
+    Ok(match raw[STATE_OFFSET as usize] {
+        READY => Header::Valid { seq, ready: true },
+        COPYING => Header::Valid { seq, ready: false },
+        _ => Header::Bad,
+    })
+}
+
+fn write_header<F: NorFlash>(
+    flash: &mut F,
+    addr: u32,
+    seq: u32,
+    state: u8,
+) -> Result<(), F::Error> {
+    let mut raw = [0; STATE_OFFSET as usize + 1];
+    raw[0..4].copy_from_slice(&MAGIC);
+    raw[4..8].copy_from_slice(&seq.to_le_bytes());
+    let crc = crc32(&raw[0..8]);
+    raw[8..12].copy_from_slice(&crc.to_le_bytes());
+    raw[STATE_OFFSET as usize] = state;
+    flash.write(addr, &raw)
+}
+
+/// Read the record at `addr` (with `room` bytes left in the sector) into `buf`.
+fn read_record<F: ReadNorFlash>(
+    flash: &mut F,
+    addr: u32,
+    room: u32,
+    buf: &mut [u8; MAX_RECORD_LEN],
+) -> Result<Record, F::Error> {
+    if room < RECORD_OVERHEAD as u32 {
+        return Ok(Record::End);
+    }
+    flash.read(addr, &mut buf[..4])?;
+    if buf[..4] == [0xFF; 4] {
+        return Ok(Record::End);
+    }
+    let key = u16::from_le_bytes([buf[0], buf[1]]);
+    let raw_len = u16::from_le_bytes([buf[2], buf[3]]);
+    let removed = raw_len & REMOVED != 0;
+    let len = usize::from(raw_len & !REMOVED);
+    let record_len = RECORD_OVERHEAD + len;
+    if key == NO_KEY || len > MAX_VALUE_LEN || record_len as u32 > room || (removed && len != 0) {
+        return Ok(Record::Torn);
+    }
+    flash.read(addr + 4, &mut buf[4..record_len])?;
+    let crc = u32::from_le_bytes(buf[4 + len..record_len].try_into().unwrap());
+    if crc32(&buf[..4 + len]) != crc {
+        return Ok(Record::Torn);
+    }
+    Ok(Record::Good { key, len, removed })
+}
+
+/// Whether the flash from `from` up to `to` is all 0xFF.
+fn is_erased<F: ReadNorFlash>(flash: &mut F, from: u32, to: u32) -> Result<bool, F::Error> {
+    let mut buf = [0; 64];
+    let mut addr = from;
+    while addr < to {
+        let chunk = &mut buf[..(to - addr).min(64) as usize];
+        flash.read(addr, chunk)?;
+        if chunk.iter().any(|byte| *byte != 0xFF) {
+            return Ok(false);
+        }
+        addr += chunk.len() as u32;
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pinetime_sim::{nor::SECTOR_SIZE, NorError, NorModel};
+
+    const SECTORS: usize = 2;
+    const A: Key<u32> = Key::new(1);
+    const B: Key<u32> = Key::new(2);
+    /// A record holding a `u32`.
+    const RECORD_LEN: u32 = RECORD_OVERHEAD as u32 + 4;
+
+    type Result<T> = core::result::Result<T, Error<NorError>>;
+
+    fn mount(flash: &mut NorModel) -> Store {
+        Store::mount(flash, 0, SECTORS).unwrap()
+    }
+
+    fn values(store: &Store, flash: &mut NorModel) -> [Option<u32>; 2] {
+        [store.get(flash, A).unwrap(), store.get(flash, B).unwrap()]
+    }
+
+    /// Cut the power after each write step of `op` in turn (with the store set up by `setup`
+    /// each time), and check that the store mounts afterwards with either the `old` values of
+    /// `A` and `B` or the `new` ones. Returns how many write steps `op` takes.
+    fn cut_everywhere(
+        setup: impl Fn(&mut Store, &mut NorModel) -> Result<()>,
+        op: impl Fn(&mut Store, &mut NorModel) -> Result<()>,
+        old: [Option<u32>; 2],
+        new: [Option<u32>; 2],
+    ) -> usize {
+        for steps in 0.. {
+            let mut flash = NorModel::new();
+            let mut store = mount(&mut flash);
+            setup(&mut store, &mut flash).unwrap();
+            assert_eq!(values(&store, &mut flash), old);
+            flash.cut_power_after(steps);
+            let result = op(&mut store, &mut flash);
+            let cut = flash.is_power_lost();
+            flash.restore_power();
+            let mut store = mount(&mut flash);
+            let values = values(&store, &mut flash);
+            if !cut {
+                result.unwrap();
+                assert_eq!(values, new);
+                return steps;
+            }
+            assert!(result.is_err());
+            assert!(
+                values == old || values == new,
+                "cut after {} steps: {:?}",
+                steps,
+                values
+            );
+            // The store still works after the cut.
+            store.set(&mut flash, B, &99).unwrap();
+            assert_eq!(store.get(&mut flash, B), Ok(Some(99)));
+        }
+        unreachable!()
+    }
+
+    #[test]
+    fn power_cut_during_set() {
+        let steps = cut_everywhere(
+            |_, _| Ok(()),
+            |store, flash| store.set(flash, A, &1),
+            [None, None],
+            [Some(1), None],
+        );
+        // The first sector's header, then the record.
+        assert_eq!(steps, STATE_OFFSET as usize + 1 + RECORD_LEN as usize);
+    }
+
+    #[test]
+    fn power_cut_during_overwrite() {
+        let steps = cut_everywhere(
+            |store, flash| {
+                store.set(flash, A, &1)?;
+                store.set(flash, B, &2)
+            },
+            |store, flash| store.set(flash, A, &3),
+            [Some(1), Some(2)],
+            [Some(3), Some(2)],
+        );
+        assert_eq!(steps, RECORD_LEN as usize);
+    }
+
+    #[test]
+    fn power_cut_during_remove() {
+        let steps = cut_everywhere(
+            |store, flash| {
+                store.set(flash, A, &1)?;
+                store.set(flash, B, &2)
+            },
+            |store, flash| store.remove(flash, A),
+            [Some(1), Some(2)],
+            [None, Some(2)],
+        );
+        assert_eq!(steps, RECORD_OVERHEAD);
+    }
+
+    /// Fill the first sector with `B` and overwrites of `A`, so that the next write has to
+    /// garbage collect it into the second.
+    fn fill_first_sector(store: &mut Store, flash: &mut NorModel) -> Result<()> {
+        store.set(flash, B, &2)?;
+        let records = (SECTOR_SIZE as u32 - HEADER_LEN) / RECORD_LEN;
+        for value in 0..records - 1 {
+            store.set(flash, A, &value)?;
+        }
+        assert_eq!(store.active, Some((0, HEADER_LEN + records * RECORD_LEN)));
+        Ok(())
+    }
+
+    #[test]
+    fn power_cut_during_garbage_collection() {
+        let last = (SECTOR_SIZE as u32 - HEADER_LEN) / RECORD_LEN - 2;
+        let steps = cut_everywhere(
+            fill_first_sector,
+            |store, flash| store.set(flash, A, &1000),
+            [Some(last), Some(2)],
+            [Some(1000), Some(2)],
+        );
+        // The second sector's header, the 2 live records copied, marking it ready, erasing the
+        // first sector, then the new record.
+        let header = STATE_OFFSET as usize + 1;
+        assert_eq!(
+            steps,
+            header + 2 * RECORD_LEN as usize + 1 + 1 + RECORD_LEN as usize
+        );
+
+        let mut flash = NorModel::new();
+        let mut store = mount(&mut flash);
+        fill_first_sector(&mut store, &mut flash).unwrap();
+        store.set(&mut flash, A, &1000).unwrap();
+        assert_eq!(flash.erase_count(0), 1);
+    }
+}
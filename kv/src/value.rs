@@ -0,0 +1,112 @@
+//! Typed keys, and how values are turned into bytes.
+use core::{convert::TryInto, marker::PhantomData};
+
+/// A key, and the type of value stored under it.
+///
+/// Keys are plain numbers on the flash, so each one should only ever be used with one type.
+/// `0xFFFF` is not a valid key (it is what erased flash reads as).
+pub struct Key<T> {
+    id: u16,
+    value: PhantomData<fn() -> T>,
+}
+
+impl<T> Key<T> {
+    pub const fn new(id: u16) -> Self {
+        Key {
+            id,
+            value: PhantomData,
+        }
+    }
+
+    pub fn id(self) -> u16 {
+        self.id
+    }
+}
+
+// Derives would require `T: Copy`.
+impl<T> Clone for Key<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for Key<T> {}
+
+/// Something that can be stored in the store.
+pub trait Value: Sized {
+    /// Write the value to the start of `buf`, returning how many bytes it took, or `None` if it
+    /// doesn't fit.
+    fn encode(&self, buf: &mut [u8]) -> Option<usize>;
+
+    /// Read a value back. Returns `None` if the bytes aren't a valid value of this type.
+    fn decode(bytes: &[u8]) -> Option<Self>;
+}
+
+macro_rules! impl_value_for_int {
+    ($($ty:ty),*) => {
+        $(
+            impl Value for $ty {
+                fn encode(&self, buf: &mut [u8]) -> Option<usize> {
+                    let bytes = self.to_le_bytes();
+                    buf.get_mut(..bytes.len())?.copy_from_slice(&bytes);
+                    Some(bytes.len())
+                }
+
+                fn decode(bytes: &[u8]) -> Option<Self> {
+                    Some(Self::from_le_bytes(bytes.try_into().ok()?))
+                }
+            }
+        )*
+    };
+}
+
+impl_value_for_int!(u8, u16, u32, u64, i8, i16, i32, i64);
+
+impl Value for bool {
+    fn encode(&self, buf: &mut [u8]) -> Option<usize> {
+        u8::from(*self).encode(buf)
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        match u8::decode(bytes)? {
+            0 => Some(false),
+            1 => Some(true),
+            _ => None,
+        }
+    }
+}
+
+impl<const N: usize> Value for [u8; N] {
+    fn encode(&self, buf: &mut [u8]) -> Option<usize> {
+        buf.get_mut(..N)?.copy_from_slice(self);
+        Some(N)
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        bytes.try_into().ok()
+    }
+}
+
+impl<const N: usize> Value for heapless::Vec<u8, N> {
+    fn encode(&self, buf: &mut [u8]) -> Option<usize> {
+        buf.get_mut(..self.len())?.copy_from_slice(self);
+        Some(self.len())
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        heapless::Vec::from_slice(bytes).ok()
+    }
+}
+
+impl<const N: usize> Value for heapless::String<N> {
+    fn encode(&self, buf: &mut [u8]) -> Option<usize> {
+        buf.get_mut(..self.len())?.copy_from_slice(self.as_bytes());
+        Some(self.len())
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let mut s = heapless::String::new();
+        s.push_str(core::str::from_utf8(bytes).ok()?).ok()?;
+        Some(s)
+    }
+}
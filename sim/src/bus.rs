@@ -0,0 +1,409 @@
+//! A fake SPI bus, with the display's chip select, data/command and reset pins, the flash's chip
+//! select, and a clock.
+//!
+//! The parts share the bus state, so they can be handed to `drivers::display::Display::from_parts`
+//! (or `drivers::flash::Flash::from_parts`) as if they were the real peripherals, and the bytes
+//! they carry are fed to a [`St7789`] or a [`NorModel`]. The display borrows its reset pin, so
+//! keep that around between displays, as the firmware does.
+//!
+//! Time only moves when something takes time: a driver waiting on the clock, bytes going over the
+//! bus, or a test saying that the CPU was busy (see [`MockBus::pass_time`]).
+use crate::{nor::NorModel, st7789::St7789};
+use core::{
+    convert::Infallible,
+    future::{self, Future},
+    pin::Pin,
+    task::{Context, Poll},
+};
+use drivers::{
+    bus::{AsyncWrite, Clock},
+    display::{Display, DisplayState},
+    flash::Flash,
+    EASY_DMA_SIZE,
+};
+use embedded_hal::{blocking, digital::v2::OutputPin};
+use std::{
+    cell::{Ref, RefCell, RefMut},
+    rc::Rc,
+};
+
+/// How long a byte takes on the bus, at the 8 MHz that the firmware runs it at.
+pub const BYTE_MICROS: u64 = 1;
+
+/// Statistics about the traffic on the bus.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct Stats {
+    /// Bytes sent with D/C low.
+    pub command_bytes: usize,
+    /// Bytes sent with D/C high.
+    pub data_bytes: usize,
+    /// Number of blocking writes.
+    pub blocking_writes: usize,
+    /// Number of async (DMA) writes.
+    pub async_writes: usize,
+    /// Bytes sent while chip select was high, so nobody was listening.
+    pub dropped_bytes: usize,
+    /// Bytes sent to (or read from) the flash.
+    pub flash_bytes: usize,
+}
+
+struct Inner {
+    display: St7789,
+    flash: NorModel,
+    cs_low: bool,
+    flash_cs_low: bool,
+    dc_high: bool,
+    stats: Stats,
+    /// Microseconds since the bus was made.
+    now_micros: u64,
+    /// When the last async (DMA) write is done.
+    dma_done_at: u64,
+}
+
+impl Inner {
+    fn set_time(&mut self, now_micros: u64) {
+        self.now_micros = now_micros;
+        self.display.set_time(now_micros);
+    }
+
+    /// Send (or receive) `len` bytes without DMA, so the CPU waits for them.
+    fn transfer_blocking(&mut self, len: usize) {
+        self.check_dma_done();
+        self.stats.blocking_writes += 1;
+        self.set_time(self.now_micros + len as u64 * BYTE_MICROS);
+    }
+
+    /// The SPIM peripheral can only do one thing at a time.
+    fn check_dma_done(&self) {
+        assert!(
+            self.now_micros >= self.dma_done_at,
+            "the bus was used before the last DMA write had finished"
+        );
+    }
+
+    fn write(&mut self, data: &[u8]) {
+        if self.flash_cs_low {
+            self.check_one_selected();
+            self.stats.flash_bytes += data.len();
+            self.flash.receive(data);
+            return;
+        }
+        if !self.cs_low {
+            self.stats.dropped_bytes += data.len();
+            return;
+        }
+        if self.dc_high {
+            self.stats.data_bytes += data.len();
+            self.display.data(data);
+        } else {
+            self.stats.command_bytes += data.len();
+            for cmd in data {
+                self.display.command(*cmd);
+            }
+        }
+    }
+
+    fn read(&mut self, words: &mut [u8]) {
+        if self.flash_cs_low {
+            self.check_one_selected();
+            self.stats.flash_bytes += words.len();
+            self.flash.transmit(words);
+        } else if self.cs_low {
+            self.display.read(words);
+        } else {
+            self.stats.dropped_bytes += words.len();
+            words.iter_mut().for_each(|byte| *byte = 0xFF);
+        }
+    }
+
+    /// Both chips talking at once would garble the bus.
+    fn check_one_selected(&self) {
+        assert!(
+            !(self.cs_low && self.flash_cs_low),
+            "display and flash are both selected"
+        );
+    }
+}
+
+/// The display driver, on the mock bus.
+pub type MockDisplay<'a> = Display<'a, MockSpi, MockPin, MockPin, MockPin, MockClock>;
+/// The flash driver, on the mock bus.
+pub type MockFlash = Flash<MockSpi, MockPin, MockClock>;
+
+/// A handle to the bus. Clones refer to the same bus.
+#[derive(Clone)]
+pub struct MockBus {
+    inner: Rc<RefCell<Inner>>,
+}
+
+impl MockBus {
+    pub fn new() -> Self {
+        MockBus {
+            inner: Rc::new(RefCell::new(Inner {
+                display: St7789::new(),
+                flash: NorModel::new(),
+                cs_low: false,
+                flash_cs_low: false,
+                dc_high: false,
+                stats: Stats::default(),
+                now_micros: 0,
+                dma_done_at: 0,
+            })),
+        }
+    }
+
+    /// The SPI peripheral.
+    pub fn spi(&self) -> MockSpi {
+        MockSpi { bus: self.clone() }
+    }
+
+    /// The display chip select pin.
+    pub fn cs_pin(&self) -> MockPin {
+        MockPin {
+            bus: self.clone(),
+            kind: PinKind::Cs,
+        }
+    }
+
+    /// The display data/command pin.
+    pub fn dc_pin(&self) -> MockPin {
+        MockPin {
+            bus: self.clone(),
+            kind: PinKind::Dc,
+        }
+    }
+
+    /// The display reset pin.
+    pub fn reset_pin(&self) -> MockPin {
+        MockPin {
+            bus: self.clone(),
+            kind: PinKind::Reset,
+        }
+    }
+
+    /// The flash chip select pin.
+    pub fn flash_cs_pin(&self) -> MockPin {
+        MockPin {
+            bus: self.clone(),
+            kind: PinKind::FlashCs,
+        }
+    }
+
+    /// The clock. Delays don't really wait, they just move the time on.
+    pub fn clock(&self) -> MockClock {
+        MockClock { bus: self.clone() }
+    }
+
+    /// The time, in microseconds since the bus was made.
+    pub fn now_micros(&self) -> u64 {
+        self.inner.borrow().now_micros
+    }
+
+    /// Move the time on, as if the CPU had been busy (working out pixels, say) for `micros`.
+    /// DMA writes carry on meanwhile.
+    pub fn pass_time(&self, micros: u64) {
+        let mut inner = self.inner.borrow_mut();
+        let now = inner.now_micros + micros;
+        inner.set_time(now);
+    }
+
+    /// A display driver using the bus, as the firmware makes them.
+    pub fn display_driver<'a>(
+        &self,
+        reset_pin: &'a mut MockPin,
+        state: &'a mut DisplayState,
+    ) -> MockDisplay<'a> {
+        Display::from_parts(
+            self.spi(),
+            reset_pin,
+            self.cs_pin(),
+            self.dc_pin(),
+            self.clock(),
+            state,
+        )
+    }
+
+    /// Run `f` with a display driver on the bus, with its own reset pin and a fresh state. The
+    /// driver starts out thinking the panel is `Uninitialised`, so `f` usually begins with
+    /// `sleep_off`.
+    pub fn with_display<R>(&self, f: impl FnOnce(&mut MockDisplay) -> R) -> R {
+        let mut reset_pin = self.reset_pin();
+        let mut state = DisplayState::default();
+        f(&mut self.display_driver(&mut reset_pin, &mut state))
+    }
+
+    /// A flash driver using the bus.
+    pub fn flash_driver(&self) -> MockFlash {
+        Flash::from_parts(self.spi(), self.flash_cs_pin(), self.clock())
+    }
+
+    /// The display on the other end of the bus.
+    pub fn display(&self) -> Ref<'_, St7789> {
+        Ref::map(self.inner.borrow(), |inner| &inner.display)
+    }
+
+    pub fn display_mut(&self) -> RefMut<'_, St7789> {
+        RefMut::map(self.inner.borrow_mut(), |inner| &mut inner.display)
+    }
+
+    /// The flash on the other end of the bus.
+    pub fn flash(&self) -> Ref<'_, NorModel> {
+        Ref::map(self.inner.borrow(), |inner| &inner.flash)
+    }
+
+    pub fn flash_mut(&self) -> RefMut<'_, NorModel> {
+        RefMut::map(self.inner.borrow_mut(), |inner| &mut inner.flash)
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.inner.borrow().stats
+    }
+
+    pub fn reset_stats(&self) {
+        self.inner.borrow_mut().stats = Stats::default();
+    }
+}
+
+impl Default for MockBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct MockSpi {
+    bus: MockBus,
+}
+
+impl blocking::spi::Write<u8> for MockSpi {
+    type Error = Infallible;
+
+    fn write(&mut self, data: &[u8]) -> Result<(), Self::Error> {
+        let mut inner = self.bus.inner.borrow_mut();
+        inner.transfer_blocking(data.len());
+        inner.write(data);
+        Ok(())
+    }
+}
+
+impl blocking::spi::Transfer<u8> for MockSpi {
+    type Error = Infallible;
+
+    fn transfer<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], Self::Error> {
+        let mut inner = self.bus.inner.borrow_mut();
+        inner.transfer_blocking(words.len());
+        inner.read(words);
+        Ok(words)
+    }
+}
+
+impl AsyncWrite for MockSpi {
+    type Error = Infallible;
+    type WriteFuture<'a> = DmaWrite;
+
+    /// The bytes reach the other end straight away, but the write isn't finished until the bus
+    /// has had time to send them.
+    fn write<'a>(&'a mut self, data: &'a [u8]) -> Self::WriteFuture<'a> {
+        assert!(
+            data.len() <= EASY_DMA_SIZE,
+            "EasyDMA can't send {} bytes in one go",
+            data.len()
+        );
+        let mut inner = self.bus.inner.borrow_mut();
+        inner.check_dma_done();
+        inner.stats.async_writes += 1;
+        inner.dma_done_at = inner.now_micros + data.len() as u64 * BYTE_MICROS;
+        inner.write(data);
+        DmaWrite {
+            bus: self.bus.clone(),
+            polled: false,
+        }
+    }
+}
+
+/// The future returned by [`MockSpi`]'s async write.
+///
+/// The first poll always returns `Pending`, so that whatever it is joined with runs while the
+/// bytes are on the wire. After that there is nothing else to do, so the next poll waits for the
+/// write to finish.
+pub struct DmaWrite {
+    bus: MockBus,
+    polled: bool,
+}
+
+impl Future for DmaWrite {
+    type Output = Result<(), Infallible>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if !self.polled {
+            self.polled = true;
+            cx.waker().wake_by_ref();
+            return Poll::Pending;
+        }
+        let mut inner = self.bus.inner.borrow_mut();
+        let now = inner.now_micros.max(inner.dma_done_at);
+        inner.set_time(now);
+        Poll::Ready(Ok(()))
+    }
+}
+
+pub struct MockClock {
+    bus: MockBus,
+}
+
+impl Clock for MockClock {
+    type Delay = future::Ready<()>;
+
+    fn delay_micros(&self, micros: u64) -> Self::Delay {
+        self.bus.pass_time(micros);
+        future::ready(())
+    }
+
+    fn now_micros(&self) -> u64 {
+        self.bus.now_micros()
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum PinKind {
+    Cs,
+    Dc,
+    Reset,
+    FlashCs,
+}
+
+pub struct MockPin {
+    bus: MockBus,
+    kind: PinKind,
+}
+
+impl OutputPin for MockPin {
+    type Error = Infallible;
+
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        let mut inner = self.bus.inner.borrow_mut();
+        match self.kind {
+            PinKind::Cs => inner.cs_low = true,
+            PinKind::Dc => inner.dc_high = false,
+            PinKind::Reset => inner.display.hold_reset(),
+            PinKind::FlashCs => inner.flash_cs_low = true,
+        }
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        let mut inner = self.bus.inner.borrow_mut();
+        match self.kind {
+            PinKind::Cs => {
+                inner.cs_low = false;
+                inner.display.end_transaction();
+            }
+            PinKind::Dc => inner.dc_high = true,
+            PinKind::Reset => inner.display.release_reset(),
+            PinKind::FlashCs => {
+                inner.flash_cs_low = false;
+                inner.flash.end_transaction();
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,15 @@
+//! Host-side models of the PineTime's SPI peripherals, so the display and flash code can be
+//! exercised without a watch on the desk.
+//!
+//! Hand the parts of a [`MockBus`] to the display driver in the `drivers` crate, draw, then look
+//! at [`St7789::screenshot`] (or save it as a png) to see what would be on the panel.
+
+pub mod bus;
+pub mod nor;
+pub mod st7789;
+
+pub use crate::{
+    bus::{DmaWrite, MockBus, MockClock, MockDisplay, MockFlash, MockPin, MockSpi, Stats},
+    nor::{NorError, NorModel, NorStats},
+    st7789::St7789,
+};
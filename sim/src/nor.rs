@@ -0,0 +1,505 @@
+//! A model of the PineTime's SPI NOR flash (an XTX XT25F32B).
+//!
+//! Memory starts out erased (all 0xFF). Like the real chip, programming can only clear bits, so
+//! an area has to be erased before it is written again. The real chip would quietly AND the new
+//! data into the old, which makes for confusing bugs, so the model panics instead (or returns
+//! [`NorError::NotErased`] through the `embedded-storage` traits).
+//!
+//! It can be driven over the [`MockBus`](crate::MockBus) with the flash driver from `drivers`, or
+//! directly through the `embedded-storage` traits.
+//!
+//! To check that data survives losing power part way through a write, [`NorModel::cut_power_after`]
+//! stops the chip after a given number of write steps (a byte programmed, or a sector erased),
+//! leaving the operation in progress half done.
+use embedded_storage::nor_flash::{
+    ErrorType, NorFlash, NorFlashError, NorFlashErrorKind, ReadNorFlash,
+};
+
+/// The size of the flash, in bytes.
+pub const FLASH_SIZE: usize = 4 * 1024 * 1024;
+pub const PAGE_SIZE: usize = 256;
+pub const SECTOR_SIZE: usize = 4 * 1024;
+pub const BLOCK_SIZE: usize = 64 * 1024;
+
+/// What `RDID` reports: XTX, SPI NOR, 2^22 bytes.
+pub const JEDEC_ID: [u8; 3] = [0x0B, 0x40, 0x16];
+
+// Instructions
+const WREN: u8 = 0x06;
+const WRDI: u8 = 0x04;
+const RDSR: u8 = 0x05;
+const READ: u8 = 0x03;
+const FAST_READ: u8 = 0x0B;
+const PP: u8 = 0x02;
+const SE: u8 = 0x20;
+const BE: u8 = 0xD8;
+const RDID: u8 = 0x9F;
+const DP: u8 = 0xB9;
+const RDP: u8 = 0xAB;
+
+// Status register bits
+const WIP: u8 = 0x01;
+const WEL: u8 = 0x02;
+
+/// How many status reads a program or erase stays busy for, so that the driver has to poll.
+const BUSY_POLLS: u32 = 2;
+
+/// Why the model refused an operation.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum NorError {
+    OutOfBounds,
+    NotAligned,
+    /// Programming would need to set a bit that is 0, which only an erase can do.
+    NotErased {
+        address: usize,
+    },
+    /// The power was cut (see [`NorModel::cut_power_after`]).
+    PowerLost,
+}
+
+impl NorFlashError for NorError {
+    fn kind(&self) -> NorFlashErrorKind {
+        match self {
+            NorError::OutOfBounds => NorFlashErrorKind::OutOfBounds,
+            NorError::NotAligned => NorFlashErrorKind::NotAligned,
+            NorError::NotErased { .. } | NorError::PowerLost => NorFlashErrorKind::Other,
+        }
+    }
+}
+
+/// Counts of what the flash has been asked to do.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct NorStats {
+    pub bytes_read: usize,
+    pub bytes_programmed: usize,
+    pub sector_erases: usize,
+    pub block_erases: usize,
+    /// Programs and erases sent without `WREN` first, or while busy. The chip ignores them.
+    pub ignored: usize,
+}
+
+pub struct NorModel {
+    mem: Vec<u8>,
+    /// How many times each sector has been erased.
+    erase_counts: Vec<u32>,
+    write_enabled: bool,
+    /// Status reads left before the current program or erase finishes.
+    busy: u32,
+    powered_down: bool,
+    /// The instruction and parameter bytes received in the current transaction.
+    received: Vec<u8>,
+    /// How many bytes of the reply have been read in the current transaction.
+    read_pos: usize,
+    stats: NorStats,
+    /// Write steps left before the power is cut, if a cut has been set up.
+    power_left: Option<usize>,
+    power_lost: bool,
+    /// Programs and erases never finish, as if the chip had failed.
+    stuck: bool,
+}
+
+impl NorModel {
+    pub fn new() -> Self {
+        NorModel {
+            mem: vec![0xFF; FLASH_SIZE],
+            erase_counts: vec![0; FLASH_SIZE / SECTOR_SIZE],
+            write_enabled: false,
+            busy: 0,
+            powered_down: false,
+            received: Vec::new(),
+            read_pos: 0,
+            stats: NorStats::default(),
+            power_left: None,
+            power_lost: false,
+            stuck: false,
+        }
+    }
+
+    /// The whole of the flash.
+    pub fn contents(&self) -> &[u8] {
+        &self.mem
+    }
+
+    /// How many times the sector containing `address` has been erased.
+    pub fn erase_count(&self, address: usize) -> u32 {
+        self.erase_counts[address / SECTOR_SIZE]
+    }
+
+    pub fn is_powered_down(&self) -> bool {
+        self.powered_down
+    }
+
+    pub fn stats(&self) -> NorStats {
+        self.stats
+    }
+
+    /// Cut the power after `steps` more write steps, where each byte programmed and each sector
+    /// erased is a step. The operation that runs out is left half done: a program stops after the
+    /// bytes it has been allowed, and an erase only clears the second half of the sector (so the
+    /// start still looks like the old data). Everything after that fails with
+    /// [`NorError::PowerLost`] until [`restore_power`](Self::restore_power).
+    pub fn cut_power_after(&mut self, steps: usize) {
+        self.power_left = Some(steps);
+    }
+
+    /// Turn the power back on, and cancel any cut that hasn't happened yet. Like the real chip
+    /// coming back up, it is no longer write enabled, busy or powered down.
+    pub fn restore_power(&mut self) {
+        self.power_left = None;
+        self.power_lost = false;
+        self.write_enabled = false;
+        self.busy = 0;
+        self.powered_down = false;
+        self.received.clear();
+        self.read_pos = 0;
+    }
+
+    /// Make programs and erases stay busy forever, as if the chip had failed. `restore_power`
+    /// doesn't fix this.
+    pub fn get_stuck(&mut self) {
+        self.stuck = true;
+    }
+
+    /// Whether a cut set up with [`cut_power_after`](Self::cut_power_after) has happened.
+    pub fn is_power_lost(&self) -> bool {
+        self.power_lost
+    }
+
+    /// Program bytes, checking that only 1s are being turned into 0s.
+    pub fn program(&mut self, address: usize, data: &[u8]) -> Result<(), NorError> {
+        let end = address
+            .checked_add(data.len())
+            .filter(|end| *end <= FLASH_SIZE)
+            .ok_or(NorError::OutOfBounds)?;
+        if let Some(pos) = self.mem[address..end]
+            .iter()
+            .zip(data)
+            .position(|(old, new)| old & new != *new)
+        {
+            return Err(NorError::NotErased {
+                address: address + pos,
+            });
+        }
+        let done = self.use_power(data.len())?;
+        self.mem[address..address + done].copy_from_slice(&data[..done]);
+        self.stats.bytes_programmed += done;
+        if done < data.len() {
+            return Err(NorError::PowerLost);
+        }
+        Ok(())
+    }
+
+    /// Erase the sector or block (`len` bytes) starting at `address`.
+    pub fn erase_area(&mut self, address: usize, len: usize) -> Result<(), NorError> {
+        if !address.is_multiple_of(len) {
+            return Err(NorError::NotAligned);
+        }
+        if address + len > FLASH_SIZE {
+            return Err(NorError::OutOfBounds);
+        }
+        let sectors = len / SECTOR_SIZE;
+        let done = self.use_power(sectors)?;
+        self.mem[address..address + done * SECTOR_SIZE].fill(0xFF);
+        if done < sectors {
+            let torn = address + done * SECTOR_SIZE;
+            self.mem[torn + SECTOR_SIZE / 2..torn + SECTOR_SIZE].fill(0xFF);
+            return Err(NorError::PowerLost);
+        }
+        for count in &mut self.erase_counts[address / SECTOR_SIZE..(address + len) / SECTOR_SIZE] {
+            *count += 1;
+        }
+        match len {
+            BLOCK_SIZE => self.stats.block_erases += 1,
+            _ => self.stats.sector_erases += 1,
+        }
+        Ok(())
+    }
+
+    /// Take up to `steps` write steps from what is left before the power cut, returning how many
+    /// can be done.
+    fn use_power(&mut self, steps: usize) -> Result<usize, NorError> {
+        if self.power_lost {
+            return Err(NorError::PowerLost);
+        }
+        match &mut self.power_left {
+            Some(left) if *left < steps => {
+                let done = *left;
+                *left = 0;
+                self.power_lost = true;
+                Ok(done)
+            }
+            Some(left) => {
+                *left -= steps;
+                Ok(steps)
+            }
+            None => Ok(steps),
+        }
+    }
+
+    // The SPI side. Bytes written while chip select is low are collected, and most instructions
+    // are carried out when it goes high again.
+
+    /// Bytes sent to the chip.
+    pub fn receive(&mut self, data: &[u8]) {
+        self.received.extend_from_slice(data);
+    }
+
+    /// Bytes clocked out of the chip.
+    pub fn transmit(&mut self, buf: &mut [u8]) {
+        let instruction = self.received.first().copied();
+        for byte in buf {
+            *byte = match instruction {
+                _ if self.powered_down || self.power_lost => 0xFF,
+                Some(RDID) => JEDEC_ID.get(self.read_pos).copied().unwrap_or(0xFF),
+                Some(RDSR) => self.status(),
+                Some(READ) | Some(FAST_READ) => self.read_byte(),
+                _ => 0xFF,
+            };
+            self.read_pos += 1;
+        }
+    }
+
+    /// Chip select went high.
+    pub fn end_transaction(&mut self) {
+        let received = std::mem::take(&mut self.received);
+        let polled = self.read_pos > 0;
+        self.read_pos = 0;
+        let (instruction, params) = match received.split_first() {
+            Some((instruction, params)) => (*instruction, params),
+            None => return,
+        };
+        if self.power_lost {
+            return;
+        }
+        if self.powered_down {
+            if instruction == RDP {
+                self.powered_down = false;
+            }
+            return;
+        }
+        if instruction == RDSR && polled {
+            if !self.stuck {
+                self.busy = self.busy.saturating_sub(1);
+            }
+            return;
+        }
+        if self.busy > 0 {
+            self.stats.ignored += 1;
+            return;
+        }
+        match instruction {
+            WREN => self.write_enabled = true,
+            WRDI => self.write_enabled = false,
+            DP => self.powered_down = true,
+            PP | SE | BE if !self.write_enabled => self.stats.ignored += 1,
+            PP => {
+                let address = address(params);
+                let data = &params[3.min(params.len())..];
+                // Addresses wrap around within the page.
+                let page = address - address % PAGE_SIZE;
+                for (i, byte) in data.iter().enumerate() {
+                    let at = page + (address + i) % PAGE_SIZE;
+                    match self.program(at, &[*byte]) {
+                        Ok(()) => (),
+                        Err(NorError::PowerLost) => return,
+                        Err(e) => panic!("page program at {:#x}: {:?}", address, e),
+                    }
+                }
+                self.finish_write();
+            }
+            SE | BE => {
+                let len = if instruction == SE {
+                    SECTOR_SIZE
+                } else {
+                    BLOCK_SIZE
+                };
+                let address = address(params);
+                // The chip ignores the low address bits.
+                let start = address - address % len;
+                match self.erase_area(start, len) {
+                    Ok(()) => (),
+                    Err(NorError::PowerLost) => return,
+                    Err(e) => panic!("erase at {:#x}: {:?}", address, e),
+                }
+                self.finish_write();
+            }
+            _ => (),
+        }
+    }
+
+    fn finish_write(&mut self) {
+        self.write_enabled = false;
+        self.busy = BUSY_POLLS;
+    }
+
+    fn status(&self) -> u8 {
+        let mut status = 0;
+        if self.busy > 0 {
+            status |= WIP;
+        }
+        if self.write_enabled {
+            status |= WEL;
+        }
+        status
+    }
+
+    /// The next byte of a read. The address goes up by one for every byte clocked out.
+    fn read_byte(&mut self) -> u8 {
+        let address = address(&self.received[1..]) + self.read_pos;
+        self.stats.bytes_read += 1;
+        self.mem[address % FLASH_SIZE]
+    }
+}
+
+impl Default for NorModel {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The 24 bit address at the start of `params`.
+fn address(params: &[u8]) -> usize {
+    let mut bytes = [0; 4];
+    for (i, byte) in params.iter().take(3).enumerate() {
+        bytes[i + 1] = *byte;
+    }
+    u32::from_be_bytes(bytes) as usize
+}
+
+impl ErrorType for NorModel {
+    type Error = NorError;
+}
+
+impl ReadNorFlash for NorModel {
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        let start = offset as usize;
+        let end = start
+            .checked_add(bytes.len())
+            .filter(|end| *end <= FLASH_SIZE)
+            .ok_or(NorError::OutOfBounds)?;
+        bytes.copy_from_slice(&self.mem[start..end]);
+        self.stats.bytes_read += bytes.len();
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        FLASH_SIZE
+    }
+}
+
+impl NorFlash for NorModel {
+    const WRITE_SIZE: usize = 1;
+    const ERASE_SIZE: usize = SECTOR_SIZE;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        let (from, to) = (from as usize, to as usize);
+        if from % SECTOR_SIZE != 0 || to % SECTOR_SIZE != 0 || from > to {
+            return Err(NorError::NotAligned);
+        }
+        for sector in (from..to).step_by(SECTOR_SIZE) {
+            self.erase_area(sector, SECTOR_SIZE)?;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.program(offset as usize, bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Send one instruction over the SPI side, as a transaction.
+    fn send(nor: &mut NorModel, bytes: &[u8]) {
+        nor.receive(bytes);
+        nor.end_transaction();
+    }
+
+    #[test]
+    fn programming_only_clears_bits() {
+        let mut nor = NorModel::new();
+        NorFlash::write(&mut nor, 100, &[0xF0, 0x0F]).unwrap();
+        // Clearing more bits is fine.
+        NorFlash::write(&mut nor, 100, &[0x30, 0x0F]).unwrap();
+        assert_eq!(&nor.contents()[99..103], [0xFF, 0x30, 0x0F, 0xFF]);
+        // Setting one isn't, and nothing is written.
+        assert_eq!(
+            NorFlash::write(&mut nor, 99, &[0x00, 0x00, 0x1F]),
+            Err(NorError::NotErased { address: 101 })
+        );
+        assert_eq!(&nor.contents()[99..103], [0xFF, 0x30, 0x0F, 0xFF]);
+        assert_eq!(nor.stats().bytes_programmed, 4);
+    }
+
+    #[test]
+    fn erase_before_write() {
+        let mut nor = NorModel::new();
+        NorFlash::write(&mut nor, SECTOR_SIZE as u32 - 1, &[0x00, 0x00]).unwrap();
+        assert!(NorFlash::write(&mut nor, SECTOR_SIZE as u32, &[0x01]).is_err());
+        // Only the sector that is erased can be written again.
+        NorFlash::erase(&mut nor, SECTOR_SIZE as u32, 2 * SECTOR_SIZE as u32).unwrap();
+        NorFlash::write(&mut nor, SECTOR_SIZE as u32, &[0x01]).unwrap();
+        assert_eq!(
+            NorFlash::write(&mut nor, SECTOR_SIZE as u32 - 1, &[0x01]),
+            Err(NorError::NotErased {
+                address: SECTOR_SIZE - 1
+            })
+        );
+        assert_eq!(nor.erase_count(0), 0);
+        assert_eq!(nor.erase_count(SECTOR_SIZE), 1);
+    }
+
+    #[test]
+    fn erases_must_line_up() {
+        let mut nor = NorModel::new();
+        assert_eq!(
+            nor.erase_area(SECTOR_SIZE, BLOCK_SIZE),
+            Err(NorError::NotAligned)
+        );
+        assert_eq!(
+            nor.erase_area(FLASH_SIZE, SECTOR_SIZE),
+            Err(NorError::OutOfBounds)
+        );
+        assert_eq!(
+            nor.program(FLASH_SIZE - 1, &[0, 0]),
+            Err(NorError::OutOfBounds)
+        );
+        nor.erase_area(BLOCK_SIZE, BLOCK_SIZE).unwrap();
+        assert_eq!(nor.stats().block_erases, 1);
+        assert_eq!(nor.erase_count(2 * BLOCK_SIZE - 1), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "NotErased { address: 258 }")]
+    fn page_program_over_old_data_panics() {
+        let mut nor = NorModel::new();
+        nor.program(258, &[0x00]).unwrap();
+        send(&mut nor, &[WREN]);
+        send(&mut nor, &[PP, 0x00, 0x01, 0x00, 0xFF, 0xFF, 0x01]);
+    }
+
+    #[test]
+    fn program_needs_write_enable() {
+        let mut nor = NorModel::new();
+        send(&mut nor, &[PP, 0x00, 0x00, 0x10, 0x00]);
+        send(&mut nor, &[SE, 0x00, 0x00, 0x00]);
+        assert_eq!(nor.stats().ignored, 2);
+        assert_eq!(nor.contents()[0x10], 0xFF);
+
+        send(&mut nor, &[WREN]);
+        send(&mut nor, &[PP, 0x00, 0x00, 0x10, 0x00]);
+        assert_eq!(nor.contents()[0x10], 0x00);
+        // Busy until the status has been read a few times.
+        let mut status = [0];
+        nor.receive(&[RDSR]);
+        nor.transmit(&mut status);
+        nor.end_transaction();
+        assert_eq!(status[0] & WIP, WIP);
+        // And the write enable is used up.
+        assert_eq!(status[0] & WEL, 0);
+    }
+}
@@ -0,0 +1,555 @@
+//! A model of the ST7789 display controller.
+//!
+//! Only the commands that the firmware sends are understood, anything else is logged and ignored.
+//! The controller has 240x320 pixels of video memory (GRAM), of which the PineTime's panel shows
+//! the first 240 lines (after scrolling is applied).
+//!
+//! The bus's clock keeps the model up to date with the time, so it can panic when a command comes
+//! sooner than the datasheet allows.
+use image::{Rgb, RgbImage};
+use std::{env, path::Path};
+
+/// Number of columns in GRAM.
+pub const GRAM_WIDTH: usize = 240;
+/// Number of lines in GRAM.
+pub const GRAM_HEIGHT: usize = 320;
+/// Width of the part of the panel you can see.
+pub const VISIBLE_WIDTH: usize = 240;
+/// Height of the part of the panel you can see.
+pub const VISIBLE_HEIGHT: usize = 240;
+
+/// How long the panel needs after `SLPIN` (or a reset) before it can take `SLPOUT`.
+const SLEEP_IN_MICROS: u64 = 120_000;
+/// How long the panel takes to reset, before it will take commands.
+const RESET_MICROS: u64 = 5_000;
+
+// Command bytes (see `drivers::display::Instruction`).
+const SWRESET: u8 = 0x01;
+const RDDID: u8 = 0x04;
+const RDDST: u8 = 0x09;
+const SLPIN: u8 = 0x10;
+const SLPOUT: u8 = 0x11;
+const PTLON: u8 = 0x12;
+const NORON: u8 = 0x13;
+const INVOFF: u8 = 0x20;
+const INVON: u8 = 0x21;
+const DISPOFF: u8 = 0x28;
+const DISPON: u8 = 0x29;
+const CASET: u8 = 0x2A;
+const RASET: u8 = 0x2B;
+const RAMWR: u8 = 0x2C;
+const PTLAR: u8 = 0x30;
+const VSCRDEF: u8 = 0x33;
+const MADCTL: u8 = 0x36;
+const VSCSAD: u8 = 0x37;
+const COLMOD: u8 = 0x3A;
+// Analog tuning. These are recorded in the command log, but don't change what we draw.
+const PORCTRL: u8 = 0xB2;
+const GCTRL: u8 = 0xB7;
+const VCOMS: u8 = 0xBB;
+const LCMCTRL: u8 = 0xC0;
+const VDVVRHEN: u8 = 0xC2;
+const VRHS: u8 = 0xC3;
+const VDVS: u8 = 0xC4;
+const FRCTRL2: u8 = 0xC6;
+const PWCTRL1: u8 = 0xD0;
+const PVGAMCTRL: u8 = 0xE0;
+const NVGAMCTRL: u8 = 0xE1;
+
+/// What `RDDID` reports: the ST7789V's default manufacturer, version and driver IDs.
+pub const DISPLAY_ID: [u8; 3] = [0x85, 0x85, 0x52];
+
+// MADCTL bits
+const MADCTL_MY: u8 = 1 << 7;
+const MADCTL_MX: u8 = 1 << 6;
+const MADCTL_MV: u8 = 1 << 5;
+
+pub struct St7789 {
+    /// Video memory, row-major, one rgb565 color per pixel.
+    gram: Vec<u16>,
+    /// The command whose parameters we are currently receiving.
+    cmd: Option<u8>,
+    /// Parameter bytes received for the current command.
+    params: Vec<u8>,
+    /// Bytes of a pixel (or pair of pixels, in 12 bit mode) during a memory write.
+    pixel_bytes: Vec<u8>,
+    /// How many bytes of the reply to a read command have been read.
+    read_pos: usize,
+    madctl: u8,
+    colmod: u8,
+    /// Column address window (inclusive).
+    cols: (u16, u16),
+    /// Row address window (inclusive).
+    rows: (u16, u16),
+    /// Current position of the write cursor, in column/row address space.
+    cursor: (u16, u16),
+    /// Top fixed, scroll, bottom fixed areas.
+    scroll_definition: (u16, u16, u16),
+    scroll_start: u16,
+    /// Partial area start and end lines (inclusive).
+    partial_area: (u16, u16),
+    partial_mode: bool,
+    inverted: bool,
+    sleeping: bool,
+    display_on: bool,
+    /// RESX is low, so everything sent is ignored.
+    in_reset: bool,
+    /// The time, as far as the bus's clock has got.
+    now_micros: u64,
+    /// When the panel last went to sleep (or was reset), until it wakes.
+    asleep_since: Option<u64>,
+    /// When the panel was last reset, by the pin or by `SWRESET`.
+    reset_at: Option<u64>,
+    /// Commands we don't model.
+    unknown_commands: Vec<u8>,
+    /// Every command with its parameters, except memory writes.
+    log: Vec<(u8, Vec<u8>)>,
+}
+
+impl St7789 {
+    /// A controller in its power-on state.
+    pub fn new() -> Self {
+        St7789 {
+            gram: vec![0; GRAM_WIDTH * GRAM_HEIGHT],
+            cmd: None,
+            params: Vec::new(),
+            pixel_bytes: Vec::new(),
+            read_pos: 0,
+            madctl: 0,
+            colmod: 0x66,
+            cols: (0, GRAM_WIDTH as u16 - 1),
+            rows: (0, GRAM_HEIGHT as u16 - 1),
+            cursor: (0, 0),
+            scroll_definition: (0, GRAM_HEIGHT as u16, 0),
+            scroll_start: 0,
+            partial_area: (0, GRAM_HEIGHT as u16 - 1),
+            partial_mode: false,
+            inverted: false,
+            sleeping: true,
+            display_on: false,
+            in_reset: false,
+            now_micros: 0,
+            asleep_since: Some(0),
+            reset_at: None,
+            unknown_commands: Vec::new(),
+            log: Vec::new(),
+        }
+    }
+
+    /// The RESX pin was pulled low. GRAM contents are kept, registers go back to defaults.
+    pub fn hard_reset(&mut self) {
+        let gram = std::mem::take(&mut self.gram);
+        let log = std::mem::take(&mut self.log);
+        *self = St7789 {
+            gram,
+            log,
+            now_micros: self.now_micros,
+            asleep_since: Some(self.now_micros),
+            reset_at: Some(self.now_micros),
+            ..St7789::new()
+        };
+    }
+
+    /// The RESX pin is low, and stays low until `release_reset`.
+    pub fn hold_reset(&mut self) {
+        self.hard_reset();
+        self.in_reset = true;
+    }
+
+    pub fn release_reset(&mut self) {
+        if self.in_reset {
+            self.in_reset = false;
+            self.asleep_since = Some(self.now_micros);
+            self.reset_at = Some(self.now_micros);
+        }
+    }
+
+    /// The time has moved on, to `now_micros` since the bus was made.
+    pub fn set_time(&mut self, now_micros: u64) {
+        self.now_micros = now_micros;
+    }
+
+    /// The chip select was released, which ends the current command.
+    pub fn end_transaction(&mut self) {
+        self.finish_command();
+    }
+
+    /// A byte was received with D/C low.
+    pub fn command(&mut self, cmd: u8) {
+        if self.in_reset {
+            return;
+        }
+        if let Some(at) = self.reset_at {
+            let since = self.now_micros - at;
+            assert!(
+                since >= RESET_MICROS,
+                "command {:#04x} only {} µs after a reset",
+                cmd,
+                since
+            );
+        }
+        self.finish_command();
+        match cmd {
+            SWRESET => self.hard_reset(),
+            SLPIN => {
+                self.sleeping = true;
+                self.asleep_since = Some(self.now_micros);
+            }
+            SLPOUT => {
+                if let Some(since) = self.asleep_since.take() {
+                    let asleep = self.now_micros - since;
+                    assert!(
+                        asleep >= SLEEP_IN_MICROS,
+                        "SleepOut only {} µs after SleepIn or a reset",
+                        asleep
+                    );
+                }
+                self.sleeping = false;
+            }
+            PTLON => self.partial_mode = true,
+            NORON => self.partial_mode = false,
+            INVOFF => self.inverted = false,
+            INVON => self.inverted = true,
+            DISPOFF => self.display_on = false,
+            DISPON => self.display_on = true,
+            RAMWR => {
+                self.cursor = (self.cols.0, self.rows.0);
+                self.pixel_bytes.clear();
+            }
+            RDDID | RDDST | CASET | RASET | PTLAR | VSCRDEF | MADCTL | VSCSAD | COLMOD => (),
+            PORCTRL | GCTRL | VCOMS | LCMCTRL | VDVVRHEN | VRHS | VDVS | FRCTRL2 | PWCTRL1
+            | PVGAMCTRL | NVGAMCTRL => (),
+            other => self.unknown_commands.push(other),
+        }
+        self.cmd = Some(cmd);
+    }
+
+    /// Bytes were received with D/C high.
+    pub fn data(&mut self, data: &[u8]) {
+        if self.in_reset {
+            return;
+        }
+        match self.cmd {
+            Some(RAMWR) => {
+                for byte in data {
+                    self.pixel_bytes.push(*byte);
+                    self.decode_pixels();
+                }
+            }
+            Some(_) => {
+                self.params.extend_from_slice(data);
+                self.apply_params();
+            }
+            None => (),
+        }
+    }
+
+    /// Clock bytes out of the controller. Only read commands reply, otherwise the line floats
+    /// high.
+    pub fn read(&mut self, buf: &mut [u8]) {
+        let reply = self.reply();
+        for byte in buf {
+            *byte = reply.get(self.read_pos).copied().unwrap_or(0xFF);
+            self.read_pos += 1;
+        }
+    }
+
+    /// Commands that were received but that this model doesn't understand.
+    pub fn unknown_commands(&self) -> &[u8] {
+        &self.unknown_commands
+    }
+
+    /// The commands received so far (other than memory writes), each with its parameter bytes.
+    ///
+    /// This survives resets, so it can be used to check exactly what was sent during init.
+    pub fn command_log(&self) -> &[(u8, Vec<u8>)] {
+        &self.log
+    }
+
+    pub fn clear_command_log(&mut self) {
+        self.log.clear();
+    }
+
+    pub fn madctl(&self) -> u8 {
+        self.madctl
+    }
+
+    pub fn colmod(&self) -> u8 {
+        self.colmod
+    }
+
+    pub fn is_inverted(&self) -> bool {
+        self.inverted
+    }
+
+    pub fn is_sleeping(&self) -> bool {
+        self.sleeping
+    }
+
+    pub fn is_display_on(&self) -> bool {
+        self.display_on
+    }
+
+    pub fn is_in_reset(&self) -> bool {
+        self.in_reset
+    }
+
+    pub fn scroll_start(&self) -> u16 {
+        self.scroll_start
+    }
+
+    /// The lit lines (inclusive), if in partial mode.
+    pub fn partial_area(&self) -> Option<(u16, u16)> {
+        if self.partial_mode {
+            Some(self.partial_area)
+        } else {
+            None
+        }
+    }
+
+    /// The raw contents of video memory at the given physical column & row.
+    pub fn gram_pixel(&self, col: usize, row: usize) -> u16 {
+        self.gram[row * GRAM_WIDTH + col]
+    }
+
+    /// Which GRAM row is shown on the given panel line, taking scrolling into account.
+    pub fn gram_row_for_line(&self, line: u16) -> u16 {
+        let (tfa, vsa, _) = self.scroll_definition;
+        if line < tfa || line >= tfa + vsa || vsa == 0 {
+            line
+        } else {
+            let ssa = self.scroll_start.max(tfa);
+            tfa + (line - tfa + ssa - tfa) % vsa
+        }
+    }
+
+    /// The colors you would see on the panel.
+    ///
+    /// The PineTime's IPS panel shows true colors when inversion is *on*, so when inversion is off
+    /// the colors come out inverted. When the panel is asleep or off it is black, and in partial
+    /// mode lines outside the partial area are black.
+    pub fn screenshot(&self) -> RgbImage {
+        let mut out = RgbImage::new(VISIBLE_WIDTH as u32, VISIBLE_HEIGHT as u32);
+        if self.sleeping || !self.display_on {
+            return out;
+        }
+        for line in 0..VISIBLE_HEIGHT {
+            if let Some((start, end)) = self.partial_area() {
+                if !(start..=end).contains(&(line as u16)) {
+                    continue;
+                }
+            }
+            let row = self.gram_row_for_line(line as u16) as usize;
+            for col in 0..VISIBLE_WIDTH {
+                let mut color = self.gram_pixel(col, row);
+                if !self.inverted {
+                    color = !color;
+                }
+                out.put_pixel(col as u32, line as u32, rgb565_to_rgb888(color));
+            }
+        }
+        out
+    }
+
+    /// Write what you would see on the panel to a png file.
+    pub fn save_png(&self, path: impl AsRef<Path>) -> image::ImageResult<()> {
+        self.screenshot().save(path)
+    }
+
+    /// Check what you would see on the panel against a png saved earlier. Panics if any pixel
+    /// differs, after saving the screenshot to the temp directory to compare by eye.
+    ///
+    /// With `UPDATE_SCREENSHOTS` set in the environment, the screenshot is saved over `golden`
+    /// instead. Look at the changes before committing them!
+    pub fn assert_screenshot(&self, golden: impl AsRef<Path>) {
+        let golden = golden.as_ref();
+        let actual = self.screenshot();
+        if env::var_os("UPDATE_SCREENSHOTS").is_some() {
+            actual.save(golden).expect("saving the screenshot");
+            return;
+        }
+        let expected = image::open(golden)
+            .unwrap_or_else(|err| panic!("reading {}: {}", golden.display(), err))
+            .to_rgb8();
+        let wrong = expected
+            .pixels()
+            .zip(actual.pixels())
+            .filter(|(expected, actual)| expected != actual)
+            .count();
+        if wrong > 0 || expected.dimensions() != actual.dimensions() {
+            let name = golden.file_name().expect("screenshots are files");
+            let saved = env::temp_dir().join(name);
+            actual.save(&saved).expect("saving the screenshot");
+            panic!(
+                "{} pixels differ from {}, the screenshot is in {}",
+                wrong,
+                golden.display(),
+                saved.display()
+            );
+        }
+    }
+
+    // helpers
+
+    fn apply_params(&mut self) {
+        let p = &self.params;
+        let be = |idx: usize| u16::from_be_bytes([p[idx], p[idx + 1]]);
+        match self.cmd {
+            Some(CASET) if p.len() == 4 => self.cols = (be(0), be(2)),
+            Some(RASET) if p.len() == 4 => self.rows = (be(0), be(2)),
+            Some(PTLAR) if p.len() == 4 => self.partial_area = (be(0), be(2)),
+            Some(VSCRDEF) if p.len() == 6 => self.scroll_definition = (be(0), be(2), be(4)),
+            Some(VSCSAD) if p.len() == 2 => self.scroll_start = be(0),
+            Some(MADCTL) if p.len() == 1 => self.madctl = p[0],
+            Some(COLMOD) if p.len() == 1 => self.colmod = p[0],
+            _ => (),
+        }
+    }
+
+    fn finish_command(&mut self) {
+        match self.cmd {
+            Some(RAMWR) | None => (),
+            Some(cmd) => self.log.push((cmd, std::mem::take(&mut self.params))),
+        }
+        self.cmd = None;
+        self.params.clear();
+        self.pixel_bytes.clear();
+        self.read_pos = 0;
+    }
+
+    /// The bytes on the wire in response to the current command, including the dummy bit.
+    fn reply(&self) -> Vec<u8> {
+        let (value, bits) = match self.cmd {
+            Some(RDDID) => (
+                u32::from_be_bytes([0, DISPLAY_ID[0], DISPLAY_ID[1], DISPLAY_ID[2]]),
+                24,
+            ),
+            Some(RDDST) => (self.status(), 32),
+            _ => return Vec::new(),
+        };
+        // dummy bit, then the value, padded out to a whole number of bytes.
+        let len = (bits + 1usize).div_ceil(8);
+        let wire = (value as u64) << (len * 8 - 1 - bits);
+        wire.to_be_bytes()[8 - len..].to_vec()
+    }
+
+    /// The 32 bit value returned by `RDDST`.
+    fn status(&self) -> u32 {
+        let mut status = (self.madctl as u32 & 0b1111_1100) << 23;
+        status |= (self.colmod as u32 & 0b111) << 20;
+        let flags = [
+            (31, !self.sleeping),
+            (18, self.partial_mode),
+            (17, !self.sleeping),
+            (16, !self.partial_mode),
+            (13, self.inverted),
+            (10, self.display_on),
+        ];
+        for (bit, set) in flags.iter() {
+            if *set {
+                status |= 1 << bit;
+            }
+        }
+        status
+    }
+
+    /// Write out the pixels in `pixel_bytes` once there are enough bytes, according to COLMOD.
+    ///
+    /// GRAM is stored as rgb565, so 18 bit colors lose their bottom bit of red and blue, and 12
+    /// bit colors are expanded.
+    fn decode_pixels(&mut self) {
+        let bytes = &self.pixel_bytes;
+        let rgb565 =
+            |r: u8, g: u8, b: u8| (u16::from(r) << 11) | (u16::from(g) << 5) | u16::from(b);
+        let colors: Vec<u16> = match (self.colmod & 0b111, bytes.len()) {
+            // 12 bit: 2 pixels in 3 bytes, 4 bits per channel.
+            (0b011, 3) => {
+                let nibbles = [
+                    bytes[0] >> 4,
+                    bytes[0] & 0xf,
+                    bytes[1] >> 4,
+                    bytes[1] & 0xf,
+                    bytes[2] >> 4,
+                    bytes[2] & 0xf,
+                ];
+                nibbles
+                    .chunks(3)
+                    .map(|c| {
+                        rgb565(
+                            c[0] << 1 | c[0] >> 3,
+                            c[1] << 2 | c[1] >> 2,
+                            c[2] << 1 | c[2] >> 3,
+                        )
+                    })
+                    .collect()
+            }
+            // 18 bit: 6 bits per channel, at the top of each byte.
+            (0b110, 3) => vec![rgb565(bytes[0] >> 3, bytes[1] >> 2, bytes[2] >> 3)],
+            // not a whole pixel yet.
+            (0b011, _) | (0b110, _) => return,
+            // 16 bit (and the unsupported formats, which we treat the same).
+            (_, 2) => vec![u16::from_be_bytes([bytes[0], bytes[1]])],
+            _ => return,
+        };
+        self.pixel_bytes.clear();
+        for color in colors {
+            self.write_pixel(color);
+        }
+    }
+
+    fn write_pixel(&mut self, color: u16) {
+        let (x, y) = self.cursor;
+        if let Some((col, row)) = self.physical(x, y) {
+            self.gram[row * GRAM_WIDTH + col] = color;
+        }
+        // advance the cursor, wrapping at the end of the window
+        if x >= self.cols.1 {
+            self.cursor = (
+                self.cols.0,
+                if y >= self.rows.1 { self.rows.0 } else { y + 1 },
+            );
+        } else {
+            self.cursor = (x + 1, y);
+        }
+    }
+
+    /// Map a column/row address to a GRAM location, using MADCTL.
+    ///
+    /// Row/column exchange happens first, then the mirroring is done over the physical size of
+    /// GRAM. This is why the firmware needs an 80 pixel offset when mirroring rows.
+    fn physical(&self, x: u16, y: u16) -> Option<(usize, usize)> {
+        let (mut col, mut row) = if self.madctl & MADCTL_MV != 0 {
+            (y as usize, x as usize)
+        } else {
+            (x as usize, y as usize)
+        };
+        if col >= GRAM_WIDTH || row >= GRAM_HEIGHT {
+            return None;
+        }
+        if self.madctl & MADCTL_MX != 0 {
+            col = GRAM_WIDTH - 1 - col;
+        }
+        if self.madctl & MADCTL_MY != 0 {
+            row = GRAM_HEIGHT - 1 - row;
+        }
+        Some((col, row))
+    }
+}
+
+impl Default for St7789 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Expand a 16 bit color to 24 bits.
+pub fn rgb565_to_rgb888(color: u16) -> Rgb<u8> {
+    let r = ((color >> 11) & 0x1f) as u32;
+    let g = ((color >> 5) & 0x3f) as u32;
+    let b = (color & 0x1f) as u32;
+    Rgb([
+        (r * 255 / 31) as u8,
+        (g * 255 / 63) as u8,
+        (b * 255 / 31) as u8,
+    ])
+}
@@ -0,0 +1,191 @@
+//! Looking assets up in a bundle, in the model flash chip.
+use assets::{
+    bundle::{BundleHeader, ENTRY_LEN, HEADER_LEN},
+    crc::crc32,
+    AssetKind, Bundle, BundleError, Entry,
+};
+use drivers::bundle::{AssetBuf, AssetError, AssetKey, AssetName, FlashBundle};
+use pinetime_sim::MockBus;
+
+/// Not at the start of the flash, so addresses have to be worked out from it.
+const START: u32 = 0x1000;
+const BUNDLE: FlashBundle = FlashBundle::new(START, START + 0x1_0000);
+
+/// A bundle of the assets, with ids in the order they are given.
+fn bundle(assets: &[(&str, AssetKind, &[u8])]) -> Vec<u8> {
+    let mut offset = (HEADER_LEN + assets.len() * ENTRY_LEN) as u32;
+    let mut table = Vec::new();
+    let mut data = Vec::new();
+    for (id, (name, kind, asset)) in assets.iter().enumerate() {
+        let entry = Entry::new(name, id as u16, *kind, offset, asset).unwrap();
+        table.extend_from_slice(&entry.to_bytes());
+        data.extend_from_slice(asset);
+        offset += asset.len() as u32;
+    }
+    let header = BundleHeader {
+        count: assets.len() as u16,
+        table_crc: crc32(&table),
+    };
+    let mut bundle = header.to_bytes().to_vec();
+    bundle.extend(table);
+    bundle.extend(data);
+    bundle
+}
+
+fn clock() -> Vec<u8> {
+    (0..600).map(|i| (i * 7 % 251) as u8).collect()
+}
+
+fn assets() -> Vec<u8> {
+    let clock = clock();
+    bundle(&[
+        ("clock.img", AssetKind::Image, &clock),
+        ("small.fnt", AssetKind::Font, b"abc"),
+    ])
+}
+
+/// A bus with the bundle in its flash.
+fn bus_with(bundle: &[u8]) -> MockBus {
+    let bus = MockBus::new();
+    bus.flash_mut().program(START as usize, bundle).unwrap();
+    bus
+}
+
+fn name(name: &str) -> AssetKey {
+    AssetKey::Name(AssetName::from(name))
+}
+
+fn asset_buf(len: usize) -> AssetBuf {
+    AssetBuf::new(Box::leak(vec![0; len].into_boxed_slice()))
+}
+
+#[test]
+fn round_trips() {
+    let data = assets();
+    let bundle = Bundle::parse(&data).unwrap();
+    let entries: Vec<Entry> = bundle.entries().map(Result::unwrap).collect();
+    assert_eq!(entries.len(), 2);
+    for entry in entries {
+        assert_eq!(Entry::parse(&entry.to_bytes()), Ok(entry));
+    }
+    let font = bundle.find("small.fnt").unwrap();
+    assert_eq!((font.id, font.kind), (1, AssetKind::Font));
+    assert_eq!(bundle.get(&font), Ok(&b"abc"[..]));
+    assert_eq!(
+        bundle.get(&bundle.find("clock.img").unwrap()),
+        Ok(&clock()[..])
+    );
+}
+
+#[test]
+fn finds_by_name_and_id() {
+    let data = assets();
+    let bus = bus_with(&data);
+    let mut flash = bus.flash_driver();
+    let entry = Bundle::parse(&data).unwrap().find("small.fnt").unwrap();
+
+    let font = BUNDLE
+        .find(&mut flash, &name("small.fnt"))
+        .unwrap()
+        .unwrap();
+    assert_eq!(font.address, START + entry.offset);
+    assert_eq!((font.id, font.len, font.crc), (1, 3, crc32(b"abc")));
+    assert!(font.font().is_some() && font.image().is_none());
+    assert_eq!(BUNDLE.find(&mut flash, &AssetKey::Id(1)), Ok(Some(font)));
+
+    let clock = BUNDLE.find(&mut flash, &AssetKey::Id(0)).unwrap().unwrap();
+    assert_eq!(BUNDLE.find(&mut flash, &name("clock.img")), Ok(Some(clock)));
+    assert!(clock.image().is_some());
+
+    assert_eq!(BUNDLE.find(&mut flash, &name("clock")), Ok(None));
+    assert_eq!(BUNDLE.find(&mut flash, &AssetKey::Id(2)), Ok(None));
+}
+
+#[test]
+fn checks_the_header_and_table() {
+    // Nothing written yet.
+    let bus = MockBus::new();
+    let mut flash = bus.flash_driver();
+    assert_eq!(
+        BUNDLE.read_header(&mut flash),
+        Err(AssetError::Bundle(BundleError::BadMagic))
+    );
+
+    let mut data = assets();
+    // A letter of the first name.
+    data[HEADER_LEN] ^= 0x01;
+    let bus = bus_with(&data);
+    let mut flash = bus.flash_driver();
+    let bad_crc = AssetError::Bundle(BundleError::BadTableCrc);
+    assert_eq!(BUNDLE.read_header(&mut flash), Err(bad_crc));
+    assert_eq!(BUNDLE.find(&mut flash, &AssetKey::Id(1)), Err(bad_crc));
+}
+
+#[test]
+fn assets_must_be_in_the_bundle() {
+    let data = assets();
+    let bus = bus_with(&data);
+    let mut flash = bus.flash_driver();
+    // Just too short for the last asset.
+    let bundle = FlashBundle::new(START, START + data.len() as u32 - 1);
+    assert_eq!(
+        bundle.find(&mut flash, &name("small.fnt")),
+        Err(AssetError::Bundle(BundleError::OutOfBounds))
+    );
+    assert!(bundle.find(&mut flash, &name("clock.img")).is_ok());
+}
+
+#[test]
+fn checks_assets_against_their_crc() {
+    let mut data = assets();
+    let last = data.len() - 1;
+    data[last] ^= 0x01;
+    let bus = bus_with(&data);
+    let mut flash = bus.flash_driver();
+    let font = BUNDLE
+        .find(&mut flash, &name("small.fnt"))
+        .unwrap()
+        .unwrap();
+    let bad_crc = AssetError::Bundle(BundleError::BadCrc);
+    let mut buf = asset_buf(64);
+    assert_eq!(buf.load(&mut flash, font), Err(bad_crc));
+    assert_eq!(buf.check(&mut flash, font), Err(bad_crc));
+    // The image is still fine.
+    let clock = BUNDLE.find(&mut flash, &AssetKey::Id(0)).unwrap().unwrap();
+    assert_eq!(buf.check(&mut flash, clock), Ok(()));
+}
+
+#[test]
+fn keeps_the_last_asset() {
+    let bus = bus_with(&assets());
+    let mut flash = bus.flash_driver();
+    let font = BUNDLE
+        .find(&mut flash, &name("small.fnt"))
+        .unwrap()
+        .unwrap();
+    let clock = BUNDLE
+        .find(&mut flash, &name("clock.img"))
+        .unwrap()
+        .unwrap();
+    let bytes_read = || bus.flash().stats().bytes_read;
+    let mut buf = asset_buf(64);
+
+    assert_eq!(buf.load(&mut flash, clock), Err(AssetError::TooBig(600)));
+    let before = bytes_read();
+    assert_eq!(buf.load(&mut flash, font), Ok(&b"abc"[..]));
+    assert_eq!(bytes_read(), before + 3);
+    assert_eq!(buf.load(&mut flash, font), Ok(&b"abc"[..]));
+    assert_eq!(bytes_read(), before + 3);
+
+    // Too big to load, but it can be checked, and that is remembered too.
+    assert_eq!(buf.check(&mut flash, clock), Ok(()));
+    assert_eq!(bytes_read(), before + 3 + 600);
+    assert_eq!(buf.check(&mut flash, clock), Ok(()));
+    assert_eq!(bytes_read(), before + 3 + 600);
+
+    // Once the flash has changed, both are read again.
+    buf.invalidate();
+    assert_eq!(buf.load(&mut flash, font), Ok(&b"abc"[..]));
+    assert_eq!(buf.check(&mut flash, clock), Ok(()));
+    assert_eq!(bytes_read(), before + 2 * (3 + 600));
+}
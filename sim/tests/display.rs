@@ -0,0 +1,294 @@
+//! The display driver's traffic on the bus, checked against the model panel.
+use drivers::{
+    bus::Clock,
+    display::{PanelPower, PixelFormat, Point, Rectangle, Rotation, Size},
+};
+use futures::executor::block_on;
+use pinetime_sim::{bus::BYTE_MICROS, MockBus, St7789, Stats};
+
+/// Every pixel a different color.
+fn pattern(len: usize) -> impl Iterator<Item = u16> + Clone {
+    (0..len as u16).map(|i| i.wrapping_mul(0x9e37))
+}
+
+/// Draw the pattern over `area`, and return the traffic that took.
+fn draw_pattern(bus: &MockBus, area: Rectangle, format: PixelFormat) -> Stats {
+    bus.with_display(|display| {
+        block_on(async {
+            display.sleep_off().await;
+            // Columns and rows as x and y, so the memory can be checked directly.
+            display.set_rotation(Rotation::Deg90).await;
+            display.set_pixel_format(format).await;
+            bus.reset_stats();
+            let len = (area.size.width * area.size.height) as usize;
+            display.draw_rect_iter_pixels(area, pattern(len)).await;
+        })
+    });
+    bus.stats()
+}
+
+#[test]
+fn draws_in_dma_sized_chunks() {
+    // (width, height, bytes per pixel in the format, bytes of pixels, transfers)
+    let cases = [
+        (1, 1, PixelFormat::Rgb565, 2, 1),
+        (10, 10, PixelFormat::Rgb565, 200, 1),
+        // Just over one transfer.
+        (8, 16, PixelFormat::Rgb565, 256, 2),
+        (240, 240, PixelFormat::Rgb565, 115_200, 452),
+        // Exactly one transfer, then just over.
+        (85, 1, PixelFormat::Rgb666, 255, 1),
+        (86, 1, PixelFormat::Rgb666, 258, 2),
+        (170, 1, PixelFormat::Rgb444, 255, 1),
+        // Half a pair of pixels at the end.
+        (171, 1, PixelFormat::Rgb444, 257, 2),
+        (240, 240, PixelFormat::Rgb444, 86_400, 339),
+    ];
+    for (width, height, format, pixel_bytes, transfers) in cases {
+        let bus = MockBus::new();
+        let area = Rectangle::new(Point::zero(), Size::new(width, height));
+        let stats = draw_pattern(&bus, area, format);
+        assert_eq!(
+            stats,
+            Stats {
+                // CASET, RASET and RAMWR.
+                command_bytes: 3,
+                // Start and end column, start and end row, then the pixels.
+                data_bytes: 8 + pixel_bytes,
+                // The commands and the address window.
+                blocking_writes: 5,
+                async_writes: transfers,
+                ..Stats::default()
+            },
+            "{}x{} in {:?}",
+            width,
+            height,
+            format
+        );
+    }
+}
+
+#[test]
+fn draws_every_pixel_in_order() {
+    for (width, height) in [(1, 1), (7, 3), (128, 2), (240, 240)] {
+        let bus = MockBus::new();
+        let area = Rectangle::new(Point::zero(), Size::new(width, height));
+        draw_pattern(&bus, area, PixelFormat::Rgb565);
+        let expected: Vec<u16> = pattern((width * height) as usize).collect();
+        let display = bus.display();
+        let mut drawn = Vec::new();
+        for row in 0..height as usize {
+            for col in 0..width as usize {
+                drawn.push(display.gram_pixel(col, row));
+            }
+        }
+        assert!(drawn == expected, "{}x{}", width, height);
+    }
+}
+
+#[test]
+fn draws_nothing_for_an_empty_area() {
+    let bus = MockBus::new();
+    let stats = draw_pattern(&bus, Rectangle::zero(), PixelFormat::Rgb565);
+    assert_eq!(stats.async_writes, 0);
+    assert_eq!(stats.data_bytes, 8);
+}
+
+/// Working out the next buffer of pixels happens while the last one is on the wire, so a full
+/// screen takes about as long as sending it, rather than as long as working it out and sending
+/// it.
+#[test]
+fn fills_while_sending() {
+    let area = Rectangle::new(Point::zero(), Size::new(240, 240));
+    let pixels = 240 * 240;
+    // Half as long as sending the pixel's 2 bytes.
+    let pixel_micros = BYTE_MICROS;
+    let fill_micros = pixels as u64 * pixel_micros;
+    let send_micros = pixels as u64 * 2 * BYTE_MICROS;
+    let draw = |blocking: bool| {
+        let bus = MockBus::new();
+        bus.with_display(|display| {
+            block_on(async {
+                display.sleep_off().await;
+                let pixels = pattern(pixels).inspect(|_| bus.pass_time(pixel_micros));
+                let start = bus.now_micros();
+                if blocking {
+                    display.draw_rect_iter_blocking(area, pixels.flat_map(u16::to_be_bytes));
+                } else {
+                    display.draw_rect_iter_pixels(area, pixels).await;
+                }
+                bus.now_micros() - start
+            })
+        })
+    };
+    let blocking = draw(true);
+    let double_buffered = draw(false);
+    assert!(blocking >= fill_micros + send_micros, "{} µs", blocking);
+    // Only the first buffer is filled with nothing being sent.
+    let first_fill = (drivers::EASY_DMA_SIZE / 2) as u64 * pixel_micros;
+    assert!(
+        double_buffered < send_micros + first_fill + 100,
+        "{} µs, blocking took {} µs",
+        double_buffered,
+        blocking
+    );
+}
+
+// Commands, as the model logs them.
+const SWRESET: u8 = 0x01;
+const SLPIN: u8 = 0x10;
+const SLPOUT: u8 = 0x11;
+const DISPOFF: u8 = 0x28;
+const DISPON: u8 = 0x29;
+
+/// The commands sent since the last call, without their parameters.
+fn take_commands(bus: &MockBus) -> Vec<u8> {
+    let mut display = bus.display_mut();
+    let commands = display.command_log().iter().map(|(cmd, _)| *cmd).collect();
+    display.clear_command_log();
+    commands
+}
+
+#[test]
+fn power_transitions() {
+    let bus = MockBus::new();
+    bus.with_display(|display| {
+        block_on(async {
+            // Not set up yet, so already asleep.
+            display.sleep_on().await;
+            assert_eq!(display.power(), PanelPower::Uninitialised);
+            assert_eq!(take_commands(&bus), []);
+
+            display.sleep_off().await;
+            assert_eq!(display.power(), PanelPower::Active);
+            let commands = take_commands(&bus);
+            assert_eq!(commands[..2], [SWRESET, SLPOUT]);
+            assert_eq!(commands.last(), Some(&DISPON));
+            assert!(!bus.display().is_sleeping() && bus.display().is_display_on());
+
+            display.sleep_off().await;
+            assert_eq!(take_commands(&bus), []);
+
+            display.sleep_on().await;
+            assert_eq!(display.power(), PanelPower::Sleeping);
+            assert_eq!(take_commands(&bus), [SLPIN]);
+            assert!(bus.display().is_sleeping());
+            display.sleep_on().await;
+            assert_eq!(take_commands(&bus), []);
+
+            // Waking from sleep doesn't need setting up again.
+            display.sleep_off().await;
+            assert_eq!(display.power(), PanelPower::Active);
+            assert_eq!(take_commands(&bus), [SLPOUT, DISPON]);
+            assert!(!bus.display().is_sleeping());
+
+            display.power_off().await;
+            assert_eq!(display.power(), PanelPower::Off);
+            assert_eq!(take_commands(&bus), [DISPOFF, SLPIN]);
+            assert!(bus.display().is_in_reset());
+
+            // Everything was lost, so it is set up from scratch.
+            display.sleep_off().await;
+            assert_eq!(display.power(), PanelPower::Active);
+            assert_eq!(take_commands(&bus)[..2], [SWRESET, SLPOUT]);
+            assert!(!bus.display().is_in_reset());
+
+            // Already asleep, so it only needs holding in reset.
+            display.sleep_on().await;
+            take_commands(&bus);
+            display.power_off().await;
+            assert_eq!(display.power(), PanelPower::Off);
+            assert_eq!(take_commands(&bus), []);
+            assert!(bus.display().is_in_reset());
+        })
+    });
+}
+
+#[test]
+fn waking_waits_for_the_panel_to_settle() {
+    let bus = MockBus::new();
+    bus.with_display(|display| {
+        block_on(async {
+            display.sleep_off().await;
+            let start = bus.now_micros();
+            display.sleep_on().await;
+            // SleepIn takes 10 ms, then waking waits out the rest of the 120 ms, then SleepOut
+            // takes another 10 ms. Sending SleepIn, SleepOut and DisplayOn takes a byte each.
+            display.sleep_off().await;
+            assert_eq!(bus.now_micros() - start, 130_000 + 3 * BYTE_MICROS);
+
+            // After a long sleep there is nothing left to wait for.
+            display.sleep_on().await;
+            bus.clock().delay_millis(500).await;
+            let start = bus.now_micros();
+            display.sleep_off().await;
+            assert_eq!(bus.now_micros() - start, 10_000 + 2 * BYTE_MICROS);
+        })
+    });
+}
+
+#[test]
+#[should_panic(expected = "SleepOut only 50000 µs after SleepIn")]
+fn the_model_checks_sleep_timing() {
+    let mut panel = St7789::new();
+    panel.set_time(200_000);
+    panel.command(SLPOUT);
+    panel.command(SLPIN);
+    panel.set_time(250_000);
+    panel.command(SLPOUT);
+}
+
+#[test]
+fn partial_mode_survives_sleep() {
+    let bus = MockBus::new();
+    bus.with_display(|display| {
+        block_on(async {
+            display.sleep_off().await;
+            display.enter_partial_mode(100, 139).await;
+            let lit = bus.display().partial_area();
+            assert!(lit.is_some());
+
+            display.sleep_on().await;
+            display.sleep_off().await;
+            assert_eq!(bus.display().partial_area(), lit);
+
+            // The panel forgets everything when it is off, so it has to be told again.
+            display.power_off().await;
+            assert_eq!(bus.display().partial_area(), None);
+            display.sleep_off().await;
+            assert_eq!(bus.display().partial_area(), lit);
+
+            display.leave_partial_mode().await;
+            assert_eq!(bus.display().partial_area(), None);
+        })
+    });
+}
+
+#[test]
+fn probing_resets_the_panel() {
+    let bus = MockBus::new();
+    bus.with_display(|display| {
+        block_on(async {
+            display.sleep_off().await;
+            let (id, status) = display.probe().await;
+            assert!(id.is_plausible(), "{:?}", id);
+            assert!(status.sleeping && !status.display_on, "{:?}", status);
+            assert_eq!(display.power(), PanelPower::Uninitialised);
+            // So waking it sets it up again.
+            take_commands(&bus);
+            display.sleep_off().await;
+            assert_eq!(take_commands(&bus)[..2], [SWRESET, SLPOUT]);
+        })
+    });
+}
+
+#[test]
+#[should_panic(expected = "command 0x04 only 1000 µs after a reset")]
+fn the_model_checks_reset_timing() {
+    let mut panel = St7789::new();
+    panel.set_time(200_000);
+    panel.hold_reset();
+    panel.release_reset();
+    panel.set_time(201_000);
+    panel.command(0x04);
+}
@@ -0,0 +1,170 @@
+//! The flash driver against the model flash chip.
+use drivers::flash::{EraseSize, FlashError, BLOCK_SIZE, FLASH_SIZE, PAGE_SIZE, SECTOR_SIZE};
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+use futures::executor::block_on;
+use pinetime_sim::{nor::JEDEC_ID, MockBus};
+
+/// Bytes that are different from their neighbours and from 0xFF.
+fn pattern(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 7 % 251) as u8).collect()
+}
+
+#[test]
+fn reads_the_jedec_id() {
+    let bus = MockBus::new();
+    let mut flash = bus.flash_driver();
+    let id = flash.read_jedec_id();
+    assert_eq!([id.manufacturer, id.memory_type, id.capacity], JEDEC_ID);
+    assert!(id.is_plausible());
+    assert_eq!(id.size(), Some(FLASH_SIZE));
+}
+
+#[test]
+fn writes_across_pages() {
+    let bus = MockBus::new();
+    let mut flash = bus.flash_driver();
+    // From part way through one page to part way through the third.
+    let address = 3 * PAGE_SIZE - 10;
+    let data = pattern(300);
+    block_on(flash.write(address, &data)).unwrap();
+    let start = address as usize;
+    assert_eq!(&bus.flash().contents()[start..start + 300], &data[..]);
+    assert_eq!(bus.flash().contents()[start - 1], 0xFF);
+    assert_eq!(bus.flash().contents()[start + 300], 0xFF);
+    assert_eq!(bus.flash().stats().bytes_programmed, 300);
+
+    let mut buf = [0; 300];
+    flash.read(address, &mut buf).unwrap();
+    assert_eq!(&buf[..], &data[..]);
+    // The display's chip select was never touched.
+    assert_eq!(bus.stats().dropped_bytes, 0);
+    assert_eq!(bus.stats().data_bytes + bus.stats().command_bytes, 0);
+}
+
+#[test]
+fn erase_then_write_again() {
+    let bus = MockBus::new();
+    let mut flash = bus.flash_driver();
+    block_on(async {
+        flash.write(SECTOR_SIZE, &[0x00; 16]).await.unwrap();
+        flash.erase(SECTOR_SIZE, EraseSize::Sector).await.unwrap();
+        flash.write(SECTOR_SIZE, &[0x55; 16]).await.unwrap();
+    });
+    let nor = bus.flash();
+    let start = SECTOR_SIZE as usize;
+    assert_eq!(&nor.contents()[start..start + 16], [0x55; 16]);
+    assert_eq!(nor.contents()[start + 16], 0xFF);
+    assert_eq!(nor.erase_count(SECTOR_SIZE as usize), 1);
+    assert_eq!(nor.stats().sector_erases, 1);
+    // The erase was waited for, polling rather than spinning.
+    assert!(bus.now_micros() >= 5_000);
+}
+
+#[test]
+#[should_panic(expected = "NotErased")]
+fn writing_without_erasing_panics() {
+    let bus = MockBus::new();
+    let mut flash = bus.flash_driver();
+    block_on(async {
+        flash.write(0, &[0x0F]).await.unwrap();
+        flash.write(0, &[0xF0]).await.unwrap();
+    });
+}
+
+#[test]
+fn erases_blocks() {
+    let bus = MockBus::new();
+    let mut flash = bus.flash_driver();
+    flash.write_blocking(BLOCK_SIZE - 1, &[0; 2]).unwrap();
+    flash.erase_blocking(BLOCK_SIZE, EraseSize::Block).unwrap();
+    let nor = bus.flash();
+    assert_eq!(nor.contents()[BLOCK_SIZE as usize - 1], 0x00);
+    assert_eq!(nor.contents()[BLOCK_SIZE as usize], 0xFF);
+    assert_eq!(nor.stats().block_erases, 1);
+    assert_eq!(nor.erase_count(2 * BLOCK_SIZE as usize - 1), 1);
+}
+
+#[test]
+fn refuses_bad_areas() {
+    let bus = MockBus::new();
+    let mut flash = bus.flash_driver();
+    block_on(async {
+        assert_eq!(
+            flash.erase(SECTOR_SIZE, EraseSize::Block).await,
+            Err(FlashError::NotAligned)
+        );
+        assert_eq!(
+            flash.erase(FLASH_SIZE, EraseSize::Sector).await,
+            Err(FlashError::OutOfBounds)
+        );
+        assert_eq!(
+            flash.write(FLASH_SIZE - 1, &[0, 0]).await,
+            Err(FlashError::OutOfBounds)
+        );
+    });
+    assert_eq!(
+        flash.read(FLASH_SIZE, &mut [0]),
+        Err(FlashError::OutOfBounds)
+    );
+    // Nothing was sent.
+    assert_eq!(bus.stats().flash_bytes, 0);
+}
+
+#[test]
+fn deep_power_down() {
+    let bus = MockBus::new();
+    let mut flash = bus.flash_driver();
+    flash.write_blocking(0, &[0x12]).unwrap();
+    flash.power_down();
+    assert!(bus.flash().is_powered_down());
+    assert!(!flash.read_jedec_id().is_plausible());
+    let mut buf = [0];
+    flash.read(0, &mut buf).unwrap();
+    assert_eq!(buf, [0xFF]);
+
+    block_on(flash.release_power_down());
+    assert!(!bus.flash().is_powered_down());
+    flash.read(0, &mut buf).unwrap();
+    assert_eq!(buf, [0x12]);
+}
+
+#[test]
+fn embedded_storage() {
+    let bus = MockBus::new();
+    let mut flash = bus.flash_driver();
+    let data = pattern(600);
+    NorFlash::write(&mut flash, 2 * SECTOR_SIZE - 100, &data).unwrap();
+    NorFlash::erase(&mut flash, 2 * SECTOR_SIZE, 3 * SECTOR_SIZE).unwrap();
+    let mut buf = vec![0; 600];
+    ReadNorFlash::read(&mut flash, 2 * SECTOR_SIZE - 100, &mut buf).unwrap();
+    // The part in the erased sector is gone.
+    assert_eq!(&buf[..100], &data[..100]);
+    assert!(buf[100..].iter().all(|byte| *byte == 0xFF));
+    assert_eq!(
+        NorFlash::erase(&mut flash, 0, 100),
+        Err(FlashError::NotAligned)
+    );
+    assert_eq!(ReadNorFlash::capacity(&flash), FLASH_SIZE as usize);
+}
+
+#[test]
+fn gives_up_on_a_stuck_chip() {
+    let bus = MockBus::new();
+    let mut flash = bus.flash_driver();
+    bus.flash_mut().get_stuck();
+    block_on(async {
+        let start = bus.now_micros();
+        assert_eq!(
+            flash.erase(0, EraseSize::Sector).await,
+            Err(FlashError::Timeout)
+        );
+        let waited = bus.now_micros() - start;
+        assert!((500_000..600_000).contains(&waited), "{} µs", waited);
+        assert_eq!(flash.write(0, &[0]).await, Err(FlashError::Timeout));
+    });
+    assert_eq!(flash.write_blocking(0, &[0]), Err(FlashError::Timeout));
+    assert_eq!(
+        flash.erase_blocking(0, EraseSize::Block),
+        Err(FlashError::Timeout)
+    );
+}
@@ -0,0 +1,107 @@
+//! Drawing raw images straight from the model flash, taking turns with the display on the bus as
+//! the firmware does for `DrawFlashImage`.
+use assets::{Encoding, ImageHeader};
+use drivers::display::{
+    Background, FlashImage, Image, PlacedImage, Point, Rgb565, RgbColor, Unstreamable,
+    DISPLAY_WIDTH,
+};
+use futures::executor::block_on;
+use image::RgbImage;
+use pinetime_sim::MockBus;
+
+static CLOCK_BG: &[u8] = include_bytes!("../../data/pictures/clock_bg.rgb565");
+static STARDEW: &[u8] = include_bytes!("../../data/pictures/stardew_240x240.rgb565");
+
+/// Somewhere past the first sector, so the rows don't start on a page boundary.
+const ADDRESS: u32 = 0x1_0100;
+
+/// Write `data` to the flash, then draw it from there a row at a time.
+fn draw_from_flash(data: &[u8], top_left: Point, scale: u8) -> Result<RgbImage, Unstreamable> {
+    let bus = MockBus::new();
+    let mut flash = bus.flash_driver();
+    block_on(flash.write(ADDRESS, data)).unwrap();
+    bus.with_display(|display| {
+        block_on(async {
+            display.sleep_off().await;
+            let mut raw = [0; ImageHeader::LEN];
+            flash.read(ADDRESS, &mut raw).unwrap();
+            let header = ImageHeader::parse(&raw).unwrap();
+            let image = FlashImage::new(ADDRESS, &header, top_left, scale)?;
+            let mut buf = [0; DISPLAY_WIDTH * 2];
+            let row = &mut buf[..image.row_len()];
+            for y in 0..image.height() {
+                flash.read(image.row_address(y), row).unwrap();
+                display
+                    .draw_rect_iter_pixels(image.row_area(y), image.row_pixels(row))
+                    .await;
+            }
+            Ok(())
+        })
+    })?;
+    let screenshot = bus.display().screenshot();
+    Ok(screenshot)
+}
+
+/// Draw `data` from RAM instead.
+fn draw_from_ram(data: &[u8], top_left: Point, scale: u8) -> RgbImage {
+    let bus = MockBus::new();
+    bus.with_display(|display| {
+        block_on(async {
+            display.sleep_off().await;
+            let image = PlacedImage::new(top_left, Image::parse(data).unwrap(), scale);
+            display
+                .draw_image(&image, &Background::Color(Rgb565::BLACK))
+                .await;
+        })
+    });
+    let screenshot = bus.display().screenshot();
+    screenshot
+}
+
+#[test]
+fn matches_drawing_from_ram() {
+    let cases = [
+        (CLOCK_BG, Point::new(10, 20), 1),
+        (CLOCK_BG, Point::new(3, 100), 2),
+        (CLOCK_BG, Point::new(50, 0), 4),
+        (STARDEW, Point::zero(), 1),
+    ];
+    for (data, top_left, scale) in cases {
+        let from_flash = draw_from_flash(data, top_left, scale).unwrap();
+        let from_ram = draw_from_ram(data, top_left, scale);
+        assert!(
+            from_flash == from_ram,
+            "{} bytes at {:?}, scale {}",
+            data.len(),
+            top_left,
+            scale
+        );
+        // Something was drawn.
+        assert!(from_flash.pixels().any(|pixel| pixel.0 != [0; 3]));
+    }
+}
+
+#[test]
+fn refuses_what_it_cannot_stream() {
+    let rle = ImageHeader::new(Encoding::Rle565, 8, 8).to_bytes();
+    assert_eq!(
+        draw_from_flash(&rle, Point::zero(), 1).err(),
+        Some(Unstreamable::Unsupported(Encoding::Rle565))
+    );
+    let masked = ImageHeader::new(Encoding::Rgb565, 8, 8)
+        .with_mask(1)
+        .to_bytes();
+    assert_eq!(
+        draw_from_flash(&masked, Point::zero(), 1).err(),
+        Some(Unstreamable::Unsupported(Encoding::Rgb565))
+    );
+    let wide = ImageHeader::new(Encoding::Rgb565, 241, 1).to_bytes();
+    assert_eq!(
+        draw_from_flash(&wide, Point::zero(), 1).err(),
+        Some(Unstreamable::TooWide(241))
+    );
+    assert_eq!(
+        draw_from_flash(STARDEW, Point::zero(), 2).err(),
+        Some(Unstreamable::TooTall(240))
+    );
+}
@@ -0,0 +1,375 @@
+//! The display driver drawing on the model panel, checked against the pngs in
+//! `tests/screenshots`.
+//!
+//! The screenshots are the panel's own way up, so with the default rotation everything is on its
+//! side (see `Rotation`). After a change that is meant to alter what is drawn, run with
+//! `UPDATE_SCREENSHOTS=1` to save new pngs, and look at them before committing.
+use assets::{
+    font::{self, FontData, DATA_OFFSET, KEYS, NO_GLYPH},
+    Encoding, ImageHeader,
+};
+use drivers::display::{
+    text::Font, Background, Filter, Graphic, Image, IntoStorage, PixelFormat, PlacedImage, Point,
+    Rectangle, Rgb565, RgbColor, Rotation, Size, Style,
+};
+use futures::executor::block_on;
+use pinetime_sim::MockBus;
+use std::path::PathBuf;
+
+static CLOCK_BG: &[u8] = include_bytes!("../../data/pictures/clock_bg.qoi");
+
+fn golden(name: &str) -> PathBuf {
+    [env!("CARGO_MANIFEST_DIR"), "tests", "screenshots", name]
+        .iter()
+        .collect()
+}
+
+fn clock_bg() -> Image<'static> {
+    Image::parse(CLOCK_BG).unwrap()
+}
+
+/// A font file with a glyph for each of `glyphs`, drawn with `#` for palette color 1, `+` for 2,
+/// `*` for 3 and `.` for transparent.
+fn font_file(height: u16, glyphs: &[(u8, &[&str])]) -> Vec<u8> {
+    let mut keys = [NO_GLYPH; KEYS];
+    let mut data = Vec::new();
+    for (ch, rows) in glyphs {
+        assert_eq!(rows.len(), usize::from(height));
+        keys[usize::from(*ch)] = data.len() as u32;
+        let pixels: Vec<u8> = rows
+            .iter()
+            .flat_map(|row| row.bytes())
+            .map(|px| match px {
+                b'.' => 0,
+                b'#' => 1,
+                b'+' => 2,
+                b'*' => 3,
+                _ => panic!("unknown pixel {}", px as char),
+            })
+            .collect();
+        data.extend_from_slice(&(pixels.len() as u32).to_be_bytes());
+        data.extend(pixels.chunks(4).map(|chunk| {
+            chunk
+                .iter()
+                .chain([0; 4].iter())
+                .take(4)
+                .fold(0, |byte, px| byte << 2 | px)
+        }));
+    }
+    let palette = [0xFFFF, 0xFD20, 0x0010];
+    let mut file = font::header(height, palette, &keys).to_vec();
+    assert_eq!(file.len(), DATA_OFFSET);
+    file.extend(data);
+    file
+}
+
+#[rustfmt::skip]
+const H: &[&str] = &[
+    "#...#.",
+    "#...#.",
+    "#####+",
+    "#...#+",
+    "#...#+",
+    ".+...+",
+];
+#[rustfmt::skip]
+const I: &[&str] = &[
+    ".*.",
+    "...",
+    "##.",
+    ".#+",
+    "###",
+    ".++",
+];
+
+/// A 20x20 disc, red at the top and yellow at the bottom, with a 1 bit mask (hard edged) or a 4
+/// bit one (fading out towards the edge).
+fn icon_file(mask_bits: u8) -> Vec<u8> {
+    const SIZE: u16 = 20;
+    let header = ImageHeader::new(Encoding::Rgb565, SIZE, SIZE).with_mask(mask_bits);
+    let mut alphas = Vec::new();
+    let mut pixels = Vec::new();
+    for y in 0..SIZE {
+        for x in 0..SIZE {
+            let (dx, dy) = (f32::from(x) - 9.5, f32::from(y) - 9.5);
+            let distance = (dx * dx + dy * dy).sqrt();
+            alphas.push(match mask_bits {
+                1 => u8::from(distance < 9.0),
+                _ => ((10.0 - distance) * 4.0).clamp(0.0, 15.0) as u8,
+            });
+            pixels.extend_from_slice(&(0xF800u16 | (y * 3) << 5).to_be_bytes());
+        }
+    }
+    let mut file = header.to_bytes().to_vec();
+    let per_byte = 8 / usize::from(mask_bits);
+    file.extend(alphas.chunks(per_byte).map(|chunk| {
+        chunk
+            .iter()
+            .fold(0, |byte, alpha| byte << mask_bits | alpha)
+    }));
+    file.extend(pixels);
+    file
+}
+
+#[test]
+fn rectangles() {
+    let bus = MockBus::new();
+    bus.with_display(|display| {
+        block_on(async {
+            display.sleep_off().await;
+            display.clear(Rgb565::new(4, 8, 4)).await;
+            let red = Rgb565::RED.into_storage().to_be_bytes();
+            display
+                .draw_rect_color(Rectangle::new(Point::new(10, 10), Size::new(100, 40)), red)
+                .await;
+            // Half off the screen.
+            let green = Rgb565::GREEN.into_storage().to_be_bytes();
+            display
+                .draw_rect_color(
+                    Rectangle::new(Point::new(200, 100), Size::new(40, 200)),
+                    green,
+                )
+                .await;
+            // A gradient, so that the order of the pixels shows.
+            let area = Rectangle::new(Point::new(20, 120), Size::new(64, 64));
+            let pixels = (0..64u16).flat_map(|y| (0..64u16).map(move |x| (x / 2) << 11 | y));
+            display.draw_rect_iter_pixels(area, pixels).await;
+        })
+    });
+    bus.display().assert_screenshot(golden("rectangles.png"));
+}
+
+#[test]
+fn images() {
+    let bus = MockBus::new();
+    let bg = Background::Color(Rgb565::BLUE);
+    bus.with_display(|display| {
+        block_on(async {
+            display.sleep_off().await;
+            display.clear(Rgb565::BLUE).await;
+            let image = PlacedImage::new(Point::new(4, 4), clock_bg(), 2);
+            display.draw_image(&image, &bg).await;
+            let part = Rectangle::new(Point::new(8, 8), Size::new(24, 16));
+            for (top_left, filter) in [
+                (Point::new(124, 20), Filter::Nearest),
+                (Point::new(124, 130), Filter::Bilinear),
+            ] {
+                let image = PlacedImage::with_source(
+                    top_left,
+                    clock_bg(),
+                    part,
+                    Size::new(108, 72),
+                    filter,
+                );
+                display.draw_image(&image, &bg).await;
+            }
+        })
+    });
+    bus.display().assert_screenshot(golden("images.png"));
+}
+
+/// Icons with 1 and 4 bit masks, over a color and over an image. Where the second icon hangs off
+/// the bottom of the image it is over black.
+#[test]
+fn masked_images() {
+    let (icon_1, icon_4) = (icon_file(1), icon_file(4));
+    let icons = [
+        Image::parse(&icon_1).unwrap(),
+        Image::parse(&icon_4).unwrap(),
+    ];
+    let bus = MockBus::new();
+    bus.with_display(|display| {
+        block_on(async {
+            display.sleep_off().await;
+            display.clear(Rgb565::new(8, 16, 8)).await;
+            let color = Background::Color(Rgb565::BLUE);
+            let blue = Rgb565::BLUE.into_storage().to_be_bytes();
+            let left = Rectangle::new(Point::new(8, 8), Size::new(80, 152));
+            display.draw_rect_color(left, blue).await;
+            let clock = PlacedImage::new(Point::new(110, 8), clock_bg(), 2);
+            display.draw_image(&clock, &color).await;
+            let image = Background::Image(clock);
+            for (i, icon) in icons.iter().enumerate() {
+                let y = 20 + 72 * i as i32;
+                let over_color = PlacedImage::new(Point::new(18, y), *icon, 3);
+                display.draw_image(&over_color, &color).await;
+                let over_image = PlacedImage::new(Point::new(130 + 30 * i as i32, y), *icon, 3);
+                display.draw_image(&over_image, &image).await;
+            }
+        })
+    });
+    bus.display().assert_screenshot(golden("masked_images.png"));
+}
+
+#[test]
+fn text() {
+    let font = font_file(6, &[(b'H', H), (b'i', I)]);
+    let font = Font::from_data(FontData::parse(&font).unwrap());
+    let bus = MockBus::new();
+    let image = PlacedImage::new(Point::new(64, 64), clock_bg(), 2);
+    bus.with_display(|display| {
+        block_on(async {
+            display.sleep_off().await;
+            display.clear(Rgb565::BLACK).await;
+            let bg = Background::Color(Rgb565::new(0, 0, 8));
+            display
+                .draw_text(Point::new(8, 8), "Hi", 4, &font, &bg)
+                .await;
+            // There is no glyph for `?`, so it is skipped.
+            display
+                .draw_text(Point::new(8, 40), "H?iH", 2, &font, &bg)
+                .await;
+            display.draw_image(&image, &bg).await;
+            // The image shows through the text, and where the text goes past the image it is
+            // black.
+            let bg = Background::Image(image);
+            display
+                .draw_text(Point::new(80, 150), "HiHi", 6, &font, &bg)
+                .await;
+        })
+    });
+    bus.display().assert_screenshot(golden("text.png"));
+}
+
+#[test]
+fn graphics() {
+    let graphics = [
+        Graphic::Line {
+            start: Point::new(-10, 0),
+            end: Point::new(239, 239),
+            color: Rgb565::RED,
+            width: 3,
+        },
+        Graphic::Rectangle {
+            area: Rectangle::new(Point::new(20, 100), Size::new(80, 50)),
+            corner_radius: 12,
+            style: Style {
+                fill: Some(Rgb565::YELLOW),
+                stroke: Some(Rgb565::BLACK),
+                stroke_width: 2,
+            },
+        },
+        Graphic::Circle {
+            top_left: Point::new(140, 20),
+            diameter: 70,
+            style: Style::stroke(Rgb565::BLUE, 4),
+        },
+        Graphic::Triangle {
+            vertices: [
+                Point::new(150, 200),
+                Point::new(230, 230),
+                Point::new(250, 150),
+            ],
+            style: Style::fill(Rgb565::GREEN),
+        },
+        Graphic::Text {
+            top_left: Point::new(10, 10),
+            text: "12:34".into(),
+            color: Rgb565::BLACK,
+        },
+    ];
+    let bus = MockBus::new();
+    bus.with_display(|display| {
+        block_on(async {
+            display.sleep_off().await;
+            display.clear(Rgb565::WHITE).await;
+        });
+        for graphic in &graphics {
+            graphic.draw(display).unwrap();
+        }
+    });
+    bus.display().assert_screenshot(golden("graphics.png"));
+}
+
+/// The same picture in every rotation and pixel format: an image with a red bar across the top
+/// and a green bar down the left.
+#[test]
+fn rotations_and_formats() {
+    let rotations = [
+        ("deg0", Rotation::Deg0),
+        ("deg90", Rotation::Deg90),
+        ("deg180", Rotation::Deg180),
+        ("deg270", Rotation::Deg270),
+        ("mirrored_deg0", Rotation::MirroredDeg0),
+        ("mirrored_deg90", Rotation::MirroredDeg90),
+        ("mirrored_deg180", Rotation::MirroredDeg180),
+        ("mirrored_deg270", Rotation::MirroredDeg270),
+    ];
+    let formats = [
+        ("444", PixelFormat::Rgb444),
+        ("565", PixelFormat::Rgb565),
+        ("666", PixelFormat::Rgb666),
+    ];
+    for (rotation_name, rotation) in rotations {
+        for (format_name, format) in formats {
+            let bus = MockBus::new();
+            bus.with_display(|display| {
+                block_on(async {
+                    display.sleep_off().await;
+                    display.set_rotation(rotation).await;
+                    display.set_pixel_format(format).await;
+                    display.clear(Rgb565::BLACK).await;
+                    let bg = Background::Color(Rgb565::BLACK);
+                    let image = PlacedImage::new(Point::new(30, 30), clock_bg(), 3);
+                    display.draw_image(&image, &bg).await;
+                    let red = Rgb565::RED.into_storage().to_be_bytes();
+                    // An odd width, so that rgb444 has half a pair of pixels at the end.
+                    let top = Rectangle::new(Point::zero(), Size::new(239, 10));
+                    display.draw_rect_color(top, red).await;
+                    let green = Rgb565::GREEN.into_storage().to_be_bytes();
+                    let left = Rectangle::new(Point::new(0, 10), Size::new(10, 100));
+                    display.draw_rect_color(left, green).await;
+                })
+            });
+            let name = format!("rotation_{}_{}.png", rotation_name, format_name);
+            bus.display().assert_screenshot(golden(&name));
+        }
+    }
+}
+
+#[test]
+fn scrolling() {
+    let bus = MockBus::new();
+    bus.with_display(|display| {
+        block_on(async {
+            display.sleep_off().await;
+            display.clear(Rgb565::BLACK).await;
+            display.define_scroll_region(20, 40).await;
+            display.scroll(100).await;
+            // Bands every 20 lines, across the fixed areas and the scroll area.
+            for band in 0..12 {
+                let color = if band % 2 == 0 {
+                    Rgb565::CYAN
+                } else {
+                    Rgb565::MAGENTA
+                };
+                let area = Rectangle::new(Point::new(band * 8, band * 20), Size::new(120, 20));
+                let color = color.into_storage().to_be_bytes();
+                display.draw_rect_color(area, color).await;
+            }
+            let bg = Background::Color(Rgb565::BLACK);
+            let image = PlacedImage::new(Point::new(130, 90), clock_bg(), 2);
+            display.draw_image(&image, &bg).await;
+        })
+    });
+    bus.display().assert_screenshot(golden("scrolling.png"));
+}
+
+#[test]
+fn sleeping_panel_is_black() {
+    let bus = MockBus::new();
+    bus.with_display(|display| {
+        block_on(async {
+            display.sleep_off().await;
+            display.clear(Rgb565::WHITE).await;
+            display.sleep_on().await;
+            assert!(bus.display().screenshot().pixels().all(|px| px.0 == [0; 3]));
+            display.sleep_off().await;
+            assert!(bus
+                .display()
+                .screenshot()
+                .pixels()
+                .all(|px| px.0 == [255; 3]));
+        })
+    });
+}
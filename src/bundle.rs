@@ -0,0 +1,25 @@
+//! Images and fonts from an asset bundle (see `assets::bundle`, and `tools pack`) at the start of
+//! the external flash, so they can be changed without reflashing the firmware.
+//!
+//! Look an asset up with `display::Cmd::FindAsset`, then pass the handle to
+//! `Cmd::DrawImageAsset`, or to `PlacedText::with_font` for `Cmd::DrawText`. Handles hold the
+//! asset's place and CRC, so they stay valid until the bundle is rewritten.
+//!
+//! Raw rgb565 images are streamed from the flash (like `Cmd::DrawFlashImage`), everything else is
+//! copied into a buffer in the display task and checked against its CRC first.
+use crate::{flash::FlashError, settings};
+// The lookup and its types are in the `drivers` crate, so they can be tested on the host.
+pub use drivers::bundle::{
+    Asset, AssetBuf, AssetError, AssetKey, AssetName, FlashBundle, FontAsset, ImageAsset,
+};
+
+/// Where the bundle is written.
+pub const START: u32 = 0;
+/// Assets must end before this, so they don't run into the settings store.
+pub const END: u32 = settings::START;
+/// The bundle, between `START` and `END`.
+pub const BUNDLE: FlashBundle = FlashBundle::new(START, END);
+/// The biggest asset that can be loaded into RAM. Raw rgb565 images can be any size.
+pub const ASSET_BUF_LEN: usize = 8 * 1024;
+
+pub type AssetLookupError = AssetError<FlashError>;
@@ -1,40 +1,57 @@
-use core::iter;
-use defmt::{assert, panic, unwrap, Format};
+use defmt::{assert, unwrap, Format};
+use drivers::bus::{AsyncWrite, Clock};
 use embassy::{
-    channel::mpsc,
-    time::{Duration, Timer},
+    channel::{mpsc, signal::Signal},
+    time::{Duration, Instant, Timer},
     traits::spi::{FullDuplex, Write},
 };
 use embassy_nrf::{
-    gpio::{FlexPin, Level, NoPin, Output, OutputDrive},
+    gpio::{FlexPin, Level, Output, OutputDrive},
     interrupt,
-    peripherals::{P0_02, P0_03, P0_04, P0_05, P0_14, P0_18, P0_22, P0_23, P0_25, P0_26, TWISPI0},
+    peripherals::{
+        P0_02, P0_03, P0_04, P0_05, P0_14, P0_18, P0_22, P0_23, P0_25, P0_26, PWM0, TWISPI0,
+    },
     spim::{self, Spim},
 };
-use embedded_hal::digital::v2::OutputPin;
+use embedded_hal::blocking;
 use heapless::String;
 
-pub use embedded_graphics::{
-    geometry::{Point, Size},
-    pixelcolor::{IntoStorage, Rgb565, RgbColor},
-    primitives::Rectangle,
+use crate::{
+    bundle::{
+        self, Asset, AssetBuf, AssetError, AssetKey, AssetLookupError, AssetName, FontAsset,
+        ImageAsset, ASSET_BUF_LEN,
+    },
+    flash::{EraseSize, Flash, FlashError, FLASH_SIZE},
+    settings::{self, SettingData, SettingsError, MAX_SETTING_LEN},
 };
+use assets::{Encoding, FontData, ImageError, ImageHeader};
+use kv::{Key, Store, Value};
 
+mod backlight;
 mod text;
 
-use self::text::FONT;
-
-const DISPLAY_WIDTH: usize = 240;
-const DISPLAY_HEIGHT: usize = 240;
-const DISPLAY_PIXELS: usize = DISPLAY_WIDTH * DISPLAY_HEIGHT;
+use self::backlight::BacklightPwm;
+use drivers::display::text::Font;
+// The display driver and its types are in the `drivers` crate, so they can be tested on the host.
+pub use drivers::display::{
+    Background, Backlight, Display, DisplayId, DisplayMode, DisplayState, DisplayStatus, Filter,
+    FlashImage, Graphic, Graphics, Image, IntoStorage, Orientation, PanelConfig, PanelPower,
+    PinConfig, Pins, PixelFormat, PlacedImage, Point, Rectangle, Rgb565, RgbColor, Rotation, Scene,
+    ScrollState, Size, Style, Unstreamable, DISPLAY_HEIGHT, DISPLAY_WIDTH, MAX_DRAWABLES,
+    MAX_GRAPHICS,
+};
 
 /// These are the commands that can be sent to the SPI (the display and the nor flash memory)
 #[derive(Format)]
 pub enum Cmd {
-    /// Wake the display
+    /// Wake the display. This is quick if it was asleep, but it has to be set up from scratch
+    /// (about 150 ms) after a reset or `PowerOff`.
     SleepOff,
-    /// Power down the display
+    /// Put the display to sleep. It keeps its settings and what was on screen.
     SleepOn,
+    /// Turn the display off completely, by holding it in reset. This uses the least power, but
+    /// what is on screen (and anything drawn until the next `SleepOff`) is lost.
+    PowerOff,
     /// Fill a rectangular area with the given color
     FillRectWithColor {
         /// The area to fill
@@ -42,12 +59,134 @@ pub enum Cmd {
         /// The color to fill it with
         color: Rgb565,
     },
-    /// Draw an image to screen
-    DrawImage { image: PlacedImage },
+    /// Draw an image to screen. Where the image has an alpha mask, `bg` shows through.
+    DrawImage {
+        image: PlacedImage<'static>,
+        bg: Background,
+    },
     /// Draw text to screen
-    DrawText { text: PlacedText, bg: TextBg },
+    DrawText { text: PlacedText, bg: Background },
+    /// Draw an image stored in the external flash at `address`, scaled up by a whole number.
+    ///
+    /// Only uncompressed rgb565 images without a mask can be drawn this way, up to the width of
+    /// the screen. Errors are logged.
+    DrawFlashImage {
+        address: u32,
+        top_left: Point,
+        scale: u8,
+    },
+    /// Draw an image from the asset bundle (see `bundle`), scaled up by a whole number. Where the
+    /// image has an alpha mask, `bg` shows through.
+    ///
+    /// Raw rgb565 images without a mask are streamed from the flash, others must fit in
+    /// `ASSET_BUF_LEN` bytes. Errors are logged.
+    DrawImageAsset {
+        image: ImageAsset,
+        top_left: Point,
+        scale: u8,
+        bg: Background,
+    },
+    /// Draw some `embedded-graphics` primitives, in order.
+    ///
+    /// The list is too big to send by value, so it is lent to the display task (e.g. from
+    /// `cortex_m::singleton!`), and handed back through `done` once drawn.
+    DrawGraphics {
+        graphics: &'static mut Graphics,
+        done: Reply<&'static mut Graphics>,
+    },
+    /// Redraw the part of a scene that has changed since it was last drawn (see `Scene`).
+    ///
+    /// The scene is lent in the same way as for `DrawGraphics`, and handed back through `done`
+    /// marked as drawn.
+    DrawScene {
+        scene: &'static mut Scene,
+        done: Reply<&'static mut Scene>,
+    },
     /// Change the backlight level
     SetBacklight { level: Backlight },
+    /// Smoothly change the backlight level over `duration_ms` milliseconds.
+    ///
+    /// Later commands wait until the fade has finished.
+    FadeBacklight { to: Backlight, duration_ms: u32 },
+    /// Set up hardware scrolling, with fixed areas at the top and bottom of the panel. The offset
+    /// is reset to 0.
+    ///
+    /// These are in panel lines, which run along the x or y axis depending on the rotation (with
+    /// the default rotation line 0 is at x = 239).
+    DefineScrollRegion { top_fixed: u16, bottom_fixed: u16 },
+    /// Scroll the area between the fixed areas, so that `offset` lines into it is shown first.
+    Scroll { offset: u16 },
+    /// Low power mode where only panel lines `start..=end` are lit (e.g. a strip showing the
+    /// time), and the backlight is at its lowest level.
+    ///
+    /// The mode survives `SleepOn`/`SleepOff`, until `LeavePartialMode` is sent.
+    EnterPartialMode { start: u16, end: u16 },
+    /// Move the lit band while in partial mode.
+    UpdatePartialArea { start: u16, end: u16 },
+    /// Light the whole panel again, and put the backlight back to where it was.
+    LeavePartialMode,
+    /// Change which way up the screen is. Drawing coordinates are always in screen space for the
+    /// current rotation.
+    ///
+    /// What is already on screen is not moved, so this should be followed by a redraw.
+    SetRotation { rotation: Rotation },
+    /// Change how many bits per pixel are sent to the display. Images are still given in rgb565,
+    /// they are converted on the way out.
+    ///
+    /// What is already on screen is kept, but loses precision when going to `Rgb444`.
+    SetPixelFormat { format: PixelFormat },
+    /// Change the gamma curves, voltages and timings of the panel. These are kept across sleep.
+    SetPanelConfig { config: PanelConfig },
+    /// Fill `buf` from the external flash. The buffer is lent, like the list for
+    /// `DrawGraphics`, and handed back through `done`.
+    FlashRead {
+        address: u32,
+        buf: &'static mut [u8],
+        done: Reply<(&'static mut [u8], Result<(), FlashError>)>,
+    },
+    /// Program bytes into the external flash. The area must have been erased first.
+    ///
+    /// Writing over the settings store (see `settings::START`) makes it be mounted again from the
+    /// flash, and writing over the asset bundle makes loaded assets be read again.
+    ///
+    /// The data is lent in the same way as for `FlashRead`.
+    FlashWrite {
+        address: u32,
+        data: &'static mut [u8],
+        done: Reply<(&'static mut [u8], Result<(), FlashError>)>,
+    },
+    /// Erase a sector or block of the external flash, setting it to 0xFF. Like `FlashWrite`, this
+    /// makes the settings store and loaded assets be read again if they were in the way.
+    FlashErase {
+        address: u32,
+        size: EraseSize,
+        done: Reply<Result<(), FlashError>>,
+    },
+    /// Read a setting from the store (see `settings`), or `None` if it isn't set.
+    ReadSetting {
+        key: u16,
+        done: Reply<Result<Option<SettingData>, SettingsError>>,
+    },
+    /// Save a setting. Use `Cmd::write_setting` to build this from a typed key.
+    WriteSetting {
+        key: u16,
+        data: SettingData,
+        done: Reply<Result<(), SettingsError>>,
+    },
+    RemoveSetting {
+        key: u16,
+        done: Reply<Result<(), SettingsError>>,
+    },
+    /// Look an asset up in the bundle, or `None` if there isn't one with that name or id.
+    FindAsset {
+        key: AssetKey,
+        done: Reply<Result<Option<Asset>, AssetLookupError>>,
+    },
+    /// Fire `done` once all the commands sent before this one have finished.
+    ///
+    /// The channel only tells you when a command was queued, use this to find out when it has
+    /// actually hit the screen.
+    Fence { done: Reply<()> },
     /// A high-level command to display a power on indicator.
     PowerOn,
 }
@@ -58,40 +197,128 @@ impl Cmd {
         Self::FillRectWithColor { area, color }
     }
 
-    pub fn draw_image(top_left: Point, data: &'static [u8], scale: u8) -> Self {
+    pub fn draw_image(top_left: Point, image: Image<'static>, scale: u8) -> Self {
+        Self::draw_image_over(top_left, image, scale, Background::Color(Rgb565::BLACK))
+    }
+
+    /// Draw an image with an alpha mask (e.g. an icon) over `bg`.
+    pub fn draw_image_over(
+        top_left: Point,
+        image: Image<'static>,
+        scale: u8,
+        bg: Background,
+    ) -> Self {
         Cmd::DrawImage {
-            image: PlacedImage::new(top_left, data, scale),
+            image: PlacedImage::new(top_left, image, scale),
+            bg,
+        }
+    }
+
+    pub fn draw_image_asset(top_left: Point, image: ImageAsset, scale: u8) -> Self {
+        Cmd::DrawImageAsset {
+            image,
+            top_left,
+            scale,
+            bg: Background::Color(Rgb565::BLACK),
         }
     }
 
     pub fn draw_text(top_left: Point, text: String<16>, scale: u8) -> Self {
         Cmd::DrawText {
             text: PlacedText::new(top_left, text, scale),
-            bg: TextBg::Color(Rgb565::BLACK),
+            bg: Background::Color(Rgb565::BLACK),
+        }
+    }
+
+    /// Panics if the name is longer than `assets::bundle::NAME_LEN` bytes.
+    pub fn find_asset(name: &str, done: Reply<Result<Option<Asset>, AssetLookupError>>) -> Self {
+        let mut key = AssetName::new();
+        unwrap!(key.push_str(name), "asset name is too long");
+        Cmd::FindAsset {
+            key: AssetKey::Name(key),
+            done,
+        }
+    }
+
+    pub fn read_setting<T>(
+        key: Key<T>,
+        done: Reply<Result<Option<SettingData>, SettingsError>>,
+    ) -> Self {
+        Cmd::ReadSetting {
+            key: key.id(),
+            done,
+        }
+    }
+
+    /// Panics if the value is longer than `MAX_SETTING_LEN` bytes.
+    pub fn write_setting<T: Value>(
+        key: Key<T>,
+        value: &T,
+        done: Reply<Result<(), SettingsError>>,
+    ) -> Self {
+        let mut buf = [0; MAX_SETTING_LEN];
+        let len = unwrap!(value.encode(&mut buf), "setting is too long");
+        Cmd::WriteSetting {
+            key: key.id(),
+            data: unwrap!(SettingData::from_slice(&buf[..len])),
+            done,
+        }
+    }
+
+    pub fn remove_setting<T>(key: Key<T>, done: Reply<Result<(), SettingsError>>) -> Self {
+        Cmd::RemoveSetting {
+            key: key.id(),
+            done,
         }
     }
 }
 
-#[derive(Format)]
-pub enum TextBg {
-    Color(Rgb565),
-    Image(PlacedImage),
+/// A way for the display task to send something back, once it has handled a command.
+pub struct Reply<T: 'static>(pub &'static Signal<T>);
+
+impl<T: Send> Reply<T> {
+    pub fn send(self, value: T) {
+        self.0.signal(value)
+    }
 }
 
-#[derive(Format)]
-pub struct PlacedImage {
-    pub top_left: Point,
-    pub data: &'static [u8],
-    pub scale: u8,
+impl<T> Format for Reply<T> {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(f, "Reply")
+    }
 }
 
-impl PlacedImage {
-    pub fn new(top_left: Point, data: &'static [u8], scale: u8) -> Self {
-        PlacedImage {
-            top_left,
-            data,
-            scale,
-        }
+/// Why an image in the external flash couldn't be drawn.
+#[derive(Debug, Format, Copy, Clone, PartialEq, Eq)]
+pub enum FlashImageError {
+    Flash(FlashError),
+    Image(ImageError),
+    Unstreamable(Unstreamable),
+    /// An image from the asset bundle couldn't be loaded.
+    Asset(AssetLookupError),
+}
+
+impl From<FlashError> for FlashImageError {
+    fn from(e: FlashError) -> Self {
+        FlashImageError::Flash(e)
+    }
+}
+
+impl From<ImageError> for FlashImageError {
+    fn from(e: ImageError) -> Self {
+        FlashImageError::Image(e)
+    }
+}
+
+impl From<Unstreamable> for FlashImageError {
+    fn from(e: Unstreamable) -> Self {
+        FlashImageError::Unstreamable(e)
+    }
+}
+
+impl From<AssetLookupError> for FlashImageError {
+    fn from(e: AssetLookupError) -> Self {
+        FlashImageError::Asset(e)
     }
 }
 
@@ -100,18 +327,33 @@ pub struct PlacedText {
     pub top_left: Point,
     pub text: String<16>,
     pub scale: u8,
+    pub font: TextFont,
 }
 
 impl PlacedText {
     pub fn new(top_left: Point, text: String<16>, scale: u8) -> Self {
+        Self::with_font(top_left, text, scale, TextFont::Builtin)
+    }
+
+    pub fn with_font(top_left: Point, text: String<16>, scale: u8, font: TextFont) -> Self {
         PlacedText {
             top_left,
             text,
             scale,
+            font,
         }
     }
 }
 
+/// Which font to draw text in.
+#[derive(Format, Copy, Clone)]
+pub enum TextFont {
+    /// The font compiled into the firmware.
+    Builtin,
+    /// A font from the asset bundle. It must fit in `ASSET_BUF_LEN` bytes.
+    Asset(FontAsset),
+}
+
 pub type Channel = crate::Channel<Cmd>;
 pub type Sender<'ch> = crate::Sender<'ch, Cmd>;
 
@@ -125,6 +367,8 @@ pub struct DisplayFlashSpi {
     bl_mid_pin: FlexPin<'static, P0_22>,
     /// Backlight high brightness pin
     bl_high_pin: FlexPin<'static, P0_23>,
+    /// PWM for dimming backlight pins
+    bl_pwm: BacklightPwm,
     /// SPI master periperal
     spim: TWISPI0,
     /// SPI master periperal interrupt
@@ -133,25 +377,26 @@ pub struct DisplayFlashSpi {
     spi_clock_pin: P0_02,
     /// SPI master -> slave data pin
     spi_mosi_pin: P0_03,
-    /// SPI slave -> master data pin. I believe this isn't connected for the display.
+    /// SPI slave -> master data pin. Shared between the display and the flash.
     spi_miso_pin: P0_04,
-    /// Display reset pin
-    reset_pin: P0_26,
+    /// Display reset pin. Kept driven between commands, as it is held low when the panel is off.
+    reset_pin: Output<'static, P0_26>,
     /// Display chip select pin
     display_cs_pin: P0_25,
     /// Flash mem chip select pin
     flash_cs_pin: P0_05,
     /// Display data/command switch (low for command, high for data)
     dc_pin: P0_18,
-}
-
-/// Levels that the backlight can be set to.
-#[derive(Debug, Format)]
-pub enum Backlight {
-    Off,
-    Low,
-    Mid,
-    High,
+    /// Display settings that need to outlive a single `Display`.
+    state: DisplayState,
+    /// The current backlight level.
+    backlight: Backlight,
+    /// The backlight level to go back to when leaving partial mode.
+    normal_backlight: Backlight,
+    /// The settings store, once it has been found in the flash.
+    settings: Option<Store>,
+    /// Where assets are loaded to draw them. Taken while in use.
+    asset_buf: Option<AssetBuf>,
 }
 
 impl DisplayFlashSpi {
@@ -159,6 +404,7 @@ impl DisplayFlashSpi {
         bl_low_pin: P0_14,
         bl_mid_pin: P0_22,
         bl_high_pin: P0_23,
+        bl_pwm: PWM0,
         spim: TWISPI0,
         spim_irq: interrupt::SPIM0_SPIS0_TWIM0_TWIS0_SPI0_TWI0,
         spi_clock_pin: P0_02,
@@ -175,10 +421,14 @@ impl DisplayFlashSpi {
         unwrap!(bl_low_pin.set_low());
         unwrap!(bl_mid_pin.set_low());
         unwrap!(bl_high_pin.set_low());
+        let reset_pin = Output::new(reset_pin, Level::High, OutputDrive::Standard);
+        // Too big for the stack. There is only one `DisplayFlashSpi`, so this is only run once.
+        let asset_buf = unwrap!(cortex_m::singleton!(: [u8; ASSET_BUF_LEN] = [0; ASSET_BUF_LEN]));
         Self {
             bl_low_pin,
             bl_mid_pin,
             bl_high_pin,
+            bl_pwm: BacklightPwm::new(bl_pwm),
             spim,
             spim_irq,
             spi_clock_pin,
@@ -188,6 +438,11 @@ impl DisplayFlashSpi {
             display_cs_pin,
             flash_cs_pin,
             dc_pin,
+            state: DisplayState::default(),
+            backlight: Backlight::OFF,
+            normal_backlight: Backlight::OFF,
+            settings: None,
+            asset_buf: Some(AssetBuf::new(asset_buf)),
         }
     }
 
@@ -197,582 +452,538 @@ impl DisplayFlashSpi {
         match cmd {
             Cmd::SleepOff => self.display().sleep_off().await,
             Cmd::SleepOn => self.display().sleep_on().await,
+            Cmd::PowerOff => self.display().power_off().await,
             Cmd::FillRectWithColor { area, color } => {
                 self.display()
                     .draw_rect_color(area, color.into_storage().to_be_bytes())
                     .await;
             }
-            Cmd::DrawImage {
-                image:
-                    PlacedImage {
-                        top_left,
-                        data,
-                        scale,
-                    },
-            } => self.display().draw_image(top_left, data, scale).await,
-            Cmd::DrawText { text, bg } => {
-                self.display().draw_text(text).await;
+            Cmd::DrawImage { image, bg } => self.display().draw_image(&image, &bg).await,
+            Cmd::DrawImageAsset {
+                image,
+                top_left,
+                scale,
+                bg,
+            } => self.draw_image_asset(image, top_left, scale, &bg).await,
+            Cmd::DrawText { text, bg } => self.draw_text(&text, &bg).await,
+            Cmd::DrawFlashImage {
+                address,
+                top_left,
+                scale,
+            } => self.draw_flash_image(address, top_left, scale).await,
+            Cmd::DrawGraphics { graphics, done } => {
+                let mut display = self.display();
+                for graphic in graphics.iter() {
+                    unwrap!(graphic.draw(&mut display));
+                }
+                done.send(graphics);
+            }
+            Cmd::DrawScene { scene, done } => {
+                self.display().draw_frame(scene).await;
+                scene.mark_drawn();
+                done.send(scene);
+            }
+            Cmd::SetBacklight { level } => match self.state.mode {
+                // Stay dim, but remember what was asked for.
+                DisplayMode::Partial { .. } => self.normal_backlight = level,
+                DisplayMode::Normal => self.set_backlight(level),
+            },
+            Cmd::FadeBacklight { to, duration_ms } => match self.state.mode {
+                DisplayMode::Partial { .. } => self.normal_backlight = to,
+                DisplayMode::Normal => {
+                    self.fade_backlight(to, Duration::from_millis(duration_ms.into()))
+                        .await
+                }
+            },
+            Cmd::DefineScrollRegion {
+                top_fixed,
+                bottom_fixed,
+            } => {
+                self.display()
+                    .define_scroll_region(top_fixed, bottom_fixed)
+                    .await
+            }
+            Cmd::Scroll { offset } => self.display().scroll(offset).await,
+            Cmd::EnterPartialMode { start, end } => {
+                if let DisplayMode::Normal = self.state.mode {
+                    self.normal_backlight = self.backlight;
+                }
+                self.display().enter_partial_mode(start, end).await;
+                self.set_backlight(Backlight::MIN);
+            }
+            Cmd::UpdatePartialArea { start, end } => match self.state.mode {
+                DisplayMode::Partial { .. } => self.display().enter_partial_mode(start, end).await,
+                DisplayMode::Normal => defmt::warn!("not in partial mode, ignoring new area"),
+            },
+            Cmd::LeavePartialMode => {
+                self.display().leave_partial_mode().await;
+                self.set_backlight(self.normal_backlight);
+            }
+            Cmd::SetRotation { rotation } => self.display().set_rotation(rotation).await,
+            Cmd::SetPixelFormat { format } => self.display().set_pixel_format(format).await,
+            Cmd::SetPanelConfig { config } => self.display().set_panel_config(config).await,
+            Cmd::FlashRead { address, buf, done } => {
+                let result = self.flash_read(address, buf).await;
+                done.send((buf, result));
+            }
+            Cmd::FlashWrite {
+                address,
+                data,
+                done,
+            } => {
+                self.flash_changing(address, data.len() as u32);
+                let result = self.flash_write(address, data).await;
+                done.send((data, result));
+            }
+            Cmd::FlashErase {
+                address,
+                size,
+                done,
+            } => {
+                self.flash_changing(address, size.len());
+                done.send(self.flash_erase(address, size).await)
+            }
+            Cmd::ReadSetting { key, done } => {
+                let result = self
+                    .with_settings(|store, flash| {
+                        let mut buf = [0; MAX_SETTING_LEN];
+                        let len = store.get_raw(flash, key, &mut buf)?;
+                        Ok(len.map(|len| unwrap!(SettingData::from_slice(&buf[..len]))))
+                    })
+                    .await;
+                done.send(result);
+            }
+            Cmd::WriteSetting { key, data, done } => {
+                let result = self
+                    .with_settings(|store, flash| store.set_raw(flash, key, &data))
+                    .await;
+                done.send(result);
+            }
+            Cmd::RemoveSetting { key, done } => {
+                let result = self
+                    .with_settings(|store, flash| store.remove_raw(flash, key))
+                    .await;
+                done.send(result);
+            }
+            Cmd::FindAsset { key, done } => {
+                let mut flash = self.flash();
+                flash.release_power_down().await;
+                let result = bundle::BUNDLE.find(&mut flash, &key);
+                flash.power_down();
+                done.send(result);
             }
-            Cmd::SetBacklight { level } => self.set_backlight(level),
+            // Commands are handled in order, so everything before this is done.
+            Cmd::Fence { done } => done.send(()),
             Cmd::PowerOn => {
                 self.display().power_on().await;
-                self.set_backlight(Backlight::High);
+                self.set_backlight(Backlight::HIGH);
                 Timer::after(Duration::from_secs(3)).await;
-                self.set_backlight(Backlight::Off);
+                self.set_backlight(Backlight::OFF);
             }
         }
         //defmt::debug!("finished cmd");
     }
 
     pub fn set_backlight(&mut self, level: Backlight) {
-        use Backlight::*;
-        // TODO remove me once I've checked this actually works.
-        match level {
-            Off => {
-                self.bl_low_pin.set_as_disconnected();
-                self.bl_mid_pin.set_as_disconnected();
-                self.bl_high_pin.set_as_disconnected();
-            }
-            Low => {
-                self.bl_low_pin.set_as_output(OutputDrive::Standard);
-                self.bl_mid_pin.set_as_disconnected();
-                self.bl_high_pin.set_as_disconnected();
-            }
-            Mid => {
-                self.bl_low_pin.set_as_disconnected();
-                self.bl_mid_pin.set_as_output(OutputDrive::Standard);
-                self.bl_high_pin.set_as_disconnected();
-            }
-            High => {
-                self.bl_low_pin.set_as_disconnected();
-                self.bl_mid_pin.set_as_disconnected();
-                self.bl_high_pin.set_as_output(OutputDrive::Standard);
-            }
+        // GPIO numbers of the backlight pins, for the PWM.
+        const LOW_PSEL: u32 = 14;
+        const MID_PSEL: u32 = 22;
+        const HIGH_PSEL: u32 = 23;
+
+        self.backlight = level;
+        let PinConfig { steady, dimmed } = level.pins();
+        self.bl_pwm.stop();
+        let on = match dimmed {
+            Some((pin, _)) => steady.with(pin),
+            None => steady,
+        };
+        // Disconnected pins are off, outputs are on (they are driven low).
+        if on.contains(Pins::LOW) {
+            self.bl_low_pin.set_as_output(OutputDrive::Standard);
+        } else {
+            self.bl_low_pin.set_as_disconnected();
         }
-    }
-
-    pub fn display<'a>(&'a mut self) -> Display<'a> {
-        Display::new(
-            &mut self.spim,
-            &mut self.spim_irq,
-            &mut self.spi_clock_pin,
-            &mut self.spi_mosi_pin,
-            &mut self.reset_pin,
-            &mut self.display_cs_pin,
-            &mut self.dc_pin,
-        )
-    }
-}
-
-/// Initialize the display.
-///
-/// The SPI is powered down when this function exits. The backlight is handled separately.
-pub struct Display<'a> {
-    spim: Spim<'a, TWISPI0>,
-    reset_pin: Output<'a, P0_26>,
-    cs_pin: Output<'a, P0_25>,
-    dc_pin: Output<'a, P0_18>,
-}
-
-impl<'a> Display<'a> {
-    fn new(
-        spim: &'a mut TWISPI0,
-        irq: &'a mut interrupt::SPIM0_SPIS0_TWIM0_TWIS0_SPI0_TWI0,
-        sck_pin: &'a mut P0_02,
-        mosi_pin: &'a mut P0_03,
-        reset_pin: &'a mut P0_26,
-        cs_pin: &'a mut P0_25,
-        dc_pin: &'a mut P0_18,
-    ) -> Display<'a> {
-        let mut config = spim::Config::default();
-        config.frequency = spim::Frequency::M8;
-        config.mode = spim::MODE_3;
-        config.orc = 122;
-        let spim = Spim::new(spim, irq, sck_pin, NoPin, mosi_pin, config);
-        let reset_pin = Output::new(reset_pin, Level::High, OutputDrive::Standard);
-        // Drive low to send spi data, then drive high to signal end of data.
-        let cs_pin = Output::new(cs_pin, Level::High, OutputDrive::Standard);
-        // We will always set this before sending a command or data.
-        let dc_pin = Output::new(dc_pin, Level::Low, OutputDrive::Standard);
-        Self {
-            spim,
-            reset_pin,
-            cs_pin,
-            dc_pin,
+        if on.contains(Pins::MID) {
+            self.bl_mid_pin.set_as_output(OutputDrive::Standard);
+        } else {
+            self.bl_mid_pin.set_as_disconnected();
+        }
+        if on.contains(Pins::HIGH) {
+            self.bl_high_pin.set_as_output(OutputDrive::Standard);
+        } else {
+            self.bl_high_pin.set_as_disconnected();
+        }
+        if let Some((pin, duty)) = dimmed {
+            let psel = match pin {
+                Pins::LOW => LOW_PSEL,
+                Pins::MID => MID_PSEL,
+                _ => HIGH_PSEL,
+            };
+            self.bl_pwm.start(psel, duty);
         }
     }
 
-    /// Turns on the screen.
-    pub async fn sleep_off(&mut self) {
-        self.hard_reset().await;
-        unwrap!(self.cs_pin.set_low());
-        self.soft_reset().await;
-        self.sleep_out().await;
-        self.pixel_format(
-            RgbInterfaceColorFormat::_65KOfRgb,
-            ControlInterfaceColorFormat::_16BitPerPixel,
-        );
-        self.vertical_scrolling_definition(0, 320, 0);
-        self.memory_data_access_control(
-            ColDir::BottomToTop,
-            RowDir::LeftToRight,
-            true,
-            ColDir::BottomToTop,
-            ColorOrder::Rgb,
-            RowDir::LeftToRight,
-        );
-        self.invert_display(true).await;
-        self.send_command(Instruction::NormalModeOn);
-        Timer::after(Duration::from_millis(10)).await;
-        self.send_command(Instruction::DisplayOn);
-        unwrap!(self.cs_pin.set_high());
-    }
-
-    pub async fn sleep_on(&mut self) {
-        unwrap!(self.cs_pin.set_low());
-        self.sleep_in().await;
-        unwrap!(self.cs_pin.set_high());
-    }
-
-    /// Copy a pre-existing buffer of data in RAM onto the screen.
-    pub async fn draw_rect_buf(
-        &mut self,
-        area: Rectangle,
-        // This byte array should contain 16 bit Rgb565 colors in big-endian order.
-        buf: &[u8],
-    ) {
-        self.draw_rect_iter(area, buf.iter().copied()).await
+    /// Change the backlight level a little at a time, so it looks smooth.
+    pub async fn fade_backlight(&mut self, to: Backlight, duration: Duration) {
+        const STEP: Duration = Duration::from_millis(20);
+        let from = i32::from(self.backlight.0);
+        let to = i32::from(to.0);
+        let steps = (duration.as_millis() / STEP.as_millis()).max(1) as i32;
+        for step in 1..=steps {
+            let level = from + (to - from) * step / steps;
+            self.set_backlight(Backlight(level as u8));
+            if step != steps {
+                Timer::after(STEP).await;
+            }
+        }
     }
 
-    /// Draw an image at the given location. The image must be [width, height, data...];
-    pub async fn draw_image(&mut self, top_left: Point, buf: &[u8], scale: u8) {
-        struct Scaler<'a> {
-            scale: u8,
-            width: u8,
-            buf: &'a [u8],
-            pos: usize,
+    /// Check that the display panel is connected and responding, logging an error if it isn't.
+    pub async fn check_panel(&mut self) -> bool {
+        let (id, status) = self.display().probe().await;
+        if !id.is_plausible() {
+            defmt::error!(
+                "display panel is not responding (read id {:?}): check the display connector",
+                id
+            );
+            return false;
         }
+        defmt::info!("display panel found: {:?}, {:?}", id, status);
+        true
+    }
+
+    /// Check that the flash is responding, logging an error if it isn't, and put it into deep
+    /// power-down. Flash commands wake it up while they run.
+    pub async fn check_flash(&mut self) -> bool {
+        let mut flash = self.flash();
+        flash.release_power_down().await;
+        let id = flash.read_jedec_id();
+        flash.power_down();
+        if !id.is_plausible() {
+            defmt::error!("flash is not responding (read id {:?})", id);
+            return false;
+        }
+        defmt::info!("flash found: {:?}, {:?} bytes", id, id.size());
+        true
+    }
 
-        impl<'a> Scaler<'a> {
-            fn new(scale: u8, width: u8, buf: &'a [u8]) -> Self {
-                debug_assert_eq!(buf.len() % width as usize, 0);
-                Scaler {
-                    scale,
-                    width,
-                    buf,
-                    pos: 0,
-                }
+    /// Find the settings store in the flash, tidying up after any write that was cut short by a
+    /// reset. If this fails, settings commands try again.
+    pub async fn mount_settings(&mut self) -> bool {
+        match self.with_settings(|store, _| Ok(store.len())).await {
+            Ok(keys) => {
+                defmt::info!("settings store has {=usize} keys", keys);
+                true
+            }
+            Err(e) => {
+                defmt::error!("cannot mount settings store: {:?}", e);
+                false
             }
         }
+    }
 
-        impl Iterator for Scaler<'_> {
-            type Item = u8;
-            fn next(&mut self) -> Option<Self::Item> {
-                // our job is: given a location on the scaled image, find the location on the
-                // original image.
-                let scaled_width = self.width as usize * self.scale as usize;
-
-                // pixels come in pairs of bytes (u16).
-                let (pos, byte) = (self.pos / 2, self.pos % 2);
-                let (row, col) = (pos / scaled_width, pos % scaled_width);
-                let (orig_row, orig_col) = (row / self.scale as usize, col / self.scale as usize);
-                let out = self
-                    .buf
-                    .get((orig_row * self.width as usize + orig_col) * 2 + byte)?;
-                self.pos += 1;
-                Some(*out)
+    /// Run `op` on the settings store, with the flash awake.
+    async fn with_settings<T>(
+        &mut self,
+        op: impl FnOnce(&mut Store, &mut PineTimeFlash<'_>) -> Result<T, SettingsError>,
+    ) -> Result<T, SettingsError> {
+        let store = self.settings.take();
+        let mut flash = self.flash();
+        flash.release_power_down().await;
+        let store = match store {
+            Some(store) => Ok(store),
+            None => Store::mount(&mut flash, settings::START, settings::SECTORS),
+        };
+        let (store, result) = match store {
+            Ok(mut store) => {
+                let result = op(&mut store, &mut flash);
+                (Some(store), result)
             }
+            Err(e) => (None, Err(e)),
+        };
+        flash.power_down();
+        drop(flash);
+        self.settings = store;
+        result
+    }
+
+    async fn flash_read(&mut self, address: u32, buf: &mut [u8]) -> Result<(), FlashError> {
+        let mut flash = self.flash();
+        flash.release_power_down().await;
+        let result = flash.read(address, buf);
+        flash.power_down();
+        result
+    }
+
+    async fn flash_write(&mut self, address: u32, data: &[u8]) -> Result<(), FlashError> {
+        let mut flash = self.flash();
+        flash.release_power_down().await;
+        let result = flash.write(address, data).await;
+        flash.power_down();
+        result
+    }
+
+    async fn flash_erase(&mut self, address: u32, size: EraseSize) -> Result<(), FlashError> {
+        let mut flash = self.flash();
+        flash.release_power_down().await;
+        let result = flash.erase(address, size).await;
+        flash.power_down();
+        result
+    }
+
+    /// Draw an image from the external flash. See `Cmd::DrawFlashImage`.
+    pub async fn draw_flash_image(&mut self, address: u32, top_left: Point, scale: u8) {
+        self.flash().release_power_down().await;
+        let result = self.draw_flash_image_rows(address, top_left, scale).await;
+        self.flash().power_down();
+        if let Err(e) = result {
+            defmt::error!(
+                "cannot draw image from flash at {=u32:#x}: {:?}",
+                address,
+                e
+            );
         }
-
-        assert!(scale > 0);
-        let width = buf[0];
-        let height = buf[1];
-        defmt::debug!(
-            "Drawing image: size ({=u8}, {=u8}) orig ({=u8}, {=u8})",
-            width * scale,
-            height * scale,
-            width,
-            height
-        );
-        let buf = &buf[2..];
-        let area = Rectangle::new(
-            top_left,
-            Size::new(width as u32 * scale as u32, height as u32 * scale as u32),
-        );
-        self.draw_rect_iter(area, Scaler::new(scale, width, buf))
-            .await
     }
 
-    /// Draw a filled Rectangle with the given color.
-    ///
-    /// This method copies into an intermediate buffer. It assumes that the color has already been
-    /// converted into bytes, with the first byte sent on the wire first. (i.e. big endian)
-    pub async fn draw_rect_color<const COLOR_BYTES: usize>(
+    /// Draw an image from the asset bundle. See `Cmd::DrawImageAsset`.
+    pub async fn draw_image_asset(
         &mut self,
-        area: Rectangle,
-        color: [u8; COLOR_BYTES],
+        image: ImageAsset,
+        top_left: Point,
+        scale: u8,
+        bg: &Background,
     ) {
-        self.draw_rect_iter(
-            area,
-            iter::repeat(&color)
-                .take((area.size.width * area.size.height) as usize)
-                .flatten()
-                .copied(),
-        )
-        .await;
-    }
-
-    pub async fn clear(&mut self, color: Rgb565) {
-        self.draw_rect_color(
-            Rectangle::new(Point::new(0, 0), Size::new(240, 240)),
-            color.into_storage().to_be_bytes(),
-        )
-        .await
-    }
-
-    /// Only works on ascii - will get garbage with anything else.
-    pub async fn draw_text(&mut self, text: PlacedText) {
-        let mut top_left = text.top_left;
-        for ch in text.text.chars().map(|ch| ch as u8) {
-            defmt::info!("ch {}", ch as char);
-            let extents = match FONT.extents(ch) {
-                Some(e) => e,
-                None => {
-                    defmt::warn!("character {} cannot be drawn", ch as char);
-                    continue;
-                }
-            };
-            defmt::info!("get pixels");
-            // just output black when transparent for now.
-            let pixels = FONT.pixels(extents, text.scale).map(|col| match col {
-                text::Color::Opaque(v) => v,
-                _ => 0xFE6F,
-            });
-            defmt::info!("draw text");
-            self.draw_rect_iter_pixels(
-                Rectangle::new(top_left, FONT.extents_size(extents, text.scale)),
-                pixels,
-            )
+        let asset = image.asset();
+        let mut asset_buf = unwrap!(self.asset_buf.take());
+        self.flash().release_power_down().await;
+        let result = self
+            .draw_image_asset_inner(&mut asset_buf, asset, top_left, scale, bg)
             .await;
-            top_left.x += (extents.width(&FONT) * usize::from(text.scale)) as i32;
+        self.flash().power_down();
+        self.asset_buf = Some(asset_buf);
+        if let Err(e) = result {
+            defmt::error!("cannot draw image asset {=u16}: {:?}", asset.id, e);
         }
     }
 
-    pub async fn draw_rect_iter_pixels(
+    async fn draw_image_asset_inner(
         &mut self,
-        area: Rectangle,
-        pixel_colors: impl IntoIterator<Item = u16>,
-    ) {
-        self.draw_rect_iter(
-            area,
-            pixel_colors
-                .into_iter()
-                .flat_map(|color| color.to_be_bytes()),
-        )
-        .await
+        asset_buf: &mut AssetBuf,
+        asset: Asset,
+        top_left: Point,
+        scale: u8,
+        bg: &Background,
+    ) -> Result<(), FlashImageError> {
+        let mut raw = [0; ImageHeader::LEN];
+        self.flash().read(asset.address, &mut raw)?;
+        // QOI images have their own header, so don't match.
+        let streamable = matches!(
+            ImageHeader::parse(&raw),
+            Ok(header) if header.encoding == Encoding::Rgb565 && header.mask_bits().is_none()
+        );
+        if streamable && asset.len as usize > ASSET_BUF_LEN {
+            asset_buf.check(&mut self.flash(), asset)?;
+            return self
+                .draw_flash_image_rows(asset.address, top_left, scale)
+                .await;
+        }
+        let data = asset_buf.load(&mut self.flash(), asset)?;
+        let image = Image::parse(data)?;
+        let image = PlacedImage::new(top_left, image, scale);
+        self.display().draw_image(&image, bg).await;
+        Ok(())
+    }
+
+    /// Draw text, loading its font from the asset bundle if it isn't the built in one.
+    pub async fn draw_text(&mut self, text: &PlacedText, bg: &Background) {
+        let font = match text.font {
+            TextFont::Builtin => {
+                let font = self::text::builtin();
+                return self
+                    .display()
+                    .draw_text(text.top_left, &text.text, text.scale, &font, bg)
+                    .await;
+            }
+            TextFont::Asset(font) => font.asset(),
+        };
+        let mut asset_buf = unwrap!(self.asset_buf.take());
+        self.flash().release_power_down().await;
+        let data = asset_buf.load(&mut self.flash(), font);
+        self.flash().power_down();
+        match data.and_then(|data| FontData::parse(data).map_err(AssetError::Font)) {
+            Ok(data) => {
+                let font = Font::from_data(data);
+                self.display()
+                    .draw_text(text.top_left, &text.text, text.scale, &font, bg)
+                    .await;
+            }
+            Err(e) => defmt::error!("cannot load font asset {=u16}: {:?}", font.id, e),
+        }
+        self.asset_buf = Some(asset_buf);
     }
 
-    /// Copy data from an iterator onto the screen.
+    /// `len` bytes of the flash from `address` are about to change, so anything that was loaded
+    /// from there might be out of date.
     ///
-    /// This method copies into an intermediate buffer. This is the core method for getting pixels
-    /// on screen: every other method uses this one.
-    ///
-    /// TODO get the next buffer ready while we are DMAing the current one.
-    pub async fn draw_rect_iter(&mut self, area: Rectangle, data: impl IntoIterator<Item = u8>) {
-        check_rect_u16(area);
-        let tl = area.top_left;
-        // No area means top-left = bottom-right
-        let br = match area.bottom_right() {
-            Some(x) => x,
-            None => tl,
-        };
-
-        unwrap!(self.cs_pin.set_low());
-        self.set_address_window(tl.x as u16, tl.y as u16, br.x as u16, br.y as u16);
-
-        self.send_command(Instruction::WriteToRam);
-        // chunk into slices of max EASY_DMA_SIZE
-        let mut buffer = [0u8; crate::EASY_DMA_SIZE];
-        let mut cnt = 0;
-        let mut i = 0;
-        for (idx, byte) in data.into_iter().enumerate() {
-            *unwrap!(buffer.get_mut(i)) = byte;
-            i += 1;
-            if i >= crate::EASY_DMA_SIZE {
-                //defmt::debug!("send buffer {} (idx {=usize})", cnt, idx);
-                self.send_data_blocking(&buffer);
-                i = 0;
-                cnt += 1;
+    /// The settings store keeps track of where its records are, so it has to be mounted again
+    /// from what ends up in the flash.
+    fn flash_changing(&mut self, address: u32, len: u32) {
+        let end = address.saturating_add(len);
+        let overlaps = |start: u32, region_end: u32| address < region_end && end > start;
+        if overlaps(bundle::START, bundle::END) {
+            if let Some(asset_buf) = &mut self.asset_buf {
+                asset_buf.invalidate();
             }
         }
-        // send the last buffer
-        if i > 0 {
-            self.send_data(&buffer[..i]).await;
+        if overlaps(settings::START, FLASH_SIZE) {
+            self.settings = None;
         }
-        unwrap!(self.cs_pin.set_high());
-    }
-
-    pub async fn power_on(&mut self) {
-        self.sleep_off().await;
-        // clear screen
-        self.clear(Rgb565::WHITE).await;
-    }
-
-    // Display commands and their args.
-
-    #[inline]
-    async fn hard_reset(&mut self) {
-        //defmt::debug!("hard_reset");
-        unwrap!(self.reset_pin.set_high());
-        Timer::after(Duration::from_micros(10)).await;
-        unwrap!(self.reset_pin.set_low());
-        Timer::after(Duration::from_micros(10)).await;
-        unwrap!(self.reset_pin.set_high());
-        Timer::after(Duration::from_micros(10)).await;
-    }
-
-    #[inline]
-    async fn soft_reset(&mut self) {
-        //defmt::debug!("soft_reset");
-        self.send_command(Instruction::SoftwareReset);
-        Timer::after(Duration::from_millis(150)).await;
-    }
-
-    #[inline]
-    async fn sleep_out(&mut self) {
-        //defmt::debug!("sleep_out");
-        self.send_command(Instruction::SleepOut);
-        Timer::after(Duration::from_millis(10)).await;
-    }
-
-    #[inline]
-    async fn sleep_in(&mut self) {
-        //defmt::debug!("sleep_out");
-        self.send_command(Instruction::SleepIn);
-        Timer::after(Duration::from_millis(10)).await;
     }
 
-    #[inline]
-    fn pixel_format(
+    /// The flash and display take turns on the bus: read a row of the image, then draw it
+    /// (`scale` times over).
+    async fn draw_flash_image_rows(
         &mut self,
-        color: RgbInterfaceColorFormat,
-        ctrl: ControlInterfaceColorFormat, // this is the one that we use I think
-    ) {
-        //defmt::debug!("pixel_format");
-        // 16bit 65k colors
-        self.send_command(Instruction::InterfacePixelFormat);
-        self.send_data_blocking(&[color as u8 | ctrl as u8]);
-    }
-
-    /// Invert the colors of the display, so e.g. red would become cyan.
-    #[inline]
-    async fn invert_display(&mut self, invert: bool) {
-        if invert {
-            self.send_command(Instruction::DisplayInversionOn);
-        } else {
-            self.send_command(Instruction::DisplayInversionOff);
+        address: u32,
+        top_left: Point,
+        scale: u8,
+    ) -> Result<(), FlashImageError> {
+        let mut raw = [0; ImageHeader::LEN];
+        self.flash().read(address, &mut raw)?;
+        let header = ImageHeader::parse(&raw)?;
+        let image = FlashImage::new(address, &header, top_left, scale)?;
+        let mut buf = [0; DISPLAY_WIDTH * 2];
+        let row = &mut buf[..image.row_len()];
+        for y in 0..image.height() {
+            self.flash().read(image.row_address(y), row)?;
+            self.display()
+                .draw_rect_iter_pixels(image.row_area(y), image.row_pixels(row))
+                .await;
         }
-        Timer::after(Duration::from_millis(10)).await;
+        Ok(())
     }
 
-    #[inline]
-    fn vertical_scrolling_definition(
-        &mut self,
-        top_fixed_area: u16,
-        vertical_scrolling_area: u16,
-        bottom_fixed_area: u16,
-    ) {
-        //defmt::debug!("vertical_scrolling_definition");
-        debug_assert_eq!(
-            top_fixed_area + vertical_scrolling_area + bottom_fixed_area,
-            320
+    /// The external flash. It is in deep power-down between commands, so call
+    /// `release_power_down` first (and `power_down` after).
+    pub fn flash<'a>(&'a mut self) -> PineTimeFlash<'a> {
+        let spim = PineTimeSpi::new(
+            &mut self.spim,
+            &mut self.spim_irq,
+            &mut self.spi_clock_pin,
+            &mut self.spi_mosi_pin,
+            &mut self.spi_miso_pin,
         );
-        let tfa = top_fixed_area.to_be_bytes();
-        let vsa = vertical_scrolling_area.to_be_bytes();
-        let bfa = bottom_fixed_area.to_be_bytes();
-        let data = [tfa[0], tfa[1], vsa[0], vsa[1], bfa[0], bfa[1]];
-        self.send_command(Instruction::VerticalScrollingDefinition);
-        self.send_data_blocking(&data);
+        let cs_pin = Output::new(&mut self.flash_cs_pin, Level::High, OutputDrive::Standard);
+        Flash::from_parts(spim, cs_pin, EmbassyClock)
     }
 
-    #[inline]
-    fn memory_data_access_control(
-        &mut self,
-        page_address_order: ColDir,
-        column_address_order: RowDir,
-        page_col_order_reverse: bool,
-        line_address_order: ColDir,
-        color_order: ColorOrder,
-        display_data_latch_order: RowDir,
-    ) {
-        // let's hope this all disappears through optimization (constant propagation)
-        let mut mdac = 0;
-        if matches!(page_address_order, ColDir::BottomToTop) {
-            mdac |= 1 << 7;
-        }
-        if matches!(column_address_order, RowDir::RightToLeft) {
-            mdac |= 1 << 6;
-        }
-        if page_col_order_reverse {
-            mdac |= 1 << 5;
-        }
-        if matches!(line_address_order, ColDir::BottomToTop) {
-            mdac |= 1 << 4;
-        }
-        if matches!(color_order, ColorOrder::Bgr) {
-            mdac |= 1 << 3;
-        }
-        if matches!(display_data_latch_order, RowDir::RightToLeft) {
-            mdac |= 1 << 2;
-        }
-        self.send_command(Instruction::MemoryDataAccessControl);
-        self.send_data_blocking(&[mdac]);
+    pub fn display<'a>(&'a mut self) -> PineTimeDisplay<'a> {
+        let spim = PineTimeSpi::new(
+            &mut self.spim,
+            &mut self.spim_irq,
+            &mut self.spi_clock_pin,
+            &mut self.spi_mosi_pin,
+            &mut self.spi_miso_pin,
+        );
+        // Drive low to send spi data, then drive high to signal end of data.
+        let cs_pin = Output::new(&mut self.display_cs_pin, Level::High, OutputDrive::Standard);
+        // We will always set this before sending a command or data.
+        let dc_pin = Output::new(&mut self.dc_pin, Level::Low, OutputDrive::Standard);
+        Display::from_parts(
+            spim,
+            &mut self.reset_pin,
+            cs_pin,
+            dc_pin,
+            EmbassyClock,
+            &mut self.state,
+        )
     }
+}
 
-    #[inline]
-    fn set_address_window(&mut self, startx: u16, starty: u16, endx: u16, endy: u16) {
-        // The screen we use (ST7789) actually has video memory that is slightly bigger than the
-        // screen, and supports scrolling. I think this is there to mean you can update the image
-        // faster than you can write data to it using the SPI.
-        // Because we need to change the display's orientation, we actually show the bottom bit of
-        // the framebuffer. This offset means we draw to the part of the framebuffer that is
-        // visible.
-        const SCROLL_OFFSET: u16 = 80;
-        let startx = (startx + SCROLL_OFFSET).to_be_bytes();
-        let starty = starty.to_be_bytes();
-        let endx = (endx + SCROLL_OFFSET).to_be_bytes();
-        let endy = endy.to_be_bytes();
-
-        // column address
-        self.send_command(Instruction::ColumnAddressSet);
-        self.send_data_blocking(&[startx[0], startx[1], endx[0], endx[1]]);
-
-        // row address
-        self.send_command(Instruction::RowAddressSet);
-        self.send_data_blocking(&[starty[0], starty[1], endy[0], endy[1]]);
-    }
+/// The display as it is wired up on the PineTime.
+pub type PineTimeDisplay<'a> = Display<
+    'a,
+    PineTimeSpi<'a>,
+    Output<'static, P0_26>,
+    Output<'a, P0_25>,
+    Output<'a, P0_18>,
+    EmbassyClock,
+>;
 
-    #[inline]
-    fn vertical_scroll_start_address(&mut self, line: u16) {
-        self.send_command(Instruction::VerticalScrollStartAddress);
-        self.send_data_blocking(&line.to_be_bytes());
-    }
+/// The flash as it is wired up on the PineTime.
+pub type PineTimeFlash<'a> = Flash<PineTimeSpi<'a>, Output<'a, P0_05>, EmbassyClock>;
 
-    // raw methods for commands & data
+/// The SPI bus, shared by the display and the flash. This gives the drivers the traits they need
+/// (see `drivers::bus`) on top of embassy's SPIM.
+pub struct PineTimeSpi<'a>(Spim<'a, TWISPI0>);
 
-    #[inline]
-    fn send_command(&mut self, inst: Instruction) {
-        unwrap!(self.dc_pin.set_low());
-        unwrap!(embedded_hal::blocking::spi::Write::write(
-            &mut self.spim,
-            &[inst.into()]
-        ));
+impl<'a> PineTimeSpi<'a> {
+    fn new(
+        spim: &'a mut TWISPI0,
+        irq: &'a mut interrupt::SPIM0_SPIS0_TWIM0_TWIS0_SPI0_TWI0,
+        sck_pin: &'a mut P0_02,
+        mosi_pin: &'a mut P0_03,
+        miso_pin: &'a mut P0_04,
+    ) -> Self {
+        PineTimeSpi(Spim::new(
+            spim,
+            irq,
+            sck_pin,
+            miso_pin,
+            mosi_pin,
+            spim_config(),
+        ))
     }
+}
 
-    #[inline]
-    fn send_data_blocking(&mut self, data: &[u8]) {
-        unwrap!(self.dc_pin.set_high());
-        unwrap!(embedded_hal::blocking::spi::Write::write(
-            &mut self.spim,
-            data
-        ));
-    }
+impl<'s> AsyncWrite for PineTimeSpi<'s> {
+    type Error = spim::Error;
+    type WriteFuture<'a>
+        = <Spim<'s, TWISPI0> as Write<u8>>::WriteFuture<'a>
+    where
+        Self: 'a;
 
-    #[inline]
-    async fn send_data(&mut self, data: &[u8]) {
-        /*
-        defmt::debug!(
-            "writing data at {=usize:x} -> {=usize:x}",
-            data.as_ptr() as _,
-            (data.as_ptr() as usize) + data.len()
-        );
-        */
-        unwrap!(self.dc_pin.set_high());
-        //defmt::debug!("let's go");
-        unwrap!(self.spim.write(data).await);
-        //defmt::debug!("write finished");
+    fn write<'a>(&'a mut self, data: &'a [u8]) -> Self::WriteFuture<'a> {
+        Write::write(&mut self.0, data)
     }
 }
 
-/// ST7789 instructions (some of them).
-#[repr(u8)]
-enum Instruction {
-    NoOp = 0x00,
-    SoftwareReset = 0x01,
-    ReadDisplayID = 0x04,
-    ReadDisplayStatus = 0x09,
-    SleepIn = 0x10,
-    SleepOut = 0x11,
-    PartialModeOn = 0x12,
-    NormalModeOn = 0x13,
-    DisplayInversionOff = 0x20,
-    DisplayInversionOn = 0x21,
-    DisplayOff = 0x28,
-    DisplayOn = 0x29,
-    ColumnAddressSet = 0x2A,
-    RowAddressSet = 0x2B,
-    WriteToRam = 0x2C,
-    ReadFromRam = 0x2E,
-    PartialStartEndAddressSet = 0x30,
-    VerticalScrollingDefinition = 0x33,
-    MemoryDataAccessControl = 0x36,
-    VerticalScrollStartAddress = 0x37,
-    InterfacePixelFormat = 0x3A,
-    VcomOffsetSet = 0xC5,
-}
+impl blocking::spi::Write<u8> for PineTimeSpi<'_> {
+    type Error = spim::Error;
 
-#[repr(u8)]
-enum RgbInterfaceColorFormat {
-    _65KOfRgb = 0b0101_0000,
-    _262KOfRgb = 0b0110_0000,
+    fn write(&mut self, words: &[u8]) -> Result<(), Self::Error> {
+        blocking::spi::Write::write(&mut self.0, words)
+    }
 }
 
-#[repr(u8)]
-enum ControlInterfaceColorFormat {
-    _12BitPerPixel = 0b0011,
-    _16BitPerPixel = 0b0101,
-    // default (after hw reset and power on)
-    _18BitPerPixel = 0b0110,
-    _16MTruncated = 0b0111,
-}
+impl blocking::spi::Transfer<u8> for PineTimeSpi<'_> {
+    type Error = spim::Error;
 
-enum ColDir {
-    TopToBottom,
-    BottomToTop,
+    fn transfer<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], Self::Error> {
+        blocking::spi::Transfer::transfer(&mut self.0, words)
+    }
 }
 
-enum RowDir {
-    LeftToRight,
-    RightToLeft,
-}
+/// Waiting on embassy's timer.
+pub struct EmbassyClock;
 
-enum ColorOrder {
-    Rgb,
-    Bgr,
-}
+impl Clock for EmbassyClock {
+    type Delay = Timer;
+
+    fn delay_micros(&self, micros: u64) -> Timer {
+        Timer::after(Duration::from_micros(micros))
+    }
 
-impl Into<u8> for Instruction {
-    fn into(self) -> u8 {
-        self as u8
+    fn now_micros(&self) -> u64 {
+        Instant::now().as_micros()
     }
 }
 
-fn check_rect_u16(r: Rectangle) {
-    let tl = r.top_left;
-    // No area means top-left = bottom-right
-    let br = match r.bottom_right() {
-        Some(x) => x,
-        None => tl,
-    };
-    let max = u16::MAX as i32;
-    defmt::assert!(
-        tl.x >= 0
-            && tl.x <= max
-            && tl.y >= 0
-            && tl.y <= max
-            && br.x >= 0
-            && br.x <= max
-            && br.y >= 0
-            && br.y <= max
-    );
+/// The bus settings. Both the display and the flash can work with these.
+fn spim_config() -> spim::Config {
+    let mut config = spim::Config::default();
+    config.frequency = spim::Frequency::M8;
+    config.mode = spim::MODE_3;
+    config.orc = 122;
+    config
 }
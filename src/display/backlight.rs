@@ -0,0 +1,64 @@
+//! Backlight dimming with PWM. The levels are worked out in `drivers::display::Backlight`.
+use embassy_nrf::{pac, peripherals::PWM0};
+
+/// Dims one backlight pin using the PWM0 peripheral.
+///
+/// The pin still needs to be set as an output through GPIO: the PWM only takes over its level.
+pub struct BacklightPwm {
+    _pwm: PWM0,
+    /// The compare value, read by the PWM's EasyDMA when the sequence starts.
+    seq: [u16; 1],
+}
+
+impl BacklightPwm {
+    /// The PWM counts to this, so duty cycles map directly onto the counter.
+    const COUNTER_TOP: u16 = 255;
+    /// Set in a compare value to start the period low and go high at the compare. Our pins are
+    /// active low, so this makes the compare value the on time.
+    const RISING_EDGE: u16 = 1 << 15;
+
+    pub fn new(pwm: PWM0) -> Self {
+        BacklightPwm {
+            _pwm: pwm,
+            seq: [0],
+        }
+    }
+
+    /// Drive the pin with GPIO number `psel` for `duty / 255` of the time.
+    pub fn start(&mut self, psel: u32, duty: u8) {
+        let r = Self::regs();
+        self.seq[0] = u16::from(duty) | Self::RISING_EDGE;
+        r.psel.out[0].write(|w| unsafe { w.bits(psel) });
+        r.enable.write(|w| w.enable().enabled());
+        r.mode.write(|w| w.updown().up());
+        // 1 MHz / 256 is about 4 kHz, too fast to flicker.
+        r.prescaler.write(|w| w.prescaler().div_16());
+        r.countertop
+            .write(|w| unsafe { w.countertop().bits(Self::COUNTER_TOP) });
+        r.decoder
+            .write(|w| w.load().common().mode().refresh_count());
+        r.loop_.write(|w| w.cnt().disabled());
+        // The peripheral keeps outputting the last value when the sequence ends, so it only
+        // needs to read the buffer once.
+        r.seq0
+            .ptr
+            .write(|w| unsafe { w.bits(self.seq.as_ptr() as u32) });
+        r.seq0.cnt.write(|w| unsafe { w.bits(1) });
+        r.seq0.refresh.write(|w| unsafe { w.bits(0) });
+        r.seq0.enddelay.write(|w| unsafe { w.bits(0) });
+        r.tasks_seqstart[0].write(|w| unsafe { w.bits(1) });
+    }
+
+    /// Stop the PWM and hand the pin back to GPIO.
+    pub fn stop(&mut self) {
+        let r = Self::regs();
+        r.tasks_stop.write(|w| unsafe { w.bits(1) });
+        r.enable.write(|w| w.enable().disabled());
+        r.psel.out[0].write(|w| w.connect().disconnected());
+    }
+
+    fn regs() -> &'static pac::pwm0::RegisterBlock {
+        // We own the PWM0 peripheral.
+        unsafe { &*pac::PWM0::ptr() }
+    }
+}
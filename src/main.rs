@@ -4,6 +4,7 @@
 #![allow(dead_code)]
 #![feature(type_alias_impl_trait)]
 #![feature(const_generics_defaults)]
+#![feature(generic_associated_types)]
 
 extern crate panic_abort;
 
@@ -15,11 +16,11 @@ use defmt::{unwrap, Format};
 use defmt_rtt as _;
 use embassy::{
     blocking_mutex::kind::CriticalSection,
-    channel::mpsc,
+    channel::{mpsc, signal::Signal},
     executor::{Executor, Spawner},
     interrupt::InterruptExt,
     task,
-    time::{Duration, Timer},
+    time::{Duration, Instant, Timer},
     util::Forever,
 };
 use embassy_nrf::{
@@ -42,23 +43,28 @@ use pin_utils::pin_mut;
 
 mod battery;
 //mod ble;
+mod bundle;
 mod display;
 mod power_button;
+mod settings;
 
 //use crate::display::DisplayOff;
+use drivers::flash;
+
 use crate::{
     battery::Battery,
     display::{Backlight, DisplayFlashSpi},
 };
 
 const CHANNEL_SIZE: usize = 3;
-const EASY_DMA_SIZE: usize = 255;
-const BG_IMAGE: &[u8] = include_bytes!("../data/pictures/clock_bg.rgb565");
+const BG_IMAGE: &[u8] = include_bytes!("../data/pictures/clock_bg.rle565");
 
 static EXECUTOR: Forever<Executor> = Forever::new();
 static BATTERY_CHANNEL: Forever<battery::Channel> = Forever::new();
 static DISPLAY_FLASH_CHANNEL: Forever<display::Channel> = Forever::new();
 static MAIN_CHANNEL: Forever<Channel<Cmd>> = Forever::new();
+/// Fired by the display task when it reaches a `display::Cmd::Fence`.
+static DISPLAY_DONE: Signal<()> = Signal::new();
 
 type Channel<C, M = CriticalSection, const S: usize = CHANNEL_SIZE> = mpsc::Channel<M, C, S>;
 type Sender<'ch, C, M = CriticalSection, const S: usize = CHANNEL_SIZE> =
@@ -95,8 +101,8 @@ fn main() -> ! {
         let spim0_irq = interrupt::take!(SPIM0_SPIS0_TWIM0_TWIS0_SPI0_TWI0);
         spim0_irq.set_priority(Priority::P2);
         let display_flash_spi = DisplayFlashSpi::new(
-            p.P0_14, p.P0_22, p.P0_23, p.TWISPI0, spim0_irq, p.P0_02, p.P0_03, p.P0_04, p.P0_26,
-            p.P0_25, p.P0_05, p.P0_18,
+            p.P0_14, p.P0_22, p.P0_23, p.PWM0, p.TWISPI0, spim0_irq, p.P0_02, p.P0_03, p.P0_04,
+            p.P0_26, p.P0_25, p.P0_05, p.P0_18,
         );
 
         // Setup battery task
@@ -160,6 +166,11 @@ async fn display_flash_task(
     mut display: DisplayFlashSpi,
     mut channel: Receiver<'static, display::Cmd>,
 ) {
+    // Carry on if these fail: the other device is still usable, and we've logged the problem.
+    display.check_panel().await;
+    if display.check_flash().await {
+        display.mount_settings().await;
+    }
     while let Some(cmd) = channel.recv().await {
         display.handle(cmd).await;
     }
@@ -250,6 +261,7 @@ async fn main_task(
     /* powerup indication */
     //unwrap!(display_channel.send(display::Cmd::PowerOn).await);
 
+    let bg_image = unwrap!(display::Image::parse(BG_IMAGE));
     let mut cnt = 0;
     loop {
         match unwrap!(main_channel.recv().await) {
@@ -258,6 +270,7 @@ async fn main_task(
                 cnt += 1;
                 unwrap!(battery_channel.send(battery::Cmd::SampleBattery).await);
                 defmt::info!("show some stuff");
+                let draw_start = Instant::now();
                 //debug!("sleep off");
                 unwrap!(display_channel.send(display::Cmd::SleepOff).await);
 
@@ -273,38 +286,53 @@ async fn main_task(
                 defmt::debug!("draw image");
                 unwrap!(
                     display_channel
-                        .send(display::Cmd::draw_image(Point::new(2, 2), BG_IMAGE, 4,))
+                        .send(display::Cmd::draw_image(Point::new(2, 2), bg_image, 4,))
                         .await
                 );
                 unwrap!(
                     display_channel
-                        .send(display::Cmd::draw_text(
-                            Point::new(0, 0),
-                            {
-                                let mut s = String::new();
-                                unwrap!(s.push_str("12:32"));
-                                s
-                            },
-                            5,
-                        ))
+                        .send(display::Cmd::DrawText {
+                            text: display::PlacedText::new(
+                                Point::new(0, 0),
+                                {
+                                    let mut s = String::new();
+                                    unwrap!(s.push_str("12:32"));
+                                    s
+                                },
+                                5,
+                            ),
+                            bg: display::Background::Image(display::PlacedImage::new(
+                                Point::new(2, 2),
+                                bg_image,
+                                4
+                            )),
+                        })
                         .await
                 );
                 defmt::debug!("backlight up");
                 unwrap!(
                     display_channel
                         .send(display::Cmd::SetBacklight {
-                            level: Backlight::High,
+                            level: Backlight::HIGH,
+                        })
+                        .await
+                );
+                // Wait for the display to catch up, so the 5 secs start when the screen is lit.
+                unwrap!(
+                    display_channel
+                        .send(display::Cmd::Fence {
+                            done: display::Reply(&DISPLAY_DONE),
                         })
                         .await
                 );
-                // This waits 5 secs after the message was sent - not necessarily the same as when
-                // the operation was completed (TODO).
+                DISPLAY_DONE.wait().await;
+                defmt::debug!("drawing took {=u64}ms", draw_start.elapsed().as_millis());
                 // TODO don't sleep in the main thread, this is just for an example for now.
                 Timer::after(Duration::from_secs(5)).await;
                 unwrap!(
                     display_channel
                         .send(display::Cmd::SetBacklight {
-                            level: Backlight::Off,
+                            level: Backlight::OFF,
                         })
                         .await
                 );
@@ -388,7 +416,7 @@ async fn main_task(
                 unwrap!(
                     display_channel
                         .send(display::Cmd::SetBacklight {
-                            level: Backlight::Off,
+                            level: Backlight::OFF,
                         })
                         .await
                 );
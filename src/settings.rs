@@ -0,0 +1,35 @@
+//! Settings that are kept across a reset, in a key/value store at the end of the external flash.
+//!
+//! The store belongs to the display task (it owns the SPI bus), and is used through
+//! `display::Cmd::ReadSetting`, `WriteSetting` and `RemoveSetting`. The keys below say what type
+//! each setting is: build commands with `Cmd::write_setting` and read replies with `decode`, so
+//! the bytes always go through the right type.
+//!
+//! Key numbers are stored in the flash, so they must never be reused for something else.
+use crate::{
+    display::{Backlight, Rotation},
+    flash::{FlashError, FLASH_SIZE, SECTOR_SIZE},
+};
+use kv::{Key, Value};
+
+/// How many 4K sectors the store uses. Settings are small, but more sectors means fewer erases.
+pub const SECTORS: usize = 8;
+/// The store is at the end of the flash, out of the way of images.
+pub const START: u32 = FLASH_SIZE - SECTORS as u32 * SECTOR_SIZE;
+/// The longest setting that can be sent in a `display::Cmd`.
+pub const MAX_SETTING_LEN: usize = 64;
+
+/// A setting's bytes, on its way to or from the store.
+pub type SettingData = heapless::Vec<u8, MAX_SETTING_LEN>;
+pub type SettingsError = kv::Error<FlashError>;
+
+/// The backlight level to use when the screen is on.
+pub const BACKLIGHT: Key<Backlight> = Key::new(1);
+pub const ROTATION: Key<Rotation> = Key::new(2);
+/// The time zone, in minutes east of UTC.
+pub const TIME_ZONE: Key<i16> = Key::new(3);
+
+/// Decode a setting read with `Cmd::ReadSetting`, as the type of its key.
+pub fn decode<T: Value>(_key: Key<T>, data: &[u8]) -> Option<T> {
+    T::decode(data)
+}
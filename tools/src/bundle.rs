@@ -0,0 +1,115 @@
+use crate::Result;
+use assets::{
+    bundle::{BundleHeader, ENTRY_LEN, HEADER_LEN},
+    crc::crc32,
+    AssetKind, Bundle, Entry, FontData, Image,
+};
+use qu::ick_use::*;
+use std::{
+    convert::TryFrom,
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// A file to go in the bundle.
+struct Asset {
+    name: String,
+    kind: AssetKind,
+    data: Vec<u8>,
+}
+
+pub(crate) fn pack(srcs: Vec<PathBuf>, out: PathBuf) -> Result {
+    let mut assets = Vec::new();
+    for src in srcs {
+        if src.is_dir() {
+            let dir = fs::read_dir(&src)
+                .context(format!("could not read directory \"{}\"", src.display()))?;
+            for file in dir {
+                let path = file?.path();
+                if !path.is_file() {
+                    continue;
+                }
+                match load_asset(&path)? {
+                    Some(asset) => assets.push(asset),
+                    None => log::info!("skipping \"{}\"", path.display()),
+                }
+            }
+        } else {
+            // Something asked for by name should be in the bundle.
+            let asset = load_asset(&src)?.ok_or(format_err!(
+                "\"{}\" is not an image or font the firmware can read",
+                src.display()
+            ))?;
+            assets.push(asset);
+        }
+    }
+
+    assets.sort_by(|a, b| a.name.cmp(&b.name));
+    if let Some(pair) = assets.windows(2).find(|pair| pair[0].name == pair[1].name) {
+        return Err(format_err!(
+            "more than one asset is called \"{}\"",
+            pair[0].name
+        ));
+    }
+    let count = u16::try_from(assets.len())
+        .map_err(|_| format_err!("too many assets ({}), the max is 65535", assets.len()))?;
+
+    let mut offset = HEADER_LEN + assets.len() * ENTRY_LEN;
+    let mut table = Vec::with_capacity(assets.len() * ENTRY_LEN);
+    for (id, asset) in assets.iter().enumerate() {
+        let at = u32::try_from(offset).map_err(|_| format_err!("bundle is over 4GB"))?;
+        let entry = Entry::new(&asset.name, id as u16, asset.kind, at, &asset.data)
+            .map_err(|e| format_err!("can't add \"{}\": {}", asset.name, e))?;
+        table.extend(&entry.to_bytes());
+        offset += asset.data.len();
+    }
+    let header = BundleHeader {
+        count,
+        table_crc: crc32(&table),
+    };
+
+    let mut bundle = header.to_bytes().to_vec();
+    bundle.extend(&table);
+    for asset in &assets {
+        bundle.extend(&asset.data);
+    }
+
+    // Check we can read it back.
+    let parsed = Bundle::parse(&bundle).map_err(|e| format_err!("bundle is invalid: {}", e))?;
+    for entry in parsed.entries() {
+        let entry = entry.map_err(|e| format_err!("bundle is invalid: {}", e))?;
+        parsed
+            .get(&entry)
+            .map_err(|e| format_err!("bundle is invalid: {}", e))?;
+        println!(
+            "{:>5} {:<32} {:?} at {} ({} bytes)",
+            entry.id,
+            entry.name(),
+            entry.kind,
+            entry.offset,
+            entry.len
+        );
+    }
+
+    fs::write(&out, &bundle).context(format!("could not write bundle \"{}\"", out.display()))?;
+    println!("wrote {} assets ({} bytes)", assets.len(), bundle.len());
+    Ok(())
+}
+
+/// Read a file, if it is an image or font the firmware can read.
+fn load_asset(path: &Path) -> Result<Option<Asset>> {
+    let data = fs::read(path).context(format!("could not read \"{}\"", path.display()))?;
+    let kind = if Image::parse(&data).is_ok() {
+        AssetKind::Image
+    } else if FontData::parse(&data).is_ok() {
+        AssetKind::Font
+    } else {
+        return Ok(None);
+    };
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or(format_err!("\"{}\" has no name", path.display()))?
+        .to_owned();
+    Ok(Some(Asset { name, kind, data }))
+}
@@ -0,0 +1,272 @@
+//! Encoders for the image formats in the `assets` crate.
+use assets::{qoi, rle::MAX_PACKET};
+
+/// Raw big-endian rgb565.
+pub fn raw(pixels: &[u16]) -> Vec<u8> {
+    pixels.iter().flat_map(|p| p.to_be_bytes()).collect()
+}
+
+/// Run-length encode pixels (see `assets::rle` for the format).
+pub fn rle(pixels: &[u16]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut literals: Vec<u16> = Vec::new();
+    let mut pos = 0;
+    while pos < pixels.len() {
+        let run = pixels[pos..]
+            .iter()
+            .take(MAX_PACKET)
+            .take_while(|p| **p == pixels[pos])
+            .count();
+        // A run of 2 costs 3 bytes, the same or less than adding them to a literal packet.
+        if run >= 2 {
+            flush_literals(&mut out, &mut literals);
+            out.push(0x80 | (run - 1) as u8);
+            out.extend(&pixels[pos].to_be_bytes());
+            pos += run;
+        } else {
+            literals.push(pixels[pos]);
+            if literals.len() == MAX_PACKET {
+                flush_literals(&mut out, &mut literals);
+            }
+            pos += 1;
+        }
+    }
+    flush_literals(&mut out, &mut literals);
+    out
+}
+
+fn flush_literals(out: &mut Vec<u8>, literals: &mut Vec<u16>) {
+    if literals.is_empty() {
+        return;
+    }
+    out.push((literals.len() - 1) as u8);
+    for p in literals.drain(..) {
+        out.extend(&p.to_be_bytes());
+    }
+}
+
+/// Palette-indexed pixels (see `assets::indexed` for the format), at `bits` bits per pixel.
+///
+/// `palette` must have 1 to 256 colors, and every index must fit in `bits`.
+pub fn indexed(palette: &[u16], indices: &[u8], bits: u8) -> Vec<u8> {
+    let mut out = vec![(palette.len() - 1) as u8];
+    for color in palette {
+        out.extend(&color.to_be_bytes());
+    }
+    out.extend(pack(indices, bits));
+    out
+}
+
+/// An alpha mask (see `assets::mask`), from 8 bit alphas.
+pub fn mask(alphas: &[u8], bits: u8) -> Vec<u8> {
+    let max = (1 << bits) - 1;
+    let scaled: Vec<u8> = alphas
+        .iter()
+        .map(|a| ((u16::from(*a) * max + 127) / 255) as u8)
+        .collect();
+    pack(&scaled, bits)
+}
+
+/// Pack `bits` bit values into bytes, most significant bits first (see `assets::indexed::unpack`).
+pub fn pack(values: &[u8], bits: u8) -> Vec<u8> {
+    let per_byte = usize::from(8 / bits);
+    values
+        .chunks(per_byte)
+        .map(|chunk| {
+            let mut byte = 0;
+            for (i, value) in chunk.iter().enumerate() {
+                byte |= value << (8 - bits as usize * (i + 1));
+            }
+            byte
+        })
+        .collect()
+}
+
+/// A QOI file (see `assets::qoi`), from rgba pixels.
+pub fn qoi(width: u32, height: u32, rgba: &[u8]) -> Vec<u8> {
+    let has_alpha = rgba.chunks(4).any(|p| p[3] != 255);
+    let mut out = qoi::MAGIC.to_vec();
+    out.extend(&width.to_be_bytes());
+    out.extend(&height.to_be_bytes());
+    // channels, and colorspace (sRGB with linear alpha).
+    out.extend(&[if has_alpha { 4 } else { 3 }, 0]);
+
+    let mut cache = [[0; 4]; 64];
+    let mut prev = [0, 0, 0, 255];
+    let mut run = 0;
+    for px in rgba.chunks(4) {
+        let px = [px[0], px[1], px[2], px[3]];
+        if px == prev {
+            run += 1;
+            // 63 and 64 would clash with OP_RGB and OP_RGBA.
+            if run == 62 {
+                out.push(qoi::OP_RUN | (run - 1));
+                run = 0;
+            }
+            continue;
+        }
+        if run > 0 {
+            out.push(qoi::OP_RUN | (run - 1));
+            run = 0;
+        }
+
+        let hash = qoi::hash(px);
+        if cache[hash] == px {
+            out.push(qoi::OP_INDEX | hash as u8);
+        } else if px[3] != prev[3] {
+            out.push(qoi::OP_RGBA);
+            out.extend(&px);
+        } else {
+            let diff = |i: usize| px[i].wrapping_sub(prev[i]) as i8;
+            let (dr, dg, db) = (diff(0), diff(1), diff(2));
+            let (dr_dg, db_dg) = (dr.wrapping_sub(dg), db.wrapping_sub(dg));
+            if [dr, dg, db].iter().all(|d| (-2..=1).contains(d)) {
+                out.push(
+                    qoi::OP_DIFF | ((dr + 2) as u8) << 4 | ((dg + 2) as u8) << 2 | (db + 2) as u8,
+                );
+            } else if (-32..=31).contains(&dg)
+                && (-8..=7).contains(&dr_dg)
+                && (-8..=7).contains(&db_dg)
+            {
+                out.push(qoi::OP_LUMA | (dg + 32) as u8);
+                out.push(((dr_dg + 8) as u8) << 4 | (db_dg + 8) as u8);
+            } else {
+                out.push(qoi::OP_RGB);
+                out.extend(&px[..3]);
+            }
+        }
+        cache[hash] = px;
+        prev = px;
+    }
+    if run > 0 {
+        out.push(qoi::OP_RUN | (run - 1));
+    }
+    out.extend(&qoi::END_MARKER);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assets::{
+        indexed, mask,
+        rle::{self, Decoder},
+    };
+
+    /// Runs of 2, 128 and 129, then 128 pixels that are all different.
+    fn pixels() -> Vec<u16> {
+        let mut pixels = vec![0x1111; 2];
+        pixels.extend([0x2222; 128]);
+        pixels.extend([0x3333; 129]);
+        pixels.extend((0..128).map(|i| i * 3));
+        pixels
+    }
+
+    #[test]
+    fn rle_round_trip() {
+        let pixels = pixels();
+        let data = rle(&pixels);
+        // Four runs (the run of 129 takes two), then one literal packet.
+        assert_eq!(data.len(), 4 * 3 + 1 + 128 * 2);
+        assert_eq!(rle::pixel_count(&data), Ok(pixels.len()));
+        assert_eq!(Decoder::new(&data).collect::<Vec<_>>(), pixels);
+        assert_eq!(rle(&[]), []);
+    }
+
+    #[test]
+    fn rle_single_pixels_are_literals() {
+        // A run of 1 between literals doesn't break the packet.
+        let pixels = [1, 2, 3, 3, 4];
+        assert_eq!(rle(&pixels), [0x01, 0, 1, 0, 2, 0x81, 0, 3, 0x00, 0, 4]);
+        // Literals are split every 128 pixels.
+        let pixels: Vec<u16> = (0..129).collect();
+        let data = rle(&pixels);
+        assert_eq!((data[0], data[1 + 128 * 2]), (127, 0));
+        assert_eq!(Decoder::new(&data).collect::<Vec<_>>(), pixels);
+    }
+
+    #[test]
+    fn indexed_round_trip() {
+        let palette = [0xF800, 0x07E0, 0x001F];
+        let indices = [0, 1, 2, 1, 0];
+        let data = indexed(&palette, &indices, 2);
+        // The palette, then 5 indices in 2 bytes.
+        assert_eq!(data.len(), 1 + 3 * 2 + 2);
+        let image = indexed::Indexed::parse(&data, 2, indices.len()).unwrap();
+        let decoded: Vec<u16> = indexed::Decoder::new(image).collect();
+        assert_eq!(decoded, [0xF800, 0x07E0, 0x001F, 0x07E0, 0xF800]);
+    }
+
+    /// QOI data (after the header), decoded back to rgba.
+    fn qoi_rgba(data: &[u8], pixel_count: usize) -> Vec<[u8; 4]> {
+        let mut decoder = qoi::Decoder::new(&data[qoi::HEADER_LEN..], pixel_count);
+        let mut cache = qoi::Cache::new();
+        std::iter::from_fn(|| decoder.next_rgba(&mut cache)).collect()
+    }
+
+    #[test]
+    fn qoi_round_trip() {
+        // A run too long for one op, a small step, a color from the cache, a bigger step, a jump,
+        // and a change of alpha.
+        let mut pixels = vec![[10, 20, 30, 255]; 70];
+        pixels.extend([
+            [11, 19, 30, 255],
+            [10, 20, 30, 255],
+            [35, 46, 52, 255],
+            [200, 0, 100, 255],
+            [200, 0, 100, 128],
+        ]);
+        let data = qoi(5, 15, &pixels.concat());
+        assert_eq!(data[..4], qoi::MAGIC);
+        // 4 channels, as there is some alpha.
+        assert_eq!(data[12], 4);
+        assert_eq!(
+            data[qoi::HEADER_LEN..data.len() - 8],
+            [
+                qoi::OP_RGB,
+                10,
+                20,
+                30,
+                qoi::OP_RUN | 61,
+                qoi::OP_RUN | 6,
+                qoi::OP_DIFF | 0b11_01_10,
+                qoi::OP_INDEX | qoi::hash([10, 20, 30, 255]) as u8,
+                qoi::OP_LUMA | (26 + 32),
+                7 << 4 | 4,
+                qoi::OP_RGB,
+                200,
+                0,
+                100,
+                qoi::OP_RGBA,
+                200,
+                0,
+                100,
+                128,
+            ]
+        );
+        assert_eq!(data[data.len() - 8..], qoi::END_MARKER);
+        assert_eq!(qoi_rgba(&data, pixels.len()), pixels);
+    }
+
+    /// The `.qoi` pictures are what `convert-qoi` makes of the pngs next to them.
+    #[test]
+    fn qoi_pictures() {
+        let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("../data/pictures");
+        for name in ["clock_bg", "stardew-chickens"] {
+            let png = image::open(dir.join(name).with_extension("png")).unwrap();
+            let png = png.to_rgba8();
+            let data = qoi(png.width(), png.height(), png.as_raw());
+            let expected = std::fs::read(dir.join(name).with_extension("qoi")).unwrap();
+            assert!(data == expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn mask_rounds_alphas() {
+        assert_eq!(mask(&[0, 127, 128, 255], 1), [0b0011_0000]);
+        let data = mask(&[0, 17, 136, 255], 4);
+        assert_eq!(data, [0x01, 0x8F]);
+        let alphas: Vec<u8> = mask::Alphas::new(&data, 4, 4).collect();
+        assert_eq!(alphas, [0, 17, 136, 255]);
+    }
+}
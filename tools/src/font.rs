@@ -1,11 +1,11 @@
-use crate::{load_image, ConvertFont, Result};
+use crate::{encode, load_image, ConvertFont, Result};
+use assets::{indexed, FontData};
 use image::{GenericImageView, Pixel, Rgba, RgbaImage};
 use qu::ick_use::*;
 use std::{
     collections::{BTreeMap, HashSet},
     convert::{TryFrom, TryInto},
-    fmt::{self, Write},
-    fs,
+    fmt, fs,
     path::Path,
 };
 
@@ -45,12 +45,14 @@ pub(crate) fn convert_font(config: ConvertFont) -> Result {
         font_gen.print_ch(ch as u8);
     }
 
-    fs::write(&config.dst, &font_gen.gen())?;
+    let data = font_gen.to_bytes()?;
+    FontData::parse(&data).map_err(|e| format_err!("encoded font is invalid: {}", e))?;
+    fs::write(&config.dst, &data)?;
 
     Ok(())
 }
 
-/// A structure that can be serialized as a font into rust source code
+/// A font, ready to be written out as a font file (see `assets::font`).
 struct FontGen {
     /// Height of font (no concept of baseline etc.).
     height: u32,
@@ -65,50 +67,27 @@ struct FontGen {
 }
 
 impl FontGen {
-    fn gen(&self) -> String {
-        let mut out = format!(
-            "Font {{\n\
-            height: {},\n\
-            palette: [{}, {}, {}],\n\
-            keys: [{}",
-            self.height, self.palette[0], self.palette[1], self.palette[2], self.keys[0]
-        );
-
-        for i in 1..128 {
-            write!(out, ", {}", self.keys[i]).unwrap();
-        }
-        let mut i = self.pixels.iter();
-        write!(out, "],\npixels: &[{}", i.next().unwrap()).unwrap();
-        for p in i {
-            write!(out, ", {}", p).unwrap();
-        }
-        write!(out, "]\n}}").unwrap();
-        out
+    /// The font as a file, to be built into the firmware or packed into an asset bundle.
+    fn to_bytes(&self) -> Result<Vec<u8>> {
+        let height = u16::try_from(self.height)
+            .map_err(|_| format_err!("font is too tall ({}), the max is 65535", self.height))?;
+        let mut out = assets::font::header(height, self.palette, &self.keys).to_vec();
+        out.extend(&self.pixels);
+        Ok(out)
     }
 
     /// For testing
     fn print_ch(&self, ch: u8) {
-        fn val_at(buf: &[u8], pos: usize) -> u8 {
-            let byte = buf[pos / 4];
-            match pos % 4 {
-                3 => byte & 0b11,
-                2 => (byte >> 2) & 0b11,
-                1 => (byte >> 4) & 0b11,
-                0 => (byte >> 6) & 0b11,
-                _ => panic!(),
-            }
-        }
-
         let offset = self.keys[ch as usize];
         if offset == u32::MAX {
             panic!("{} not supported", ch as char);
         }
         let offset = offset as usize;
         let len = u32::from_be_bytes(self.pixels[offset..offset + 4].try_into().unwrap()) as usize;
-        let pixels = &self.pixels[offset + 4..offset + 4 + (len + 3) / 4];
+        let pixels = &self.pixels[offset + 4..offset + 4 + len.div_ceil(4)];
         let width = len / usize::try_from(self.height).unwrap();
         for idx in 0..len {
-            match val_at(pixels, idx) {
+            match indexed::unpack(pixels, 2, idx) {
                 0 => print!(" "),
                 1 => print!("█"),
                 2 => print!("▒"),
@@ -173,28 +152,19 @@ impl SourceImage {
         let len = width * self.height;
         px.extend_from_slice(&len.to_be_bytes());
 
-        // Then write pixel data. 4 pixels per byte. Last byte extended with 0.
-        let mut byte_idx = 0;
-        let mut byte = 0; // accumulator
-        for (_x, _y, pixel) in self.img.view(x, 0, width, self.height).pixels() {
-            //log::debug!("handling pixel {}x{}", x, y);
-            if byte_idx == 4 {
-                byte_idx = 0;
-                px.push(byte);
-                byte = 0;
-            }
-
-            byte = (byte << 2) | self.color_idx(pixel);
-
-            byte_idx += 1;
-        }
-        if byte_idx != 0 {
-            px.push(byte);
-        }
+        // Then write pixel data, packed like an indexed image. 4 pixels per byte, the last byte
+        // extended with 0.
+        let indices: Vec<u8> = self
+            .img
+            .view(x, 0, width, self.height)
+            .pixels()
+            .map(|(_x, _y, pixel)| self.color_idx(pixel))
+            .collect();
+        px.extend(encode::pack(&indices, 2));
 
         // sanity check
         assert!(
-            old_len + 4 + (usize::try_from(len).unwrap() + 3) / 4 == px.len(),
+            old_len + 4 + usize::try_from(len).unwrap().div_ceil(4) == px.len(),
             "{} + 4 + ({} + 3) / 4 == {}",
             old_len,
             len,
@@ -1,9 +1,13 @@
+mod bundle;
+mod encode;
 mod font;
 
-use crate::font::convert_font;
+use crate::{bundle::pack, font::convert_font};
+use assets::{Encoding, Image, ImageHeader};
 use image::{DynamicImage, GenericImageView, Pixel};
 use qu::ick_use::*;
 use std::{
+    convert::TryInto,
     fs,
     path::{Path, PathBuf},
     str::FromStr,
@@ -29,9 +33,45 @@ enum Cmd {
         /// If not set, then the input image size will be used.
         #[structopt(long, short, parse(try_from_str))]
         size: Option<Size>,
+        /// How to store the pixels: 'raw', 'rle' (run-length encoded) or 'indexed' (with a
+        /// palette).
+        #[structopt(long, short, default_value = "raw")]
+        encoding: OutputEncoding,
+        /// The most colors in the palette, for 'indexed' (2 to 256). The image is quantized if it
+        /// has more.
+        #[structopt(long, default_value = "16")]
+        colors: usize,
+        /// Add an alpha mask with 1 or 4 bits per pixel, from the image's alpha channel. Without
+        /// this, alpha is ignored.
+        #[structopt(long)]
+        mask: Option<u8>,
     },
-    /// Converts a font into the format we expect. Outputs rust code.
+    /// Converts images (e.g. the PNGs in data/pictures) into QOI files, which the firmware can
+    /// draw as they are.
+    ConvertQoi {
+        /// The images to convert.
+        #[structopt(parse(from_os_str), required = true)]
+        srcs: Vec<PathBuf>,
+        /// Where to put the `.qoi` files. Defaults to next to each source image.
+        #[structopt(long, short, parse(from_os_str))]
+        out_dir: Option<PathBuf>,
+    },
+    /// Converts a font into the format we expect (see `assets::font`). The firmware's built in
+    /// font is `data/fonts/build/font.bin`.
     ConvertFont(ConvertFont),
+    /// Packs images and fonts into an asset bundle, to be written to the external flash.
+    ///
+    /// Directories are searched (not recursively) for images and fonts the firmware can read
+    /// (e.g. from `convert-image`, `convert-qoi` or `convert-font`), and anything else
+    /// is skipped. Each asset is named after its file, and numbered in order of name.
+    Pack {
+        /// Files, or directories (e.g. data/pictures and data/fonts).
+        #[structopt(parse(from_os_str), required = true)]
+        srcs: Vec<PathBuf>,
+        /// Where to put the bundle.
+        #[structopt(long, short, parse(from_os_str))]
+        out: PathBuf,
+    },
 }
 
 #[derive(StructOpt)]
@@ -75,16 +115,54 @@ impl FromStr for Size {
     }
 }
 
+pub enum OutputEncoding {
+    Raw,
+    Rle,
+    Indexed,
+}
+
+impl FromStr for OutputEncoding {
+    type Err = Error;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input {
+            "raw" => Ok(OutputEncoding::Raw),
+            "rle" => Ok(OutputEncoding::Rle),
+            "indexed" => Ok(OutputEncoding::Indexed),
+            _ => Err(format_err!(
+                "unknown encoding \"{}\" (expected raw, rle or indexed)",
+                input
+            )),
+        }
+    }
+}
+
 #[qu::ick]
 fn main(opt: Opt) -> Result {
     match opt.cmd {
-        Cmd::ConvertImage { src, dst, size } => convert_image(src, dst, size)?,
+        Cmd::ConvertImage {
+            src,
+            dst,
+            size,
+            encoding,
+            colors,
+            mask,
+        } => convert_image(src, dst, size, encoding, colors, mask)?,
+        Cmd::ConvertQoi { srcs, out_dir } => convert_qoi(srcs, out_dir)?,
         Cmd::ConvertFont(config) => convert_font(config)?,
+        Cmd::Pack { srcs, out } => pack(srcs, out)?,
     }
     Ok(())
 }
 
-fn convert_image(src: PathBuf, dst: PathBuf, size: Option<Size>) -> Result {
+fn convert_image(
+    src: PathBuf,
+    dst: PathBuf,
+    size: Option<Size>,
+    encoding: OutputEncoding,
+    colors: usize,
+    mask: Option<u8>,
+) -> Result {
     let src = load_image(&src)?;
 
     if let Some(size) = size {
@@ -96,38 +174,134 @@ fn convert_image(src: PathBuf, dst: PathBuf, size: Option<Size>) -> Result {
         }
     }
 
-    let img_out = image_to_bytes_rgb565(&src);
+    let pixels = image_to_rgb565(&src);
+    let (encoding, data) = match encoding {
+        OutputEncoding::Raw => (Encoding::Rgb565, encode::raw(&pixels)),
+        OutputEncoding::Rle => (Encoding::Rle565, encode::rle(&pixels)),
+        OutputEncoding::Indexed => {
+            if !(2..=256).contains(&colors) {
+                return Err(format_err!("--colors must be 2 to 256, not {}", colors));
+            }
+            let (palette, indices) = quantize(&src, &pixels, colors);
+            println!("using a palette of {} colors", palette.len());
+            let encoding = Encoding::indexed_for(palette.len()).unwrap();
+            let bits = encoding.index_bits().unwrap();
+            (encoding, encode::indexed(&palette, &indices, bits))
+        }
+    };
+    let mut header = image_header(&src, encoding)?;
+    let mask = match mask {
+        Some(bits @ 1) | Some(bits @ 4) => {
+            header = header.with_mask(bits);
+            let alphas: Vec<u8> = src.pixels().map(|(_, _, pixel)| pixel[3]).collect();
+            encode::mask(&alphas, bits)
+        }
+        Some(bits) => return Err(format_err!("--mask must be 1 or 4, not {}", bits)),
+        None => vec![],
+    };
+    let mut img_out = header.to_bytes().to_vec();
+    img_out.extend(&mask);
+    img_out.extend(&data);
+    // Check we can read it back.
+    Image::parse(&img_out).map_err(|e| format_err!("encoded image is invalid: {}", e))?;
     fs::write(&dst, &img_out)
         .context(format!("could not write dst image \"{}\"", dst.display()))?;
+    println!(
+        "wrote {} bytes of pixel data ({:.1}% of raw rgb565)",
+        data.len(),
+        100. * data.len() as f64 / (pixels.len() * 2) as f64
+    );
+    if !mask.is_empty() {
+        println!("and {} bytes of mask", mask.len());
+    }
 
     Ok(())
 }
 
-fn image_to_bytes_rgb565(img: &DynamicImage) -> Vec<u8> {
-    /// Scale a number in f64 space then round.
-    fn scale(input: u8, scale: f64) -> u8 {
-        (input as f64 * scale) as u8
+fn convert_qoi(srcs: Vec<PathBuf>, out_dir: Option<PathBuf>) -> Result {
+    for src in srcs {
+        let img = load_image(&src)?;
+        // The firmware can't draw anything bigger.
+        image_header(&img, Encoding::Qoi)?;
+        let data = encode::qoi(img.width(), img.height(), &img.to_rgba8().into_raw());
+        Image::parse(&data).map_err(|e| format_err!("encoded image is invalid: {}", e))?;
+
+        let dst = match &out_dir {
+            Some(dir) => dir.join(src.file_name().unwrap()),
+            None => src.clone(),
+        }
+        .with_extension("qoi");
+        fs::write(&dst, &data)
+            .context(format!("could not write dst image \"{}\"", dst.display()))?;
+        println!(
+            "wrote \"{}\" ({} bytes, {:.1}% of raw rgb565)",
+            dst.display(),
+            data.len(),
+            100. * data.len() as f64 / (img.width() * img.height() * 2) as f64
+        );
     }
-    // first 2 bytes are width and height (u8)
-    let (width, height) = img.dimensions();
-    assert!(
-        width < 256 && height < 256,
-        "only support images upto 256 (the screen is 240)"
-    );
-    let mut output = vec![width as u8, height as u8];
+    Ok(())
+}
+
+fn image_to_rgb565(img: &DynamicImage) -> Vec<u16> {
+    let mut output = Vec::with_capacity((img.width() * img.height()) as usize);
     for (_x, _y, pixel) in img.pixels() {
         // pixel is type Rgba<u8>, which makes things nice
         let chan = pixel.channels();
-        let r = scale(chan[0], 31. / 255.) as u16; // 5 bit
-        let g = scale(chan[1], 63. / 255.) as u16; // 6 bit
-        let b = scale(chan[2], 31. / 255.) as u16; // 5 bit
-        let val = r << (5 + 6) | g << 5 | b;
-        output.extend(&val.to_be_bytes());
-        // ignore alpha (unsupported)
+        output.push(rgb565(chan[0], chan[1], chan[2]));
+        // alpha goes in the mask, if there is one
     }
     output
 }
 
+fn rgb565(r: u8, g: u8, b: u8) -> u16 {
+    /// Scale a number in f64 space then round.
+    fn scale(input: u8, scale: f64) -> u16 {
+        (input as f64 * scale) as u16
+    }
+    let r = scale(r, 31. / 255.); // 5 bit
+    let g = scale(g, 63. / 255.); // 6 bit
+    let b = scale(b, 31. / 255.); // 5 bit
+    r << (5 + 6) | g << 5 | b
+}
+
+/// Pick a palette of at most `colors` colors, and the palette index of each pixel.
+///
+/// If the image already has few enough colors they are used exactly, otherwise they are
+/// picked by a NeuQuant neural network.
+fn quantize(img: &DynamicImage, pixels: &[u16], colors: usize) -> (Vec<u16>, Vec<u8>) {
+    let mut distinct: Vec<u16> = pixels.to_vec();
+    distinct.sort_unstable();
+    distinct.dedup();
+    if distinct.len() <= colors {
+        let indices = pixels
+            .iter()
+            .map(|p| distinct.binary_search(p).unwrap() as u8)
+            .collect();
+        return (distinct, indices);
+    }
+
+    let rgba = img.to_rgba8().into_raw();
+    let quant = color_quant::NeuQuant::new(10, colors, &rgba);
+    let palette = quant
+        .color_map_rgb()
+        .chunks(3)
+        .map(|c| rgb565(c[0], c[1], c[2]))
+        .collect();
+    let indices = rgba.chunks(4).map(|p| quant.index_of(p) as u8).collect();
+    (palette, indices)
+}
+
+fn image_header(img: &DynamicImage, encoding: Encoding) -> Result<ImageHeader> {
+    let (width, height) = img.dimensions();
+    let too_big = || format_err!("image is too big ({}x{}), the max is 65535", width, height);
+    Ok(ImageHeader::new(
+        encoding,
+        width.try_into().map_err(|_| too_big())?,
+        height.try_into().map_err(|_| too_big())?,
+    ))
+}
+
 fn load_image(path: &Path) -> Result<DynamicImage> {
     #[inline]
     fn load_image_inner(path: &Path) -> Result<DynamicImage> {